@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+/// Least recently used cache of decompressed entries, bounded by a byte budget
+pub struct EntryCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<String, (u64, Vec<u8>)>,
+    // Last use of every entry, oldest first
+    order: BTreeMap<u64, String>,
+}
+
+impl EntryCache {
+    pub fn new(budget: usize) -> EntryCache {
+        EntryCache {
+            budget,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Cached content of `key`, marking it as recently used
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.get_with(key, |data| data.to_vec())
+    }
+
+    /// Apply `f` to the cached content of `key` without copying all of it
+    pub fn get_with<R>(&mut self, key: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        self.tick += 1;
+        let (used, data) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        self.order.insert(self.tick, key.to_string());
+        *used = self.tick;
+        Some(f(data))
+    }
+
+    /// Store `data` under `key`, evicting the least recently used entries to stay within budget
+    ///
+    /// Data larger than the whole budget is not cached.
+    pub fn insert(&mut self, key: &str, data: &[u8]) {
+        if data.len() > self.budget {
+            return;
+        }
+        self.remove(key);
+
+        while self.used + data.len() > self.budget {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.used += data.len();
+        self.order.insert(self.tick, key.to_string());
+        self.entries
+            .insert(key.to_string(), (self.tick, data.to_vec()));
+    }
+
+    /// Drop all cached entries
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((used, data)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.used -= data.len();
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::QZFile;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, Write};
+
+// Files packed so far are recorded at most this often, after every batch in tests
+#[cfg(not(test))]
+pub(crate) const INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+#[cfg(test)]
+pub(crate) const INTERVAL: std::time::Duration = std::time::Duration::ZERO;
+
+// Body packed so far for `out_file`, kept when interrupted
+pub(crate) fn body_path(out_file: &str) -> String {
+    format!("{out_file}.partial")
+}
+
+// Log of the files stored in the body at `body_path`
+pub(crate) fn log_path(out_file: &str) -> String {
+    format!("{out_file}.checkpoint")
+}
+
+// First line of a checkpoint log, what the body was packed with
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Settings {
+    // Checksum over everything that changes how files are compressed and ordered
+    pub(crate) fingerprint: u32,
+    // Dictionary the files were compressed with, a trained one would differ on resume
+    pub(crate) dictionary: Option<Vec<u8>>,
+}
+
+// A file packed before the checkpoint, one line each in stored order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum Record {
+    // Archive path and entry as placed in the body
+    Stored(String, QZFile),
+    // Archive path and why it could not be read
+    Failed(String, String),
+}
+
+impl Record {
+    pub(crate) fn path(&self) -> &str {
+        match self {
+            Record::Stored(path, _) | Record::Failed(path, _) => path,
+        }
+    }
+}
+
+// Settings and files recorded at `path`, `None` without a log
+//
+// The last line may have been cut off by the interruption, records end at the first
+// line that does not parse.
+pub(crate) fn load(path: &str) -> Option<(Settings, Vec<Record>)> {
+    let f = fs::File::open(path).ok()?;
+    let mut lines = std::io::BufReader::new(f).lines();
+    let settings = serde_json::from_str(&lines.next()?.ok()?).ok()?;
+    let records = lines
+        .map_while(|line| serde_json::from_str(&line.ok()?).ok())
+        .collect();
+    Some((settings, records))
+}
+
+// Checkpoint log being written while packing
+pub(crate) struct Log {
+    file: fs::File,
+    // Files recorded so far, in stored order
+    pub(crate) recorded: usize,
+    last: std::time::Instant,
+}
+
+impl Log {
+    // Start the log at `path` with `settings` and the files already in the body
+    //
+    // Written next to it and renamed, so an interruption leaves the previous log.
+    pub(crate) fn create(
+        path: &str,
+        settings: &Settings,
+        records: &[Record],
+    ) -> std::io::Result<Log> {
+        let tmp = format!("{path}.tmp");
+        let mut w = std::io::BufWriter::new(fs::File::create(&tmp)?);
+        writeln!(w, "{}", serde_json::to_string(settings)?)?;
+        for record in records {
+            writeln!(w, "{}", serde_json::to_string(record)?)?;
+        }
+        w.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_data()?;
+        fs::rename(&tmp, path)?;
+        Ok(Log {
+            file: fs::OpenOptions::new().append(true).open(path)?,
+            recorded: records.len(),
+            last: std::time::Instant::now(),
+        })
+    }
+
+    // Whether the next checkpoint is due
+    pub(crate) fn due(&self) -> bool {
+        self.last.elapsed() >= INTERVAL
+    }
+
+    // Record `records`, whose data has to be on disk already
+    pub(crate) fn append(&mut self, records: &[Record]) -> std::io::Result<()> {
+        let mut lines = vec![];
+        for record in records {
+            lines.extend(serde_json::to_vec(record)?);
+            lines.push(b'\n');
+        }
+        self.file.write_all(&lines)?;
+        self.file.sync_data()?;
+        self.recorded += records.len();
+        self.last = std::time::Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use crate::CompressionAlgo;
+use std::io::{BufRead, Read, Write};
+
+/// Compression level used unless configured otherwise
+pub const DEFAULT_LEVEL: i32 = 5;
+
+/// LZ4-HC level used for high compression
+pub const LZ4_HC_LEVEL: u32 = 9;
+
+/// Largest zstd dictionary trained for an archive, the default size of zstd
+pub const MAX_DICTIONARY_SIZE: usize = 112_640;
+
+/// Magic number at the start of trained zstd dictionaries (little endian)
+pub const DICTIONARY_MAGIC: [u8; 4] = [0x37, 0xa4, 0x30, 0xec];
+
+/// Smallest zstd window log, see `compress_zstd_tuned`
+pub const MIN_WINDOW_LOG: u32 = 10;
+
+/// Largest zstd window log every reader decodes without extra settings,
+/// the pure Rust decoder used on wasm accepts windows of up to 100 MiB
+pub const MAX_WINDOW_LOG: u32 = 26;
+
+/// First archive format version storing LZ4 data as frames,
+/// earlier versions store raw LZ4 blocks without any framing
+pub const LZ4_FRAME_VERSION: u8 = 2;
+
+/// Compress `data` with `algo`
+///
+/// `level` only applies to algorithms that support levels.
+/// LZ4 data is written in the LZ4 frame format.
+pub fn compress(data: &[u8], algo: &CompressionAlgo, level: i32) -> std::io::Result<Vec<u8>> {
+    compress_versioned(data, algo, level, crate::FORMAT_VERSION)
+}
+
+/// Decompress `data` that was compressed with `algo`
+pub fn decompress(data: &[u8], algo: &CompressionAlgo) -> std::io::Result<Vec<u8>> {
+    decompress_with_limit(data, algo, u64::MAX - 1)
+}
+
+/// Decompress at most `limit + 1` bytes of `data`,
+/// so output larger than `limit` is detected without inflating all of it
+pub fn decompress_with_limit(
+    data: &[u8],
+    algo: &CompressionAlgo,
+    limit: u64,
+) -> std::io::Result<Vec<u8>> {
+    decompress_versioned(data, algo, limit, crate::FORMAT_VERSION, &[])
+}
+
+/// Train a zstd dictionary of at most `max_size` bytes on `samples`
+///
+/// Fails if there is too little sample data to train on.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> std::io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+/// Training dictionaries needs the C library, which is not built for wasm
+#[cfg(target_arch = "wasm32")]
+pub fn train_dictionary(_samples: &[Vec<u8>], _max_size: usize) -> std::io::Result<Vec<u8>> {
+    Err(unsupported("zstd dictionary"))
+}
+
+/// Compress `data` with zstd at `level`, using a dictionary from `train_dictionary`
+///
+/// The same dictionary is needed to decompress the result.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn compress_with_dictionary(
+    data: &[u8],
+    level: i32,
+    dictionary: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    let mut encoder = zstd::stream::write::Encoder::with_dictionary(vec![], level, dictionary)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn compress_with_dictionary(
+    _data: &[u8],
+    _level: i32,
+    _dictionary: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    Err(unsupported("zstd"))
+}
+
+/// Decompress zstd `data` that was compressed with `dictionary`
+pub fn decompress_with_dictionary(data: &[u8], dictionary: &[u8]) -> std::io::Result<Vec<u8>> {
+    decompress_versioned(
+        data,
+        &CompressionAlgo::ZSTD,
+        u64::MAX - 1,
+        crate::FORMAT_VERSION,
+        dictionary,
+    )
+}
+
+/// Compress `data` with zstd at `level`, matching across long distances if `long_distance`
+/// and with a window of `2^window_log` bytes if given
+///
+/// Long-distance matching uses a window of `2^MAX_WINDOW_LOG` bytes unless told otherwise,
+/// the window never exceeds the size of `data` so small files keep a small one.
+/// The result is decoded like any other zstd data. `dictionary` may be empty.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn compress_zstd_tuned(
+    data: &[u8],
+    level: i32,
+    dictionary: &[u8],
+    long_distance: bool,
+    window_log: Option<u32>,
+) -> std::io::Result<Vec<u8>> {
+    let size = data.len() as u64;
+    let mut encoder = Encoder::zstd(vec![], level, dictionary, long_distance, window_log, size)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn compress_zstd_tuned(
+    _data: &[u8],
+    _level: i32,
+    _dictionary: &[u8],
+    _long_distance: bool,
+    _window_log: Option<u32>,
+) -> std::io::Result<Vec<u8>> {
+    Err(unsupported("zstd"))
+}
+
+/// Compress `data` as an LZ4 frame using LZ4-HC at `level`
+///
+/// Slower than `compress`, but smaller and decoded like any other LZ4 frame.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn compress_lz4_hc(data: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+    let mut encoder = Encoder::lz4_hc(vec![], level)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// LZ4-HC needs the C library, which is not built for wasm
+#[cfg(target_arch = "wasm32")]
+pub fn compress_lz4_hc(_data: &[u8], _level: u32) -> std::io::Result<Vec<u8>> {
+    Err(unsupported("LZ4-HC"))
+}
+
+// Streaming compressor writing to `W`, so data is compressed piece by piece
+// without holding all of it
+pub(crate) enum Encoder<W: Write> {
+    #[cfg(not(target_arch = "wasm32"))]
+    Zstd(zstd::stream::zio::Writer<W, zstd::stream::raw::Encoder>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Lz4Hc(lz4::Encoder<W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+    // Stored as is, for uncompressed files being packed
+    #[cfg_attr(not(feature = "fs"), allow(dead_code))]
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    // Zstd at `level` as `compress_zstd_tuned` compresses, `size` bytes of data
+    // bounding the window
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn zstd(
+        out: W,
+        level: i32,
+        dictionary: &[u8],
+        long_distance: bool,
+        window_log: Option<u32>,
+        size: u64,
+    ) -> std::io::Result<Encoder<W>> {
+        use zstd::stream::raw::CParameter;
+
+        let mut encoder = zstd::stream::raw::Encoder::with_dictionary(level, dictionary)?;
+        if long_distance {
+            encoder.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+        }
+        let window_log = window_log.or(long_distance.then_some(MAX_WINDOW_LOG));
+        if let Some(window_log) = window_log {
+            let needed = size.next_power_of_two().trailing_zeros();
+            let window_log = window_log.min(needed.max(MIN_WINDOW_LOG));
+            encoder.set_parameter(CParameter::WindowLog(window_log))?;
+        }
+        Ok(Encoder::Zstd(zstd::stream::zio::Writer::new(out, encoder)))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn zstd(
+        _out: W,
+        _level: i32,
+        _dictionary: &[u8],
+        _long_distance: bool,
+        _window_log: Option<u32>,
+        _size: u64,
+    ) -> std::io::Result<Encoder<W>> {
+        Err(unsupported("zstd"))
+    }
+
+    // LZ4 frames using LZ4-HC at `level`, see `compress_lz4_hc`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn lz4_hc(out: W, level: u32) -> std::io::Result<Encoder<W>> {
+        lz4::EncoderBuilder::new()
+            .level(level)
+            .build(out)
+            .map(Encoder::Lz4Hc)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn lz4_hc(_out: W, _level: u32) -> std::io::Result<Encoder<W>> {
+        Err(unsupported("LZ4-HC"))
+    }
+
+    // LZ4 frames as `compress` writes them
+    pub(crate) fn lz4(out: W) -> Encoder<W> {
+        Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(out))
+    }
+
+    // End the compressed stream, returning the writer it went to
+    pub(crate) fn finish(self) -> std::io::Result<W> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Zstd(mut w) => {
+                w.finish()?;
+                Ok(w.into_inner().0)
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Lz4Hc(w) => {
+                let (out, res) = w.finish();
+                res.map(|_| out)
+            }
+            Encoder::Lz4(w) => w.finish().map_err(std::io::Error::other),
+            Encoder::None(out) => Ok(out),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Zstd(w) => w.write(buf),
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Lz4Hc(w) => w.write(buf),
+            Encoder::Lz4(w) => w.write(buf),
+            Encoder::None(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Zstd(w) => w.flush(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Encoder::Lz4Hc(w) => w.flush(),
+            Encoder::Lz4(w) => w.flush(),
+            Encoder::None(w) => w.flush(),
+        }
+    }
+}
+
+// Streaming zstd decoder, the C library where it builds and a pure Rust decoder on wasm
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type ZstdDecoder<R> = zstd::stream::read::Decoder<R>;
+
+// Boxed since the pure Rust decoder keeps its large buffers inline
+#[cfg(target_arch = "wasm32")]
+pub(crate) type ZstdDecoder<R> = Box<ruzstd::StreamingDecoder<R, ruzstd::FrameDecoder>>;
+
+// Decoder for zstd data compressed with `dictionary`, empty for data compressed without one
+pub(crate) fn zstd_decoder<R: BufRead>(
+    reader: R,
+    dictionary: &[u8],
+) -> std::io::Result<ZstdDecoder<R>> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return zstd::stream::read::Decoder::with_dictionary(reader, dictionary);
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        let mut decoder = ruzstd::FrameDecoder::new();
+        if !dictionary.is_empty() {
+            let dictionary = ruzstd::decoding::dictionary::Dictionary::decode_dict(dictionary)
+                .map_err(std::io::Error::other)?;
+            decoder
+                .add_dict(dictionary)
+                .map_err(std::io::Error::other)?;
+        }
+        ruzstd::StreamingDecoder::new_with_decoder(reader, decoder)
+            .map(Box::new)
+            .map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn unsupported(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{what} compression is not available on this target"),
+    )
+}
+
+// Compress for an archive of format version `format`
+pub(crate) fn compress_versioned(
+    data: &[u8],
+    algo: &CompressionAlgo,
+    level: i32,
+    format: u8,
+) -> std::io::Result<Vec<u8>> {
+    match algo {
+        #[cfg(not(target_arch = "wasm32"))]
+        CompressionAlgo::ZSTD => zstd::stream::encode_all(data, level),
+        #[cfg(target_arch = "wasm32")]
+        CompressionAlgo::ZSTD => {
+            let _ = level;
+            Err(unsupported("zstd"))
+        }
+        CompressionAlgo::LZ4 if format < LZ4_FRAME_VERSION => {
+            Ok(lz4_compression::compress::compress(data))
+        }
+        CompressionAlgo::LZ4 => {
+            let mut encoder = Encoder::lz4(vec![]);
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        CompressionAlgo::NONE => Ok(data.to_vec()),
+    }
+}
+
+// Decompress data of an archive of format version `format`, see `decompress_with_limit`,
+// zstd data with `dictionary` unless it is empty
+pub(crate) fn decompress_versioned(
+    data: &[u8],
+    algo: &CompressionAlgo,
+    limit: u64,
+    format: u8,
+    dictionary: &[u8],
+) -> std::io::Result<Vec<u8>> {
+    let mut decoded = vec![];
+    match algo {
+        CompressionAlgo::ZSTD => {
+            zstd_decoder(data, dictionary)?
+                .take(limit + 1)
+                .read_to_end(&mut decoded)?;
+        }
+        // Raw blocks carry no length, so they can only be inflated as a whole
+        CompressionAlgo::LZ4 if format < LZ4_FRAME_VERSION => {
+            decoded = match lz4_compression::decompress::decompress(data) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{err:?}"),
+                    ))
+                }
+            };
+        }
+        CompressionAlgo::LZ4 => {
+            lz4_flex::frame::FrameDecoder::new(data)
+                .take(limit + 1)
+                .read_to_end(&mut decoded)?;
+        }
+        CompressionAlgo::NONE => decoded = data.to_vec(),
+    }
+    Ok(decoded)
+}
+
+// Decompress as much of damaged `data` as possible, see `decompress_versioned`,
+// keeping what was decoded before the damage
+#[cfg(feature = "fs")]
+pub(crate) fn decompress_partial(
+    data: &[u8],
+    algo: &CompressionAlgo,
+    limit: u64,
+    format: u8,
+) -> Vec<u8> {
+    let mut decoded = vec![];
+    match algo {
+        CompressionAlgo::ZSTD => {
+            if let Ok(decoder) = zstd_decoder(data, &[]) {
+                let _ = decoder.take(limit + 1).read_to_end(&mut decoded);
+            }
+        }
+        // Raw blocks are only inflated as a whole
+        CompressionAlgo::LZ4 if format < LZ4_FRAME_VERSION => {
+            decoded = lz4_compression::decompress::decompress(data).unwrap_or_default();
+        }
+        CompressionAlgo::LZ4 => {
+            let _ = lz4_flex::frame::FrameDecoder::new(data)
+                .take(limit + 1)
+                .read_to_end(&mut decoded);
+        }
+        CompressionAlgo::NONE => decoded = data.to_vec(),
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_algorithm() {
+        let data = b"round trip data, ".repeat(1000);
+        for algo in [
+            CompressionAlgo::ZSTD,
+            CompressionAlgo::LZ4,
+            CompressionAlgo::NONE,
+        ] {
+            for input in [&data[..], b""] {
+                let compressed = compress(input, &algo, DEFAULT_LEVEL).unwrap();
+                assert_eq!(decompress(&compressed, &algo).unwrap(), input);
+            }
+        }
+        let lz4 = compress(&data, &CompressionAlgo::LZ4, DEFAULT_LEVEL).unwrap();
+        let zstd = compress(&data, &CompressionAlgo::ZSTD, DEFAULT_LEVEL).unwrap();
+        assert!(lz4.len() < data.len() && zstd.len() < data.len());
+    }
+
+    #[test]
+    fn reads_framed_lz4_and_the_raw_blocks_of_older_archives() {
+        let data = b"framed or not, ".repeat(1000);
+        let algo = CompressionAlgo::LZ4;
+        let framed = compress_versioned(&data, &algo, DEFAULT_LEVEL, LZ4_FRAME_VERSION).unwrap();
+        assert_eq!(framed[..4], [0x04, 0x22, 0x4d, 0x18]);
+        let decoded = decompress_versioned(&framed, &algo, u64::MAX - 1, LZ4_FRAME_VERSION, &[]);
+        assert_eq!(decoded.unwrap(), data);
+
+        let raw = lz4_compression::compress::compress(&data);
+        let old = LZ4_FRAME_VERSION - 1;
+        assert_eq!(
+            compress_versioned(&data, &algo, DEFAULT_LEVEL, old).unwrap(),
+            raw
+        );
+        let decoded = decompress_versioned(&raw, &algo, u64::MAX - 1, old, &[]);
+        assert_eq!(decoded.unwrap(), data);
+        assert!(decompress_versioned(&raw, &algo, u64::MAX - 1, LZ4_FRAME_VERSION, &[]).is_err());
+    }
+}
@@ -19,18 +19,86 @@ impl std::fmt::Display for ReadError {
     }
 }
 
+/// Why creating or rewriting an archive failed
+#[derive(Debug)]
+pub enum WriteError {
+    /// A file or directory being packed, or the archive being rewritten, could not be read,
+    /// with its path and the error
+    SourceRead(String, std::io::Error),
+    /// The archive or a file written along with it could not be written, with its path
+    /// and the error
+    Output(String, std::io::Error),
+    /// The named file could not be compressed with the algorithm
+    Compression(String, crate::CompressionAlgo, std::io::Error),
+    /// Options or files that cannot be packed as given, e.g. a level out of range or two
+    /// sources stored under the same name
+    InvalidInput(String),
+    Other(String),
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::SourceRead(_, err)
+            | WriteError::Output(_, err)
+            | WriteError::Compression(_, _, err) => Some(err),
+            WriteError::InvalidInput(_) | WriteError::Other(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WriteError::SourceRead(path, err) => write!(f, "failed to read {path}: {err}"),
+            WriteError::Output(path, err) => write!(f, "failed to write {path}: {err}"),
+            WriteError::Compression(name, algo, err) => {
+                write!(f, "failed to compress {name} with {algo}: {err}")
+            }
+            WriteError::InvalidInput(msg) | WriteError::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum FileReadError {
     NotAFile,
     NotFound,
-    CompressionError,
+    /// A component of the path is a file, given as the path up to and including it
+    NotADirectory(String),
+    /// The path contains `..` or other components that are no names
+    InvalidPath,
+    /// The stored bytes match their checksum but fail to decompress, with the name of the
+    /// entry and why, e.g. a truncated stream
+    CompressionError(String, String),
+    /// Checksum of the stored bytes and the expected one, in this order
     Checksum(u32, u32),
+    /// The archive ends before the stored bytes of the entry do
+    Truncated,
+    UnsafePath(String),
+    Missing(Vec<String>),
     Other(String),
 }
 
 impl std::fmt::Display for FileReadError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            FileReadError::NotAFile => write!(f, "not a file"),
+            FileReadError::NotFound => write!(f, "not found"),
+            FileReadError::NotADirectory(path) => write!(f, "{path} is not a directory"),
+            FileReadError::InvalidPath => write!(f, "invalid path"),
+            FileReadError::CompressionError(name, reason) => {
+                write!(f, "failed to decompress {name}: {reason}")
+            }
+            FileReadError::Checksum(hash, expected) => write!(
+                f,
+                "checksum mismatch, expected {expected:08x} but got {hash:08x}"
+            ),
+            FileReadError::Truncated => write!(f, "archive is truncated within the stored data"),
+            FileReadError::UnsafePath(path) => write!(f, "unsafe path {path}"),
+            FileReadError::Missing(paths) => write!(f, "not found: {}", paths.join(", ")),
+            FileReadError::Other(msg) => write!(f, "{msg}"),
+        }
     }
 }
 
@@ -39,6 +107,8 @@ impl std::error::Error for FileReadError {}
 #[derive(Debug)]
 pub enum EntryError {
     NothingFound,
+    /// A component of the path is a file, given as the path up to and including it
+    NotADirectory(String),
     PathError,
     Other(String),
 }
@@ -51,9 +121,27 @@ impl std::fmt::Display for EntryError {
 
 impl std::error::Error for EntryError {}
 
+impl From<EntryError> for FileReadError {
+    fn from(err: EntryError) -> FileReadError {
+        match err {
+            EntryError::NothingFound => FileReadError::NotFound,
+            EntryError::NotADirectory(path) => FileReadError::NotADirectory(path),
+            EntryError::PathError => FileReadError::InvalidPath,
+            EntryError::Other(msg) => FileReadError::Other(msg),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ListingError {
+    /// The path is a file, not a directory
     IsFile,
+    /// Nothing exists at the path
+    NotFound,
+    /// A component of the path is a file, given as the path up to and including it
+    NotADirectory(String),
+    /// The path contains `..` or other components that are no names
+    InvalidPath,
     Other(String),
 }
 
@@ -64,3 +152,35 @@ impl std::fmt::Display for ListingError {
 }
 
 impl std::error::Error for ListingError {}
+
+impl From<EntryError> for ListingError {
+    fn from(err: EntryError) -> ListingError {
+        match err {
+            EntryError::NothingFound => ListingError::NotFound,
+            EntryError::NotADirectory(path) => ListingError::NotADirectory(path),
+            EntryError::PathError => ListingError::InvalidPath,
+            EntryError::Other(msg) => ListingError::Other(msg),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseCompressionError {
+    name: String,
+}
+
+impl ParseCompressionError {
+    pub fn new(name: &str) -> ParseCompressionError {
+        ParseCompressionError {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for ParseCompressionError {}
+
+impl std::fmt::Display for ParseCompressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "unknown compression algorithm '{}'", self.name)
+    }
+}
@@ -0,0 +1,1243 @@
+use crate::{errors, in_pool, walk_entries, IoLimit, QZArchive, QZEntry, QZFile};
+use rayon::prelude::*;
+use std::fs;
+
+// Give `path` the stored owner, skipping silently if the process is not allowed to
+#[cfg(unix)]
+fn restore_owner(
+    path: &std::path::Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> std::io::Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    match std::os::unix::fs::chown(path, uid, gid) {
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_owner(
+    _path: &std::path::Path,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &std::path::Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, _path: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::other(
+        "symbolic links can only be extracted on unix",
+    ))
+}
+
+// Links followed when resolving a link before giving up, as for the `ELOOP` limit
+const MAX_LINK_DEPTH: usize = 40;
+
+// Where a link at `link` pointing to `target` leads, both relative to the destination
+//
+// `links` holds the target of every link being extracted, so chains of links are
+// followed regardless of the order they are created in. Returns `None` for absolute
+// targets, targets leaving the destination and chains longer than `depth`.
+fn resolve_link(
+    link: &[String],
+    target: &str,
+    links: &std::collections::HashMap<Vec<String>, String>,
+    depth: usize,
+) -> Option<Vec<String>> {
+    if target.is_empty() {
+        return None;
+    }
+    let (_, parents) = link.split_last()?;
+    let parents = parents
+        .iter()
+        .map(|p| std::path::Component::Normal(p.as_ref()));
+
+    let mut path: Vec<String> = vec![];
+    for c in parents.chain(std::path::Path::new(target).components()) {
+        match c {
+            std::path::Component::Normal(name) => {
+                path.push(name.to_str()?.to_string());
+                if let Some(next) = links.get(&path) {
+                    path = resolve_link(&path, next, links, depth.checked_sub(1)?)?;
+                }
+            }
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                path.pop()?;
+            }
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+// Apply stored extended attributes, skipping those the process or filesystem does not allow
+#[cfg(feature = "xattr")]
+fn restore_xattrs(
+    path: &std::path::Path,
+    attrs: &std::collections::BTreeMap<String, Vec<u8>>,
+) -> std::io::Result<()> {
+    for (name, value) in attrs {
+        match xattr::set(path, name, value) {
+            Err(err)
+                if err.kind() == std::io::ErrorKind::PermissionDenied
+                    || err.kind() == std::io::ErrorKind::Unsupported => {}
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+// Join archive path components onto `dest`, refusing anything that could escape it
+fn safe_join(
+    dest: &std::path::Path,
+    components: &[&str],
+) -> Result<std::path::PathBuf, errors::FileReadError> {
+    let mut path = dest.to_path_buf();
+
+    for c in components {
+        if c.is_empty() || *c == "." || *c == ".." || c.contains(['/', '\\', '\0']) {
+            return Err(errors::FileReadError::UnsafePath(components.join("/")));
+        }
+        path.push(c);
+    }
+
+    Ok(path)
+}
+
+// Longest file name most filesystems accept, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+// Longest path the platform accepts, in bytes
+#[cfg(windows)]
+const MAX_PATH_LEN: usize = 32767;
+#[cfg(not(windows))]
+const MAX_PATH_LEN: usize = 4095;
+
+// Fail if the entry at `a_path` cannot be created at `target` as one of the names below
+// the destination, `location`, or the whole path is too long
+fn check_path_len(
+    a_path: &str,
+    location: &[String],
+    target: &std::path::Path,
+) -> Result<(), errors::FileReadError> {
+    if let Some(name) = location.iter().find(|c| c.len() > MAX_NAME_LEN) {
+        return Err(errors::FileReadError::Other(format!(
+            "cannot extract {a_path}, the name {name} is longer than {MAX_NAME_LEN} bytes"
+        )));
+    }
+    let len = target.as_os_str().len();
+    if len > MAX_PATH_LEN {
+        return Err(errors::FileReadError::Other(format!(
+            "cannot extract {a_path}, its path would be {len} bytes long, \
+             more than the {MAX_PATH_LEN} bytes allowed"
+        )));
+    }
+    Ok(())
+}
+
+// `name`, numbered before its extension if it was taken already
+fn flat_name(name: &str, taken: &mut std::collections::HashSet<String>) -> String {
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+
+    let mut candidate = name.to_string();
+    let mut n = 0;
+    while taken.contains(&candidate) {
+        n += 1;
+        candidate = format!("{stem}-{n}{ext}");
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
+
+/// Mismatch between an archive and a directory on disk, found by `QZArchive::verify_against_dir`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Entry exists in both, but its type, size or content differs
+    Differs(String),
+    /// Entry of the archive is missing from the directory
+    Missing(String),
+    /// Entry of the directory is not part of the archive
+    Extra(String),
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Difference::Differs(path) => write!(f, "{path} differs"),
+            Difference::Missing(path) => write!(f, "{path} is missing"),
+            Difference::Extra(path) => write!(f, "{path} is not in the archive"),
+        }
+    }
+}
+
+// Paths of everything below `dir`, relative to it
+fn walk_dir(dir: &std::path::Path) -> std::io::Result<Vec<Vec<String>>> {
+    let mut paths = vec![];
+    let mut stack = vec![(dir.to_path_buf(), vec![])];
+
+    while let Some((path, components)) = stack.pop() {
+        for e in fs::read_dir(&path)? {
+            let e = e?;
+            let mut e_components: Vec<String> = components.clone();
+            e_components.push(e.file_name().to_string_lossy().to_string());
+            if e.file_type()?.is_dir() {
+                stack.push((e.path(), e_components.clone()));
+            }
+            paths.push(e_components);
+        }
+    }
+
+    Ok(paths)
+}
+
+// Names and links shared by the paths extracted into one destination
+#[derive(Default)]
+struct ExtractState {
+    // Names already taken when flattening
+    flattened: std::collections::HashSet<String>,
+    // Target of each extracted symbolic link by its path relative to the destination
+    links: std::collections::HashMap<Vec<String>, String>,
+    // Archive paths of the files left out by the filter
+    skipped: Vec<String>,
+}
+
+// Create the destination directory `dest` if missing, refusing anything else in its place
+fn prepare_dest(dest: &std::path::Path) -> Result<(), errors::FileReadError> {
+    match fs::metadata(dest) {
+        Ok(m) if m.is_dir() => Ok(()),
+        Ok(_) => Err(errors::FileReadError::Other(format!(
+            "destination {} exists and is not a directory",
+            dest.display()
+        ))),
+        Err(_) => fs::create_dir_all(dest).map_err(|err| {
+            errors::FileReadError::Other(format!(
+                "failed to create destination {}: {err}",
+                dest.display()
+            ))
+        }),
+    }
+}
+
+impl QZFile {
+    // Whether `target` already holds exactly the stored content, with either line endings
+    // if they were normalized
+    fn is_extracted(&self, target: &std::path::Path) -> bool {
+        let (Some(size), Some(checksum)) = (self.original_size, self.original_checksum) else {
+            return false;
+        };
+        match fs::metadata(target) {
+            Ok(m) if m.is_file() && (m.len() == size || self.crlf) => {}
+            _ => return false,
+        }
+        let Ok(data) = fs::read(target) else {
+            return false;
+        };
+        let matches = |data: &[u8]| data.len() as u64 == size && crc32fast::hash(data) == checksum;
+        matches(&data) || self.crlf && crate::crlf_to_lf(&data).is_some_and(|text| matches(&text))
+    }
+}
+
+impl QZArchive {
+    // Write the file `f` stored at `a_path` to `target`, as a hard link to `original` if given,
+    // falling back to a copy where linking fails
+    //
+    // Gives false if the file was read back as `set_verify_written` asks and did not match.
+    fn extract_file(
+        &self,
+        f: &QZFile,
+        a_path: &str,
+        target: &std::path::Path,
+        original: Option<&std::path::Path>,
+        io: &IoLimit,
+    ) -> Result<bool, errors::FileReadError> {
+        let write_err = |err: std::io::Error| {
+            errors::FileReadError::Other(format!("failed to write {}: {err}", target.display()))
+        };
+
+        let done = self.resume && f.is_extracted(target);
+        let linked = done
+            || original.is_some_and(|original| {
+                let _ = fs::remove_file(target);
+                fs::hard_link(original, target).is_ok()
+            });
+        let mut intact = true;
+        if !linked {
+            let mut data = f.read_file(self).map_err(|err| {
+                errors::FileReadError::Other(format!("failed to extract {a_path}: {err}"))
+            })?;
+            if f.crlf && self.restore_line_endings {
+                data = crate::lf_to_crlf(&data);
+            }
+            let (size, checksum) = (data.len() as u64, crc32fast::hash(&data));
+            io.run(|| fs::write(target, data)).map_err(write_err)?;
+            if self.verify_written {
+                intact = io.run(|| fs::read(target)).is_ok_and(|data| {
+                    data.len() as u64 == size && crc32fast::hash(&data) == checksum
+                });
+            }
+        }
+        if self.preserve_owner {
+            restore_owner(target, f.uid, f.gid).map_err(write_err)?;
+        }
+        #[cfg(feature = "xattr")]
+        if self.restore_xattrs {
+            restore_xattrs(target, &f.xattrs).map_err(write_err)?;
+        }
+        self.progress.0.file_done(a_path, f.content_size());
+        Ok(intact)
+    }
+
+    /// Restore the stored owner and group of extracted entries
+    ///
+    /// Only takes effect where the process may change ownership, usually when running as root,
+    /// otherwise ownership is left as is.
+    pub fn set_preserve_owner(&mut self, preserve: bool) {
+        self.preserve_owner = preserve;
+    }
+
+    /// Skip files that were already extracted completely, to continue an interrupted extraction
+    ///
+    /// A file counts as complete if its size and checksum on disk match the stored content,
+    /// entries of archives that predate content checksums are always extracted.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// Extract every file directly into the destination under its own name, dropping directories
+    ///
+    /// When several extracted files share a name, later ones get a numeric suffix
+    /// before their extension in stored order (`notes.txt`, `notes-1.txt`, `notes-2.txt`).
+    pub fn set_flatten(&mut self, flatten: bool) {
+        self.flatten = flatten;
+    }
+
+    /// Write text files stored with normalized line endings with CRLF again, as they were packed
+    ///
+    /// Only files marked with `QZFile::crlf` are affected, see
+    /// `QZArchiveBuilder::normalize_line_endings`. Reading files always gives them with LF.
+    pub fn set_restore_line_endings(&mut self, restore: bool) {
+        self.restore_line_endings = restore;
+    }
+
+    /// Read every extracted file back and compare it with what was written
+    ///
+    /// Catches data damaged on its way to storage, e.g. by a faulty filesystem or network
+    /// share, which checksums checked when reading the archive cannot. Freshly written data
+    /// usually comes back from the system cache, so the disk itself goes unchecked.
+    /// Extraction then fails once all files are written, naming those that differ.
+    pub fn set_verify_written(&mut self, verify: bool) {
+        self.verify_written = verify;
+    }
+
+    /// Reapply stored extended attributes to extracted entries
+    #[cfg(feature = "xattr")]
+    pub fn set_restore_xattrs(&mut self, restore: bool) {
+        self.restore_xattrs = restore;
+    }
+
+    /// Compare the archive against `dir`, e.g. after extracting it or to check a backup
+    ///
+    /// Files are compared by size and content checksum, archives that predate content
+    /// checksums are compared against the decompressed data. Returns every difference,
+    /// an empty list means the directory matches.
+    pub fn verify_against_dir(
+        &self,
+        dir: impl AsRef<std::path::Path>,
+    ) -> Result<Vec<Difference>, errors::FileReadError> {
+        let dir = dir.as_ref();
+        let mut differences = vec![];
+        let mut known = std::collections::HashSet::new();
+
+        for (path, e) in walk_entries(&self.header.root) {
+            let a_path = format!("/{}", path.join("/"));
+            let target = safe_join(dir, &path)?;
+            known.insert(path.join("/"));
+
+            let meta = match fs::symlink_metadata(&target) {
+                Ok(meta) => meta,
+                Err(_) => {
+                    differences.push(Difference::Missing(a_path));
+                    continue;
+                }
+            };
+            let same = match e {
+                QZEntry::Dir(_) => meta.is_dir(),
+                QZEntry::File(f) if f.symlink.is_some() => {
+                    meta.file_type().is_symlink()
+                        && fs::read_link(&target).is_ok_and(|t| t.to_str() == f.symlink.as_deref())
+                }
+                QZEntry::File(f) => {
+                    meta.is_file()
+                        && match (f.original_size, f.original_checksum) {
+                            (Some(_), Some(_)) => f.is_extracted(&target),
+                            _ => {
+                                let data = f.read_file(self).map_err(|err| {
+                                    errors::FileReadError::Other(format!(
+                                        "failed to read {a_path}: {err}"
+                                    ))
+                                })?;
+                                fs::read(&target).is_ok_and(|disk| disk == data)
+                            }
+                        }
+                }
+            };
+            if !same {
+                differences.push(Difference::Differs(a_path));
+            }
+        }
+
+        let on_disk = walk_dir(dir).map_err(|err| {
+            errors::FileReadError::Other(format!("failed to read {}: {err}", dir.display()))
+        })?;
+        let mut extra: Vec<String> = on_disk
+            .into_iter()
+            .map(|path| path.join("/"))
+            .filter(|path| !known.contains(path))
+            .map(|path| format!("/{path}"))
+            .collect();
+        extra.sort();
+        differences.extend(extra.into_iter().map(Difference::Extra));
+
+        Ok(differences)
+    }
+
+    /// Extract the whole archive into `dest`
+    ///
+    /// Like all extraction it creates `dest` if missing and fails up front if something
+    /// other than a directory is in its place.
+    /// Directories are created first, files are then decompressed and written in parallel,
+    /// see `set_threads`.
+    pub fn extract_all(
+        &self,
+        dest: impl AsRef<std::path::Path>,
+        strip: usize,
+    ) -> Result<(), errors::FileReadError> {
+        self.extract("/", dest, strip)
+    }
+
+    /// Extract several files or directories into `dest`, keeping their paths within the archive
+    ///
+    /// Every path that exists is extracted, requested paths missing from the archive
+    /// are reported afterwards as `FileReadError::Missing`. Where all of them go is
+    /// checked first, nothing is written if any of them cannot be extracted.
+    pub fn extract_paths(
+        &self,
+        paths: &[impl AsRef<std::path::Path>],
+        dest: impl AsRef<std::path::Path>,
+        strip: usize,
+    ) -> Result<(), errors::FileReadError> {
+        let mut found = vec![];
+        let mut missing = vec![];
+        for path in paths {
+            let path = path.as_ref();
+            match self.get_entry(path) {
+                Ok(_) => found.push(path),
+                Err(errors::EntryError::NothingFound) => {
+                    missing.push(path.display().to_string());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.extract_into(
+            &found,
+            dest.as_ref(),
+            strip,
+            &|_, _| true,
+            &mut ExtractState::default(),
+        )?;
+
+        if !missing.is_empty() {
+            return Err(errors::FileReadError::Missing(missing));
+        }
+
+        Ok(())
+    }
+
+    /// Extract the file or directory at `path` into `dest`, keeping its path within the archive
+    ///
+    /// `strip` leading components are removed from every extracted path,
+    /// entries left without any components are skipped. Symbolic links that would
+    /// lead outside of `dest` are refused as `FileReadError::UnsafePath`.
+    pub fn extract(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        dest: impl AsRef<std::path::Path>,
+        strip: usize,
+    ) -> Result<(), errors::FileReadError> {
+        self.extract_into(
+            &[path.as_ref()],
+            dest.as_ref(),
+            strip,
+            &|_, _| true,
+            &mut ExtractState::default(),
+        )
+    }
+
+    /// Like `extract`, but only writes the files for which `filter` returns true
+    ///
+    /// `filter` is called with the archive path (`/dir/file`) and the stored file of every
+    /// file and symbolic link below `path`, directories are always created.
+    /// Returns the archive paths of the files it left out.
+    pub fn extract_filtered(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        dest: impl AsRef<std::path::Path>,
+        strip: usize,
+        filter: impl Fn(&str, &QZFile) -> bool,
+    ) -> Result<Vec<String>, errors::FileReadError> {
+        let mut state = ExtractState::default();
+        self.extract_into(&[path.as_ref()], dest.as_ref(), strip, &filter, &mut state)?;
+        Ok(state.skipped)
+    }
+
+    // Extract `paths` together, `state` is shared by all paths extracted into `dest`
+    fn extract_into(
+        &self,
+        paths: &[&std::path::Path],
+        dest: &std::path::Path,
+        strip: usize,
+        filter: &dyn Fn(&str, &QZFile) -> bool,
+        state: &mut ExtractState,
+    ) -> Result<(), errors::FileReadError> {
+        let roots = paths
+            .iter()
+            .map(|path| self.get_entry(path))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Entries below several of the requested paths are extracted once
+        let mut entries = vec![];
+        let mut seen = std::collections::HashSet::new();
+        for (path, entry) in paths.iter().zip(&roots) {
+            let base: Vec<&str> = path
+                .components()
+                .filter_map(|c| match c {
+                    std::path::Component::Normal(name) => name.to_str(),
+                    _ => None,
+                })
+                .collect();
+
+            let below = walk_entries(entry).into_iter().map(|(rel, e)| {
+                let mut e_path = base.clone();
+                e_path.extend(rel);
+                (e_path, e)
+            });
+            for (e_path, e) in std::iter::once((base.clone(), entry)).chain(below) {
+                if seen.insert(e_path.clone()) {
+                    entries.push((e_path, e));
+                }
+            }
+        }
+
+        let write_err = |path: &std::path::Path, err: std::io::Error| {
+            errors::FileReadError::Other(format!("failed to write {}: {err}", path.display()))
+        };
+
+        prepare_dest(dest)?;
+
+        // Decide where everything goes first, so links can be checked against all others
+        // and paths too long to create fail before anything is written
+        let mut located = vec![];
+        for (e_path, e) in entries {
+            if e_path.len() <= strip {
+                continue;
+            }
+            if let QZEntry::File(f) = e {
+                let a_path = format!("/{}", e_path.join("/"));
+                if !filter(&a_path, f) {
+                    state.skipped.push(a_path);
+                    continue;
+                }
+            }
+            let location: Vec<String> = if self.flatten {
+                if let QZEntry::Dir(_) = e {
+                    continue;
+                }
+                vec![flat_name(e.name(), &mut state.flattened)]
+            } else {
+                e_path[strip..].iter().map(|c| c.to_string()).collect()
+            };
+            let components: Vec<&str> = location.iter().map(|c| c.as_str()).collect();
+            let target = safe_join(dest, &components)?;
+            check_path_len(&format!("/{}", e_path.join("/")), &location, &target)?;
+            if let QZEntry::File(QZFile {
+                symlink: Some(link),
+                ..
+            }) = e
+            {
+                state.links.insert(location.clone(), link.clone());
+            }
+            located.push((e_path, location, target, e));
+        }
+
+        let progress = self.progress.0.as_ref();
+        let files = located.iter().filter_map(|(_, _, _, e)| match e {
+            QZEntry::File(f) => Some(f.content_size()),
+            QZEntry::Dir(_) => None,
+        });
+        progress.add_work(files.clone().count() as u64, files.sum());
+
+        // Directories and symbolic links go first, files are written once all exist
+        let mut files = vec![];
+        for (e_path, location, target, e) in located {
+            match e {
+                QZEntry::Dir(d) => {
+                    fs::create_dir_all(&target).map_err(|err| write_err(&target, err))?;
+                    if self.preserve_owner {
+                        restore_owner(&target, d.uid, d.gid)
+                            .map_err(|err| write_err(&target, err))?;
+                    }
+                    #[cfg(feature = "xattr")]
+                    if self.restore_xattrs {
+                        restore_xattrs(&target, &d.xattrs)
+                            .map_err(|err| write_err(&target, err))?;
+                    }
+                }
+                QZEntry::File(f) => {
+                    if let Some(parent) = target.parent() {
+                        fs::create_dir_all(parent).map_err(|err| write_err(parent, err))?;
+                    }
+                    // Links leaving `dest` would let later entries be written outside of it
+                    if let Some(link) = &f.symlink {
+                        if resolve_link(&location, link, &state.links, MAX_LINK_DEPTH).is_none() {
+                            return Err(errors::FileReadError::UnsafePath(format!(
+                                "{} -> {link}",
+                                location.join("/")
+                            )));
+                        }
+                        let _ = fs::remove_file(&target);
+                        create_symlink(link, &target).map_err(|err| write_err(&target, err))?;
+                        progress.file_done(&format!("/{}", e_path.join("/")), 0);
+                        continue;
+                    }
+                    files.push((format!("/{}", e_path.join("/")), target, f));
+                }
+            }
+        }
+
+        // Hard links to a file extracted along with them are made once it is written
+        let written: std::collections::HashSet<&str> =
+            files.iter().map(|(a_path, _, _)| a_path.as_str()).collect();
+        let (links, files): (Vec<_>, Vec<_>) = files.iter().partition(|(_, _, f)| {
+            f.hard_link
+                .as_ref()
+                .is_some_and(|link| written.contains(link.as_str()))
+        });
+
+        let io = IoLimit::new(self.io_threads);
+        let intact: Vec<bool> = in_pool(self.pool.as_ref(), || {
+            files
+                .par_iter()
+                .map(|(a_path, target, f)| self.extract_file(f, a_path, target, None, &io))
+                .collect::<Result<_, _>>()
+        })?;
+        #[cfg(test)]
+        self.io_peak
+            .fetch_max(io.peak(), std::sync::atomic::Ordering::SeqCst);
+        let mut damaged: Vec<&str> = files
+            .iter()
+            .zip(intact)
+            .filter(|(_, intact)| !intact)
+            .map(|((a_path, _, _), _)| a_path.as_str())
+            .collect();
+
+        let extracted: std::collections::HashMap<&str, &std::path::Path> = files
+            .iter()
+            .map(|(a_path, target, _)| (a_path.as_str(), target.as_path()))
+            .collect();
+        for (a_path, target, f) in links {
+            let original = f
+                .hard_link
+                .as_ref()
+                .and_then(|link| extracted.get(link.as_str()).copied());
+            if !self.extract_file(f, a_path, target, original, &io)? {
+                damaged.push(a_path);
+            }
+        }
+
+        if !damaged.is_empty() {
+            return Err(errors::FileReadError::Other(format!(
+                "extracted files read back differently: {}",
+                damaged.join(", ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::read_archive;
+    use crate::testing::{pack_files, patch_file, scratch_dir};
+    use crate::CompressionAlgo;
+
+    #[test]
+    fn names_the_damaged_entry_when_extraction_fails() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/sub/a.txt".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), b"A");
+
+        let dest = scratch_dir();
+        let a = read_archive(&out_file).unwrap();
+        let err = a.extract_all(&dest, 0).unwrap_err().to_string();
+        assert!(err.contains("/sub/a.txt"), "{err}");
+        assert!(err.contains("checksum mismatch, expected"), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    // Paths of the files below `dir`, relative to it and sorted
+    fn files_below(dir: &std::path::Path) -> Vec<String> {
+        let mut files = vec![];
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(d) = pending.pop() {
+            for e in std::fs::read_dir(&d).unwrap() {
+                let path = e.unwrap().path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let rel = path.strip_prefix(dir).unwrap();
+                    files.push(rel.to_str().unwrap().replace('\\', "/"));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn strips_leading_components() {
+        let (dir, out_file) = pack_files(
+            &[("top.txt", b"top"), ("a/b/c.txt", b"c"), ("a/d.txt", b"d")],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+
+        let dest = scratch_dir();
+        a.extract_all(&dest, 1).unwrap();
+        assert_eq!(files_below(&dest), ["b/c.txt", "d.txt"]);
+        assert_eq!(std::fs::read(dest.join("b/c.txt")).unwrap(), b"c");
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let dest = scratch_dir();
+        a.extract_all(&dest, 2).unwrap();
+        assert_eq!(files_below(&dest), ["c.txt"]);
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let dest = scratch_dir();
+        a.extract("/a/b", &dest, 2).unwrap();
+        assert_eq!(files_below(&dest), ["c.txt"]);
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn resumed_extraction_skips_completed_files() {
+        let (dir, out_file) = pack_files(
+            &[("done.txt", b"complete"), ("partial.txt", b"interrupted")],
+            |b| b.compression(CompressionAlgo::NONE),
+        );
+        let mut a = read_archive(&out_file).unwrap();
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        std::fs::write(dest.join("partial.txt"), b"inter").unwrap();
+
+        // Damage the completed file's body, rewriting it would fail its checksum
+        let f = a.lookup_file("/done.txt".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), b"C");
+        assert!(a.extract_all(&dest, 0).is_err());
+
+        std::fs::write(dest.join("partial.txt"), b"inter").unwrap();
+        a.set_resume(true);
+        a.extract_all(&dest, 0).unwrap();
+        assert_eq!(std::fs::read(dest.join("done.txt")).unwrap(), b"complete");
+        assert_eq!(
+            std::fs::read(dest.join("partial.txt")).unwrap(),
+            b"interrupted"
+        );
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn resolves_links_only_within_the_destination() {
+        let path = |p: &str| p.split('/').map(String::from).collect::<Vec<_>>();
+        let mut links = std::collections::HashMap::new();
+        links.insert(path("a/up"), "..".to_string());
+        links.insert(path("a/out"), "up/..".to_string());
+        links.insert(path("b/up"), "../a/up".to_string());
+        links.insert(path("loop"), "loop".to_string());
+
+        let resolve =
+            |link: &str, target: &str| super::resolve_link(&path(link), target, &links, 8);
+        assert_eq!(resolve("a/l", "../b/./c"), Some(path("b/c")));
+        assert_eq!(resolve("l", "a/up/b"), Some(path("b")));
+        assert_eq!(resolve("a/l", "../../b"), None);
+        assert_eq!(resolve("l", "a/out/b"), None);
+        assert_eq!(resolve("l", "b/up/.."), None);
+        assert_eq!(resolve("l", "/etc/passwd"), None);
+        assert_eq!(resolve("l", "loop"), None);
+        assert_eq!(resolve("l", ""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extracts_links_inside_the_destination_only() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        std::os::unix::fs::symlink("sub/a.txt", dir.join("inside")).unwrap();
+        crate::QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .create()
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        let link = std::fs::read_link(dest.join("inside")).unwrap();
+        assert_eq!(link, std::path::Path::new("sub/a.txt"));
+        assert_eq!(std::fs::read(dest.join("inside")).unwrap(), b"alpha");
+        let _ = std::fs::remove_dir_all(&dest);
+
+        for target in ["../../escaped", "/etc"] {
+            let _ = std::fs::remove_file(dir.join("outside"));
+            std::os::unix::fs::symlink(target, dir.join("outside")).unwrap();
+            crate::QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+                .create()
+                .unwrap();
+            let a = read_archive(&out_file).unwrap();
+            let dest = scratch_dir();
+            let err = a.extract_all(&dest, 0).unwrap_err();
+            assert!(
+                matches!(err, crate::errors::FileReadError::UnsafePath(_)),
+                "{err}"
+            );
+            assert!(std::fs::symlink_metadata(dest.join("outside")).is_err());
+            let _ = std::fs::remove_dir_all(&dest);
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reports_files_written_differently_than_extracted() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("b.txt", b"beta")], |b| b);
+        let mut a = read_archive(&out_file).unwrap();
+        // Writes to the device are dropped, as a failing disk would
+        let dest = scratch_dir();
+        std::os::unix::fs::symlink("/dev/null", dest.join("a.txt")).unwrap();
+        a.extract_all(&dest, 0).unwrap();
+
+        a.set_verify_written(true);
+        let err = a.extract_all(&dest, 0).unwrap_err().to_string();
+        assert!(err.contains("read back differently: /a.txt"), "{err}");
+        assert!(!err.contains("b.txt"), "{err}");
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stores_hard_links_once_and_links_them_again() {
+        use std::os::unix::fs::MetadataExt;
+
+        let data = b"shared ".repeat(1000);
+        let (dir, out_file) = pack_files(&[("a.txt", &data)], |b| b);
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::hard_link(dir.join("a.txt"), dir.join("sub/b.txt")).unwrap();
+        let stats = crate::QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .create_with_stats()
+            .unwrap();
+        assert_eq!((stats.files, stats.hard_links), (2, 1));
+
+        let a = read_archive(&out_file).unwrap();
+        let (first, link) = (
+            a.lookup_file("/a.txt".as_ref()).unwrap(),
+            a.lookup_file("/sub/b.txt".as_ref()).unwrap(),
+        );
+        // Whichever is listed first holds the data
+        assert!(
+            link.hard_link.as_deref() == Some("/a.txt")
+                || first.hard_link.as_deref() == Some("/sub/b.txt")
+        );
+        assert_eq!(
+            (first.offset(), first.index_size),
+            (link.offset(), link.index_size)
+        );
+        assert_eq!(a.read_file("/sub/b.txt").unwrap(), data);
+
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        let (first, link) = (
+            std::fs::metadata(dest.join("a.txt")).unwrap(),
+            std::fs::metadata(dest.join("sub/b.txt")).unwrap(),
+        );
+        assert_eq!(first.ino(), link.ino());
+        assert_eq!(std::fs::read(dest.join("sub/b.txt")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_owners_when_run_as_root() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        // Files are created owned by the effective user
+        let source = std::fs::metadata(dir.join("sub/a.txt")).unwrap();
+        if source.uid() != 0 {
+            eprintln!("skipping, changing owners needs root");
+            let _ = std::fs::remove_file(&out_file);
+            let _ = std::fs::remove_dir_all(&dir);
+            return;
+        }
+        let mut a = read_archive(&out_file).unwrap();
+        let f = crate::write::find_file_mut(&mut a.header.root, "/sub/a.txt").unwrap();
+        (f.uid, f.gid) = (Some(1234), Some(5678));
+        a.refresh_index();
+
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        let m = std::fs::metadata(dest.join("sub/a.txt")).unwrap();
+        assert_eq!((m.uid(), m.gid()), (source.uid(), source.gid()));
+        let _ = std::fs::remove_dir_all(&dest);
+
+        a.set_preserve_owner(true);
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        let m = std::fs::metadata(dest.join("sub/a.txt")).unwrap();
+        assert_eq!((m.uid(), m.gid()), (1234, 5678));
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "xattr")]
+    #[test]
+    fn round_trips_extended_attributes() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        if xattr::set(dir.join("sub/a.txt"), "user.qz.test", b"value").is_err() {
+            eprintln!("skipping, the filesystem does not support extended attributes");
+            let _ = std::fs::remove_file(&out_file);
+            let _ = std::fs::remove_dir_all(&dir);
+            return;
+        }
+        xattr::set(dir.join("sub"), "user.qz.dir", b"").unwrap();
+        crate::QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .xattrs(true)
+            .create()
+            .unwrap();
+
+        let mut a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/sub/a.txt".as_ref()).unwrap();
+        assert_eq!(f.xattrs.get("user.qz.test").unwrap(), b"value");
+        a.set_restore_xattrs(true);
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        let value = xattr::get(dest.join("sub/a.txt"), "user.qz.test").unwrap();
+        assert_eq!(value.as_deref(), Some(&b"value"[..]));
+        let value = xattr::get(dest.join("sub"), "user.qz.dir").unwrap();
+        assert_eq!(value.as_deref(), Some(&b""[..]));
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn flattening_renames_colliding_names() {
+        let mut taken = std::collections::HashSet::new();
+        let names: Vec<String> = ["x.txt", "x.txt", "x-1.txt", "x.txt", ".rc", ".rc", "README"]
+            .iter()
+            .map(|name| super::flat_name(name, &mut taken))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "x.txt",
+                "x-1.txt",
+                "x-1-1.txt",
+                "x-2.txt",
+                ".rc",
+                ".rc-1",
+                "README"
+            ]
+        );
+
+        let (dir, out_file) = pack_files(
+            &[("a/x.txt", b"a"), ("b/x.txt", b"b"), ("b/c/x.txt", b"c")],
+            |b| b,
+        );
+        let mut a = read_archive(&out_file).unwrap();
+        a.set_flatten(true);
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        assert_eq!(files_below(&dest), ["x-1.txt", "x-2.txt", "x.txt"]);
+        let mut content: Vec<Vec<u8>> = files_below(&dest)
+            .iter()
+            .map(|f| std::fs::read(dest.join(f)).unwrap())
+            .collect();
+        content.sort();
+        assert_eq!(content, [b"a", b"b", b"c"]);
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compares_archives_against_directories() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                ("sub/b.txt", b"beta"),
+                ("sub/c.txt", b"gamma"),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+        assert!(a.verify_against_dir(&dir).unwrap().is_empty());
+
+        // Same size, other content
+        std::fs::write(dir.join("sub/b.txt"), b"BETA").unwrap();
+        assert_eq!(
+            a.verify_against_dir(&dir).unwrap(),
+            [super::Difference::Differs("/sub/b.txt".to_string())]
+        );
+
+        std::fs::remove_file(dir.join("a.txt")).unwrap();
+        std::fs::write(dir.join("new.txt"), b"new").unwrap();
+        // Archive entries come in stored order, extra files last
+        let differences = a.verify_against_dir(&dir).unwrap();
+        assert_eq!(differences.len(), 3);
+        assert!(differences.contains(&super::Difference::Missing("/a.txt".to_string())));
+        assert!(differences.contains(&super::Difference::Differs("/sub/b.txt".to_string())));
+        assert_eq!(
+            differences[2],
+            super::Difference::Extra("/new.txt".to_string())
+        );
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_destinations_that_are_files() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        let dest = scratch_dir().join("file");
+        std::fs::write(&dest, b"in the way").unwrap();
+        for res in [a.extract_all(&dest, 0), a.extract("/a.txt", &dest, 0)] {
+            let err = res.unwrap_err().to_string();
+            assert!(err.contains("exists and is not a directory"), "{err}");
+        }
+        assert_eq!(std::fs::read(&dest).unwrap(), b"in the way");
+
+        // Missing ones are created, parents included
+        let missing = dest.with_file_name("new/nested");
+        a.extract_all(&missing, 0).unwrap();
+        assert_eq!(std::fs::read(missing.join("a.txt")).unwrap(), b"alpha");
+        let _ = std::fs::remove_dir_all(dest.parent().unwrap());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_overlong_paths_before_writing_anything() {
+        // Names within the limit, nested deeper than any path may be long
+        let name = "d".repeat(200);
+        let deep = vec![name.as_str(); 25].join("/");
+        let mut files = crate::vfs::MemoryFs::new();
+        files.add_file("a.txt", "alpha");
+        files.add_file(format!("{deep}/b.txt"), "beta");
+        files.add_file(format!("{}/c.txt", "n".repeat(300)), "gamma");
+        let data =
+            crate::QZArchiveBuilder::from_source(std::sync::Arc::new(files), "", "unused.qz")
+                .create_in_memory()
+                .unwrap();
+        let a = crate::read_archive_from_bytes(data).unwrap();
+
+        let dest = scratch_dir();
+        assert!(a.get_entry(format!("/{deep}")).is_ok());
+        let err = a
+            .extract_paths(&["/a.txt".to_string(), format!("/{deep}")], &dest, 0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("bytes long"), "{err}");
+        assert!(files_below(&dest).is_empty());
+
+        let long = format!("/{}", "n".repeat(300));
+        let err = a
+            .extract_paths(&["/a.txt".to_string(), long], &dest, 0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("longer than 255 bytes"), "{err}");
+        assert!(files_below(&dest).is_empty());
+
+        a.extract_paths(&["/a.txt"], &dest, 0).unwrap();
+        assert_eq!(files_below(&dest), ["a.txt"]);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn extracts_the_same_tree_on_any_number_of_threads() {
+        let files: Vec<(String, Vec<u8>)> = (0..64)
+            .map(|i| {
+                (
+                    format!("d{}/f{i}.txt", i % 5),
+                    format!("file {i} ").repeat(i).into(),
+                )
+            })
+            .collect();
+        let listed: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&listed, |b| b);
+
+        let mut a = read_archive(&out_file).unwrap();
+        a.set_threads(1).unwrap();
+        let serial = scratch_dir();
+        a.extract_all(&serial, 0).unwrap();
+        a.set_threads(8).unwrap();
+        let parallel = scratch_dir();
+        a.extract_all(&parallel, 0).unwrap();
+
+        assert_eq!(files_below(&serial).len(), files.len());
+        assert_eq!(files_below(&serial), files_below(&parallel));
+        for (name, data) in &files {
+            assert_eq!(&std::fs::read(serial.join(name)).unwrap(), data);
+            assert_eq!(&std::fs::read(parallel.join(name)).unwrap(), data);
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&serial);
+        let _ = std::fs::remove_dir_all(&parallel);
+    }
+
+    #[test]
+    fn writes_no_more_files_at_once_than_io_threads() {
+        let files: Vec<(String, Vec<u8>)> = (0..64)
+            .map(|i| (format!("f{i}.txt"), format!("file {i} ").repeat(i).into()))
+            .collect();
+        let listed: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&listed, |b| b);
+
+        let mut a = read_archive(&out_file).unwrap();
+        a.set_threads(4).unwrap();
+        a.set_io_threads(1);
+        a.set_verify_written(true);
+        let target = scratch_dir();
+        a.extract_all(&target, 0).unwrap();
+
+        let peak = a.io_peak.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(peak, 1);
+        assert_eq!(files_below(&target).len(), files.len());
+        for (name, data) in &files {
+            assert_eq!(&std::fs::read(target.join(name)).unwrap(), data);
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&target);
+    }
+
+    #[test]
+    fn extracts_only_the_files_the_filter_accepts() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                ("b.log", b"beta"),
+                ("sub/c.txt", b"gamma"),
+                ("sub/d.log", b"delta"),
+                ("sub/empty/e.log", b"epsilon"),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+
+        let dest = scratch_dir();
+        let seen = std::sync::Mutex::new(vec![]);
+        let mut skipped = a
+            .extract_filtered("/", &dest, 0, |path, f| {
+                seen.lock().unwrap().push(path.to_string());
+                f.name.ends_with(".txt")
+            })
+            .unwrap();
+        skipped.sort();
+        assert_eq!(skipped, ["/b.log", "/sub/d.log", "/sub/empty/e.log"]);
+        assert_eq!(files_below(&dest), ["a.txt", "sub/c.txt"]);
+        assert_eq!(std::fs::read(dest.join("sub/c.txt")).unwrap(), b"gamma");
+        // Directories are created even if none of their files is written
+        assert!(dest.join("sub/empty").is_dir());
+        assert_eq!(seen.lock().unwrap().len(), 5);
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let dest = scratch_dir();
+        let skipped = a
+            .extract_filtered("/sub", &dest, 0, |path, _| path == "/sub/d.log")
+            .unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(files_below(&dest), ["sub/d.log"]);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn round_trips_crlf_line_endings() {
+        let big = b"a longer line\r\n".repeat(100_000);
+        let files: &[(&str, &[u8])] = &[
+            ("crlf.txt", b"one\r\ntwo\r\n"),
+            ("big.txt", &big),
+            ("mixed.txt", b"one\r\ntwo\n"),
+            ("lf.txt", b"one\ntwo\n"),
+            ("binary", b"one\0\r\n"),
+        ];
+        let (dir, out_file) = pack_files(files, |b| b.normalize_line_endings(true));
+        let mut a = read_archive(&out_file).unwrap();
+
+        let converted = |path: &str| a.lookup_file(path.as_ref()).unwrap().crlf;
+        assert!(converted("/crlf.txt") && converted("/big.txt"));
+        assert!(!converted("/mixed.txt") && !converted("/lf.txt") && !converted("/binary"));
+        assert_eq!(a.read_file("/crlf.txt").unwrap(), b"one\ntwo\n");
+        assert_eq!(
+            a.read_file("/big.txt").unwrap(),
+            b"a longer line\n".repeat(100_000)
+        );
+        assert_eq!(a.read_file("/mixed.txt").unwrap(), b"one\r\ntwo\n");
+
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        assert_eq!(std::fs::read(dest.join("crlf.txt")).unwrap(), b"one\ntwo\n");
+        let _ = std::fs::remove_dir_all(&dest);
+
+        a.set_restore_line_endings(true);
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        for (name, data) in files {
+            assert_eq!(&std::fs::read(dest.join(name)).unwrap(), data, "{name}");
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+}
@@ -1,11 +1,52 @@
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::fs::File;
 use std::io::Read;
-use std::io::Seek;
 use std::io::Write;
 
+mod cache;
+#[cfg(feature = "fs")]
+mod checkpoint;
+pub mod compression;
 pub mod errors;
+#[cfg(feature = "fs")]
+mod extract;
+#[cfg(feature = "fs")]
+mod pack;
+pub mod progress;
+pub mod reader;
+pub mod recovery;
+#[cfg(feature = "fs")]
+mod salvage;
+pub mod size;
+pub mod source;
+#[cfg(feature = "fs")]
+pub mod testing;
+pub mod vfs;
+pub mod volume;
+#[cfg(feature = "fs")]
+mod write;
+
+use source::ReadAt;
+
+pub use progress::{NoProgress, ProgressSink, SkipReason};
+pub use reader::QZFileReader;
+pub use recovery::QZRecovery;
+pub use size::{human_size, SizeUnits};
+pub use source::RangeSource;
+pub use vfs::{FileSource, MemoryFs};
+pub use volume::QZVolumes;
+
+#[cfg(feature = "fs")]
+pub use extract::Difference;
+#[cfg(feature = "fs")]
+pub use pack::{
+    create_archive, create_archive_with_config, create_archive_with_dictionary, AlgoStats,
+    CompressionConfig, FileOrder, PackStats, QZArchiveBuilder, SpecialFiles,
+};
+#[cfg(feature = "fs")]
+pub use salvage::{try_read_archive, SalvagedArchive};
+#[cfg(feature = "fs")]
+pub use write::{join_volumes, open_rw, write_archive, QZArchiveMut};
 
 //   -----------
 //   | STRUCTS |
@@ -18,6 +59,51 @@ pub enum CompressionAlgo {
     NONE,
 }
 
+impl std::str::FromStr for CompressionAlgo {
+    type Err = errors::ParseCompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" => Ok(CompressionAlgo::ZSTD),
+            "lz4" => Ok(CompressionAlgo::LZ4),
+            "none" => Ok(CompressionAlgo::NONE),
+            _ => Err(errors::ParseCompressionError::new(s)),
+        }
+    }
+}
+
+impl CompressionAlgo {
+    // Byte identifying the algorithm in the archive prefix
+    #[cfg(feature = "fs")]
+    fn tag(&self) -> u8 {
+        match self {
+            CompressionAlgo::NONE => 0,
+            CompressionAlgo::ZSTD => 1,
+            CompressionAlgo::LZ4 => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<CompressionAlgo> {
+        match tag {
+            0 => Some(CompressionAlgo::NONE),
+            1 => Some(CompressionAlgo::ZSTD),
+            2 => Some(CompressionAlgo::LZ4),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            CompressionAlgo::ZSTD => "zstd",
+            CompressionAlgo::LZ4 => "lz4",
+            CompressionAlgo::NONE => "none",
+        };
+        write!(f, "{name}")
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QZFile {
     pub name: String,
@@ -25,63 +111,382 @@ pub struct QZFile {
     pub checksum: u32,
     index_start: u64,
     pub index_size: u64,
+    /// Size of the uncompressed content
+    #[serde(default)]
+    pub original_size: Option<u64>,
+    /// CRC32 of the uncompressed content
+    #[serde(default)]
+    pub original_checksum: Option<u32>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    /// Path of an earlier file this one is a hard link to, both share their stored data
+    #[serde(default)]
+    pub hard_link: Option<String>,
+    /// Target of a symbolic link, stored as given, links hold no data
+    #[serde(default)]
+    pub symlink: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Extended attributes, only captured and restored with the `xattr` feature
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub xattrs: std::collections::BTreeMap<String, Vec<u8>>,
+    /// Independently compressed pieces of the stored data, empty if it is stored as one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+    /// The text file had CRLF line endings, stored converted to LF,
+    /// see `QZArchiveBuilder::normalize_line_endings`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub crlf: bool,
+    /// The file is a qz archive itself, see `QZArchive::open_nested`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub nested: bool,
+}
+
+/// Part of a file stored in chunks, compressed on its own so it can be read by itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    /// Offset of the stored chunk relative to the stored data of its file
+    pub offset: u64,
+    /// Size of the stored chunk
+    pub size: u64,
+    /// Size of the chunk once decompressed
+    pub original_size: u64,
+    /// CRC32 of the stored chunk
+    pub checksum: u32,
+}
+
+impl Chunk {
+    // Decompress the stored bytes of this chunk of `name`, which must inflate to `original_size`
+    fn decompress(
+        &self,
+        name: &str,
+        stored: &[u8],
+        compression: &CompressionAlgo,
+        archive: &QZArchive,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let data = compression::decompress_versioned(
+            stored,
+            compression,
+            self.original_size,
+            archive.format,
+            archive.dictionary(),
+        )
+        .map_err(|err| decompress_error(name, err))?;
+        check_decompressed_len(name, data.len() as u64, self.original_size)?;
+        Ok(data)
+    }
+}
+
+// Error for stored bytes of `name` that fail to decompress, telling a truncated stream apart
+pub(crate) fn decompress_error(name: &str, err: std::io::Error) -> errors::FileReadError {
+    let reason = match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => "truncated stream".to_string(),
+        _ => format!("invalid data, {err}"),
+    };
+    errors::FileReadError::CompressionError(name.to_string(), reason)
+}
+
+// Fail unless the content of `name` decompressed to the `expected` length
+fn check_decompressed_len(
+    name: &str,
+    len: u64,
+    expected: u64,
+) -> Result<(), errors::FileReadError> {
+    match len.cmp(&expected) {
+        std::cmp::Ordering::Equal => Ok(()),
+        std::cmp::Ordering::Less => Err(errors::FileReadError::CompressionError(
+            name.to_string(),
+            format!("truncated stream, decompressed {len} of {expected} bytes"),
+        )),
+        std::cmp::Ordering::Greater => Err(errors::FileReadError::CompressionError(
+            name.to_string(),
+            format!("invalid data, decompressed more than {expected} bytes"),
+        )),
+    }
+}
+
+// `data` with its CRLF line endings turned into LF, `None` unless it is text ending every
+// line with CRLF
+//
+// Text is told from binary data by the absence of NUL bytes. Mixed line endings could not
+// be restored, so files having any LF on its own are left as they are.
+#[cfg(feature = "fs")]
+pub(crate) fn crlf_to_lf(data: &[u8]) -> Option<Vec<u8>> {
+    if data.contains(&0) || !data.contains(&b'\n') {
+        return None;
+    }
+    let mut text = Vec::with_capacity(data.len());
+    let mut lines = CrlfToLf::default();
+    lines.convert(data, &mut text);
+    lines.finish(&mut text).then_some(text)
+}
+
+// `crlf_to_lf` for data read piece by piece
+#[cfg(feature = "fs")]
+#[derive(Default)]
+pub(crate) struct CrlfToLf {
+    // Carriage return ending the previous piece, kept until the next byte shows
+    // whether it ends a line
+    cr: bool,
+    lines: bool,
+    // The data is binary or has lines not ending in CRLF
+    mixed: bool,
+}
+
+#[cfg(feature = "fs")]
+impl CrlfToLf {
+    // Append `piece` to `text` with CRLF line endings turned into LF
+    pub(crate) fn convert(&mut self, piece: &[u8], text: &mut Vec<u8>) {
+        for b in piece {
+            match (std::mem::take(&mut self.cr), b) {
+                (true, b'\n') => self.lines = true,
+                (cr, b) => {
+                    if cr {
+                        text.push(b'\r');
+                    }
+                    match b {
+                        b'\r' => {
+                            self.cr = true;
+                            continue;
+                        }
+                        b'\n' | 0 => self.mixed = true,
+                        _ => {}
+                    }
+                }
+            }
+            text.push(*b);
+        }
+    }
+
+    // Append what is left to `text`, whether all of the data was CRLF text
+    pub(crate) fn finish(&mut self, text: &mut Vec<u8>) -> bool {
+        if std::mem::take(&mut self.cr) {
+            text.push(b'\r');
+        }
+        self.lines && !self.mixed
+    }
+}
+
+// `text` with its LF line endings turned back into CRLF, undoing `crlf_to_lf`
+#[cfg(feature = "fs")]
+pub(crate) fn lf_to_crlf(text: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(text.len() + text.len() / 16);
+    for b in text {
+        if *b == b'\n' {
+            data.push(b'\r');
+        }
+        data.push(*b);
+    }
+    data
+}
+
+// Error for stored bytes that could not be read, telling a truncated archive apart
+fn stored_read_error(err: std::io::Error) -> errors::FileReadError {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => errors::FileReadError::Truncated,
+        _ => errors::FileReadError::Other(format!("failed to read archive: {err}")),
+    }
 }
 
 impl QZFile {
-    // Return file data from archive with header offset
-    fn read_file(&self, archive: &str, offset: u64) -> Result<Vec<u8>, errors::FileReadError> {
-        let mut f = File::open(archive).unwrap();
+    /// Offset of the stored data relative to the start of the archive body
+    ///
+    /// The stored data spans `index_size` bytes from there. Add `QZArchive::body_offset`
+    /// for the position within the archive file, or pass it to `QZArchive::read_at`.
+    pub fn offset(&self) -> u64 {
+        self.index_start
+    }
+
+    // Return file data from archive
+    fn read_file(&self, archive: &QZArchive) -> Result<Vec<u8>, errors::FileReadError> {
+        let read_buf = self.read_stored(archive)?;
+
+        // CHECKSUM
+
+        let hash = crc32fast::hash(&read_buf);
+        archive.check_checksum(&self.name, hash, self.checksum)?;
+
+        self.decompress_stored(archive, read_buf)
+    }
+
+    // Stored bytes of this entry, as compressed
+    fn read_stored(&self, archive: &QZArchive) -> Result<Vec<u8>, errors::FileReadError> {
         let mut read_buf: Vec<u8> = vec![0u8; self.index_size as usize];
-        f.seek(std::io::SeekFrom::Start(offset + self.index_start))
-            .unwrap();
-        let res = f.read_exact(&mut read_buf);
-        if res.is_err() {
+
+        // Empty entries occupy no body bytes, so there is nothing to read
+        if self.index_size > 0 {
+            archive
+                .source
+                .read_exact_at(&mut read_buf, archive.stored_position(self))
+                .map_err(stored_read_error)?;
+        }
+        Ok(read_buf)
+    }
+
+    // Decompress the stored bytes `read_buf` of this entry
+    fn decompress_stored(
+        &self,
+        archive: &QZArchive,
+        read_buf: Vec<u8>,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        if !self.chunks.is_empty() {
+            let mut data = Vec::with_capacity(self.original_size.unwrap_or(0) as usize);
+            for c in &self.chunks {
+                let stored = read_buf
+                    .get(c.offset as usize..c.offset.saturating_add(c.size) as usize)
+                    .ok_or_else(|| {
+                        errors::FileReadError::CompressionError(
+                            self.name.clone(),
+                            "chunk exceeds the stored data".to_string(),
+                        )
+                    })?;
+                data.extend(c.decompress(&self.name, stored, &self.compression, archive)?);
+            }
+            return Ok(data);
+        }
+        let data = match self.compression {
+            CompressionAlgo::NONE => read_buf,
+            _ => compression::decompress_versioned(
+                &read_buf,
+                &self.compression,
+                u64::MAX - 1,
+                archive.format,
+                archive.dictionary(),
+            )
+            .map_err(|err| decompress_error(&self.name, err))?,
+        };
+        if let (Some(size), false) = (self.original_size, self.symlink.is_some()) {
+            check_decompressed_len(&self.name, data.len() as u64, size)?;
+        }
+        Ok(data)
+    }
+
+    // Read and decompress chunk `i` on its own, checked against the checksum of the chunk
+    fn read_chunk(&self, archive: &QZArchive, i: usize) -> Result<Vec<u8>, errors::FileReadError> {
+        let c = &self.chunks[i];
+        if c.offset.saturating_add(c.size) > self.index_size {
             return Err(errors::FileReadError::Other(format!(
-                "{:?}",
-                res.unwrap_err()
+                "chunk {i} of {} exceeds its stored data",
+                self.name
             )));
         }
+        let mut stored = vec![0u8; c.size as usize];
+        archive
+            .source
+            .read_exact_at(&mut stored, archive.stored_position(self) + c.offset)
+            .map_err(stored_read_error)?;
 
-        //println!("reading {:?}", self);
+        let hash = crc32fast::hash(&stored);
+        archive.check_checksum(&format!("chunk {i} of {}", self.name), hash, c.checksum)?;
+        c.decompress(&self.name, &stored, &self.compression, archive)
+    }
 
-        // CHECKSUM
+    // Check the stored bytes of the entry against its checksum without decompressing,
+    // hard links are checked with the file holding their data
+    fn is_valid(&self, archive: &QZArchive) -> Result<(), errors::FileReadError> {
+        match self.hard_link {
+            Some(_) => Ok(()),
+            None => self.is_stored_intact(archive),
+        }
+    }
 
-        let hash = crc32fast::hash(&read_buf);
+    // Decompress the entry as a stream and check it, never holding more of it in memory
+    // than a buffer and a chunk
+    fn is_content_valid(&self, archive: &QZArchive) -> Result<(), errors::FileReadError> {
+        use std::io::Read;
+
+        let mut reader = QZFileReader::new(archive, self)?;
+        let mut buffer = vec![0u8; archive.buffer_size];
+        let mut hasher = crc32fast::Hasher::new();
+        let mut len = 0;
+        let expected_len = self.original_size.filter(|_| self.symlink.is_none());
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(self.stream_error(archive, err)),
+            };
+            hasher.update(&buffer[..n]);
+            len += n as u64;
+            // Stop early instead of inflating data of any size
+            if let Some(size) = expected_len.filter(|size| len > *size) {
+                self.check_stored(archive)?;
+                return check_decompressed_len(&self.name, len, size);
+            }
+        }
+
+        if let Some(size) = expected_len {
+            check_decompressed_len(&self.name, len, size)?;
+        }
+        if let Some(checksum) = self.original_checksum {
+            let what = format!("content of {}", self.name);
+            archive.check_checksum(&what, hasher.finalize(), checksum)?;
+        }
+        Ok(())
+    }
+
+    // Why streaming the entry failed with `err`, damaged stored bytes take precedence
+    // over the decompression failure they cause
+    fn stream_error(&self, archive: &QZArchive, err: std::io::Error) -> errors::FileReadError {
+        if let Err(err) = self.check_stored(archive) {
+            return err;
+        }
+        let err = match err.kind() {
+            std::io::ErrorKind::InvalidData => match err.into_inner() {
+                Some(inner) => match inner.downcast::<errors::FileReadError>() {
+                    Ok(inner) => return *inner,
+                    Err(inner) => std::io::Error::new(std::io::ErrorKind::InvalidData, inner),
+                },
+                None => std::io::ErrorKind::InvalidData.into(),
+            },
+            _ => err,
+        };
+        decompress_error(&self.name, err)
+    }
+
+    // Check the stored bytes against the checksum as archive reads do, warning only
+    // if checksums are not enforced
+    fn check_stored(&self, archive: &QZArchive) -> Result<(), errors::FileReadError> {
+        let hash = self.stored_checksum(archive)?;
+        archive.check_checksum(&self.name, hash, self.checksum)
+    }
+
+    // Check the stored bytes against the checksum without decompressing, in chunks
+    fn is_stored_intact(&self, archive: &QZArchive) -> Result<(), errors::FileReadError> {
+        let hash = self.stored_checksum(archive)?;
         if hash != self.checksum {
             return Err(errors::FileReadError::Checksum(hash, self.checksum));
         }
+        Ok(())
+    }
 
-        // COMPRESSION
+    // CRC32 of the stored bytes, read in `buffer_size` pieces
+    fn stored_checksum(&self, archive: &QZArchive) -> Result<u32, errors::FileReadError> {
+        let mut buffer = vec![0u8; archive.buffer_size.min(self.index_size as usize)];
+        let mut hasher = crc32fast::Hasher::new();
+        let mut pos = 0;
 
-        match self.compression {
-            CompressionAlgo::ZSTD => {
-                let res = zstd::stream::decode_all(&read_buf[0..read_buf.len()]);
-                if res.is_err() {
-                    return Err(errors::FileReadError::CompressionError);
-                }
-                read_buf = res.unwrap();
-            }
-            CompressionAlgo::LZ4 => {
-                let res = lz4_compression::decompress::decompress(&read_buf);
-                if res.is_err() {
-                    return Err(errors::FileReadError::CompressionError);
-                }
-                read_buf = res.unwrap();
-            }
-            CompressionAlgo::NONE => {}
+        while pos < self.index_size {
+            let n = buffer.len().min((self.index_size - pos) as usize);
+            archive
+                .source
+                .read_exact_at(&mut buffer[..n], archive.stored_position(self) + pos)
+                .map_err(stored_read_error)?;
+            hasher.update(&buffer[..n]);
+            pos += n as u64;
         }
-
-        Ok(read_buf)
+        Ok(hasher.finalize())
     }
 
-    fn is_valid(&self, archive: &str, offset: u64) -> Result<(), errors::FileReadError> {
-        let res = self.read_file(archive, offset);
-        match res {
-            Ok(_) => Ok(()),
-            Err(errors::FileReadError::Checksum(real, exp)) => {
-                Err(errors::FileReadError::Checksum(real, exp))
-            }
-            _ => Err(errors::FileReadError::Other(String::new())),
+    // Uncompressed bytes this entry accounts for, hard links count as empty
+    fn content_size(&self) -> u64 {
+        if self.hard_link.is_some() {
+            0
+        } else {
+            self.original_size.unwrap_or(self.index_size)
         }
     }
 }
@@ -90,6 +495,13 @@ impl QZFile {
 pub struct QZDir {
     pub name: String,
     pub content: Vec<QZEntry>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Extended attributes, only captured and restored with the `xattr` feature
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub xattrs: std::collections::BTreeMap<String, Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -98,145 +510,321 @@ pub enum QZEntry {
     File(QZFile),
 }
 
+impl QZEntry {
+    /// Name of the file or directory
+    pub fn name(&self) -> &str {
+        match self {
+            QZEntry::Dir(d) => &d.name,
+            QZEntry::File(f) => &f.name,
+        }
+    }
+
+    /// Whether this is a directory, a symbolic link or a regular file
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            QZEntry::Dir(_) => EntryKind::Dir,
+            QZEntry::File(f) if f.symlink.is_some() => EntryKind::Symlink,
+            QZEntry::File(_) => EntryKind::File,
+        }
+    }
+}
+
+/// Type of an entry, see `QZArchive::ls_filtered`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Regular file, hard links included
+    File,
+    Dir,
+    Symlink,
+}
+
+// All entries below `entry` with their path components relative to it, in stored order
+fn walk_entries(entry: &QZEntry) -> Vec<(Vec<&str>, &QZEntry)> {
+    let mut entries = vec![];
+    let mut stack: Vec<(Vec<&str>, &QZEntry)> = vec![];
+
+    if let QZEntry::Dir(d) = entry {
+        for c in d.content.iter().rev() {
+            stack.push((vec![c.name()], c));
+        }
+    }
+
+    while let Some((path, e)) = stack.pop() {
+        if let QZEntry::Dir(d) = e {
+            for c in d.content.iter().rev() {
+                let mut c_path = path.clone();
+                c_path.push(c.name());
+                stack.push((c_path, c));
+            }
+        }
+        entries.push((path, e));
+    }
+
+    entries
+}
+
 /// Header for QZ Archive
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QZArchiveHeader {
+    /// Name of the archive, empty if it has none
+    #[serde(default)]
     pub name: String,
+    /// Description of the archive, empty if it has none
+    #[serde(default)]
     pub info: String,
     pub version: String,
     root: QZEntry,
+    #[serde(default)]
+    pub volumes: Option<QZVolumes>,
+    #[serde(default)]
+    pub metadata: std::collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub recovery: Option<QZRecovery>,
+    /// zstd dictionary all `ZSTD` entries are compressed with, see `QZArchiveBuilder::dictionary`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<Vec<u8>>,
+    /// CRC32 of the whole body as written, see `QZArchive::check_body`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_checksum: Option<u32>,
 }
 
-// Turn directory structure into QZEntry structure
-fn pack_dir(dir: &str, compression: CompressionAlgo) -> QZEntry {
-    let mut content: Vec<QZEntry> = vec![];
+impl QZArchiveHeader {
+    /// Root directory of the archive tree
+    pub fn root(&self) -> &QZEntry {
+        &self.root
+    }
 
-    let paths = fs::read_dir(dir).unwrap();
+    /// Uncompressed size of all files, hard links counted once
+    ///
+    /// Files of archives that predate stored original sizes count with their stored size.
+    pub fn total_uncompressed_size(&self) -> u64 {
+        walk_entries(&self.root)
+            .into_iter()
+            .map(|(_, e)| match e {
+                QZEntry::File(f) => f.content_size(),
+                QZEntry::Dir(_) => 0,
+            })
+            .sum()
+    }
+}
 
-    for p in paths {
-        let p = p.unwrap();
-        //println!("Scanning {}", p.path().display());
-        if p.metadata().unwrap().is_file() {
-            let f = QZFile {
-                name: String::from(p.path().file_name().unwrap().to_str().unwrap()),
-                compression: compression.clone(),
-                checksum: 0,
-                index_start: 0,
-                index_size: 0,
-            };
-            content.push(QZEntry::File(f));
-        } else if p.metadata().unwrap().is_dir() {
-            let d = pack_dir(p.path().to_str().unwrap(), compression.clone());
-            content.push(d);
+impl std::fmt::Display for QZArchiveHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (mut files, mut dirs, mut stored, mut original) = (0, 0, 0, 0);
+        let mut sizes_known = true;
+        for (_, e) in walk_entries(&self.root) {
+            match e {
+                QZEntry::Dir(_) => dirs += 1,
+                QZEntry::File(file) => {
+                    files += 1;
+                    // Hard links share the data of their target
+                    if file.hard_link.is_none() {
+                        stored += file.index_size;
+                        match file.original_size {
+                            Some(size) => original += size,
+                            None => sizes_known = false,
+                        }
+                    }
+                }
+            }
         }
-    }
 
-    return QZEntry::Dir(QZDir {
-        name: std::path::Path::new(dir)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-        content,
-    });
+        match self.name.as_str() {
+            "" => writeln!(f, "QZ Archive v.{} (unnamed)", self.version)?,
+            name => writeln!(f, "QZ Archive v.{}: '{name}'", self.version)?,
+        }
+        // Descriptions read from files usually end in a newline
+        let info = self.info.trim_end();
+        if !info.is_empty() {
+            writeln!(f, "{info}")?;
+        }
+        writeln!(f, "{files} files in {dirs} directories")?;
+        let size = |bytes| human_size(bytes, SizeUnits::Decimal);
+        if sizes_known {
+            writeln!(f, "{} stored in {}", size(original), size(stored))?;
+        } else {
+            writeln!(f, "{} stored", size(stored))?;
+        }
+        if let Some(volumes) = &self.volumes {
+            writeln!(
+                f,
+                "split into {} volumes of {}",
+                volumes.count,
+                human_size(volumes.size, SizeUnits::Decimal)
+            )?;
+        }
+        if let Some(dictionary) = &self.dictionary {
+            writeln!(f, "zstd dictionary of {}", size(dictionary.len() as u64))?;
+        }
+        for (key, value) in &self.metadata {
+            writeln!(f, "{key}: {value}")?;
+        }
+        Ok(())
+    }
 }
 
-//   ---------
-//   | WRITE |
-//   ---------
+//   ----------
+//   | FORMAT |
+//   ----------
 
-/// Creating a QZ Archive
-pub fn create_archive(
-    dir: &str,
-    out_file: &str,
-    name: &str,
-    description: &str,
-    compression: CompressionAlgo,
-) {
-    // SCAN DIR
-    let mut root = pack_dir(dir, compression);
+// Dedicated pool of `threads` threads, none for 0 to use the global pool
+fn thread_pool(threads: usize) -> Result<Option<rayon::ThreadPool>, String> {
+    if threads == 0 {
+        return Ok(None);
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map(Some)
+        .map_err(|err| format!("failed to start {threads} threads: {err}"))
+}
 
-    // PROCESS & MAKE FILE
+// Run `op` in `pool`, or in the global pool without one
+fn in_pool<R: Send>(pool: Option<&rayon::ThreadPool>, op: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
 
-    let mut files_content: Vec<u8> = vec![];
+// Bound on how many threads read or write files at once, 0 for no bound
+//
+// Lets storage that handles few requests at a time be served by fewer threads than
+// compression runs on.
+#[cfg(feature = "fs")]
+pub(crate) struct IoLimit {
+    max: usize,
+    busy: std::sync::Mutex<usize>,
+    freed: std::sync::Condvar,
+    // Operations running at once and the most that ever were, to test the bound holds
+    #[cfg(test)]
+    running: std::sync::atomic::AtomicUsize,
+    #[cfg(test)]
+    peak: std::sync::atomic::AtomicUsize,
+}
 
-    fn write_files_dir(d: &mut QZDir, path: &str, mut f_content: Vec<u8>) -> (Vec<u8>,) {
-        for e in &mut d.content {
-            match e {
-                QZEntry::Dir(ref mut d) => {
-                    // RECURSIVE
-                    let path = std::path::Path::new(path).join(&d.name);
-                    let path = path.to_str().unwrap();
-                    let res = write_files_dir(d, path, f_content);
-                    f_content = res.0;
-                }
-                QZEntry::File(ref mut f) => {
-                    let path = std::path::Path::new(path).join(&f.name);
-                    //println!("p {}", path.to_str().unwrap());
-                    println!("Adding file {:?}", &f);
-                    f.index_start = f_content.len() as u64;
-
-                    let mut file = std::fs::File::open(&path).expect("no file found");
-                    let metadata = fs::metadata(&path).expect("unable to read metadata");
-                    let mut buffer = vec![0; metadata.len() as usize];
-                    file.read_exact(&mut buffer).expect("buffer overflow");
-
-                    // COMPRESSION
-
-                    match f.compression {
-                        CompressionAlgo::ZSTD => {
-                            buffer = zstd::stream::encode_all(&buffer[0..buffer.len()], 5).unwrap();
-                        }
-                        CompressionAlgo::LZ4 => {
-                            buffer = lz4_compression::compress::compress(&buffer);
-                        }
-                        CompressionAlgo::NONE => {}
-                    }
+#[cfg(feature = "fs")]
+impl IoLimit {
+    pub(crate) fn new(max: usize) -> IoLimit {
+        IoLimit {
+            max,
+            busy: std::sync::Mutex::new(0),
+            freed: std::sync::Condvar::new(),
+            #[cfg(test)]
+            running: std::sync::atomic::AtomicUsize::new(0),
+            #[cfg(test)]
+            peak: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
 
-                    // CHECKSUM
+    // Run `op` once fewer than `max` others are running
+    pub(crate) fn run<R>(&self, op: impl FnOnce() -> R) -> R {
+        if self.max == 0 {
+            return self.counted(op);
+        }
+        let mut busy = self.busy.lock().unwrap();
+        while *busy >= self.max {
+            busy = self.freed.wait(busy).unwrap();
+        }
+        *busy += 1;
+        drop(busy);
+        let _slot = IoSlot(self);
+        self.counted(op)
+    }
 
-                    f.checksum = crc32fast::hash(&buffer);
+    #[cfg(not(test))]
+    fn counted<R>(&self, op: impl FnOnce() -> R) -> R {
+        op()
+    }
 
-                    f.index_size = buffer.len() as u64;
+    // Run `op`, counting it among those running at once in `peak`
+    #[cfg(test)]
+    fn counted<R>(&self, op: impl FnOnce() -> R) -> R {
+        use std::sync::atomic::Ordering;
 
-                    f_content.extend(buffer);
-                }
-            }
-        }
-        (f_content,)
+        let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(now, Ordering::SeqCst);
+        let res = op();
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        res
     }
 
-    if let QZEntry::Dir(ref mut d) = root {
-        let res = write_files_dir(d, dir, files_content);
-        files_content = res.0;
+    // Most operations that ran at once
+    #[cfg(test)]
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.load(std::sync::atomic::Ordering::SeqCst)
     }
+}
 
-    let archive = QZArchiveHeader {
-        name: name.to_string(),
-        info: description.to_string(),
-        version: env!("CARGO_PKG_VERSION").to_string(),
-        root,
-    };
+// Slot taken by `IoLimit::run`, freed when dropped so an operation that panics
+// does not keep it
+#[cfg(feature = "fs")]
+struct IoSlot<'a>(&'a IoLimit);
+
+#[cfg(feature = "fs")]
+impl Drop for IoSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Default size of I/O buffers
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
 
-    let mut header = serde_json::to_vec(&archive).unwrap();
+/// Smallest accepted I/O buffer size, smaller values are raised to it
+pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
 
-    // COMPRESSION
-    header = zstd::stream::encode_all(&header[0..header.len()], 5).unwrap();
+/// Format version of written archives, stored in the prefix right after the magic
+///
+/// - `1`: prefix with header compression and checksum, LZ4 stored as raw blocks
+/// - `2`: LZ4 stored in the LZ4 frame format
+/// - `3`: files may be stored in chunks, see `QZFile::chunks`
+/// - `4`: zstd entries may be compressed with the dictionary in the header
+///
+/// Archives without magic count as version `0`, they are read like version `1`.
+pub const FORMAT_VERSION: u8 = 4;
 
-    let header_size = header.len().to_ne_bytes();
+// Marks archives whose prefix carries format version, header compression and checksum
+const MAGIC: &[u8; 3] = b"QZA";
 
-    // SAVE
-    fs::File::create(out_file).unwrap();
-    let mut final_archive = fs::OpenOptions::new()
-        .write(true)
-        .append(true) // This is needed to append to file
-        .open(out_file)
-        .unwrap();
+// Magic, format version (u8), header compression tag (u8), header size (u64 little endian)
+// and header CRC32 (u32 little endian)
+const PREFIX_SIZE: u64 = 17;
 
-    final_archive.write_all(&header_size).unwrap();
-    final_archive.write_all(&header).unwrap();
-    final_archive.write_all(&files_content).unwrap();
+// Whether `data` starts with the prefix of an archive in a format this version reads,
+// archives without magic cannot be told from other data
+#[cfg(feature = "fs")]
+pub(crate) fn is_archive(data: &[u8]) -> bool {
+    data.len() as u64 >= PREFIX_SIZE
+        && data[..3] == MAGIC[..]
+        && (1..=FORMAT_VERSION).contains(&data[3])
+        && CompressionAlgo::from_tag(data[4]).is_some()
+}
+
+// Archives without magic start with the header size in native byte order
+const LEGACY_PREFIX_SIZE: u64 = 8;
+
+// Fewer header bytes cannot hold a header, neither as JSON naming the root
+// nor as the smallest zstd frame
+const MIN_HEADER_SIZE: u64 = 9;
+
+// Decompress an encoded header, refusing to inflate it beyond `MAX_HEADER_SIZE`
+fn decode_header(
+    encoded: &[u8],
+    compression: &CompressionAlgo,
+    format: u8,
+) -> Result<Vec<u8>, errors::ReadError> {
+    let decoded =
+        compression::decompress_versioned(encoded, compression, MAX_HEADER_SIZE, format, &[]).ok();
+
+    match decoded {
+        None => Err(errors::ReadError::new("failed to decompress header")),
+        Some(decoded) if decoded.len() as u64 > MAX_HEADER_SIZE => Err(errors::ReadError::new(
+            &format!("header exceeds maximum size of {MAX_HEADER_SIZE} bytes"),
+        )),
+        Some(decoded) => Ok(decoded),
+    }
 }
 
 //   --------
@@ -244,202 +832,3251 @@ pub fn create_archive(
 //   --------
 
 /// Struct for handling QZ Archives
+///
+/// `QZArchive` is `Send + Sync`: all reads are positioned reads on one shared handle,
+/// so an `Arc<QZArchive>` can serve `read_file` calls from many threads at once.
+/// Methods taking `&mut self` rewrite the archive and need exclusive access.
 pub struct QZArchive {
+    #[cfg(feature = "fs")]
     archive_file: String,
+    source: source::ArchiveSource,
     header_size: u64,
+    prefix_size: u64,
+    // Format version the archive was written with, see `FORMAT_VERSION`
+    format: u8,
+    #[cfg(feature = "fs")]
+    header_compression: CompressionAlgo,
+    buffer_size: usize,
+    #[cfg(feature = "fs")]
+    preserve_owner: bool,
+    #[cfg(feature = "fs")]
+    resume: bool,
+    #[cfg(feature = "fs")]
+    flatten: bool,
+    #[cfg(feature = "fs")]
+    restore_line_endings: bool,
+    // Files written at once when extracting, see `set_io_threads`
+    #[cfg(feature = "fs")]
+    io_threads: usize,
+    // Most files written or read back at once by any extraction, see `IoLimit::peak`
+    #[cfg(all(test, feature = "fs"))]
+    io_peak: std::sync::atomic::AtomicUsize,
+    // Read extracted files back, see `set_verify_written`
+    #[cfg(feature = "fs")]
+    verify_written: bool,
+    // Where rewrites of the archive are staged, next to it if not set
+    #[cfg(feature = "fs")]
+    temp_dir: Option<std::path::PathBuf>,
+    #[cfg(feature = "xattr")]
+    restore_xattrs: bool,
+    cache: Option<std::sync::Mutex<cache::EntryCache>>,
+    // Files of `header.root` by full path, see `build_index`
+    index: Option<std::collections::HashMap<String, QZFile>>,
+    verify_checksums: bool,
+    pool: Option<rayon::ThreadPool>,
+    progress: progress::SharedSink,
+    // Parsed `header.version`
+    written_by: Option<semver::Version>,
     pub header: QZArchiveHeader,
 }
 
-impl QZArchive {
-    /// Reading a file from archive
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, errors::FileReadError> {
-        let path = QZArchive::get_path(path);
-        let mut path_c = std::path::Path::new(&path).components();
-
-        if path_c.next() == Some(std::path::Component::RootDir) {
-            let res = QZArchive::_get_entry(path_c, &self.header.root);
-            if res.is_err() {
-                return Err(errors::FileReadError::Other(format!(
-                    "{:?}",
-                    res.unwrap_err()
-                )));
-            }
-            let res = res.unwrap();
+// Keep `QZArchive` shareable between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<QZArchive>();
+};
 
-            match res {
-                QZEntry::Dir(_) => {
-                    return Err(errors::FileReadError::NotAFile);
-                }
-                QZEntry::File(f) => {
-                    return f.read_file(&self.archive_file, self.header_size + 8);
-                }
+// File indexed at `path`, which starts at the root directory
+fn index_lookup<'a>(
+    index: &'a std::collections::HashMap<String, QZFile>,
+    path: &std::path::Path,
+) -> Option<&'a QZFile> {
+    let mut key = String::new();
+    for c in path.components().skip(1) {
+        match c {
+            std::path::Component::Normal(name) => {
+                key.push('/');
+                key.push_str(name.to_str()?);
             }
+            _ => return None,
         }
+    }
+    index.get(&key)
+}
 
-        Err(errors::FileReadError::NotFound)
+// Open an archive file, or the volumes belonging to it if `spanned`
+#[cfg(feature = "fs")]
+fn open_source(archive_file: &str, spanned: bool) -> std::io::Result<source::ArchiveSource> {
+    if spanned {
+        Ok(source::ArchiveSource::Spanned(volume::VolumeSet::open(
+            archive_file,
+        )?))
+    } else {
+        Ok(source::ArchiveSource::Single(std::fs::File::open(
+            archive_file,
+        )?))
+    }
+}
+
+impl QZArchive {
+    /// Size of the buffers used when streaming entries or rewriting the archive,
+    /// values below `MIN_BUFFER_SIZE` are raised to it
+    ///
+    /// `read_file` reads an entry with a single positioned read and needs no buffer.
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer_size = size.max(MIN_BUFFER_SIZE);
+    }
+
+    /// Look files up in a map by path from now on instead of walking the tree for each
+    ///
+    /// Speeds up reading many files one by one at the cost of a copy of every file
+    /// entry. The tree stays as it is, the map is rebuilt whenever the archive
+    /// rewrites its header.
+    pub fn build_index(&mut self) {
+        self.index = Some(
+            self.files()
+                .into_iter()
+                .map(|(path, f)| (path, f.clone()))
+                .collect(),
+        );
+    }
+
+    // Build the index again for the current header, if there is one
+    #[cfg(feature = "fs")]
+    pub(crate) fn refresh_index(&mut self) {
+        if self.index.is_some() {
+            self.build_index();
+        }
     }
 
-    pub fn check_file(&self, path: &str) -> Result<(), errors::FileReadError> {
+    // Resolve `path` to a file entry
+    fn lookup_file(&self, path: &std::path::Path) -> Result<QZFile, errors::FileReadError> {
         let path = QZArchive::get_path(path);
-        let mut path_c = std::path::Path::new(&path).components();
+        if let Some(f) = self
+            .index
+            .as_ref()
+            .and_then(|index| index_lookup(index, &path))
+        {
+            return Ok(f.clone());
+        }
+        // Anything not indexed is walked for the error to report
+        let mut path_c = path.components();
 
         if path_c.next() == Some(std::path::Component::RootDir) {
-            let res = QZArchive::_get_entry(path_c, &self.header.root);
-            if res.is_err() {
-                return Err(errors::FileReadError::Other(format!(
-                    "{:?}",
-                    res.unwrap_err()
-                )));
-            }
-            let res = res.unwrap();
+            let res = QZArchive::_get_entry(path_c, &self.header.root)?;
 
             match res {
                 QZEntry::Dir(_) => {
                     return Err(errors::FileReadError::NotAFile);
                 }
                 QZEntry::File(f) => {
-                    f.is_valid(&self.archive_file, self.header_size + 8)?;
-                    return Ok(());
+                    return Ok(f.clone());
                 }
             }
         }
+
         Err(errors::FileReadError::NotFound)
     }
 
-    fn get_path(path: &str) -> String {
-        format!("/{path}")
+    /// Version of qz the archive was written by, `None` if it is no valid semantic version
+    pub fn written_by(&self) -> Option<&semver::Version> {
+        self.written_by.as_ref()
     }
 
-    /// Get qz entry for given path
-    pub fn get_entry(&self, path: &str) -> Result<QZEntry, errors::EntryError> {
-        let path = QZArchive::get_path(path);
-        let mut path_c = std::path::Path::new(&path).components();
-
-        if path_c.next() == Some(std::path::Component::RootDir) {
-            let res = QZArchive::_get_entry(path_c, &self.header.root);
-            return res;
+    /// Fail if the archive was written by a newer, incompatible version of qz
+    ///
+    /// Versions are compatible as for Cargo, sharing the major version or for `0.x`
+    /// the minor version. Newer versions may store data this one would misread.
+    pub fn check_version(&self) -> Result<(), errors::ReadError> {
+        let Some(written_by) = &self.written_by else {
+            return Ok(());
+        };
+        let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+        let breaking = |v: &semver::Version| (v.major, if v.major == 0 { v.minor } else { 0 });
+        if breaking(written_by) > breaking(&current) {
+            return Err(errors::ReadError::new(&format!(
+                "archive was written by qz {written_by}, which is newer than {current}"
+            )));
         }
-        Err(errors::EntryError::Other(String::new()))
+        Ok(())
     }
 
-    fn _get_entry(
-        mut comp: std::path::Components,
-        current_entry: &QZEntry,
-    ) -> Result<QZEntry, errors::EntryError> {
-        if let QZEntry::Dir(current_dir) = current_entry {
-            match comp.next() {
-                Some(std::path::Component::Normal(walk_path_name)) => {
-                    for e in &current_dir.content {
-                        match e {
-                            QZEntry::Dir(d) => {
-                                //println!("matching {:?} and {:?}", d.name, walk_path_name.to_str().unwrap());
-                                if d.name == walk_path_name.to_str().unwrap() {
-                                    return QZArchive::_get_entry(comp, &QZEntry::Dir(d.clone()));
-                                }
-                            }
-                            QZEntry::File(f) => {
-                                if f.name == walk_path_name.to_str().unwrap() {
-                                    return Ok(QZEntry::File(f.clone()));
-                                }
-                            }
-                        }
-                    }
-                    return Err(errors::EntryError::NothingFound);
-                }
-                None => {
-                    return Ok(current_entry.clone());
-                }
-                _ => {
-                    return Err(errors::EntryError::PathError);
-                }
-            }
-        }
-        Err(errors::EntryError::Other(String::new()))
+    /// Report every file extracted or checked to `sink`
+    pub fn set_progress(&mut self, sink: std::sync::Arc<dyn ProgressSink>) {
+        self.progress = progress::SharedSink(sink);
     }
 
-    /// List content of directory returning list with filenames
-    pub fn ls(&self, path: &str) -> Result<Vec<String>, errors::ListingError> {
-        let path = QZArchive::get_path(path);
-        let mut path_c = std::path::Path::new(&path).components();
+    /// Verify and extract on a dedicated pool of `threads` threads, 0 uses the global rayon pool
+    ///
+    /// With 1 thread files are checked and written one after another.
+    pub fn set_threads(&mut self, threads: usize) -> Result<(), errors::ReadError> {
+        self.pool = thread_pool(threads).map_err(|err| errors::ReadError::new(&err))?;
+        Ok(())
+    }
 
-        let mut content: Vec<String> = vec![];
+    /// Write at most `threads` extracted files at once, 0 lets every thread write
+    ///
+    /// Files are still decompressed by all threads of `set_threads`, only writing them
+    /// waits, e.g. for network storage serving few requests at once.
+    #[cfg(feature = "fs")]
+    pub fn set_io_threads(&mut self, threads: usize) {
+        self.io_threads = threads;
+    }
 
-        if path_c.next() == Some(std::path::Component::RootDir) {
-            let res = QZArchive::_get_entry(path_c, &self.header.root);
-            if res.is_err() {
-                return Err(errors::ListingError::Other(format!(
-                    "{:?}",
-                    res.unwrap_err()
-                )));
-            }
-            let res = res.unwrap();
+    /// Cache decompressed entries up to `budget` bytes in total, 0 disables the cache
+    ///
+    /// When the budget is exceeded the least recently read entries are dropped.
+    pub fn set_cache_size(&mut self, budget: usize) {
+        self.cache = if budget == 0 {
+            None
+        } else {
+            Some(std::sync::Mutex::new(cache::EntryCache::new(budget)))
+        };
+    }
 
-            match res {
-                QZEntry::Dir(d) => {
-                    for e in d.content {
-                        match e {
-                            QZEntry::Dir(d) => {
-                                content.push(d.name);
-                            }
-                            QZEntry::File(f) => {
-                                content.push(f.name);
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    return Err(errors::ListingError::IsFile);
-                }
-            }
+    /// Fail reading entries whose stored data does not match its checksum, on by default
+    ///
+    /// With it off damaged entries are still decompressed as far as possible to recover
+    /// what is left, and every mismatch is reported through `ProgressSink::warning`.
+    /// This also makes `check_archive_deep` pass damaged entries that still decompress.
+    /// `check_archive`, `check_archive_quick`, and `read_at` given a checksum, always
+    /// enforce checksums.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+        // Cached data may have been read under the other policy
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
         }
-
-        Ok(content)
     }
-}
 
-/// Read Archive File and return a QZArchive Struct
-pub fn read_archive(path: &str) -> Result<QZArchive, errors::ReadError> {
-    // OPEN FILE
-    let f = File::open(path);
-    if f.is_err() {
-        return Err(errors::ReadError::new("failed to open archive file"));
+    // Check the `hash` of the stored data of `what` against the `expected` one,
+    // only warning about a mismatch if checksums are not enforced
+    fn check_checksum(
+        &self,
+        what: &str,
+        hash: u32,
+        expected: u32,
+    ) -> Result<(), errors::FileReadError> {
+        if hash == expected {
+            return Ok(());
+        }
+        if self.verify_checksums {
+            return Err(errors::FileReadError::Checksum(hash, expected));
+        }
+        self.progress.0.warning(&format!(
+            "checksum mismatch in {what}, expected {expected:08x} but got {hash:08x}"
+        ));
+        Ok(())
     }
-    let mut f = f.unwrap();
 
-    // GET HEADER
-    let mut size_buf: [u8; 8] = [0; 8];
-    let err = f.read_exact(&mut size_buf);
-    if err.is_err() {
-        return Err(errors::ReadError::new("failed to read header size"));
+    // Key identifying `path` in the cache, independent of leading or doubled slashes,
+    // `None` for paths that are no UTF-8 and so name no entry
+    fn cache_key(path: &std::path::Path) -> Option<String> {
+        let mut key = vec![];
+        for c in path.components() {
+            if let std::path::Component::Normal(c) = c {
+                key.push(c.to_str()?);
+            }
+        }
+        Some(key.join("/"))
     }
-    let size = u64::from_ne_bytes(size_buf);
-    //println!("size {}", size);
 
-    // READ HEADER
-    let mut header_buf: Vec<u8> = vec![0u8; size as usize];
-    f.seek(std::io::SeekFrom::Start(8)).unwrap();
-    let err = f.read_exact(&mut header_buf);
-    if err.is_err() {
-        return Err(errors::ReadError::new("failed to read header"));
+    fn cached(&self, path: &std::path::Path) -> Option<Vec<u8>> {
+        let cache = self.cache.as_ref()?;
+        cache.lock().unwrap().get(&QZArchive::cache_key(path)?)
     }
-    let res = zstd::stream::decode_all(&header_buf[0..header_buf.len()]);
-    if res.is_err() {
-        return Err(errors::ReadError::new("failed to decompress header"));
+
+    // Apply `f` to the cached data under `key`, if any
+    fn cached_with<R>(&self, key: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let cache = self.cache.as_ref()?;
+        cache.lock().unwrap().get_with(key, f)
     }
-    header_buf = res.unwrap();
 
-    // DESERIALIZE
-    let header: Result<QZArchiveHeader, _> = serde_json::from_slice(&header_buf);
-    if header.is_err() {
-        return Err(errors::ReadError::new("failed to decode header"));
+    // Read an entry from disk, storing the result in the cache
+    fn read_uncached(
+        &self,
+        path: &std::path::Path,
+        f: &QZFile,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let data = f.read_file(self)?;
+        if let (Some(cache), Some(key)) = (&self.cache, QZArchive::cache_key(path)) {
+            cache.lock().unwrap().insert(&key, &data);
+        }
+        Ok(data)
+    }
+
+    /// Reading a file from archive
+    pub fn read_file(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let path = path.as_ref();
+        if let Some(data) = self.cached(path) {
+            return Ok(data);
+        }
+        let f = self.lookup_file(path)?;
+        self.read_uncached(path, &f)
+    }
+
+    /// Read the file at `path`, or list the directory at `path` instead of failing
+    ///
+    /// Front-ends can show the listing where `read_file` would only return
+    /// `FileReadError::NotAFile`, the listing is ordered like `ls`.
+    pub fn read_or_archive_dir(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<FileOrDir, errors::FileReadError> {
+        let path = path.as_ref();
+        match self.read_file(path) {
+            Err(errors::FileReadError::NotAFile) => {}
+            res => return res.map(FileOrDir::File),
+        }
+        self.ls(path).map(FileOrDir::Dir).map_err(|err| {
+            errors::FileReadError::Other(format!("failed to list {}: {err}", path.display()))
+        })
+    }
+
+    /// Open the archive stored as the file at `path`, reading it into memory
+    ///
+    /// Nothing is extracted, the inner archive reads its files from the decompressed
+    /// data like one opened with `read_archive_from_bytes`. Files packed as archives
+    /// are marked with `QZFile::nested`, unmarked ones are opened all the same.
+    pub fn open_nested(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<QZArchive, errors::ReadError> {
+        let path = path.as_ref();
+        let data = self.read_file(path).map_err(|err| {
+            errors::ReadError::new(&format!("failed to read {}: {err}", path.display()))
+        })?;
+        read_archive_from_bytes(data).map_err(|err| {
+            errors::ReadError::new(&format!(
+                "{} holds no readable archive: {err}",
+                path.display()
+            ))
+        })
+    }
+
+    /// Read a file from archive, borrowing its data from the mapping where possible
+    ///
+    /// `NONE` entries of an archive mapped with `map` are returned as a slice of the mapping
+    /// after verifying their checksum, everything else is read like `read_file`.
+    pub fn read_file_borrowed(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<std::borrow::Cow<'_, [u8]>, errors::FileReadError> {
+        let path = path.as_ref();
+        let f = self.lookup_file(path)?;
+        let mapped = match (&f.compression, self.source.as_slice()) {
+            (CompressionAlgo::NONE, Some(mapped)) => mapped,
+            _ => return self.read_file(path).map(std::borrow::Cow::Owned),
+        };
+
+        let start = self.stored_position(&f) as usize;
+        let data = match mapped.get(start..start + f.index_size as usize) {
+            Some(data) => data,
+            None => {
+                return Err(errors::FileReadError::Other(
+                    "archive ends inside of entry".to_string(),
+                ))
+            }
+        };
+        self.check_checksum(&f.name, crc32fast::hash(data), f.checksum)?;
+        Ok(std::borrow::Cow::Borrowed(data))
+    }
+
+    /// Stored bytes of a file as compressed, checked against its checksum but not decompressed
+    ///
+    /// Along with the `compression` and `chunks` of its entry they are all it takes
+    /// to store the file in another archive without compressing it again. `ZSTD` data
+    /// of an archive with a dictionary only decompresses with it, see `export_dictionary`.
+    pub fn read_raw(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let f = self.lookup_file(path.as_ref())?;
+        let data = f.read_stored(self)?;
+        self.check_checksum(&f.name, crc32fast::hash(&data), f.checksum)?;
+        Ok(data)
+    }
+
+    /// Decompress the stored bytes `data` of the file at `path`, as `read_raw` returns them
+    ///
+    /// They are checked against the checksum of the entry first, so data obtained elsewhere,
+    /// e.g. by a range request for a remote archive, can be given as well.
+    pub fn decompress_raw(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let f = self.lookup_file(path.as_ref())?;
+        if data.len() as u64 != f.index_size {
+            return Err(errors::FileReadError::Other(format!(
+                "expected {} stored bytes for {} but got {}",
+                f.index_size,
+                f.name,
+                data.len()
+            )));
+        }
+        self.check_checksum(&f.name, crc32fast::hash(&data), f.checksum)?;
+        f.decompress_stored(self, data)
+    }
+
+    /// Memory-map the archive file, reads then copy from the mapping instead of the file
+    ///
+    /// Only single file archives can be mapped. The archive must not be modified
+    /// by anything else while it is mapped, rewriting it through `self` maps it again.
+    #[cfg(feature = "mmap")]
+    pub fn map(&mut self) -> Result<(), errors::ReadError> {
+        let (f, range) = match &self.source {
+            source::ArchiveSource::Single(f) => (f, None),
+            source::ArchiveSource::Embedded(f, start, len) => (f, Some((*start, *len))),
+            source::ArchiveSource::Mapped(_) | source::ArchiveSource::Memory(_) => return Ok(()),
+            source::ArchiveSource::Indexed(..) => {
+                return Err(errors::ReadError::new(
+                    "archives read through an index cannot be mapped",
+                ));
+            }
+            source::ArchiveSource::Spanned(_) => {
+                return Err(errors::ReadError::new("split archives cannot be mapped"));
+            }
+        };
+        // SAFETY: the mapping is read only and the documented contract forbids
+        // modifying the file while it is mapped
+        let mapped = unsafe {
+            match range {
+                Some((start, len)) => memmap2::MmapOptions::new()
+                    .offset(start)
+                    .len(len as usize)
+                    .map(f),
+                None => memmap2::Mmap::map(f),
+            }
+        };
+        match mapped {
+            Ok(mapped) => {
+                self.source = source::ArchiveSource::Mapped(mapped);
+                Ok(())
+            }
+            Err(err) => Err(errors::ReadError::new(&format!(
+                "failed to map {}: {err}",
+                self.archive_file
+            ))),
+        }
+    }
+
+    /// Open a file from archive for streaming, without holding all of it in memory
+    pub fn open_file(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<QZFileReader<'_>, errors::FileReadError> {
+        let f = self.lookup_file(path.as_ref())?;
+        QZFileReader::new(self, &f)
+    }
+
+    // Dictionary of the `ZSTD` entries, empty if they were compressed without one
+    fn dictionary(&self) -> &[u8] {
+        self.header.dictionary.as_deref().unwrap_or_default()
+    }
+
+    /// The zstd dictionary stored in the archive, if any
+    ///
+    /// Pack further archives with it using `QZArchiveBuilder::with_dictionary`,
+    /// which saves training one for each and lets similar archives share it.
+    pub fn export_dictionary(&self) -> Option<Vec<u8>> {
+        self.header.dictionary.clone()
+    }
+
+    /// Name of the archive, `header.name`
+    pub fn name(&self) -> &str {
+        &self.header.name
+    }
+
+    /// Description of the archive, `header.info`, empty if it has none
+    pub fn description(&self) -> &str {
+        &self.header.info
+    }
+
+    /// Path the archive was read from, as given to `read_archive`
+    ///
+    /// For split archives this is the path of the set without volume suffix,
+    /// archives read from memory have none and give an empty path.
+    #[cfg(feature = "fs")]
+    pub fn path(&self) -> &str {
+        &self.archive_file
+    }
+
+    /// Size of the header as stored, compressed unless written without header compression
+    ///
+    /// The fixed prefix in front of it is not included, the body starts at `body_offset`.
+    pub fn header_size(&self) -> u64 {
+        self.header_size
+    }
+
+    /// Position of the archive body within the archive file, entry offsets are relative to it
+    ///
+    /// For split archives this is the position within the concatenated volumes, for
+    /// archives embedded in another file the position from the start of the archive.
+    pub fn body_offset(&self) -> u64 {
+        self.prefix_size + self.header_size
+    }
+
+    // Position of the stored data of `f` within the archive, the only place entry offsets
+    // are turned into positions, the source maps them to the file holding the archive
+    pub(crate) fn stored_position(&self, f: &QZFile) -> u64 {
+        self.body_offset() + f.index_start
+    }
+
+    /// Read and decompress the `index_size` stored bytes at `index_start` of the body
+    ///
+    /// For tools keeping their own index of entries, e.g. built from `QZFile::offset`
+    /// and `QZFile::index_size`. The stored bytes are checked against `checksum` if given.
+    pub fn read_at(
+        &self,
+        index_start: u64,
+        index_size: u64,
+        compression: &CompressionAlgo,
+        checksum: Option<u32>,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let offset = self.body_offset();
+        let len = self
+            .source
+            .len()
+            .map_err(|err| errors::FileReadError::Other(format!("{err:?}")))?;
+        let end = index_start.checked_add(index_size);
+        if end
+            .zip(len.checked_sub(offset))
+            .is_none_or(|(end, body_len)| end > body_len)
+        {
+            return Err(errors::FileReadError::Other(format!(
+                "{index_size} bytes at {index_start} exceed the archive body"
+            )));
+        }
+
+        let f = QZFile {
+            name: String::new(),
+            compression: compression.clone(),
+            checksum: 0,
+            index_start,
+            index_size,
+            original_size: None,
+            original_checksum: None,
+            comment: None,
+            hard_link: None,
+            symlink: None,
+            uid: None,
+            gid: None,
+            xattrs: std::collections::BTreeMap::new(),
+            chunks: vec![],
+            crlf: false,
+            nested: false,
+        };
+        let data = f.read_stored(self)?;
+        if let Some(checksum) = checksum {
+            let hash = crc32fast::hash(&data);
+            if hash != checksum {
+                return Err(errors::FileReadError::Checksum(hash, checksum));
+            }
+        }
+        f.decompress_stored(self, data)
+    }
+
+    /// Decompress the file at `path` straight into `out`, returning the number of bytes written
+    ///
+    /// Memory use does not grow with the size of the file. The checksum is verified once
+    /// the whole entry was read, so `out` may already hold the data of a damaged entry
+    /// when `FileReadError::Checksum` is returned.
+    pub fn read_file_to(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        mut out: impl Write,
+    ) -> Result<u64, errors::FileReadError> {
+        let path = path.as_ref();
+        let mut reader = self.open_file(path)?;
+        let mut buffer = vec![0; self.buffer_size];
+        let mut written = 0;
+
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    let msg = format!("failed to read {}: {err}", path.display());
+                    return Err(err
+                        .into_inner()
+                        .and_then(|err| err.downcast::<errors::FileReadError>().ok())
+                        .map_or(errors::FileReadError::Other(msg), |err| *err));
+                }
+            };
+            out.write_all(&buffer[..n]).map_err(|err| {
+                errors::FileReadError::Other(format!("failed to write {}: {err}", path.display()))
+            })?;
+            written += n as u64;
+        }
+
+        Ok(written)
+    }
+
+    /// Read up to `len` bytes of the file at `path` starting at `offset`
+    ///
+    /// Fewer bytes are returned at the end of the file. `NONE` entries are read in place
+    /// without verifying their checksum, like seeking in `open_file`. Of files stored
+    /// in chunks only the chunks covering the range are decompressed and checked. Other
+    /// entries are decompressed as a whole, with `set_cache_size` random reads within
+    /// one file or chunk only decompress it once.
+    pub fn read_range(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, errors::FileReadError> {
+        let path = path.as_ref();
+        let f = self.lookup_file(path)?;
+
+        if let CompressionAlgo::NONE = f.compression {
+            let start = offset.min(f.index_size);
+            let end = offset.saturating_add(len as u64).min(f.index_size);
+            let mut data = vec![0; (end - start) as usize];
+            self.source
+                .read_exact_at(&mut data, self.stored_position(&f) + start)
+                .map_err(|err| {
+                    errors::FileReadError::Other(format!(
+                        "failed to read {}: {err}",
+                        path.display()
+                    ))
+                })?;
+            return Ok(data);
+        }
+
+        // Paths of entries found are UTF-8 throughout
+        let key = QZArchive::cache_key(path).unwrap();
+        let range = |data: &[u8], start: u64, end: u64| {
+            let end = end.min(data.len() as u64) as usize;
+            data[(start as usize).min(end)..end].to_vec()
+        };
+        let end = offset.saturating_add(len as u64);
+        if let Some(data) = self.cached_with(&key, |data| range(data, offset, end)) {
+            return Ok(data);
+        }
+        if f.chunks.is_empty() {
+            return Ok(range(&self.read_uncached(path, &f)?, offset, end));
+        }
+
+        let mut data = vec![];
+        let mut start = 0;
+        for (i, c) in f.chunks.iter().enumerate() {
+            if start >= end {
+                break;
+            }
+            let c_end = start + c.original_size;
+            if c_end > offset {
+                let (from, to) = (offset.saturating_sub(start), end - start);
+                // Chunks are cached apart from whole files, no path contains a NUL
+                let c_key = format!("{key}\0{i}");
+                let part = match self.cached_with(&c_key, |chunk| range(chunk, from, to)) {
+                    Some(part) => part,
+                    None => {
+                        let chunk = f.read_chunk(self, i)?;
+                        if let Some(cache) = &self.cache {
+                            cache.lock().unwrap().insert(&c_key, &chunk);
+                        }
+                        range(&chunk, from, to)
+                    }
+                };
+                data.extend(part);
+            }
+            start = c_end;
+        }
+        Ok(data)
+    }
+
+    /// Reading several files from archive, results are in the order of `paths`
+    ///
+    /// Entries are read in the order they are stored, so the archive is accessed sequentially.
+    pub fn read_files(
+        &self,
+        paths: &[impl AsRef<std::path::Path>],
+    ) -> Vec<Result<Vec<u8>, errors::FileReadError>> {
+        let mut results: Vec<Option<Result<Vec<u8>, errors::FileReadError>>> =
+            paths.iter().map(|_| None).collect();
+        let mut pending = vec![];
+
+        for (i, path) in paths.iter().enumerate() {
+            let path = path.as_ref();
+            if let Some(data) = self.cached(path) {
+                results[i] = Some(Ok(data));
+                continue;
+            }
+            match self.lookup_file(path) {
+                Ok(f) => pending.push((i, f)),
+                Err(err) => results[i] = Some(Err(err)),
+            }
+        }
+
+        pending.sort_by_key(|(_, f)| f.index_start);
+        for (i, f) in pending {
+            results[i] = Some(self.read_uncached(paths[i].as_ref(), &f));
+        }
+
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    /// Check the stored bytes of the file at `path` against its checksum, without decompressing
+    pub fn check_file(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), errors::FileReadError> {
+        self.lookup_file(path.as_ref())?.is_valid(self)
+    }
+
+    /// Decompress the file at `path` and check its content as well, see `check_archive_deep`
+    pub fn check_file_deep(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), errors::FileReadError> {
+        self.lookup_file(path.as_ref())?.is_content_valid(self)
+    }
+
+    /// Write the index of the archive to `out`, the part in front of the body holding
+    /// the header with every entry and where its data is stored
+    ///
+    /// The index is small compared to the archive. Served next to it, e.g. as
+    /// `ARCHIVE.qzidx`, a client can fetch only the index and then read single files
+    /// with range requests, see `read_archive_with_index`.
+    pub fn write_index(&self, mut out: impl std::io::Write) -> std::io::Result<()> {
+        std::io::copy(&mut self.source.reader(0, self.body_offset()), &mut out)?;
+        Ok(())
+    }
+
+    /// Header metadata as pretty printed JSON, including entry offsets
+    pub fn header_json(&self) -> String {
+        serde_json::to_string_pretty(&self.header).unwrap()
+    }
+
+    /// Check the stored bytes of every file against its checksum, returning the paths that failed
+    ///
+    /// Nothing is decompressed, so this reads the archive once at the speed of the disk and
+    /// names every damaged file, unlike `check_archive_quick`. Data that was damaged before
+    /// it was stored and fails to decompress goes unnoticed, `check_archive_deep` catches it.
+    /// Files are verified in parallel, see `set_threads`. Once all passed the body checksum
+    /// is checked as well.
+    pub fn check_archive(&self) -> Result<(), Vec<(String, errors::FileReadError)>> {
+        let stored_size = |f: &QZFile| match f.hard_link {
+            Some(_) => 0,
+            None => f.index_size,
+        };
+        self.check_files(|f| f.is_valid(self), stored_size)?;
+        if let Err(err) = self.check_body() {
+            return Err(vec![("(body)".to_string(), err)]);
+        }
+        Ok(())
+    }
+
+    /// Decompress every file and check its content as well, returning the paths that failed
+    ///
+    /// Catches everything `check_archive` does and data that fails to decompress or differs
+    /// from what was packed, at the cost of decompressing the whole archive. Files are
+    /// streamed, so memory use does not grow with their size. Once all passed the body
+    /// checksum is checked as well if checksums are enforced.
+    pub fn check_archive_deep(&self) -> Result<(), Vec<(String, errors::FileReadError)>> {
+        self.check_files(|f| f.is_content_valid(self), QZFile::content_size)?;
+        if self.verify_checksums {
+            if let Err(err) = self.check_body() {
+                return Err(vec![("(body)".to_string(), err)]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check where the files are stored in the body, returning every anomaly found
+    ///
+    /// Every byte of the body should belong to exactly one stored file. Files sharing
+    /// exactly the same bytes, as hard links do, are fine. A malformed header could
+    /// instead let different files overlap or leave parts of the body to no file,
+    /// files extending past the body are already refused when reading the archive.
+    /// Offsets count from the start of the body, issues are reported in order.
+    pub fn check_layout(&self) -> Result<Vec<LayoutIssue>, errors::FileReadError> {
+        let body_len = self.stored_body_len().map_err(|err| {
+            errors::FileReadError::Other(format!("failed to read archive: {err}"))
+        })?;
+        let mut regions: Vec<(u64, u64, String)> = self
+            .files()
+            .into_iter()
+            .filter(|(_, f)| f.index_size > 0)
+            .map(|(path, f)| {
+                let end = f.index_start.saturating_add(f.index_size);
+                (f.index_start, end, path)
+            })
+            .collect();
+        regions.sort();
+
+        let mut issues = vec![];
+        // The region reaching furthest so far, everything before its end is accounted for
+        let mut furthest: Option<(u64, u64, &str)> = None;
+        for (start, end, path) in &regions {
+            match furthest {
+                Some((f_start, f_end, _)) if (f_start, f_end) == (*start, *end) => continue,
+                Some((_, f_end, f_path)) if *start < f_end => {
+                    issues.push(LayoutIssue::Overlap(
+                        f_path.to_string(),
+                        path.clone(),
+                        *start,
+                        f_end.min(*end) - start,
+                    ));
+                }
+                Some((_, f_end, _)) if *start > f_end => {
+                    issues.push(LayoutIssue::Gap(f_end, start - f_end));
+                }
+                None if *start > 0 => issues.push(LayoutIssue::Gap(0, *start)),
+                _ => {}
+            }
+            if furthest.is_none_or(|(_, f_end, _)| *end > f_end) {
+                furthest = Some((*start, *end, path));
+            }
+        }
+        let covered = furthest.map_or(0, |(_, end, _)| end);
+        if body_len > covered {
+            issues.push(LayoutIssue::Gap(covered, body_len - covered));
+        }
+        Ok(issues)
+    }
+
+    /// Size and CRC32 of every byte of the archive, e.g. to tell whether it changed
+    /// since it was last verified
+    ///
+    /// Given as `SIZE CHECKSUM`, covering all volumes of a split archive.
+    /// Reads the whole archive once without decompressing anything.
+    pub fn fingerprint(&self) -> Result<String, errors::FileReadError> {
+        let read_err = |err: std::io::Error| {
+            errors::FileReadError::Other(format!("failed to read archive: {err}"))
+        };
+        let len = self.source.len().map_err(read_err)?;
+        let checksum = self.checksum_range(0, len).map_err(read_err)?;
+        Ok(format!("{len} {checksum:08x}"))
+    }
+
+    // CRC32 of `len` bytes of the archive from `start` on, read in `buffer_size` pieces
+    fn checksum_range(&self, start: u64, len: u64) -> std::io::Result<u32> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = vec![0; self.buffer_size];
+        let mut pos = 0;
+        while pos < len {
+            let n = (len - pos).min(buffer.len() as u64) as usize;
+            self.source.read_exact_at(&mut buffer[..n], start + pos)?;
+            hasher.update(&buffer[..n]);
+            pos += n as u64;
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Length the body should have, up to the end of the data of the file stored last
+    ///
+    /// Computed from the entries alone, see `check_body_len` for comparing it with the
+    /// body actually in the archive.
+    pub fn body_len(&self) -> u64 {
+        self.files()
+            .into_iter()
+            .map(|(_, f)| f.index_start.saturating_add(f.index_size))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Compare `body_len` with the space between header and recovery records
+    ///
+    /// A cheap check reading nothing but the archive length, a body shorter than
+    /// expected means the archive was cut short and a longer one data appended to it.
+    pub fn check_body_len(&self) -> Result<(), errors::FileReadError> {
+        let len = self.source.len().map_err(|err| {
+            errors::FileReadError::Other(format!("failed to read archive: {err}"))
+        })?;
+        let parity = self.header.recovery.as_ref().map_or(0, |r| r.parity_size());
+        let actual = len.saturating_sub(self.body_offset() + parity);
+        let expected = self.body_len();
+        match actual.cmp(&expected) {
+            std::cmp::Ordering::Less => Err(errors::FileReadError::Other(format!(
+                "{} bytes are missing at the end, the archive was cut off",
+                expected - actual
+            ))),
+            std::cmp::Ordering::Greater => Err(errors::FileReadError::Other(format!(
+                "{} bytes of unknown data follow the body",
+                actual - expected
+            ))),
+            std::cmp::Ordering::Equal => Ok(()),
+        }
+    }
+
+    // Length of the body, up to the recovery records if there are any
+    fn stored_body_len(&self) -> std::io::Result<u64> {
+        match &self.header.recovery {
+            Some(recovery) => Ok(recovery.body_size),
+            None => Ok(self.source.len()?.saturating_sub(self.body_offset())),
+        }
+    }
+
+    /// Check the whole body against the checksum stored when the archive was written
+    ///
+    /// Reads the body once in order and catches damage anywhere in it, including
+    /// truncation, but not which files it hit. Returns `false` without checking anything
+    /// for archives that predate body checksums.
+    pub fn check_body(&self) -> Result<bool, errors::FileReadError> {
+        let Some(expected) = self.header.body_checksum else {
+            return Ok(false);
+        };
+        let hash = self
+            .stored_body_len()
+            .and_then(|len| self.checksum_range(self.body_offset(), len))
+            .map_err(|err| errors::FileReadError::Other(format!("failed to read body: {err}")))?;
+        if hash != expected {
+            return Err(errors::FileReadError::Checksum(hash, expected));
+        }
+        Ok(true)
+    }
+
+    /// Check the stored bytes of every file against its checksum, without decompressing
+    ///
+    /// Faster than `check_archive` while the body is intact and still catches damage to the
+    /// archive on disk.
+    /// Uncompressed files are validated completely, for compressed files only the stored
+    /// stream is, so data that fails to decompress goes unnoticed.
+    ///
+    /// With a body checksum a single pass over the body suffices while it is intact,
+    /// otherwise every file is checked to find the damaged ones. Damage outside of
+    /// all files is reported for the path `(body)`.
+    pub fn check_archive_quick(&self) -> Result<(), Vec<(String, errors::FileReadError)>> {
+        let stored_size = |f: &QZFile| match f.hard_link {
+            // Hard links share the stored bytes of their target
+            Some(_) => 0,
+            None => f.index_size,
+        };
+
+        let body = self.check_body();
+        if let Ok(true) = body {
+            let files = self.files();
+            let progress = self.progress.0.as_ref();
+            progress.add_work(
+                files.len() as u64,
+                files.iter().map(|(_, f)| stored_size(f)).sum(),
+            );
+            for (path, f) in files {
+                progress.file_done(&path, stored_size(f));
+            }
+            return Ok(());
+        }
+
+        let res = self.check_files(|f| f.is_valid(self), stored_size);
+        match (res, body) {
+            (Ok(()), Err(err)) => Err(vec![("(body)".to_string(), err)]),
+            (res, _) => res,
+        }
+    }
+
+    // Run `check` on every file in parallel, reporting `size` bytes per file as progress
+    fn check_files(
+        &self,
+        check: impl Fn(&QZFile) -> Result<(), errors::FileReadError> + Sync,
+        size: impl Fn(&QZFile) -> u64 + Sync,
+    ) -> Result<(), Vec<(String, errors::FileReadError)>> {
+        let files = self.files();
+        let progress = self.progress.0.as_ref();
+        let total = files.iter().map(|(_, f)| size(f)).sum();
+        progress.add_work(files.len() as u64, total);
+
+        let failed: Vec<(String, errors::FileReadError)> = in_pool(self.pool.as_ref(), || {
+            files
+                .into_par_iter()
+                .filter_map(|(path, f)| {
+                    let res = check(f);
+                    progress.file_done(&path, size(f));
+                    res.err().map(|err| (path, err))
+                })
+                .collect()
+        });
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+
+    /// Every file and directory below the root with its full path
+    ///
+    /// Entries follow the stored order depth first, each directory comes right before
+    /// its content, so the order is the same every time an archive is listed.
+    pub fn entries(&self) -> Vec<(String, &QZEntry)> {
+        walk_entries(&self.header.root)
+            .into_iter()
+            .map(|(path, e)| (format!("/{}", path.join("/")), e))
+            .collect()
+    }
+
+    // All files in the archive with their full paths, in stored order
+    fn files(&self) -> Vec<(String, &QZFile)> {
+        walk_entries(&self.header.root)
+            .into_iter()
+            .filter_map(|(path, e)| match e {
+                QZEntry::File(f) => Some((format!("/{}", path.join("/")), f)),
+                QZEntry::Dir(_) => None,
+            })
+            .collect()
+    }
+
+    /// Where every file is stored, as `(path, index_start, index_size, compression, checksum)`
+    ///
+    /// Sorted by position in the body, relative to `body_offset` like `QZFile::offset`, with
+    /// the CRC32 of the stored bytes. Hard links share the position of the file they link to
+    /// and follow it. Enough to serve ranges of the archive or build an external index in one go.
+    pub fn file_table(&self) -> Vec<(String, u64, u64, CompressionAlgo, u32)> {
+        let mut files = self.files();
+        files.sort_by_key(|(_, f)| (f.index_start, f.hard_link.is_some()));
+        files
+            .into_iter()
+            .map(|(path, f)| {
+                (
+                    path,
+                    f.index_start,
+                    f.index_size,
+                    f.compression.clone(),
+                    f.checksum,
+                )
+            })
+            .collect()
+    }
+
+    /// Every file with its content checksum and size, one `CHECKSUM  SIZE  PATH` line each
+    ///
+    /// Lines follow the stored order, checksums are CRC32 in hex. Hard links are listed
+    /// with the content they share, symbolic links are left out. Values an archive predates
+    /// are given as `-`. Kept alongside an archive it allows verifying extracted files
+    /// externally, and comparing manifests shows what changed between two archives.
+    pub fn manifest(&self) -> String {
+        let mut manifest = String::new();
+        for (path, f) in self.files() {
+            if f.symlink.is_some() {
+                continue;
+            }
+            let checksum = f
+                .original_checksum
+                .map_or("-".to_string(), |c| format!("{c:08x}"));
+            let size = match (f.original_size, &f.compression) {
+                (Some(size), _) => size.to_string(),
+                (None, CompressionAlgo::NONE) => f.index_size.to_string(),
+                (None, _) => "-".to_string(),
+            };
+            manifest.push_str(&format!("{checksum}  {size}  {path}\n"));
+        }
+        manifest
+    }
+
+    /// Extract the file or directory at `path` into a `MemoryFs`, keeping its path within the archive
+    ///
+    /// Hard links become copies of the file they link to, symbolic links are kept as is.
+    pub fn extract_to_memory(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<MemoryFs, errors::FileReadError> {
+        let base = path.as_ref();
+        let entry = self.get_entry(base)?;
+
+        let mut entries = vec![(base.to_path_buf(), &entry)];
+        for (rel, e) in walk_entries(&entry) {
+            entries.push((base.join(rel.join("/")), e));
+        }
+
+        let mut tree = MemoryFs::new();
+        for (e_path, e) in entries {
+            match e {
+                QZEntry::Dir(_) => tree.add_dir(&e_path),
+                QZEntry::File(QZFile {
+                    symlink: Some(link),
+                    ..
+                }) => tree.add_symlink(&e_path, link),
+                QZEntry::File(_) => {
+                    let data = self.read_file(&e_path)?;
+                    tree.add_file(&e_path, data);
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    // `path` within the archive, relative paths start at its root
+    fn get_path(path: &std::path::Path) -> std::path::PathBuf {
+        std::path::Path::new("/").join(path)
+    }
+
+    /// Get qz entry for given path
+    ///
+    /// Paths start below the root directory, whatever name it was stored with.
+    /// `/` and the empty path give the root directory itself.
+    pub fn get_entry(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<QZEntry, errors::EntryError> {
+        let path = QZArchive::get_path(path.as_ref());
+        let mut path_c = path.components();
+
+        if path_c.next() == Some(std::path::Component::RootDir) {
+            let res = QZArchive::_get_entry(path_c, &self.header.root);
+            return res.cloned();
+        }
+        Err(errors::EntryError::PathError)
+    }
+
+    /// Stored and uncompressed size of everything below the directory at `path`,
+    /// or of the file at `path`
+    ///
+    /// Hard links count once, like in `QZArchiveHeader::total_uncompressed_size`, so the
+    /// sizes of the root directory are those of the whole archive.
+    pub fn dir_size(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(u64, u64), errors::EntryError> {
+        let entry = self.get_entry(path)?;
+        let files = match &entry {
+            QZEntry::File(f) => vec![f],
+            QZEntry::Dir(_) => walk_entries(&entry)
+                .into_iter()
+                .filter_map(|(_, e)| match e {
+                    QZEntry::File(f) => Some(f),
+                    QZEntry::Dir(_) => None,
+                })
+                .collect(),
+        };
+
+        let (mut stored, mut original) = (0, 0);
+        for f in files {
+            if f.hard_link.is_none() {
+                stored += f.index_size;
+            }
+            original += f.content_size();
+        }
+        Ok((stored, original))
+    }
+
+    fn _get_entry<'a>(
+        comp: std::path::Components,
+        current_entry: &'a QZEntry,
+    ) -> Result<&'a QZEntry, errors::EntryError> {
+        // Walk the path one component at a time instead of recursing,
+        // so deeply nested trees can't exhaust the stack
+        let mut current_entry = current_entry;
+        let mut walked = String::new();
+
+        for c in comp {
+            let current_dir = match current_entry {
+                QZEntry::Dir(d) => d,
+                QZEntry::File(_) => {
+                    return Err(errors::EntryError::NotADirectory(walked));
+                }
+            };
+
+            match c {
+                std::path::Component::Normal(walk_path_name) => {
+                    // Names that are no UTF-8 match no entry
+                    let next = current_dir
+                        .content
+                        .iter()
+                        .find(|e| *e.name() == *walk_path_name);
+                    match next {
+                        Some(e) => {
+                            current_entry = e;
+                            walked = format!("{walked}/{}", e.name());
+                        }
+                        None => {
+                            return Err(errors::EntryError::NothingFound);
+                        }
+                    }
+                }
+                _ => {
+                    return Err(errors::EntryError::PathError);
+                }
+            }
+        }
+
+        Ok(current_entry)
+    }
+
+    /// List content of directory returning list with filenames,
+    /// directories first and each group sorted by name
+    ///
+    /// `/` and the empty path list the root directory, which is empty for an archive
+    /// of an empty directory.
+    pub fn ls(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Vec<String>, errors::ListingError> {
+        Ok(listing(self.ls_iter(path)?))
+    }
+
+    /// Iterate over the entries of the directory at `path` as stored, without copying them
+    ///
+    /// Yields the same entries as `ls`, but unsorted, so listing a directory with many
+    /// entries allocates nothing.
+    pub fn ls_iter(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<impl Iterator<Item = &QZEntry>, errors::ListingError> {
+        let path = QZArchive::get_path(path.as_ref());
+        let mut path_c = path.components();
+
+        let content: &[QZEntry] = if path_c.next() == Some(std::path::Component::RootDir) {
+            match QZArchive::_get_entry(path_c, &self.header.root)? {
+                QZEntry::Dir(d) => &d.content,
+                QZEntry::File(_) => {
+                    return Err(errors::ListingError::IsFile);
+                }
+            }
+        } else {
+            // Only prefixed paths such as `C:` on Windows keep out of the root
+            return Err(errors::ListingError::InvalidPath);
+        };
+        Ok(content.iter())
+    }
+
+    /// List the entries of the directory at `path` that are of `kind`, ordered like `ls`
+    pub fn ls_filtered(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        kind: EntryKind,
+    ) -> Result<Vec<String>, errors::ListingError> {
+        Ok(listing(self.ls_iter(path)?.filter(|e| e.kind() == kind)))
+    }
+}
+
+// Names of `entries` as `ls` lists them, directories first and each group sorted by name
+fn listing<'a>(entries: impl Iterator<Item = &'a QZEntry>) -> Vec<String> {
+    let mut entries: Vec<&QZEntry> = entries.collect();
+    entries.sort_by(|a, b| {
+        let is_file = |e: &QZEntry| matches!(e, QZEntry::File(_));
+        is_file(a)
+            .cmp(&is_file(b))
+            .then_with(|| a.name().cmp(b.name()))
+    });
+    entries.into_iter().map(|e| e.name().to_string()).collect()
+}
+
+/// Anomaly in where files are stored, found by `QZArchive::check_layout`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutIssue {
+    /// Two files share part of their stored bytes, given as their paths and the offset
+    /// and length of the shared part
+    Overlap(String, String, u64, u64),
+    /// Offset and length of a part of the body no file refers to
+    Gap(u64, u64),
+}
+
+impl std::fmt::Display for LayoutIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayoutIssue::Overlap(a, b, start, len) => {
+                write!(f, "{a} and {b} share {len} bytes at offset {start}")
+            }
+            LayoutIssue::Gap(start, len) => {
+                write!(f, "{len} bytes at offset {start} belong to no file")
+            }
+        }
+    }
+}
+
+/// Content found by `QZArchive::read_or_archive_dir`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileOrDir {
+    /// Data of a file
+    File(Vec<u8>),
+    /// Names of the entries of a directory, directories first
+    Dir(Vec<String>),
+}
+
+/// Default maximum directory nesting accepted by `read_archive`
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Largest decompressed header accepted by `read_archive`
+pub const MAX_HEADER_SIZE: u64 = 256 * 1024 * 1024;
+
+// Nesting depth of raw JSON, used to bound the deserializer before it recurses
+fn json_depth(buf: &[u8]) -> usize {
+    let mut depth: usize = 0;
+    let mut max = 0;
+    let mut in_str = false;
+    let mut escaped = false;
+
+    for b in buf {
+        if in_str {
+            if escaped {
+                escaped = false;
+            } else if *b == b'\\' {
+                escaped = true;
+            } else if *b == b'"' {
+                in_str = false;
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_str = true,
+            b'{' | b'[' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    max
+}
+
+// Directory nesting depth of an entry tree
+pub(crate) fn tree_depth(root: &QZEntry) -> usize {
+    let mut max = 0;
+    let mut stack = vec![(root, 0)];
+
+    while let Some((e, depth)) = stack.pop() {
+        max = max.max(depth);
+        if let QZEntry::Dir(d) = e {
+            for c in &d.content {
+                if let QZEntry::Dir(_) = c {
+                    stack.push((c, depth + 1));
+                }
+            }
+        }
+    }
+
+    max
+}
+
+// Path and algorithm of the first file whose compression this build does not know,
+// used to explain why a header failed to decode
+fn unknown_compression(buf: &[u8]) -> Option<(String, String)> {
+    let mut de = serde_json::Deserializer::from_slice(buf);
+    de.disable_recursion_limit();
+    let header = serde_json::Value::deserialize(&mut de).ok()?;
+
+    // The root directory's name is not part of any path
+    let root = header.get("root")?.get("Dir")?;
+    let mut stack = vec![];
+    for c in root.get("content").and_then(|c| c.as_array())? {
+        stack.push((String::new(), c));
+    }
+
+    while let Some((path, e)) = stack.pop() {
+        if let Some(d) = e.get("Dir") {
+            let name = d.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            let path = format!("{path}/{name}");
+            for c in d.get("content").and_then(|c| c.as_array())? {
+                stack.push((path.clone(), c));
+            }
+        } else if let Some(f) = e.get("File") {
+            let name = f.get("name").and_then(|n| n.as_str()).unwrap_or_default();
+            let algo = f.get("compression")?;
+            let known = algo
+                .as_str()
+                .map(|a| serde_json::from_value::<CompressionAlgo>(a.into()).is_ok())
+                .unwrap_or(false);
+            if !known {
+                let algo = algo.as_str().map_or_else(|| algo.to_string(), String::from);
+                return Some((format!("{path}/{name}"), algo));
+            }
+        }
+    }
+
+    None
+}
+
+// Offset in `buf` of the position `err` was reported at
+fn error_offset(buf: &[u8], err: &serde_json::Error) -> usize {
+    let line_start: usize = buf
+        .split(|b| *b == b'\n')
+        .take(err.line().saturating_sub(1))
+        .map(|line| line.len() + 1)
+        .sum();
+    line_start + err.column()
+}
+
+// Key of the header field holding the string `err` found malformed, no valid UTF-8
+// or with a broken escape, used to explain why a header failed to decode
+fn malformed_string(buf: &[u8], err: &serde_json::Error) -> Option<String> {
+    let pos = match std::str::from_utf8(buf) {
+        Err(utf8) => utf8.valid_up_to(),
+        Ok(_) if err.to_string().contains("escape") => error_offset(buf, err),
+        Ok(_) => return None,
+    };
+
+    let mut key = None;
+    let mut start = None;
+    let mut escaped = false;
+    for (i, b) in buf.iter().enumerate().take(pos) {
+        match start {
+            Some(s) => {
+                if escaped {
+                    escaped = false;
+                } else if *b == b'\\' {
+                    escaped = true;
+                } else if *b == b'"' {
+                    start = None;
+                    let is_key = buf[i + 1..]
+                        .iter()
+                        .find(|b| !b.is_ascii_whitespace())
+                        .is_some_and(|b| *b == b':');
+                    if is_key {
+                        key = Some(String::from_utf8_lossy(&buf[s + 1..i]).into_owned());
+                    }
+                }
+            }
+            None if *b == b'"' => start = Some(i),
+            None => {}
+        }
+    }
+    key
+}
+
+// Check that every file lies within a body of `body_len` bytes
+fn validate_files(root: &QZEntry, body_len: u64) -> Result<(), errors::ReadError> {
+    for (path, e) in walk_entries(root) {
+        if let QZEntry::File(f) = e {
+            let end = f.index_start.checked_add(f.index_size);
+            if !matches!(end, Some(end) if end <= body_len) {
+                return Err(errors::ReadError::new(&format!(
+                    "file /{} lies outside of the archive body",
+                    path.join("/")
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Archive file `path` refers to and whether it is a volume set, which `archive.qz.001`
+// or a missing `archive.qz` with volumes next to it open
+#[cfg(feature = "fs")]
+fn locate_archive(path: &str) -> (&str, bool) {
+    match volume::volume_base(path) {
+        Some(base) => (base, true),
+        None => {
+            let spanned = !std::path::Path::new(path).exists()
+                && std::path::Path::new(&volume::volume_path(path, 0)).exists();
+            (path, spanned)
+        }
+    }
+}
+
+/// Check the stored bytes of an entry against its `checksum`, e.g. ones fetched elsewhere
+/// by `QZFile::offset` and `index_size`
+///
+/// Decompress them afterwards with `QZArchive::decompress_raw`, or `compression::decompress`
+/// for entries stored in one piece.
+pub fn verify_checksum(stored: &[u8], expected: u32) -> Result<(), errors::FileReadError> {
+    let hash = crc32fast::hash(stored);
+    if hash != expected {
+        return Err(errors::FileReadError::Checksum(hash, expected));
+    }
+    Ok(())
+}
+
+/// Read Archive File and return a QZArchive Struct
+#[cfg(feature = "fs")]
+pub fn read_archive(path: &str) -> Result<QZArchive, errors::ReadError> {
+    read_archive_with_max_depth(path, DEFAULT_MAX_DEPTH)
+}
+
+/// Read Archive File, rejecting directory trees nested deeper than `max_depth`
+#[cfg(feature = "fs")]
+pub fn read_archive_with_max_depth(
+    path: &str,
+    max_depth: usize,
+) -> Result<QZArchive, errors::ReadError> {
+    // OPEN FILE
+    let (path, spanned) = locate_archive(path);
+    let f = open_source(path, spanned);
+    if f.is_err() {
+        return Err(errors::ReadError::new("failed to open archive file"));
+    }
+    let f = f.unwrap();
+
+    let mut a = read_source(f, max_depth)?;
+    a.archive_file = path.to_string();
+    Ok(a)
+}
+
+/// Read an archive that starts `start` bytes into the file at `path`,
+/// e.g. one appended to an executable
+///
+/// All offsets of the archive count from `start`, the bytes before it are never read.
+/// Such an archive cannot be rewritten, as that would drop whatever precedes it.
+#[cfg(feature = "fs")]
+pub fn read_archive_at(path: &str, start: u64) -> Result<QZArchive, errors::ReadError> {
+    let len = match std::fs::metadata(path) {
+        Ok(m) => m.len().saturating_sub(start),
+        Err(_) => return Err(errors::ReadError::new("failed to open archive file")),
+    };
+    read_archive_range(path, start, len)
+}
+
+/// `read_archive_at` for an archive of `len` bytes, with other data following it
+#[cfg(feature = "fs")]
+pub fn read_archive_range(
+    path: &str,
+    start: u64,
+    len: u64,
+) -> Result<QZArchive, errors::ReadError> {
+    let f = match std::fs::File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Err(errors::ReadError::new("failed to open archive file")),
+    };
+    let source = source::ArchiveSource::Embedded(f, start, len);
+    let mut a = read_source(source, DEFAULT_MAX_DEPTH)?;
+    a.archive_file = path.to_string();
+    Ok(a)
+}
+
+/// Read an archive from a file that is already open, e.g. a temporary one removed again
+///
+/// The archive has no path, so it cannot be rewritten.
+#[cfg(feature = "fs")]
+pub fn read_archive_from_file(file: std::fs::File) -> Result<QZArchive, errors::ReadError> {
+    read_source(source::ArchiveSource::Single(file), DEFAULT_MAX_DEPTH)
+}
+
+/// Read an archive held in memory, e.g. one written by `QZArchiveBuilder::create_in_memory`
+///
+/// Nothing is read from disk. Such an archive cannot be rewritten,
+/// `set_info` and the other methods saving changes fail.
+pub fn read_archive_from_bytes(data: Vec<u8>) -> Result<QZArchive, errors::ReadError> {
+    read_source(source::ArchiveSource::Memory(data), DEFAULT_MAX_DEPTH)
+}
+
+/// Read an archive from `body` using its `index`, as written by `QZArchive::write_index`
+///
+/// The prefix and header are taken from the index, so reading a file only reads its
+/// stored data from `body`, e.g. with one HTTP range request. Offsets passed to `body`
+/// count from the start of the archive. An index that does not belong to the archive
+/// in `body` makes reads fail their checksums. Such an archive cannot be rewritten.
+pub fn read_archive_with_index(
+    index: Vec<u8>,
+    body: impl source::RangeSource + 'static,
+) -> Result<QZArchive, errors::ReadError> {
+    match body.len() {
+        Ok(len) if len >= index.len() as u64 => {}
+        Ok(_) => {
+            return Err(errors::ReadError::new(
+                "archive is shorter than its index, the index belongs to another archive",
+            ));
+        }
+        Err(err) => {
+            return Err(errors::ReadError::new(&format!(
+                "failed to read archive: {err}"
+            )));
+        }
+    }
+    let source = source::ArchiveSource::Indexed(index, Box::new(body));
+    read_source(source, DEFAULT_MAX_DEPTH)
+}
+
+/// `read_archive_from_bytes` for an archive starting `start` bytes into `data`
+pub fn read_archive_from_bytes_at(
+    mut data: Vec<u8>,
+    start: u64,
+) -> Result<QZArchive, errors::ReadError> {
+    if start > data.len() as u64 {
+        return Err(errors::ReadError::new(
+            "archive starts past the end of the data",
+        ));
+    }
+    data.drain(..start as usize);
+    read_archive_from_bytes(data)
+}
+
+// Layout of an archive as its prefix gives it
+struct Prefix {
+    // Length of the encoded header
+    header_size: u64,
+    // CRC32 of the encoded header, legacy archives store none
+    checksum: Option<u32>,
+    size: u64,
+    format: u8,
+    header_compression: CompressionAlgo,
+    archive_len: u64,
+}
+
+impl Prefix {
+    // Length of the body and the recovery records after it
+    fn trailer_len(&self) -> u64 {
+        self.archive_len - self.size - self.header_size
+    }
+}
+
+// Parse the prefix of the archive stored in `f`
+fn read_prefix(f: &source::ArchiveSource) -> Result<Prefix, errors::ReadError> {
+    let mut prefix_buf = [0u8; PREFIX_SIZE as usize];
+    let err = f.read_exact_at(&mut prefix_buf[..LEGACY_PREFIX_SIZE as usize], 0);
+    if err.is_err() {
+        return Err(errors::ReadError::new("failed to read header size"));
+    }
+
+    let (size, checksum, prefix_size, format, header_compression) = if prefix_buf[..3] == MAGIC[..]
+    {
+        let err = f.read_exact_at(&mut prefix_buf, 0);
+        if err.is_err() {
+            return Err(errors::ReadError::new("failed to read header size"));
+        }
+        let format = prefix_buf[3];
+        if format == 0 || format > FORMAT_VERSION {
+            return Err(errors::ReadError::new(&format!(
+                "unsupported archive format version {format}"
+            )));
+        }
+        let compression = match CompressionAlgo::from_tag(prefix_buf[4]) {
+            Some(compression) => compression,
+            None => {
+                return Err(errors::ReadError::new(&format!(
+                    "unknown header compression {}",
+                    prefix_buf[4]
+                )));
+            }
+        };
+        let size = u64::from_le_bytes(prefix_buf[5..13].try_into().unwrap());
+        let checksum = u32::from_le_bytes(prefix_buf[13..17].try_into().unwrap());
+        (size, Some(checksum), PREFIX_SIZE, format, compression)
+    } else {
+        // Legacy headers are always zstd compressed
+        let size = u64::from_ne_bytes(prefix_buf[..8].try_into().unwrap());
+        (size, None, LEGACY_PREFIX_SIZE, 0, CompressionAlgo::ZSTD)
+    };
+
+    // Zeroed or foreign files would otherwise fail on parsing an empty header
+    if size < MIN_HEADER_SIZE {
+        return Err(errors::ReadError::new(&format!(
+            "header size of {size} bytes is too small, this is no qz archive or it is damaged"
+        )));
     }
-    let header = header.unwrap();
 
-    //println!("header {:?}", header);
+    // Never allocate more than the archive could hold
+    let archive_len = match f.len() {
+        Ok(len) => len,
+        Err(_) => return Err(errors::ReadError::new("failed to read archive size")),
+    };
+    if size > archive_len - prefix_size {
+        return Err(errors::ReadError::new("header size exceeds archive"));
+    }
 
-    Ok(QZArchive {
-        archive_file: path.to_string(),
+    Ok(Prefix {
         header_size: size,
-        header,
+        checksum,
+        size: prefix_size,
+        format,
+        header_compression,
+        archive_len,
     })
 }
+
+// Parse and validate the archive stored in `f`
+fn read_source(f: source::ArchiveSource, max_depth: usize) -> Result<QZArchive, errors::ReadError> {
+    // GET HEADER
+    let prefix = read_prefix(&f)?;
+    let (size, prefix_size, format) = (prefix.header_size, prefix.size, prefix.format);
+
+    // READ HEADER
+    let mut header_buf: Vec<u8> = vec![0u8; size as usize];
+    let err = f.read_exact_at(&mut header_buf, prefix_size);
+    if err.is_err() {
+        return Err(errors::ReadError::new("failed to read header"));
+    }
+    if let Some(checksum) = prefix.checksum {
+        let hash = crc32fast::hash(&header_buf);
+        if hash != checksum {
+            return Err(errors::ReadError::new(&format!(
+                "header corrupted (Expected Checksum {checksum} but got {hash})"
+            )));
+        }
+    }
+    header_buf = decode_header(&header_buf, &prefix.header_compression, format)?;
+
+    // DESERIALIZE
+
+    // Every directory level adds an entry object, a dir object and a content array, a
+    // file in the innermost directory up to five more levels for itself and its chunks
+    if json_depth(&header_buf) > max_depth * 3 + 8 {
+        return Err(errors::ReadError::new(&format!(
+            "archive exceeds maximum directory depth of {max_depth}"
+        )));
+    }
+
+    let mut de = serde_json::Deserializer::from_slice(&header_buf);
+    de.disable_recursion_limit();
+    let header = match QZArchiveHeader::deserialize(&mut de) {
+        Ok(header) => header,
+        Err(err) => {
+            if let Some((path, algo)) = unknown_compression(&header_buf) {
+                return Err(errors::ReadError::new(&format!(
+                    "file {path} uses unknown compression algorithm '{algo}'"
+                )));
+            }
+            if let Some(key) = malformed_string(&header_buf, &err) {
+                return Err(errors::ReadError::new(&format!(
+                    "header field '{key}' holds malformed text"
+                )));
+            }
+            return Err(errors::ReadError::new("failed to decode header"));
+        }
+    };
+
+    // VALIDATE
+    let depth = tree_depth(&header.root);
+    if depth > max_depth {
+        return Err(errors::ReadError::new(&format!(
+            "archive nests directories {depth} levels deep, maximum is {max_depth}"
+        )));
+    }
+    let mut body_len = prefix.trailer_len();
+    if let Some(recovery) = &header.recovery {
+        if recovery.body_size > body_len {
+            return Err(errors::ReadError::new(
+                "recovery records describe a body larger than the archive",
+            ));
+        }
+        body_len = recovery.body_size;
+    }
+    validate_files(&header.root, body_len)?;
+
+    #[cfg(feature = "fs")]
+    if let source::ArchiveSource::Spanned(v) = &f {
+        let found = v.layout();
+        let consistent = match &header.volumes {
+            Some(volumes) => {
+                volumes.count == found.count && (found.count == 1 || volumes.size == found.size)
+            }
+            None => false,
+        };
+        if !consistent {
+            return Err(errors::ReadError::new(&format!(
+                "volume set is incomplete, found {} volumes of {} bytes",
+                found.count, found.size
+            )));
+        }
+    }
+
+    Ok(new_archive(f, &prefix, header))
+}
+
+// Archive stored in `f` with its parsed prefix and header
+fn new_archive(f: source::ArchiveSource, prefix: &Prefix, header: QZArchiveHeader) -> QZArchive {
+    QZArchive {
+        #[cfg(feature = "fs")]
+        archive_file: String::new(),
+        source: f,
+        header_size: prefix.header_size,
+        prefix_size: prefix.size,
+        format: prefix.format,
+        #[cfg(feature = "fs")]
+        header_compression: prefix.header_compression.clone(),
+        buffer_size: DEFAULT_BUFFER_SIZE,
+        #[cfg(feature = "fs")]
+        preserve_owner: false,
+        #[cfg(feature = "fs")]
+        resume: false,
+        #[cfg(feature = "fs")]
+        flatten: false,
+        #[cfg(feature = "fs")]
+        restore_line_endings: false,
+        #[cfg(feature = "fs")]
+        io_threads: 0,
+        #[cfg(all(test, feature = "fs"))]
+        io_peak: std::sync::atomic::AtomicUsize::new(0),
+        #[cfg(feature = "fs")]
+        verify_written: false,
+        #[cfg(feature = "fs")]
+        temp_dir: None,
+        #[cfg(feature = "xattr")]
+        restore_xattrs: false,
+        cache: None,
+        index: None,
+        verify_checksums: true,
+        pool: None,
+        progress: progress::SharedSink::default(),
+        written_by: semver::Version::parse(&header.version).ok(),
+        header,
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use crate::testing::{pack_files, patch_file, scratch_dir};
+
+    #[test]
+    fn refuses_archives_nested_deeper_than_the_max_depth() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(dir.join("a/b/c/d")).unwrap();
+        std::fs::write(dir.join("a/b/c/d/f"), b"x").unwrap();
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .create()
+            .unwrap();
+
+        assert!(read_archive_with_max_depth(out_file, 4).is_ok());
+        let err = read_archive_with_max_depth(out_file, 3).err().unwrap();
+        let err = err.to_string();
+        assert!(
+            err.contains("depth of 3") || err.contains("maximum is 3"),
+            "{err}"
+        );
+        let _ = std::fs::remove_file(out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn check_catches_damaged_compressed_bytes() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", &data), ("b.txt", b"intact")], |b| {
+            b.compression(CompressionAlgo::ZSTD)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        assert!(f.index_size < data.len() as u64);
+        patch_file(
+            &out_file,
+            a.stored_position(&f) + f.index_size / 2,
+            &[0x5a; 2],
+        );
+
+        let a = read_archive(&out_file).unwrap();
+        let failed = a.check_archive().unwrap_err();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, "/a.txt");
+        assert!(matches!(failed[0].1, errors::FileReadError::Checksum(..)));
+        assert!(a.check_file("/a.txt").is_err());
+        assert!(a.check_file("/b.txt").is_ok());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn deep_check_catches_content_the_stored_checksum_matches() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let mut a = read_archive(&out_file).unwrap();
+        let f = crate::write::find_file_mut(&mut a.header.root, "/a.txt").unwrap();
+        *f.original_checksum.as_mut().unwrap() ^= 1;
+        a.refresh_index();
+
+        assert!(a.check_archive().is_ok());
+        assert!(a.check_file("/a.txt").is_ok());
+        let failed = a.check_archive_deep().unwrap_err();
+        assert_eq!(failed[0].0, "/a.txt");
+        assert!(a.check_file_deep("/a.txt").is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn validity_checks_name_how_an_entry_is_damaged() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", &data), ("b.txt", &data)], |b| {
+            b.compression(CompressionAlgo::ZSTD)
+        });
+        let mut a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        // Overwrites the frame magic, so the stream cannot be decoded at all
+        patch_file(&out_file, a.stored_position(&f), &[0x5a; 2]);
+        let err = a.check_file("/a.txt").unwrap_err();
+        assert!(matches!(err, errors::FileReadError::Checksum(..)), "{err}");
+
+        // A checksum over the damaged bytes only shows once decompressed
+        let mut stored = vec![0; f.index_size as usize];
+        a.source
+            .read_exact_at(&mut stored, a.stored_position(&f))
+            .unwrap();
+        let f = crate::write::find_file_mut(&mut a.header.root, "/a.txt").unwrap();
+        f.checksum = crc32fast::hash(&stored);
+        a.refresh_index();
+        assert!(a.check_file("/a.txt").is_ok());
+        let err = a.check_file_deep("/a.txt").unwrap_err();
+        assert!(
+            matches!(&err, errors::FileReadError::CompressionError(name, _) if name == "a.txt"),
+            "{err}"
+        );
+
+        let f = a.lookup_file("/b.txt".as_ref()).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&out_file)
+            .unwrap();
+        file.set_len(a.stored_position(&f) + 2).unwrap();
+        let err = a.check_file("/b.txt").unwrap_err();
+        assert!(matches!(err, errors::FileReadError::Truncated), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn names_entries_cut_off_by_truncation() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", &data)], |b| b);
+        let mut a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        let end = a.stored_position(&f) + f.index_size;
+
+        // Stream cut short with a checksum over what is left
+        let mut stored = vec![0; f.index_size as usize / 2];
+        a.source
+            .read_exact_at(&mut stored, a.stored_position(&f))
+            .unwrap();
+        let f = crate::write::find_file_mut(&mut a.header.root, "/a.txt").unwrap();
+        f.index_size = stored.len() as u64;
+        f.checksum = crc32fast::hash(&stored);
+        a.refresh_index();
+        let err = a.read_file("/a.txt").unwrap_err().to_string();
+        assert!(
+            err.contains("a.txt") && err.contains("truncated stream"),
+            "{err}"
+        );
+
+        let a = read_archive(&out_file).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&out_file)
+            .unwrap();
+        file.set_len(end - 10).unwrap();
+        let dest = scratch_dir();
+        let err = a.extract_all(&dest, 0).unwrap_err().to_string();
+        assert!(err.contains("/a.txt") && err.contains("truncated"), "{err}");
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_archives_embedded_in_other_files() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", &data), ("sub/b.txt", b"beta")], |b| b);
+        let archive = std::fs::read(&out_file).unwrap();
+        let mut embedded = b"leading data".repeat(100);
+        let start = embedded.len() as u64;
+        embedded.extend(&archive);
+        embedded.extend(b"trailing data");
+        std::fs::write(&out_file, &embedded).unwrap();
+
+        let a = read_archive_range(&out_file, start, archive.len() as u64).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), data);
+        assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"beta");
+        assert!(a.check_archive().is_ok());
+
+        embedded.truncate(embedded.len() - b"trailing data".len());
+        std::fs::write(&out_file, &embedded).unwrap();
+        let a = read_archive_at(&out_file, start).unwrap();
+        assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"beta");
+        let a = read_archive_from_bytes_at(embedded, start).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), data);
+        assert!(read_archive_at(&out_file, start - 1).is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn borrowed_reads_match_owned_ones() {
+        use std::borrow::Cow;
+
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("raw.bin", &data), ("packed.txt", &data)], |b| {
+            b.compression_for("*.bin", CompressionAlgo::NONE)
+        });
+        let a = read_archive_from_bytes(std::fs::read(&out_file).unwrap()).unwrap();
+        let raw = a.read_file_borrowed("/raw.bin").unwrap();
+        assert!(matches!(raw, Cow::Borrowed(_)));
+        assert_eq!(raw, a.read_file("/raw.bin").unwrap());
+        let packed = a.read_file_borrowed("/packed.txt").unwrap();
+        assert!(matches!(packed, Cow::Owned(_)));
+        assert_eq!(packed, a.read_file("/packed.txt").unwrap());
+
+        // Files are read from disk unless mapped
+        #[cfg(feature = "mmap")]
+        {
+            let mut a = read_archive(&out_file).unwrap();
+            assert!(matches!(
+                a.read_file_borrowed("/raw.bin").unwrap(),
+                Cow::Owned(_)
+            ));
+            a.map().unwrap();
+            let raw = a.read_file_borrowed("/raw.bin").unwrap();
+            assert!(matches!(raw, Cow::Borrowed(_)));
+            assert_eq!(raw, data);
+        }
+
+        let f = a.lookup_file("/raw.bin".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), b"C");
+        let a = read_archive_from_bytes(std::fs::read(&out_file).unwrap()).unwrap();
+        let err = a.read_file_borrowed("/raw.bin").unwrap_err();
+        assert!(matches!(err, errors::FileReadError::Checksum(..)), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn streams_files_into_writers() {
+        let data: Vec<u8> = (0..300_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let (dir, out_file) = pack_files(&[("big.bin", &data), ("empty", b"")], |b| {
+            b.chunk_size(100_000)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let mut out = vec![];
+        assert_eq!(
+            a.read_file_to("/big.bin", &mut out).unwrap(),
+            data.len() as u64
+        );
+        assert!(out == data);
+        let mut out = vec![];
+        assert_eq!(a.read_file_to("/empty", &mut out).unwrap(), 0);
+        assert!(out.is_empty());
+        assert!(matches!(
+            a.read_file_to("/missing", vec![]),
+            Err(errors::FileReadError::NotFound)
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_entries_by_their_offset() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("b.txt", &data)], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/b.txt".as_ref()).unwrap();
+        let read = |start, size, checksum| a.read_at(start, size, &f.compression, checksum);
+        assert_eq!(
+            read(f.offset(), f.index_size, Some(f.checksum)).unwrap(),
+            data
+        );
+        assert_eq!(read(f.offset(), f.index_size, None).unwrap(), data);
+
+        let err = read(f.offset(), f.index_size, Some(f.checksum ^ 1)).unwrap_err();
+        assert!(matches!(err, errors::FileReadError::Checksum(..)), "{err}");
+        assert!(read(f.offset() + 1, f.index_size - 1, None).is_err());
+        assert!(read(f.offset(), f.index_size + 1_000_000, None).is_err());
+        assert!(read(u64::MAX, 2, None).is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn round_trips_trees_held_in_memory() {
+        let mut files = MemoryFs::new();
+        files.add_file("a.txt", "alpha");
+        files.add_file("sub/deeper/b.bin", vec![0u8; 10_000]);
+        files.add_dir("empty");
+        files.add_symlink("sub/link", "deeper/b.bin");
+        let data =
+            QZArchiveBuilder::from_source(std::sync::Arc::new(files.clone()), "", "unused.qz")
+                .create_in_memory()
+                .unwrap();
+        assert!(!std::path::Path::new("unused.qz").exists());
+
+        let a = read_archive_from_bytes(data).unwrap();
+        assert!(a.check_archive_deep().is_ok());
+        let extracted = a.extract_to_memory("/").unwrap();
+        let tree = |fs: &MemoryFs| {
+            fs.iter()
+                .map(|(path, node)| (path.to_path_buf(), node.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tree(&extracted), tree(&files));
+
+        let sub = a.extract_to_memory("sub/deeper").unwrap();
+        assert_eq!(sub.iter().count(), 3);
+        assert_eq!(
+            sub.read("sub/deeper/b.bin".as_ref()).unwrap(),
+            vec![0u8; 10_000]
+        );
+    }
+
+    #[test]
+    fn reads_ranges_from_the_chunks_covering_them() {
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 997).to_le_bytes())
+            .collect();
+        let (dir, out_file) = pack_files(&[("big.bin", &data)], |b| b.chunk_size(64 * 1024));
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/big.bin".as_ref()).unwrap();
+        assert_eq!(f.chunks.len(), 7);
+
+        // Spans the end of chunk 2 and the start of chunk 3
+        let (start, len) = (3 * 64 * 1024 - 100, 300);
+        let range = a.read_range("/big.bin", start, len).unwrap();
+        assert!(range == data[start as usize..start as usize + len]);
+
+        // Damage outside of the range does not affect it
+        let first = &f.chunks[0];
+        patch_file(&out_file, a.stored_position(&f) + first.offset, b"XXXX");
+        let last = &f.chunks[6];
+        patch_file(&out_file, a.stored_position(&f) + last.offset, b"XXXX");
+        let a = read_archive(&out_file).unwrap();
+        assert!(a.read_range("/big.bin", start, len).unwrap() == range);
+        assert!(a.read_range("/big.bin", 0, 10).is_err());
+        assert!(a
+            .read_range("/big.bin", data.len() as u64 - 10, 100)
+            .is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tells_apart_why_no_entry_was_found() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        let err = |path: &str| a.get_entry(path).unwrap_err();
+        assert!(matches!(
+            err("/sub/missing"),
+            errors::EntryError::NothingFound
+        ));
+        assert!(matches!(
+            err("/missing/a.txt"),
+            errors::EntryError::NothingFound
+        ));
+        assert!(matches!(
+            err("/sub/a.txt/deeper"),
+            errors::EntryError::NotADirectory(path) if path == "/sub/a.txt"
+        ));
+        assert!(matches!(
+            err("/sub/../sub/a.txt"),
+            errors::EntryError::PathError
+        ));
+        assert!(a.get_entry("/sub/./a.txt").is_ok());
+
+        // Each carries over to reading and listing
+        assert!(matches!(
+            a.read_file("/sub/missing"),
+            Err(errors::FileReadError::NotFound)
+        ));
+        assert!(matches!(
+            a.read_file("/sub/a.txt/deeper"),
+            Err(errors::FileReadError::NotADirectory(path)) if path == "/sub/a.txt"
+        ));
+        assert!(matches!(
+            a.read_file("/../a.txt"),
+            Err(errors::FileReadError::InvalidPath)
+        ));
+        assert!(matches!(
+            a.ls("/missing"),
+            Err(errors::ListingError::NotFound)
+        ));
+        assert!(matches!(
+            a.ls("/sub/a.txt"),
+            Err(errors::ListingError::IsFile)
+        ));
+        let other = errors::FileReadError::from(errors::EntryError::Other("odd".to_string()));
+        assert!(matches!(other, errors::FileReadError::Other(msg) if msg == "odd"));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn manifests_depend_on_content_only() {
+        let files: &[(&str, &[u8])] = &[("a.txt", b"alpha"), ("sub/b.txt", b"beta")];
+        let manifest = |compression: CompressionAlgo| {
+            let (dir, out_file) =
+                pack_files(files, |b| b.compression(compression).reproducible(true));
+            let manifest = read_archive(&out_file).unwrap().manifest();
+            let _ = std::fs::remove_file(&out_file);
+            let _ = std::fs::remove_dir_all(&dir);
+            manifest
+        };
+        let zstd = manifest(CompressionAlgo::ZSTD);
+        assert_eq!(zstd, manifest(CompressionAlgo::LZ4));
+        assert_eq!(zstd, manifest(CompressionAlgo::NONE));
+        assert_eq!(
+            zstd,
+            format!(
+                "{:08x}  5  /a.txt\n{:08x}  4  /sub/b.txt\n",
+                crc32fast::hash(b"alpha"),
+                crc32fast::hash(b"beta")
+            )
+        );
+    }
+
+    #[test]
+    fn reads_archives_from_removed_files() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let file = std::fs::File::open(&out_file).unwrap();
+        let _ = std::fs::remove_file(&out_file);
+
+        let a = read_archive_from_file(file).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lists_the_root_for_slash_and_the_empty_path() {
+        let (dir, out_file) = pack_files(&[("b.txt", b"beta"), ("a/x", b"x")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), ["a", "b.txt"]);
+        assert_eq!(a.ls("").unwrap(), ["a", "b.txt"]);
+        match a.get_entry("/").unwrap() {
+            QZEntry::Dir(root) => assert_eq!(root.content.len(), 2),
+            QZEntry::File(_) => panic!("root is no directory"),
+        }
+        assert!(matches!(a.get_entry("").unwrap(), QZEntry::Dir(_)));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (dir, out_file) = pack_files(&[], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        assert!(a.ls("/").unwrap().is_empty());
+        assert!(a.ls("").unwrap().is_empty());
+        match a.get_entry("/").unwrap() {
+            QZEntry::Dir(root) => assert!(root.content.is_empty()),
+            QZEntry::File(_) => panic!("root is no directory"),
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_archives_written_by_a_newer_major_version() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let mut a = read_archive(&out_file).unwrap();
+        assert!(a.check_version().is_ok());
+
+        a.written_by = Some(semver::Version::new(99, 0, 0));
+        let err = a.check_version().unwrap_err().to_string();
+        assert!(err.contains("written by qz 99.0.0"), "{err}");
+        let current = semver::Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+        a.written_by = Some(semver::Version::new(current.major, current.minor, 99));
+        assert!(a.check_version().is_ok());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn fingerprints_change_with_any_byte() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let fingerprint = read_archive(&out_file).unwrap().fingerprint().unwrap();
+        assert_eq!(
+            read_archive(&out_file).unwrap().fingerprint().unwrap(),
+            fingerprint
+        );
+        let len = std::fs::metadata(&out_file).unwrap().len();
+        assert!(fingerprint.starts_with(&format!("{len} ")));
+
+        let a = read_archive(&out_file).unwrap();
+        let pos = a.stored_position(&a.lookup_file("/a.txt".as_ref()).unwrap());
+        patch_file(&out_file, pos, b"A");
+        let a = read_archive(&out_file).unwrap();
+        assert_ne!(a.fingerprint().unwrap(), fingerprint);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_empty_files() {
+        for algo in [
+            CompressionAlgo::NONE,
+            CompressionAlgo::ZSTD,
+            CompressionAlgo::LZ4,
+        ] {
+            let (dir, out_file) = pack_files(&[("empty", b""), ("a.txt", b"alpha")], |b| {
+                b.compression(algo)
+            });
+            let a = read_archive(&out_file).unwrap();
+            assert!(a.read_file("/empty").unwrap().is_empty());
+            let mut streamed = vec![];
+            a.open_file("/empty")
+                .unwrap()
+                .read_to_end(&mut streamed)
+                .unwrap();
+            assert!(streamed.is_empty());
+            assert!(a.read_range("/empty", 0, 10).unwrap().is_empty());
+            assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+            let f = a.lookup_file("/empty".as_ref()).unwrap();
+            assert_eq!(f.original_size, Some(0));
+            assert!(a.check_archive().is_ok());
+            assert!(a.check_archive_deep().is_ok());
+            let _ = std::fs::remove_file(&out_file);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn parallel_checks_report_every_damaged_file() {
+        let names: Vec<String> = (0..40).map(|i| format!("f{i:02}.txt")).collect();
+        let data: Vec<Vec<u8>> = (0..40)
+            .map(|i| format!("file {i} ").repeat(50).into_bytes())
+            .collect();
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(data.iter().map(|d| d.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&files, |b| b.compression(CompressionAlgo::ZSTD));
+        let a = read_archive(&out_file).unwrap();
+        let damaged = ["/f03.txt", "/f17.txt", "/f38.txt"];
+        for path in damaged {
+            let f = a.lookup_file(path.as_ref()).unwrap();
+            patch_file(&out_file, a.stored_position(&f) + f.index_size / 2, &[0xa5]);
+        }
+
+        let mut a = read_archive(&out_file).unwrap();
+        a.set_threads(4).unwrap();
+        for _ in 0..5 {
+            for failed in [a.check_archive(), a.check_archive_deep()] {
+                let mut failed: Vec<String> = failed
+                    .unwrap_err()
+                    .into_iter()
+                    .map(|(path, _)| path)
+                    .collect();
+                failed.sort();
+                assert_eq!(failed, damaged);
+            }
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shared_archives_read_from_several_threads() {
+        let names: Vec<String> = (0..8).map(|i| format!("f{i}.txt")).collect();
+        let data: Vec<Vec<u8>> = (0..8)
+            .map(|i| format!("thread {i} ").repeat(400).into_bytes())
+            .collect();
+        let files: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|n| n.as_str())
+            .zip(data.iter().map(|d| d.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&files, |b| b.compression(CompressionAlgo::ZSTD));
+        let a = std::sync::Arc::new(read_archive(&out_file).unwrap());
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let a = a.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let content = a.read_file(format!("/f{i}.txt")).unwrap();
+                        assert_eq!(content, format!("thread {i} ").repeat(400).into_bytes());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_several_files_in_the_requested_order() {
+        let (dir, out_file) = pack_files(
+            &[("a", b"alpha"), ("b", b"beta"), ("sub/c", b"gamma")],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+        let results = a.read_files(&["/sub/c", "/missing", "/a", "/b", "/a"]);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap(), b"gamma");
+        assert!(matches!(results[1], Err(errors::FileReadError::NotFound)));
+        assert_eq!(results[2].as_ref().unwrap(), b"alpha");
+        assert_eq!(results[3].as_ref().unwrap(), b"beta");
+        assert_eq!(results[4].as_ref().unwrap(), b"alpha");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cached_reads_skip_decompression() {
+        let content = b"template ".repeat(200);
+        let (dir, out_file) = pack_files(&[("page.html", &content)], |b| {
+            b.compression(CompressionAlgo::ZSTD)
+        });
+        let mut a = read_archive(&out_file).unwrap();
+        a.set_cache_size(1 << 20);
+        assert_eq!(a.read_file("/page.html").unwrap(), content);
+
+        // Damage the stored data, only a read served from the cache still succeeds
+        let f = a.lookup_file("/page.html".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), &[0u8; 8]);
+        assert_eq!(a.read_file("page.html").unwrap(), content);
+        a.set_cache_size(0);
+        assert!(a.read_file("/page.html").is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reports_a_corrupted_header() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let mut data = std::fs::read(&out_file).unwrap();
+        data[PREFIX_SIZE as usize + 2] ^= 0x20;
+        let err = read_archive_from_bytes(data).err().unwrap().to_string();
+        assert!(err.contains("header corrupted"), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Archive with an uncompressed `header` declared as `size` bytes and no body
+    fn raw_archive(header: &[u8], size: u64) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend([FORMAT_VERSION, CompressionAlgo::NONE.tag()]);
+        data.extend(size.to_le_bytes());
+        data.extend(crc32fast::hash(header).to_le_bytes());
+        data.extend(header);
+        data
+    }
+
+    #[test]
+    fn refuses_malformed_headers_without_panicking() {
+        let err = read_archive_from_bytes(raw_archive(b"{}", u64::MAX - 4))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("header size exceeds archive"), "{err}");
+
+        let mut header = b"[".repeat(100_000);
+        header.extend(b"]".repeat(100_000));
+        let err = read_archive_from_bytes(raw_archive(&header, header.len() as u64))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("maximum directory depth"), "{err}");
+
+        let header = br#"{"name":"a","description":"","root":{"Dir":{"#;
+        let err = read_archive_from_bytes(raw_archive(header, header.len() as u64))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("failed to decode header"), "{err}");
+    }
+
+    #[test]
+    fn io_limit_frees_the_slot_of_a_panicking_operation() {
+        let io = IoLimit::new(1);
+        let res = std::panic::catch_unwind(|| io.run(|| panic!("read failed")));
+        assert!(res.is_err());
+        assert_eq!(io.run(|| 7), 7);
+        assert_eq!(*io.busy.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn iterated_and_filtered_listings_match_ls() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("b.txt", b"b"),
+                ("a.txt", b"a"),
+                ("z/x", b"x"),
+                ("c/y", b"y"),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+        let listed = a.ls("/").unwrap();
+        assert_eq!(listed, ["c", "z", "a.txt", "b.txt"]);
+        let mut iterated: Vec<&str> = a.ls_iter("/").unwrap().map(|e| e.name()).collect();
+        iterated.sort();
+        let mut sorted = listed.clone();
+        sorted.sort();
+        assert_eq!(iterated, sorted);
+
+        assert_eq!(a.ls_filtered("/", EntryKind::Dir).unwrap(), ["c", "z"]);
+        assert_eq!(
+            a.ls_filtered("/", EntryKind::File).unwrap(),
+            ["a.txt", "b.txt"]
+        );
+        assert!(a.ls_filtered("/", EntryKind::Symlink).unwrap().is_empty());
+        assert!(matches!(
+            a.ls_filtered("/a.txt", EntryKind::File),
+            Err(errors::ListingError::IsFile)
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reads_stored_bytes_at_the_body_offset() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("b.txt", b"beta")], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let data = std::fs::read(&out_file).unwrap();
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/b.txt".as_ref()).unwrap();
+        let start = (a.body_offset() + f.index_start) as usize;
+        assert_eq!(&data[start..start + 4], b"beta");
+        let read = a.read_at(
+            f.index_start,
+            f.index_size,
+            &f.compression,
+            Some(f.checksum),
+        );
+        assert_eq!(read.unwrap(), b"beta");
+
+        // Cut short while open, the file ends before the body starts
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&out_file)
+            .unwrap();
+        file.set_len(a.body_offset() - 1).unwrap();
+        let err = a.read_at(f.index_start, f.index_size, &f.compression, None);
+        let err = err.unwrap_err().to_string();
+        assert!(err.contains("exceed the archive body"), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn names_files_with_unknown_compression() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        let data = std::fs::read(&out_file).unwrap();
+        let a = read_archive_from_bytes(data.clone()).unwrap();
+        let mut header = serde_json::to_value(&a.header).unwrap();
+        header["root"]["Dir"]["content"][0]["Dir"]["content"][0]["File"]["compression"] =
+            "BROTLI".into();
+        let json = serde_json::to_vec(&header).unwrap();
+        let (path, algo) = unknown_compression(&json).unwrap();
+        assert_eq!((path.as_str(), algo.as_str()), ("/sub/a.txt", "BROTLI"));
+
+        let mut forged = MAGIC.to_vec();
+        forged.push(FORMAT_VERSION);
+        forged.push(CompressionAlgo::NONE.tag());
+        forged.extend_from_slice(&(json.len() as u64).to_le_bytes());
+        forged.extend_from_slice(&crc32fast::hash(&json).to_le_bytes());
+        forged.extend_from_slice(&json);
+        forged.extend_from_slice(&data[(PREFIX_SIZE + a.header_size()) as usize..]);
+        let err = read_archive_from_bytes(forged).err().unwrap().to_string();
+        assert!(
+            err.contains("file /sub/a.txt uses unknown compression algorithm 'BROTLI'"),
+            "{err}"
+        );
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Sink recording every warning
+    #[derive(Default)]
+    struct Warnings(std::sync::Mutex<Vec<String>>);
+
+    impl ProgressSink for Warnings {
+        fn add_work(&self, _files: u64, _bytes: u64) {}
+
+        fn file_done(&self, _path: &str, _bytes: u64) {}
+
+        fn warning(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn reads_damaged_entries_only_if_checksums_are_not_enforced() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), b"A");
+
+        let mut a = read_archive(&out_file).unwrap();
+        let err = a.read_file("/a.txt").unwrap_err().to_string();
+        assert!(err.contains("checksum mismatch"), "{err}");
+        assert!(a.check_archive().is_err());
+
+        let warnings = std::sync::Arc::new(Warnings::default());
+        a.set_progress(warnings.clone());
+        a.set_verify_checksums(false);
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"Alpha");
+        // Checking the archive is meant to find damage whatever reads tolerate
+        assert!(a.check_archive().is_err());
+        a.check_archive_deep().unwrap();
+        let warnings = warnings.0.lock().unwrap();
+        assert!(!warnings.is_empty());
+        assert!(
+            warnings[0].contains("checksum mismatch in"),
+            "{}",
+            warnings[0]
+        );
+        drop(warnings);
+
+        a.set_verify_checksums(true);
+        assert!(a.read_file("/a.txt").is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ls_tells_apart_why_a_path_cannot_be_listed() {
+        let (dir, out_file) = pack_files(&[("sub/a.txt", b"alpha")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+
+        assert!(matches!(
+            a.ls("/missing"),
+            Err(errors::ListingError::NotFound)
+        ));
+        assert!(matches!(
+            a.ls("/sub/missing"),
+            Err(errors::ListingError::NotFound)
+        ));
+        assert!(matches!(
+            a.ls("/sub/a.txt"),
+            Err(errors::ListingError::IsFile)
+        ));
+        assert!(matches!(
+            a.ls("/sub/a.txt/deeper"),
+            Err(errors::ListingError::NotADirectory(path)) if path == "/sub/a.txt"
+        ));
+        assert!(matches!(
+            a.ls("/sub/../sub"),
+            Err(errors::ListingError::InvalidPath)
+        ));
+        assert!(matches!(
+            a.ls_iter("/missing").map(|_| ()),
+            Err(errors::ListingError::NotFound)
+        ));
+        assert!(matches!(
+            a.ls_filtered("/missing", EntryKind::File),
+            Err(errors::ListingError::NotFound)
+        ));
+        assert_eq!(a.ls("sub").unwrap(), ["a.txt"]);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn raw_reads_return_the_stored_bytes() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(
+            &[("packed.txt", &data), ("raw.bin", &data), ("empty", b"")],
+            |b| b.compression_for("*.bin", CompressionAlgo::NONE),
+        );
+        let a = read_archive(&out_file).unwrap();
+        for path in ["/packed.txt", "/raw.bin", "/empty"] {
+            let raw = a.read_raw(path).unwrap();
+            let f = a.lookup_file(path.as_ref()).unwrap();
+            assert_eq!(raw.len() as u64, f.index_size, "{path}");
+            assert_eq!(
+                a.decompress_raw(path, raw).unwrap(),
+                a.read_file(path).unwrap()
+            );
+        }
+        assert!(a.read_raw("/packed.txt").unwrap().len() < data.len());
+        assert_eq!(a.read_raw("/raw.bin").unwrap(), data);
+
+        let f = a.lookup_file("/raw.bin".as_ref()).unwrap();
+        patch_file(&out_file, a.stored_position(&f), b"C");
+        let a = read_archive(&out_file).unwrap();
+        assert!(matches!(
+            a.read_raw("/raw.bin"),
+            Err(errors::FileReadError::Checksum(_, _))
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn names_header_fields_holding_malformed_text() {
+        let root = r#""root":{"Dir":{"name":"","content":[]}}"#;
+        let mut header = br#"{"name":"a","info":"caf"#.to_vec();
+        header.extend(b"\xc3\x28\xff");
+        header.extend(format!(r#"","version":"1",{root}}}"#).as_bytes());
+        let err = read_archive_from_bytes(raw_archive(&header, header.len() as u64))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("header field 'info' holds malformed text"),
+            "{err}"
+        );
+
+        let header = format!(r#"{{"name":"a","info":"","version":"bad \uZZZZ",{root}}}"#);
+        let err = read_archive_from_bytes(raw_archive(header.as_bytes(), header.len() as u64))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("header field 'version' holds malformed text"),
+            "{err}"
+        );
+
+        // Escaped text is fine
+        let header = format!(r#"{{"name":"caf\u00e9","info":"","version":"1",{root}}}"#);
+        let a =
+            read_archive_from_bytes(raw_archive(header.as_bytes(), header.len() as u64)).unwrap();
+        assert_eq!(a.header.name, "café");
+    }
+
+    #[test]
+    fn directory_sizes_add_up_to_the_archive() {
+        let text = b"compressible ".repeat(1024);
+        let (dir, out_file) = pack_files(
+            &[
+                ("top.txt", b"top"),
+                ("a/one.txt", &text),
+                ("a/deep/two.txt", b"two"),
+                ("b/three.txt", &text),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+
+        let (stored, original) = a.dir_size("/").unwrap();
+        assert_eq!(original, a.header.total_uncompressed_size());
+        assert_eq!(original, 3 + 3 + 2 * text.len() as u64);
+        assert_eq!(stored, a.stored_body_len().unwrap());
+        assert!(stored < original);
+
+        let parts: Vec<(u64, u64)> = ["/top.txt", "/a", "/b"]
+            .iter()
+            .map(|path| a.dir_size(path).unwrap())
+            .collect();
+        assert_eq!(parts.iter().map(|p| p.0).sum::<u64>(), stored);
+        assert_eq!(parts.iter().map(|p| p.1).sum::<u64>(), original);
+        let (deep, one) = (
+            a.dir_size("/a/deep").unwrap(),
+            a.dir_size("/a/one.txt").unwrap(),
+        );
+        assert_eq!((deep.0 + one.0, deep.1 + one.1), parts[1]);
+        assert_eq!(a.dir_size("/a/deep").unwrap().1, 3);
+        assert!(matches!(
+            a.dir_size("/missing"),
+            Err(errors::EntryError::NothingFound)
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn accessors_tell_where_the_archive_is_and_its_layout() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| {
+            b.header_compression(CompressionAlgo::NONE)
+        });
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.path(), out_file);
+
+        // The uncompressed header is the JSON between the prefix and the body
+        let data = std::fs::read(&out_file).unwrap();
+        let header = &data[(a.body_offset() - a.header_size()) as usize..a.body_offset() as usize];
+        assert_eq!((header[0], header[header.len() - 1]), (b'{', b'}'));
+        assert!(serde_json::from_slice::<serde_json::Value>(header).is_ok());
+        let file_len = std::fs::metadata(&out_file).unwrap().len();
+        assert!(a.body_offset() > a.header_size());
+        assert_eq!(file_len - a.body_offset(), a.stored_body_len().unwrap());
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        assert_eq!(a.stored_body_len().unwrap(), f.index_size);
+
+        let in_memory = read_archive_from_bytes(std::fs::read(&out_file).unwrap()).unwrap();
+        assert_eq!(in_memory.path(), "");
+        assert_eq!(in_memory.header_size(), a.header_size());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_header_sizes_too_small_for_a_header() {
+        let dir = scratch_dir();
+        let zeroed = dir.join("zeroed.qz");
+        std::fs::write(&zeroed, [0u8; 64]).unwrap();
+        let err = read_archive(zeroed.to_str().unwrap())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("header size of 0 bytes is too small"), "{err}");
+
+        for size in [0, MIN_HEADER_SIZE - 1] {
+            let err = read_archive_from_bytes(raw_archive(b"{}", size))
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(
+                err.contains(&format!("header size of {size} bytes is too small")),
+                "{err}"
+            );
+        }
+        let err = read_archive_from_bytes(raw_archive(b"{\"name\":1}", MIN_HEADER_SIZE))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(!err.contains("too small"), "{err}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn opens_archives_behind_a_junk_prefix() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("sub/b.txt", b"beta")], |b| b);
+        let plain = read_archive(&out_file).unwrap();
+        let mut data = vec![0x7f; 4093];
+        data.extend(std::fs::read(&out_file).unwrap());
+        std::fs::write(&out_file, &data).unwrap();
+        assert!(read_archive(&out_file).is_err());
+
+        let mut a = read_archive_at(&out_file, 4093).unwrap();
+        // Offsets count from the start of the archive, not of the file
+        assert_eq!(a.body_offset(), plain.body_offset());
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"beta");
+        a.check_archive_deep().unwrap();
+        let dest = scratch_dir();
+        a.extract_all(&dest, 0).unwrap();
+        assert_eq!(std::fs::read(dest.join("sub/b.txt")).unwrap(), b"beta");
+
+        let err = a.set_info(Some("renamed"), None).unwrap_err().to_string();
+        assert!(err.contains("embedded in another file"), "{err}");
+        assert_eq!(std::fs::read(&out_file).unwrap(), data);
+
+        let a = read_archive_from_bytes_at(data, 4093).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&dest);
+    }
+
+    #[test]
+    fn lists_directories_read_as_files() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                ("sub/b.txt", b"beta"),
+                ("sub/deep/c", b"c"),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+
+        assert!(matches!(
+            a.read_file("/sub"),
+            Err(errors::FileReadError::NotAFile)
+        ));
+        let listing =
+            |names: &[&str]| FileOrDir::Dir(names.iter().map(|n| n.to_string()).collect());
+        assert_eq!(
+            a.read_or_archive_dir("/sub").unwrap(),
+            listing(&["deep", "b.txt"])
+        );
+        assert_eq!(
+            a.read_or_archive_dir("/").unwrap(),
+            listing(&["sub", "a.txt"])
+        );
+        assert_eq!(
+            a.read_or_archive_dir("/sub/b.txt").unwrap(),
+            FileOrDir::File(b"beta".to_vec())
+        );
+        assert!(matches!(
+            a.read_or_archive_dir("/missing"),
+            Err(errors::FileReadError::NotFound)
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filters_listings_by_each_kind() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("b.txt", b"beta"),
+                ("a.txt", b"alpha"),
+                ("z/x", b"x"),
+                ("c/y", b"y"),
+            ],
+            |b| b,
+        );
+        std::os::unix::fs::symlink("a.txt", dir.join("to_a")).unwrap();
+        std::os::unix::fs::symlink("c", dir.join("to_c")).unwrap();
+        std::fs::hard_link(dir.join("b.txt"), dir.join("b2.txt")).unwrap();
+        QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .create()
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+
+        let all = a.ls("/").unwrap();
+        let dirs = a.ls_filtered("/", EntryKind::Dir).unwrap();
+        let files = a.ls_filtered("/", EntryKind::File).unwrap();
+        let links = a.ls_filtered("/", EntryKind::Symlink).unwrap();
+        assert_eq!(dirs, ["c", "z"]);
+        assert_eq!(files, ["a.txt", "b.txt", "b2.txt"]);
+        assert_eq!(links, ["to_a", "to_c"]);
+        assert_eq!(dirs.len() + files.len() + links.len(), all.len());
+        assert_eq!(a.ls_filtered("/c", EntryKind::File).unwrap(), ["y"]);
+        assert!(a.ls_filtered("/c", EntryKind::Dir).unwrap().is_empty());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn layout_checks_find_overlaps_and_gaps() {
+        let (dir, out_file) = pack_files(&[("a", b"aaaa"), ("b", b"bbbb"), ("c", b"cccc")], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let mut a = read_archive(&out_file).unwrap();
+        assert!(a.check_layout().unwrap().is_empty());
+
+        // Paths in the order their data is stored
+        let mut stored: Vec<(u64, String)> = a
+            .files()
+            .into_iter()
+            .map(|(path, f)| (f.index_start, path))
+            .collect();
+        stored.sort();
+        let [first, second, third] = [0, 1, 2].map(|i| stored[i].1.clone());
+
+        crate::write::find_file_mut(&mut a.header.root, &second)
+            .unwrap()
+            .index_start = 2;
+        a.refresh_index();
+        assert_eq!(
+            a.check_layout().unwrap(),
+            [
+                LayoutIssue::Overlap(first.clone(), second.clone(), 2, 2),
+                LayoutIssue::Gap(6, 2),
+            ]
+        );
+
+        // Files sharing exactly the same bytes, like hard links, are fine
+        crate::write::find_file_mut(&mut a.header.root, &second)
+            .unwrap()
+            .index_start = 0;
+        crate::write::find_file_mut(&mut a.header.root, &third)
+            .unwrap()
+            .index_start = 0;
+        a.refresh_index();
+        let issues = a.check_layout().unwrap();
+        assert_eq!(issues, [LayoutIssue::Gap(4, 8)]);
+        assert_eq!(
+            issues[0].to_string(),
+            "8 bytes at offset 4 belong to no file"
+        );
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Archive data recording every range read from it
+    struct RecordedReads(Vec<u8>, std::sync::Arc<std::sync::Mutex<Vec<(u64, usize)>>>);
+
+    impl source::RangeSource for RecordedReads {
+        fn len(&self) -> std::io::Result<u64> {
+            Ok(self.0.len() as u64)
+        }
+
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            let start = (offset as usize).min(self.0.len());
+            let n = buf.len().min(self.0.len() - start);
+            buf[..n].copy_from_slice(&self.0[start..start + n]);
+            self.1.lock().unwrap().push((offset, buf.len()));
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn sidecar_indexes_let_reads_touch_only_file_data() {
+        let data = b"compressible ".repeat(4096);
+        let (dir, out_file) = pack_files(&[("a.txt", &data), ("sub/b.txt", b"beta")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        let mut index = vec![];
+        a.write_index(&mut index).unwrap();
+        assert_eq!(index.len() as u64, a.body_offset());
+        let archive = std::fs::read(&out_file).unwrap();
+        assert_eq!(index, archive[..index.len()]);
+
+        let reads = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let body = RecordedReads(archive.clone(), reads.clone());
+        let indexed = read_archive_with_index(index, body).unwrap();
+        assert_eq!(indexed.ls("/").unwrap(), a.ls("/").unwrap());
+        reads.lock().unwrap().clear();
+        assert_eq!(indexed.read_file("/sub/b.txt").unwrap(), b"beta");
+        let f = a.lookup_file("/sub/b.txt".as_ref()).unwrap();
+        let (start, end) = (a.stored_position(&f), a.stored_position(&f) + f.index_size);
+        let reads = reads.lock().unwrap().clone();
+        assert!(!reads.is_empty());
+        assert!(
+            reads
+                .iter()
+                .all(|(offset, len)| *offset >= start && offset + *len as u64 <= end),
+            "{reads:?}"
+        );
+        assert_eq!(indexed.read_file("/a.txt").unwrap(), data);
+
+        // An index of another archive fails the checksums
+        let (other_dir, other_file) =
+            pack_files(&[("a.txt", b"other"), ("sub/b.txt", b"data")], |b| b);
+        let mut other_index = vec![];
+        read_archive(&other_file)
+            .unwrap()
+            .write_index(&mut other_index)
+            .unwrap();
+        let body = RecordedReads(archive, Default::default());
+        let mismatched = read_archive_with_index(other_index, body).unwrap();
+        assert!(mismatched.read_file("/a.txt").is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_file(&other_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&other_dir);
+    }
+
+    #[test]
+    fn name_and_description_accessors_read_the_header() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| {
+            b.name("photos").description("Holiday photos\n")
+        });
+        let mut a = read_archive(&out_file).unwrap();
+        assert_eq!(a.name(), "photos");
+        assert_eq!(a.description(), "Holiday photos\n");
+        assert_eq!(a.name(), a.header.name);
+        assert_eq!(a.description(), a.header.info);
+        let info = a.header.to_string();
+        assert!(
+            info.starts_with(&format!(
+                "QZ Archive v.{}: 'photos'\nHoliday photos\n",
+                a.header.version
+            )),
+            "{info}"
+        );
+
+        a.set_info(Some("trip"), Some("")).unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!((a.name(), a.description()), ("trip", ""));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn checks_and_decompresses_data_fetched_elsewhere() {
+        let text = b"compressible ".repeat(8192);
+        let (dir, out_file) = pack_files(&[("a.txt", &text), ("b.txt", b"beta")], |b| {
+            b.chunk_size(16 * 1024)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let archive = std::fs::read(&out_file).unwrap();
+
+        for (path, data) in [("/a.txt", &text[..]), ("/b.txt", b"beta")] {
+            let f = a.lookup_file(path.as_ref()).unwrap();
+            let start = (a.body_offset() + f.offset()) as usize;
+            let mut fetched = archive[start..start + f.index_size as usize].to_vec();
+            verify_checksum(&fetched, f.checksum).unwrap();
+            assert_eq!(a.decompress_raw(path, fetched.clone()).unwrap(), data);
+
+            let err = a
+                .decompress_raw(path, fetched[1..].to_vec())
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("stored bytes"), "{err}");
+            fetched[0] ^= 0xff;
+            assert!(matches!(
+                verify_checksum(&fetched, f.checksum),
+                Err(errors::FileReadError::Checksum(_, expected)) if expected == f.checksum
+            ));
+            assert!(a.decompress_raw(path, fetched).is_err());
+        }
+        let chunked = a.lookup_file("/a.txt".as_ref()).unwrap();
+        assert!(chunked.chunks.len() > 1);
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_tables_list_every_file_once_in_body_order() {
+        // Stored in another order than the tree lists them
+        let configure = |b: QZArchiveBuilder| {
+            b.file_order(FileOrder::Paths(vec!["/sub/c.bin".to_string()]))
+                .compression_for("*.bin", CompressionAlgo::NONE)
+        };
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                ("sub/b.txt", b"beta"),
+                ("sub/c.bin", b"gamma"),
+                ("d", b""),
+            ],
+            configure,
+        );
+        #[cfg(unix)]
+        {
+            std::fs::hard_link(dir.join("a.txt"), dir.join("sub/a2.txt")).unwrap();
+            configure(QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file))
+                .create()
+                .unwrap();
+        }
+        let a = read_archive(&out_file).unwrap();
+        let table = a.file_table();
+
+        let offsets: Vec<u64> = table.iter().map(|row| row.1).collect();
+        assert!(offsets.windows(2).all(|w| w[0] <= w[1]), "{offsets:?}");
+        assert_eq!(table[0].0, "/sub/c.bin");
+        assert_eq!(table[0].3.to_string(), "none");
+        let mut listed: Vec<&str> = table.iter().map(|row| row.0.as_str()).collect();
+        listed.sort();
+        let mut files: Vec<String> = a
+            .entries()
+            .into_iter()
+            .filter(|(_, e)| matches!(e, QZEntry::File(_)))
+            .map(|(path, _)| path)
+            .collect();
+        files.sort();
+        assert_eq!(listed, files);
+
+        let archive = std::fs::read(&out_file).unwrap();
+        for (path, start, size, _, checksum) in &table {
+            let start = (a.body_offset() + start) as usize;
+            let stored = &archive[start..start + *size as usize];
+            assert_eq!(crc32fast::hash(stored), *checksum, "{path}");
+        }
+        #[cfg(unix)]
+        {
+            // Hard links follow the file holding their data, either may be listed first
+            let row = |path: &str| table.iter().position(|row| row.0 == path).unwrap();
+            let (mut link, mut target) = (row("/sub/a2.txt"), row("/a.txt"));
+            if a.lookup_file("/a.txt".as_ref())
+                .unwrap()
+                .hard_link
+                .is_some()
+            {
+                std::mem::swap(&mut link, &mut target);
+            }
+            assert_eq!(table[link].1, table[target].1);
+            assert!(target < link);
+        }
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn indexed_lookups_use_the_map_until_it_is_rebuilt() {
+        let files: Vec<(String, Vec<u8>)> = (0..200)
+            .map(|i| {
+                (
+                    format!("d{}/f{i}", i % 10),
+                    format!("file {i}").into_bytes(),
+                )
+            })
+            .collect();
+        let listed: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&listed, |b| b);
+
+        // Renaming an entry in the tree only shows once the map is rebuilt
+        let rename = |a: &mut QZArchive| {
+            crate::write::find_file_mut(&mut a.header.root, "/d7/f7")
+                .unwrap()
+                .name = "renamed".to_string();
+        };
+        let mut walked = read_archive(&out_file).unwrap();
+        rename(&mut walked);
+        assert!(matches!(
+            walked.read_file("/d7/f7"),
+            Err(errors::FileReadError::NotFound)
+        ));
+
+        let mut a = read_archive(&out_file).unwrap();
+        a.build_index();
+        rename(&mut a);
+        for (name, data) in &files {
+            assert_eq!(&a.read_file(format!("/{name}")).unwrap(), data);
+        }
+        assert_eq!(a.read_file("//d7/./f7").unwrap(), b"file 7");
+        assert!(a.read_file("/d7/renamed").is_ok());
+
+        a.refresh_index();
+        assert!(matches!(
+            a.read_file("/d7/f7"),
+            Err(errors::FileReadError::NotFound)
+        ));
+        assert_eq!(a.read_file("/d7/renamed").unwrap(), b"file 7");
+        assert!(matches!(
+            a.read_file("/d7"),
+            Err(errors::FileReadError::NotAFile)
+        ));
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn lists_archives_stored_inside_archives() {
+        let (inner_dir, inner_file) =
+            pack_files(&[("x.txt", b"inner"), ("sub/y.txt", b"deeper")], |b| b);
+        let inner = std::fs::read(&inner_file).unwrap();
+        let (dir, out_file) = pack_files(
+            &[("parts/inner.qz", &inner), ("other.txt", b"plain")],
+            |b| b,
+        );
+
+        let a = read_archive(&out_file).unwrap();
+        assert!(a.lookup_file("/parts/inner.qz".as_ref()).unwrap().nested);
+        assert!(!a.lookup_file("/other.txt".as_ref()).unwrap().nested);
+        let nested = a.open_nested("/parts/inner.qz").unwrap();
+        assert_eq!(nested.ls("/").unwrap(), ["sub", "x.txt"]);
+        assert_eq!(nested.read_file("/sub/y.txt").unwrap(), b"deeper");
+        assert_eq!(nested.path(), "");
+
+        let err = a.open_nested("/other.txt").err().unwrap().to_string();
+        assert!(
+            err.contains("/other.txt holds no readable archive"),
+            "{err}"
+        );
+        let err = a.open_nested("/missing.qz").err().unwrap().to_string();
+        assert!(err.contains("failed to read /missing.qz"), "{err}");
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_file(&inner_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&inner_dir);
+    }
+
+    #[test]
+    fn body_lengths_reveal_truncation_and_appended_data() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("sub/b.txt", b"beta")], |b| b);
+        let a = read_archive(&out_file).unwrap();
+        let len = std::fs::metadata(&out_file).unwrap().len();
+        assert_eq!(a.body_len(), len - a.body_offset());
+        assert_eq!(a.body_len(), a.stored_body_len().unwrap());
+        a.check_body_len().unwrap();
+
+        let mut data = std::fs::read(&out_file).unwrap();
+        data.extend(b"junk");
+        std::fs::write(&out_file, &data).unwrap();
+        let appended = read_archive(&out_file).unwrap();
+        let err = appended.check_body_len().unwrap_err().to_string();
+        assert!(
+            err.contains("4 bytes of unknown data follow the body"),
+            "{err}"
+        );
+
+        // Archives cut short are refused on opening, so cut one that is open
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&out_file)
+            .unwrap();
+        f.set_len(len - 3).unwrap();
+        let err = a.check_body_len().unwrap_err().to_string();
+        assert!(err.contains("3 bytes are missing at the end"), "{err}");
+        assert!(read_archive(&out_file).is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn info_leaves_out_a_missing_description() {
+        for description in ["", "\n"] {
+            let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| {
+                b.name("").description(description)
+            });
+            let a = read_archive(&out_file).unwrap();
+            let info = a.header.to_string();
+            let lines: Vec<&str> = info.lines().collect();
+            assert_eq!(
+                lines[0],
+                format!("QZ Archive v.{} (unnamed)", a.header.version)
+            );
+            assert_eq!(lines[1], "1 files in 0 directories");
+            assert!(lines.iter().all(|line| !line.trim().is_empty()), "{info}");
+            let json: serde_json::Value = serde_json::from_str(&a.header_json()).unwrap();
+            assert_eq!(json["name"], "");
+            assert_eq!(json["info"], description);
+            let _ = std::fs::remove_file(&out_file);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+
+        // Headers without the fields at all read as empty ones
+        let header = br#"{"version":"1","root":{"Dir":{"name":"","content":[]}}}"#;
+        let a = read_archive_from_bytes(raw_archive(header, header.len() as u64)).unwrap();
+        assert_eq!((a.name(), a.description()), ("", ""));
+        assert_eq!(
+            a.header.to_string(),
+            "QZ Archive v.1 (unnamed)\n0 files in 0 directories\n0 B stored in 0 B\n"
+        );
+    }
+}
@@ -0,0 +1,4163 @@
+use crate::checkpoint::{self, Record};
+use crate::progress::{self, ProgressSink, SkipReason};
+use crate::vfs::{self, FileSource};
+use crate::write::{
+    archive_size, create_temp, encode_archive, encode_header, find_file_mut, remove_archive,
+    write_stream, write_versioned,
+};
+use crate::{
+    compression, errors, in_pool, read_archive, read_archive_from_bytes, thread_pool, volume,
+    walk_entries, Chunk, CompressionAlgo, IoLimit, QZArchiveHeader, QZDir, QZEntry, QZFile,
+    QZRecovery, DEFAULT_BUFFER_SIZE, FORMAT_VERSION, MIN_BUFFER_SIZE, PREFIX_SIZE,
+};
+use rayon::prelude::*;
+use std::fs;
+use std::io::{Read, Seek, Write};
+
+// User and group owning a file, where the platform has them
+#[cfg(unix)]
+pub(crate) fn owner(m: &fs::Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(m.uid()), Some(m.gid()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn owner(_m: &fs::Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
+
+// Extended attributes of `path`, empty unless built with the `xattr` feature
+#[cfg(feature = "xattr")]
+pub(crate) fn read_xattrs(
+    path: &std::path::Path,
+) -> Result<std::collections::BTreeMap<String, Vec<u8>>, errors::WriteError> {
+    let read_err =
+        |err: std::io::Error| errors::WriteError::SourceRead(path.display().to_string(), err);
+
+    let mut attrs = std::collections::BTreeMap::new();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => return Ok(attrs),
+        Err(err) => return Err(read_err(err)),
+    };
+    for name in names {
+        // Attributes are keyed by name in the header, which has to be valid UTF-8
+        let Some(key) = name.to_str() else {
+            continue;
+        };
+        if let Some(value) = xattr::get(path, &name).map_err(read_err)? {
+            attrs.insert(key.to_string(), value);
+        }
+    }
+    Ok(attrs)
+}
+
+#[cfg(not(feature = "xattr"))]
+pub(crate) fn read_xattrs(
+    _path: &std::path::Path,
+) -> Result<std::collections::BTreeMap<String, Vec<u8>>, errors::WriteError> {
+    Ok(std::collections::BTreeMap::new())
+}
+
+// Reject names that lookups or extraction would read as several components
+fn check_name(name: &str, path: &std::path::Path) -> Result<(), errors::WriteError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(errors::WriteError::InvalidInput(format!(
+            "cannot pack {}, '{name}' is not a valid entry name",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+// Name of `path` as stored in the archive
+fn entry_name(path: &std::path::Path) -> Result<String, errors::WriteError> {
+    let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| {
+        errors::WriteError::InvalidInput(format!(
+            "cannot pack {}, its name is not valid UTF-8",
+            path.display()
+        ))
+    })?;
+    check_name(name, path)?;
+    Ok(name.to_string())
+}
+
+// Settings shared by everything that turns paths into entries
+#[derive(Clone)]
+struct ScanOptions {
+    compression: CompressionAlgo,
+    // Algorithms for files whose name matches a pattern, see `CompressionConfig::overrides`
+    overrides: Vec<(String, CompressionAlgo)>,
+    xattrs: bool,
+    // Canonical paths that are never packed, i.e. the archive being written
+    skip: Vec<std::path::PathBuf>,
+    relative_symlinks: bool,
+    special_files: SpecialFiles,
+    // Whether subdirectories are packed, otherwise they are left out
+    recursive: bool,
+    // Whether entries whose name starts with `.` are packed when walking directories
+    hidden: bool,
+    source: std::sync::Arc<dyn FileSource>,
+    // Receives the files left out
+    progress: std::sync::Arc<dyn ProgressSink>,
+    // Files and directories left out so far, shared by all copies
+    skipped: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    // Canonical path of the directory being packed, absolute link targets below it
+    // are made relative if `relative_symlinks` is set
+    root: Option<std::path::PathBuf>,
+}
+
+impl ScanOptions {
+    // Algorithm for the file called `name`
+    fn compression_for(&self, name: &str) -> CompressionAlgo {
+        match self.overrides.iter().find(|(p, _)| glob_match(p, name)) {
+            Some((_, compression)) => compression.clone(),
+            None => self.compression.clone(),
+        }
+    }
+
+    // Whether `path` is one of the files the archive is written to
+    fn is_output(&self, path: &std::path::Path) -> bool {
+        self.skip.iter().any(|s| s.file_name() == path.file_name())
+            && fs::canonicalize(path).is_ok_and(|p| self.skip.contains(&p))
+    }
+
+    // Whether `path` has to be left out, reporting it if so
+    fn skips(&self, path: &std::path::Path) -> bool {
+        let skipped = self.is_output(path);
+        if skipped {
+            self.skip(path, SkipReason::Output);
+        }
+        skipped
+    }
+
+    // Report `path` as left out for `reason`
+    fn skip(&self, path: &std::path::Path, reason: SkipReason) {
+        self.skipped
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.progress.skipped(&path.display().to_string(), &reason);
+    }
+}
+
+// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?`
+// for a single one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the part of `name` it swallowed so far
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, swallowed)) => {
+                    p = after;
+                    n = swallowed + 1;
+                    star = Some((after, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Existing files the archive at `out_file` is written to, including temporary file and volumes
+fn output_paths(out_file: &str) -> Vec<std::path::PathBuf> {
+    let mut paths = vec![
+        out_file.to_string(),
+        format!("{out_file}.tmp"),
+        format!("{out_file}.body.tmp"),
+        checkpoint::body_path(out_file),
+        checkpoint::log_path(out_file),
+    ];
+    let mut i = 0;
+    while std::path::Path::new(&volume::volume_path(out_file, i)).exists() {
+        paths.push(volume::volume_path(out_file, i));
+        i += 1;
+    }
+    paths
+        .iter()
+        .filter_map(|p| fs::canonicalize(p).ok())
+        .collect()
+}
+
+// Entry for the file at `path`, its data is filled in by `write_file`
+fn file_entry(
+    path: &std::path::Path,
+    name: String,
+    opts: &ScanOptions,
+) -> Result<QZFile, errors::WriteError> {
+    check_name(&name, path)?;
+    let (uid, gid) = opts.source.owner(path);
+    let xattrs = if opts.xattrs {
+        opts.source.xattrs(path)?
+    } else {
+        std::collections::BTreeMap::new()
+    };
+
+    Ok(QZFile {
+        compression: opts.compression_for(&name),
+        name,
+        checksum: 0,
+        index_start: 0,
+        index_size: 0,
+        original_size: None,
+        original_checksum: None,
+        comment: None,
+        hard_link: None,
+        symlink: None,
+        uid,
+        gid,
+        xattrs,
+        chunks: vec![],
+        crlf: false,
+        nested: false,
+    })
+}
+
+// Entry for the symbolic link at `path`, which is stored instead of what it points to
+fn symlink_entry(
+    path: &std::path::Path,
+    name: String,
+    opts: &ScanOptions,
+) -> Result<QZFile, errors::WriteError> {
+    let mut target = opts
+        .source
+        .read_link(path)
+        .map_err(|err| errors::WriteError::SourceRead(path.display().to_string(), err))?;
+
+    if opts.relative_symlinks && target.is_absolute() {
+        // Resolve the parents only, the target itself may be another link
+        let resolved = match (target.parent(), target.file_name()) {
+            (Some(parent), Some(file)) => fs::canonicalize(parent).map(|p| p.join(file)),
+            _ => fs::canonicalize(&target),
+        };
+        let parent = path.parent().and_then(|p| fs::canonicalize(p).ok());
+        if let (Some(root), Ok(resolved), Some(parent)) = (&opts.root, resolved, parent) {
+            if resolved.starts_with(root) {
+                target = relative_path(&parent, &resolved);
+            }
+        }
+    }
+
+    let target = match target.to_str() {
+        Some(target) => target.to_string(),
+        None => {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot pack {}, its link target is not valid UTF-8",
+                path.display()
+            )));
+        }
+    };
+
+    let mut f = file_entry(path, name, opts)?;
+    f.compression = CompressionAlgo::NONE;
+    f.symlink = Some(target);
+    Ok(f)
+}
+
+// Path leading from the directory `from` to `to`, both absolute
+fn relative_path(from: &std::path::Path, to: &std::path::Path) -> std::path::PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path = std::path::PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for c in &to[common..] {
+        path.push(c);
+    }
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+// Fail with an error naming `source` unless it is a file or a directory
+fn check_source(source: &str) -> Result<(), errors::WriteError> {
+    match fs::metadata(source) {
+        Ok(m) if m.is_dir() || m.is_file() => Ok(()),
+        Ok(_) => Err(errors::WriteError::InvalidInput(format!(
+            "cannot pack {source}, it is neither a file nor a directory"
+        ))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(
+            errors::WriteError::InvalidInput(format!("cannot pack {source}, it does not exist")),
+        ),
+        Err(err) => Err(errors::WriteError::SourceRead(source.to_string(), err)),
+    }
+}
+
+// Turn several directories and files into one root directory, each stored under its own name
+fn pack_sources(sources: &[String], opts: &ScanOptions) -> Result<QZEntry, errors::WriteError> {
+    let mut content: Vec<QZEntry> = vec![];
+    let mut names: std::collections::HashMap<String, &str> = std::collections::HashMap::new();
+
+    for source in sources {
+        let path = std::path::Path::new(source);
+        check_source(source)?;
+        // Skipping it would leave nothing to store under its name
+        if opts.is_output(path) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot pack {source}, it is the archive being written"
+            )));
+        }
+
+        // Resolve names for paths like `.` or `dir/..`
+        let name = fs::canonicalize(path)
+            .ok()
+            .and_then(|p| p.file_name().and_then(|n| n.to_str()).map(String::from));
+        let name = match name {
+            Some(name) => name,
+            None => {
+                return Err(errors::WriteError::InvalidInput(format!(
+                    "cannot pack {source}, it has no name to store it under"
+                )));
+            }
+        };
+        check_name(&name, path)?;
+        if let Some(other) = names.insert(name.clone(), source) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "{other} and {source} would both be stored as '{name}'"
+            )));
+        }
+
+        if path.is_dir() {
+            let opts = ScanOptions {
+                root: fs::canonicalize(path).ok(),
+                ..opts.clone()
+            };
+            let mut d = pack_dir(source, &opts)?;
+            if let QZEntry::Dir(ref mut d) = d {
+                d.name = name;
+            }
+            content.push(d);
+        } else {
+            content.push(QZEntry::File(file_entry(path, name, opts)?));
+        }
+    }
+
+    Ok(QZEntry::Dir(QZDir {
+        name: String::new(),
+        content,
+        uid: None,
+        gid: None,
+        xattrs: std::collections::BTreeMap::new(),
+    }))
+}
+
+// Directory named `name` within `d`, created from the directory at `path` if missing
+fn child_dir<'a>(
+    d: &'a mut QZDir,
+    name: &str,
+    path: &std::path::Path,
+    opts: &ScanOptions,
+) -> Result<&'a mut QZDir, errors::WriteError> {
+    let i = match d.content.iter().position(|e| e.name() == name) {
+        Some(i) => i,
+        None => {
+            check_name(name, path)?;
+            let (uid, gid) = opts.source.owner(path);
+            let xattrs = if opts.xattrs {
+                opts.source.xattrs(path)?
+            } else {
+                std::collections::BTreeMap::new()
+            };
+            d.content.push(QZEntry::Dir(QZDir {
+                name: name.to_string(),
+                content: vec![],
+                uid,
+                gid,
+                xattrs,
+            }));
+            d.content.len() - 1
+        }
+    };
+
+    match &mut d.content[i] {
+        QZEntry::Dir(sub) => Ok(sub),
+        QZEntry::File(_) => Err(errors::WriteError::InvalidInput(format!(
+            "{} is listed both as a file and as a directory",
+            path.display()
+        ))),
+    }
+}
+
+// Turn several directories and files below `base` into one root directory, each stored
+// at its path relative to `base`
+//
+// Parent directories are created as needed, `base` itself stores its content at the root.
+fn pack_below(
+    base: &str,
+    sources: &[String],
+    opts: &ScanOptions,
+) -> Result<QZEntry, errors::WriteError> {
+    let base_path = fs::canonicalize(base)
+        .map_err(|err| errors::WriteError::SourceRead(base.to_string(), err))?;
+    let mut root = QZDir {
+        name: String::new(),
+        content: vec![],
+        uid: None,
+        gid: None,
+        xattrs: std::collections::BTreeMap::new(),
+    };
+
+    for source in sources {
+        let path = std::path::Path::new(source);
+        check_source(source)?;
+        if opts.is_output(path) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot pack {source}, it is the archive being written"
+            )));
+        }
+        let relative = fs::canonicalize(path)
+            .ok()
+            .and_then(|p| p.strip_prefix(&base_path).ok().map(|p| p.to_path_buf()))
+            .ok_or_else(|| {
+                errors::WriteError::InvalidInput(format!(
+                    "cannot pack {source}, it is not below {base}"
+                ))
+            })?;
+        let names = relative
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<Vec<&str>>>()
+            .ok_or_else(|| {
+                errors::WriteError::InvalidInput(format!(
+                    "cannot pack {source}, its name is not valid UTF-8"
+                ))
+            })?;
+
+        let mut disk_path = std::path::PathBuf::from(base);
+        let mut d = &mut root;
+        let Some((name, parents)) = names.split_last() else {
+            // The base itself
+            let QZEntry::Dir(dir) = pack_dir(base, opts)? else {
+                unreachable!()
+            };
+            for e in dir.content {
+                if d.content.iter().any(|other| other.name() == e.name()) {
+                    return Err(errors::WriteError::InvalidInput(format!(
+                        "cannot pack {source}, /{} is already packed",
+                        e.name()
+                    )));
+                }
+                d.content.push(e);
+            }
+            (d.uid, d.gid, d.xattrs) = (dir.uid, dir.gid, dir.xattrs);
+            continue;
+        };
+        for parent in parents {
+            disk_path.push(parent);
+            d = child_dir(d, parent, &disk_path, opts)?;
+        }
+        disk_path.push(name);
+        if d.content.iter().any(|e| e.name() == *name) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot pack {source}, /{} is already packed",
+                names.join("/")
+            )));
+        }
+
+        if disk_path.is_dir() {
+            let mut e = pack_dir(&disk_path.to_string_lossy(), opts)?;
+            if let QZEntry::Dir(ref mut sub) = e {
+                sub.name = name.to_string();
+            }
+            d.content.push(e);
+        } else {
+            d.content.push(QZEntry::File(file_entry(
+                &disk_path,
+                name.to_string(),
+                opts,
+            )?));
+        }
+    }
+
+    Ok(QZEntry::Dir(root))
+}
+
+// Turn a list of paths below `base` into a directory tree holding exactly those entries
+//
+// Paths are stored as given, so they have to be relative and may not leave `base`.
+// Listed directories are stored without their content, list that separately.
+fn pack_files(
+    base: &str,
+    files: &[String],
+    opts: &ScanOptions,
+) -> Result<QZEntry, errors::WriteError> {
+    let mut root = QZDir {
+        name: String::new(),
+        content: vec![],
+        uid: None,
+        gid: None,
+        xattrs: std::collections::BTreeMap::new(),
+    };
+
+    for file in files {
+        let mut names = vec![];
+        for c in std::path::Path::new(file).components() {
+            match c {
+                std::path::Component::Normal(name) => match name.to_str() {
+                    Some(name) => names.push(name),
+                    None => {
+                        return Err(errors::WriteError::InvalidInput(format!(
+                            "cannot pack {file}, its name is not valid UTF-8"
+                        )));
+                    }
+                },
+                std::path::Component::CurDir => {}
+                _ => {
+                    return Err(errors::WriteError::InvalidInput(format!(
+                        "cannot pack {file}, listed paths have to stay within {base}"
+                    )));
+                }
+            }
+        }
+        let Some((name, parents)) = names.split_last() else {
+            continue;
+        };
+
+        let mut path = std::path::PathBuf::from(base);
+        let mut d = &mut root;
+        for parent in parents {
+            path.push(parent);
+            d = child_dir(d, parent, &path, opts)?;
+        }
+        path.push(name);
+
+        if opts.skips(&path) {
+            continue;
+        }
+        let is_link = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+        if path.is_dir() && !is_link {
+            child_dir(d, name, &path, opts)?;
+        } else if path.is_file() || is_link {
+            match d.content.iter().find(|e| e.name() == *name) {
+                Some(QZEntry::File(_)) => {}
+                Some(QZEntry::Dir(_)) => {
+                    return Err(errors::WriteError::InvalidInput(format!(
+                        "{} is listed both as a file and as a directory",
+                        path.display()
+                    )));
+                }
+                None => {
+                    let f = if is_link {
+                        symlink_entry(&path, name.to_string(), opts)?
+                    } else {
+                        file_entry(&path, name.to_string(), opts)?
+                    };
+                    d.content.push(QZEntry::File(f));
+                }
+            }
+        } else {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot pack {}, it is neither a file nor a directory",
+                path.display()
+            )));
+        }
+    }
+
+    Ok(QZEntry::Dir(root))
+}
+
+/// What to do with fifos, sockets and device nodes found while packing a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpecialFiles {
+    /// Leave them out, warning about each
+    #[default]
+    Skip,
+    /// Fail creating the archive
+    Error,
+}
+
+/// Order files are stored in within the body, see `QZArchiveBuilder::file_order`
+///
+/// Only the layout of the body changes, directories list their entries as scanned.
+/// Every file is compressed on its own, so the order decides which files are read
+/// together but not how well they compress. Clustering similar files for a better ratio
+/// needs a solid mode compressing the body as one stream, which archives do not have.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum FileOrder {
+    /// The order directories are walked in
+    #[default]
+    Traversal,
+    /// Grouped by file extension, files without one first
+    Extension,
+    /// The archive paths listed first in the order given, e.g. `/docs/index.html`,
+    /// paths naming no file are ignored
+    Paths(Vec<String>),
+}
+
+// Arrange `files` in `order`, keeping the traversal order among files that rank equal
+fn order_files(files: &mut [FileJob], order: &FileOrder) {
+    match order {
+        FileOrder::Traversal => {}
+        FileOrder::Extension => files.sort_by_cached_key(|(_, _, a_path)| {
+            std::path::Path::new(a_path)
+                .extension()
+                .map(|e| e.to_string_lossy().into_owned())
+        }),
+        FileOrder::Paths(paths) => {
+            let rank: std::collections::HashMap<String, usize> = paths
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (format!("/{}", p.trim_start_matches('/')), i))
+                .rev()
+                .collect();
+            files.sort_by_key(|(_, _, a_path)| rank.get(a_path).copied().unwrap_or(usize::MAX));
+        }
+    }
+}
+
+// Name of a file type that is neither file, directory nor symbolic link
+#[cfg(unix)]
+pub(crate) fn special_file_kind(file_type: &fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else {
+        "special file"
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn special_file_kind(_file_type: &fs::FileType) -> &'static str {
+    "special file"
+}
+
+// Turn directory structure into QZEntry structure
+//
+// Extended attributes are captured if `opts.xattrs` is set.
+// Symbolic links are stored as links, not followed. Directories are walked with an
+// explicit stack, trees nested deeper than `DEFAULT_MAX_DEPTH` are refused as
+// `read_archive` would refuse the archive.
+fn pack_dir(dir: &str, opts: &ScanOptions) -> Result<QZEntry, errors::WriteError> {
+    let list = |dir: &str| {
+        opts.source
+            .list(std::path::Path::new(dir))
+            .map(|paths| paths.into_iter())
+            .map_err(|err| errors::WriteError::SourceRead(dir.to_string(), err))
+    };
+    // Directories being walked, innermost last, with the rest of their listing
+    // and the entries found so far
+    let mut stack = vec![(dir.to_string(), list(dir)?, vec![])];
+
+    loop {
+        let (_, paths, content) = stack.last_mut().unwrap();
+        let Some((path, kind)) = paths.next() else {
+            let (dir, _, content) = stack.pop().unwrap();
+            let d = dir_entry(&dir, content, opts)?;
+            match stack.last_mut() {
+                Some((_, _, parent)) => parent.push(d),
+                None => return Ok(d),
+            }
+            continue;
+        };
+
+        if opts.skips(&path) {
+            continue;
+        }
+        let hidden = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if hidden && !opts.hidden {
+            opts.skip(&path, SkipReason::Hidden);
+            continue;
+        }
+        match kind {
+            vfs::FileKind::Symlink => {
+                let name = entry_name(&path)?;
+                let f = symlink_entry(&path, name, opts)?;
+                content.push(QZEntry::File(f));
+            }
+            vfs::FileKind::File => {
+                let name = entry_name(&path)?;
+                let f = file_entry(&path, name, opts)?;
+                content.push(QZEntry::File(f));
+            }
+            vfs::FileKind::Dir if !opts.recursive => opts.skip(&path, SkipReason::NotRecursive),
+            vfs::FileKind::Dir => {
+                entry_name(&path)?;
+                if stack.len() > crate::DEFAULT_MAX_DEPTH {
+                    return Err(too_deep(&path.display().to_string()));
+                }
+                let path = path.to_string_lossy().into_owned();
+                let paths = list(&path)?;
+                stack.push((path, paths, vec![]));
+            }
+            vfs::FileKind::Special(kind) => match opts.special_files {
+                SpecialFiles::Skip => opts.skip(&path, SkipReason::Special(kind.to_string())),
+                SpecialFiles::Error => {
+                    return Err(errors::WriteError::InvalidInput(format!(
+                        "cannot pack {}, it is a {kind}",
+                        path.display()
+                    )));
+                }
+            },
+        }
+    }
+}
+
+// Refusal to pack `path`, which nests directories deeper than archives are read
+fn too_deep(path: &str) -> errors::WriteError {
+    errors::WriteError::InvalidInput(format!(
+        "cannot pack {path}, directories would nest more than {} levels deep",
+        crate::DEFAULT_MAX_DEPTH
+    ))
+}
+
+// Directory entry for `dir` holding `content`
+fn dir_entry(
+    dir: &str,
+    content: Vec<QZEntry>,
+    opts: &ScanOptions,
+) -> Result<QZEntry, errors::WriteError> {
+    // Lookups return the first match, so a second entry of the same name would be unreachable
+    let mut names = std::collections::HashSet::new();
+    for e in &content {
+        if !names.insert(e.name()) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "duplicate entry name '{}' in {dir}",
+                e.name()
+            )));
+        }
+    }
+
+    let (uid, gid) = opts.source.owner(std::path::Path::new(dir));
+    let attrs = if opts.xattrs {
+        opts.source.xattrs(std::path::Path::new(dir))?
+    } else {
+        std::collections::BTreeMap::new()
+    };
+
+    Ok(QZEntry::Dir(QZDir {
+        name: std::path::Path::new(dir)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string(),
+        content,
+        uid,
+        gid,
+        xattrs: attrs,
+    }))
+}
+
+/// How files are compressed when packing, see `QZArchiveBuilder::compression_config`
+///
+/// The default compresses with zstd at `compression::DEFAULT_LEVEL`, without LZ4-HC,
+/// chunks or a dictionary.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Algorithm every file is compressed with
+    pub algorithm: CompressionAlgo,
+    /// Level for `ZSTD` (1 to 22) and LZ4-HC (1 to 12), `None` uses the default of each
+    ///
+    /// Plain `LZ4` and `NONE` have no levels and ignore it.
+    pub level: Option<i32>,
+    /// Compress `LZ4` entries with LZ4-HC, see `QZArchiveBuilder::lz4_high_compression`
+    pub lz4_hc: bool,
+    /// Split files larger than this into chunks of this size,
+    /// see `QZArchiveBuilder::chunk_size`
+    pub chunk_size: Option<u64>,
+    /// Train a zstd dictionary, see `QZArchiveBuilder::dictionary`
+    pub train_dictionary: bool,
+    /// zstd dictionary to compress with instead of training one,
+    /// see `QZArchiveBuilder::with_dictionary`
+    pub dictionary: Option<Vec<u8>>,
+    /// Algorithms for files whose name matches a pattern instead of `algorithm`,
+    /// see `QZArchiveBuilder::compression_for`
+    pub overrides: Vec<(String, CompressionAlgo)>,
+    /// Let zstd match across long distances, see `QZArchiveBuilder::long_distance_matching`
+    pub long_distance: bool,
+    /// Window log for `ZSTD`, see `QZArchiveBuilder::window_log`
+    pub window_log: Option<u32>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            algorithm: CompressionAlgo::ZSTD,
+            level: None,
+            lz4_hc: false,
+            chunk_size: None,
+            train_dictionary: false,
+            dictionary: None,
+            overrides: vec![],
+            long_distance: false,
+            window_log: None,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Names accepted by `preset`, from fastest to smallest
+    pub const PRESETS: &'static [&'static str] = &["fast", "default", "max"];
+
+    /// Settings tuned for a speed and ratio trade-off, `None` for an unknown `name`
+    ///
+    /// - `fast`: plain `LZ4`, compressing and extracting fastest
+    /// - `default`: the default, `ZSTD` at `compression::DEFAULT_LEVEL`
+    /// - `max`: `ZSTD` at level 19 with long distance matching, for the smallest archives
+    pub fn preset(name: &str) -> Option<CompressionConfig> {
+        match name.to_lowercase().as_str() {
+            "fast" => Some(CompressionConfig {
+                algorithm: CompressionAlgo::LZ4,
+                ..Default::default()
+            }),
+            "default" => Some(CompressionConfig::default()),
+            "max" => Some(CompressionConfig {
+                algorithm: CompressionAlgo::ZSTD,
+                level: Some(19),
+                long_distance: true,
+                ..Default::default()
+            }),
+            _ => None,
+        }
+    }
+
+    // Fail on a level one of the algorithms does not support
+    pub(crate) fn check_level(&self) -> Result<(), errors::WriteError> {
+        let Some(level) = self.level else {
+            return Ok(());
+        };
+        let algorithms =
+            std::iter::once(&self.algorithm).chain(self.overrides.iter().map(|(_, a)| a));
+        for algorithm in algorithms {
+            let range = match algorithm {
+                CompressionAlgo::ZSTD => 1..=22,
+                CompressionAlgo::LZ4 if self.lz4_hc => 1..=12,
+                _ => continue,
+            };
+            if !range.contains(&level) {
+                return Err(errors::WriteError::InvalidInput(format!(
+                    "compression level {level} is out of range for {algorithm}, expected {} to {}",
+                    range.start(),
+                    range.end()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Fail on a window log zstd or some reader does not support
+    pub(crate) fn check_window_log(&self) -> Result<(), errors::WriteError> {
+        let range = compression::MIN_WINDOW_LOG..=compression::MAX_WINDOW_LOG;
+        match self.window_log {
+            Some(log) if !range.contains(&log) => Err(errors::WriteError::InvalidInput(format!(
+                "window log {log} is out of range, expected {} to {}",
+                range.start(),
+                range.end()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+// How the content of files is compressed beyond their algorithm
+#[derive(Clone, Copy)]
+struct Encoding<'a> {
+    level: Option<i32>,
+    lz4_hc: bool,
+    chunk_size: Option<u64>,
+    // Dictionary for `ZSTD` entries, empty without one
+    dictionary: &'a [u8],
+    long_distance: bool,
+    window_log: Option<u32>,
+    // Store text files with CRLF line endings with LF instead
+    normalize_line_endings: bool,
+}
+
+// Files up to this size are sampled to train a dictionary, larger ones gain little from it
+const DICTIONARY_SAMPLE_SIZE: u64 = 128 * 1024;
+
+// Sample data read for training, zstd suggests about a hundred times the dictionary size
+const DICTIONARY_SAMPLE_BUDGET: u64 = 100 * compression::MAX_DICTIONARY_SIZE as u64;
+
+// Train a dictionary on the small `ZSTD` files among `files`, `None` if they are too few
+fn train_dictionary(files: &[FileJob], source: &dyn FileSource) -> Option<Vec<u8>> {
+    let mut samples = vec![];
+    let mut total = 0;
+    for (f, path, _) in files {
+        if total >= DICTIONARY_SAMPLE_BUDGET {
+            break;
+        }
+        if f.symlink.is_some() || !matches!(f.compression, CompressionAlgo::ZSTD) {
+            continue;
+        }
+        if !source
+            .len(path)
+            .is_ok_and(|len| len > 0 && len <= DICTIONARY_SAMPLE_SIZE)
+        {
+            continue;
+        }
+        // Unreadable files are reported when packing them
+        if let Ok(data) = source.read(path) {
+            total += data.len() as u64;
+            samples.push(data);
+        }
+    }
+
+    // A dictionary beyond a tenth of the sampled data would cost more than it saves
+    let max_size = compression::MAX_DICTIONARY_SIZE.min(total as usize / 10);
+    compression::train_dictionary(&samples, max_size).ok()
+}
+
+// Streaming compressor for the content of `f` into `out`, `size` bytes of content
+// bounding the zstd window
+fn encoder<W: Write>(
+    f: &QZFile,
+    encoding: Encoding,
+    size: u64,
+    out: W,
+) -> std::io::Result<compression::Encoder<W>> {
+    match f.compression {
+        CompressionAlgo::LZ4 if encoding.lz4_hc => compression::Encoder::lz4_hc(
+            out,
+            encoding
+                .level
+                .map_or(compression::LZ4_HC_LEVEL, |level| level as u32),
+        ),
+        CompressionAlgo::LZ4 => Ok(compression::Encoder::lz4(out)),
+        CompressionAlgo::ZSTD => compression::Encoder::zstd(
+            out,
+            encoding.level.unwrap_or(compression::DEFAULT_LEVEL),
+            encoding.dictionary,
+            encoding.long_distance,
+            encoding.window_log,
+            size,
+        ),
+        CompressionAlgo::NONE => Ok(compression::Encoder::None(out)),
+    }
+}
+
+fn compression_error(f: &QZFile, err: std::io::Error) -> errors::WriteError {
+    errors::WriteError::Compression(f.name.clone(), f.compression.clone(), err)
+}
+
+// Compress `data` with the algorithm of `f`
+fn compress_entry(
+    f: &QZFile,
+    data: &[u8],
+    encoding: Encoding,
+) -> Result<Vec<u8>, errors::WriteError> {
+    let mut encoder =
+        encoder(f, encoding, data.len() as u64, vec![]).map_err(|err| compression_error(f, err))?;
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(|err| compression_error(f, err))
+}
+
+// Compress `data` for `f` in separate chunks of `chunk_size` bytes each,
+// returning the concatenated chunks and where each of them is stored
+fn compress_chunked(
+    f: &QZFile,
+    data: &[u8],
+    encoding: Encoding,
+    chunk_size: u64,
+) -> Result<(Vec<u8>, Vec<Chunk>), errors::WriteError> {
+    let mut stored = vec![];
+    let mut chunks = vec![];
+    for piece in data.chunks(chunk_size as usize) {
+        let compressed = compress_entry(f, piece, encoding)?;
+        chunks.push(Chunk {
+            offset: stored.len() as u64,
+            size: compressed.len() as u64,
+            original_size: piece.len() as u64,
+            checksum: crc32fast::hash(&compressed),
+        });
+        stored.extend(compressed);
+    }
+    Ok((stored, chunks))
+}
+
+// Compress `data` for `f` again at `level`, in chunks of the same size if it was chunked
+// and is still compressed
+pub(crate) fn recompress_data(
+    f: &QZFile,
+    data: &[u8],
+    level: i32,
+    dictionary: &[u8],
+) -> Result<ReadFile, errors::WriteError> {
+    let encoding = Encoding {
+        level: Some(level),
+        lz4_hc: false,
+        chunk_size: None,
+        dictionary,
+        long_distance: false,
+        window_log: None,
+        normalize_line_endings: false,
+    };
+    let (stored, chunks) = match f.chunks.first() {
+        Some(c) if !matches!(f.compression, CompressionAlgo::NONE) => {
+            compress_chunked(f, data, encoding, c.original_size.max(1))?
+        }
+        _ => (compress_entry(f, data, encoding)?, vec![]),
+    };
+    Ok(ReadFile::new(stored, data, chunks, f.crlf, f.nested))
+}
+
+// Append the compressed content of `f` to `body`, recording offset, size and checksums in `f`
+pub(crate) fn append_file(f: &mut QZFile, file: ReadFile, body: &mut Vec<u8>) {
+    body.extend(place_file(f, file, body.len() as u64));
+}
+
+// Record `file` in `f` as stored at `index_start`, returning the bytes to store there
+fn place_file(f: &mut QZFile, file: ReadFile, index_start: u64) -> Vec<u8> {
+    place(f, file.stored, index_start);
+    file.data
+}
+
+// Record in `f` that its data is stored at `index_start` as `stored` describes
+fn place(f: &mut QZFile, stored: Stored, index_start: u64) {
+    f.chunks = stored.chunks;
+    f.index_start = index_start;
+    f.original_size = Some(stored.original_size);
+    f.original_checksum = Some(stored.original_checksum);
+    f.checksum = stored.checksum;
+    f.index_size = stored.size;
+    f.crlf = stored.crlf;
+    f.nested = stored.nested;
+}
+
+// Body of an archive being packed
+//
+// Written to a temporary file when packing to disk, so memory use does not grow with
+// the archive. Only its length and checksum are kept when just measuring.
+struct Body {
+    len: u64,
+    hasher: crc32fast::Hasher,
+    spool: Spool,
+    // Checkpoints of a body kept when interrupted, see `QZArchiveBuilder::resume`
+    resume: Option<Resume>,
+}
+
+// Checkpoint state of a resumable body
+struct Resume {
+    log_path: String,
+    // What an interrupted run recorded, until taken up
+    previous: Option<(checkpoint::Settings, Vec<Record>)>,
+    log: Option<checkpoint::Log>,
+}
+
+enum Spool {
+    Memory(Vec<u8>),
+    File(std::io::BufWriter<fs::File>, std::path::PathBuf),
+    Discard,
+}
+
+impl Body {
+    fn new(spool: Spool) -> Body {
+        Body {
+            len: 0,
+            hasher: crc32fast::Hasher::new(),
+            spool,
+            resume: None,
+        }
+    }
+
+    // Body kept next to `out_file` with checkpoints, continuing the one of an interrupted run
+    fn resumable(out_file: &str, buffer_size: usize) -> Result<Body, errors::WriteError> {
+        let (path, log_path) = (
+            checkpoint::body_path(out_file),
+            checkpoint::log_path(out_file),
+        );
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|err| errors::WriteError::Output(path.clone(), err))?;
+        let f = std::io::BufWriter::with_capacity(buffer_size, f);
+        let mut body = Body::new(Spool::File(f, path.into()));
+        body.resume = Some(Resume {
+            previous: checkpoint::load(&log_path),
+            log_path,
+            log: None,
+        });
+        Ok(body)
+    }
+
+    // What an interrupted run packed with the same `fingerprint`, if any
+    fn previous(&mut self, fingerprint: u32) -> Option<(checkpoint::Settings, Vec<Record>)> {
+        self.resume
+            .as_mut()?
+            .previous
+            .take()
+            .filter(|(settings, _)| settings.fingerprint == fingerprint)
+    }
+
+    // Cut the body to its first `len` bytes, whose checksum `hasher` holds
+    fn truncate(&mut self, len: u64, hasher: crc32fast::Hasher) -> Result<(), errors::WriteError> {
+        match &mut self.spool {
+            Spool::Memory(body) => body.truncate(len as usize),
+            Spool::File(w, path) => {
+                w.flush()
+                    .and_then(|_| w.get_ref().set_len(len))
+                    .and_then(|_| w.get_mut().seek(std::io::SeekFrom::Start(len)))
+                    .map_err(|err| errors::WriteError::Output(path.display().to_string(), err))?;
+            }
+            Spool::Discard => {}
+        }
+        self.len = len;
+        self.hasher = hasher;
+        Ok(())
+    }
+
+    // The body written so far for reading, `None` unless spooled to a file
+    fn reader(&mut self) -> Option<std::io::BufReader<fs::File>> {
+        match &mut self.spool {
+            Spool::File(w, path) => {
+                w.flush().ok()?;
+                fs::File::open(&*path).ok().map(std::io::BufReader::new)
+            }
+            _ => None,
+        }
+    }
+
+    // Start recording checkpoints with `settings`, the files up to `done` being packed
+    fn start_checkpoints(
+        &mut self,
+        settings: checkpoint::Settings,
+        done: &[FileJob],
+        failed: &std::collections::BTreeMap<String, (String, String)>,
+    ) -> Result<(), errors::WriteError> {
+        let Some(resume) = &mut self.resume else {
+            return Ok(());
+        };
+        let log = checkpoint::Log::create(&resume.log_path, &settings, &records(done, failed))
+            .map_err(|err| errors::WriteError::Output(resume.log_path.clone(), err))?;
+        resume.log = Some(log);
+        Ok(())
+    }
+
+    // Record the files up to `done` once the next checkpoint is due
+    //
+    // Their data is synced to disk first, so whatever the log lists is in the body.
+    fn checkpoint(
+        &mut self,
+        done: &[FileJob],
+        failed: &std::collections::BTreeMap<String, (String, String)>,
+    ) -> Result<(), errors::WriteError> {
+        let Some(Resume {
+            log: Some(log),
+            log_path,
+            ..
+        }) = &mut self.resume
+        else {
+            return Ok(());
+        };
+        if !log.due() {
+            return Ok(());
+        }
+        let res = match &mut self.spool {
+            Spool::File(w, _) => w.flush().and_then(|_| w.get_ref().sync_data()),
+            _ => Ok(()),
+        };
+        res.and_then(|_| log.append(&records(&done[log.recorded..], failed)))
+            .map_err(|err| errors::WriteError::Output(log_path.clone(), err))
+    }
+
+    // Body spooled to a temporary file for `out_file`, see `create_temp`
+    fn spooled(
+        out_file: &str,
+        temp_dir: Option<&std::path::Path>,
+        buffer_size: usize,
+    ) -> Result<Body, errors::WriteError> {
+        let (f, path) = create_temp(&format!("{out_file}.body"), temp_dir)
+            .map_err(|err| errors::WriteError::Output(out_file.to_string(), err))?;
+        let f = std::io::BufWriter::with_capacity(buffer_size, f);
+        Ok(Body::new(Spool::File(f, path)))
+    }
+
+    // Append `file` for `f`
+    fn append(&mut self, f: &mut QZFile, file: ReadFile) -> Result<(), errors::WriteError> {
+        let data = place_file(f, file, self.len);
+        self.write(&data)
+    }
+
+    // Compress the file at `path` for `f` straight into the body, see `stream_file`,
+    // returning how many bytes it holds or why it could not be read
+    //
+    // Reading and compressing alternate, so the file keeps its slot of `state.io`
+    // throughout. What was written of a file that turns out unreadable is cut off again.
+    fn stream(
+        &mut self,
+        f: &mut QZFile,
+        path: &std::path::Path,
+        state: &PackState,
+        size: u64,
+    ) -> Result<Result<u64, String>, errors::WriteError> {
+        let (start, hasher) = (self.len, self.hasher.clone());
+        let mut out = BodyWriter {
+            body: self,
+            error: None,
+        };
+        let open = || state.source.open(path);
+        let res = state
+            .io
+            .run(|| stream_file(f, open, state.encoding, size, &mut out));
+        if let Some(err) = out.error {
+            return Err(err);
+        }
+        match res? {
+            Ok(stored) => {
+                let input_size = stored.original_size;
+                place(f, stored, start);
+                Ok(Ok(input_size))
+            }
+            Err(err) => {
+                self.truncate(start, hasher)?;
+                Ok(Err(err))
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), errors::WriteError> {
+        self.len += data.len() as u64;
+        self.hasher.update(data);
+        match &mut self.spool {
+            Spool::Memory(body) => body.extend_from_slice(data),
+            Spool::File(w, path) => {
+                w.write_all(data)
+                    .map_err(|err| errors::WriteError::Output(path.display().to_string(), err))?;
+            }
+            Spool::Discard => {}
+        }
+        Ok(())
+    }
+
+    fn checksum(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    // The body as held in memory, empty if it was not kept there
+    fn into_bytes(mut self) -> Vec<u8> {
+        match &mut self.spool {
+            Spool::Memory(body) => std::mem::take(body),
+            _ => vec![],
+        }
+    }
+
+    // Write the archive for `header` with this body to `out_file`
+    fn write_archive(
+        mut self,
+        builder: &QZArchiveBuilder,
+        header: &mut QZArchiveHeader,
+    ) -> Result<(), errors::WriteError> {
+        let temp_dir = builder.temp_dir.as_deref();
+        let (buffer_size, volume_size) = (builder.buffer_size, builder.volume_size);
+        match &mut self.spool {
+            Spool::File(w, path) => {
+                let spooled = w
+                    .flush()
+                    .and_then(|_| fs::File::open(&*path))
+                    .map_err(|err| errors::WriteError::Output(path.display().to_string(), err))?;
+                let mut body = std::io::BufReader::with_capacity(buffer_size, spooled);
+                write_stream(
+                    &builder.out_file,
+                    header,
+                    &builder.header_compression,
+                    &mut body,
+                    self.len,
+                    &[],
+                    volume_size,
+                    buffer_size,
+                    FORMAT_VERSION,
+                    temp_dir,
+                )
+            }
+            Spool::Memory(body) => write_versioned(
+                &builder.out_file,
+                header,
+                &builder.header_compression,
+                body,
+                volume_size,
+                buffer_size,
+                FORMAT_VERSION,
+                temp_dir,
+            ),
+            Spool::Discard => Err(errors::WriteError::Other(
+                "archive body was not kept".to_string(),
+            )),
+        }?;
+        // Complete, nothing is left to resume
+        if let Some(resume) = self.resume.take() {
+            let _ = fs::remove_file(&resume.log_path);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        // A resumable body stays for the next run until the archive is written
+        if let (Spool::File(_, path), None) = (&self.spool, &self.resume) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Compressed data streamed into the body, keeping the error of a failed write
+// to report instead of the compression error it surfaces as
+struct BodyWriter<'a> {
+    body: &'a mut Body,
+    error: Option<errors::WriteError>,
+}
+
+impl Write for BodyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.body.write(buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(err) => {
+                self.error = Some(err);
+                Err(std::io::Error::other("failed to write the archive body"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Checkpoint records of the packed files `done`
+fn records(
+    done: &[FileJob],
+    failed: &std::collections::BTreeMap<String, (String, String)>,
+) -> Vec<Record> {
+    done.iter()
+        .map(|(f, _, a_path)| match failed.get(a_path) {
+            Some((_, err)) => Record::Failed(a_path.clone(), err.clone()),
+            None => Record::Stored(a_path.clone(), (**f).clone()),
+        })
+        .collect()
+}
+
+// What a packed file stores, besides its compressed data
+pub(crate) struct Stored {
+    size: u64,
+    checksum: u32,
+    original_size: u64,
+    original_checksum: u32,
+    chunks: Vec<Chunk>,
+    crlf: bool,
+    nested: bool,
+}
+
+// Content of a file read and compressed for packing
+pub(crate) struct ReadFile {
+    data: Vec<u8>,
+    stored: Stored,
+}
+
+impl ReadFile {
+    // `data` compressed from `original`, in `chunks` if it was chunked
+    fn new(
+        data: Vec<u8>,
+        original: &[u8],
+        chunks: Vec<Chunk>,
+        crlf: bool,
+        nested: bool,
+    ) -> ReadFile {
+        let stored = Stored {
+            size: data.len() as u64,
+            checksum: crc32fast::hash(&data),
+            original_size: original.len() as u64,
+            original_checksum: crc32fast::hash(original),
+            chunks,
+            crlf,
+            nested,
+        };
+        ReadFile { data, stored }
+    }
+}
+
+// Files up to this size are read whole and compressed in parallel, larger ones are
+// streamed into the body one after another, see `stream_file`
+const STREAM_SIZE: u64 = 1024 * 1024;
+
+// Pieces files are read in when streaming
+const STREAM_PIECE: usize = 64 * 1024;
+
+// Read the file at `path` and compress it for `f`, `Ok(Err(_))` if it cannot be read
+//
+// Only reading takes a slot of `io`, the file is compressed from memory.
+fn read_file_entry(
+    f: &QZFile,
+    path: &std::path::Path,
+    source: &dyn FileSource,
+    io: &IoLimit,
+    encoding: Encoding,
+    size: u64,
+) -> Result<Result<ReadFile, String>, errors::WriteError> {
+    let original = match io.run(|| source.read(path)) {
+        Ok(original) => original,
+        Err(err) => return Ok(Err(err.to_string())),
+    };
+    let mut data = vec![];
+    let open = || Ok(Box::new(original.as_slice()) as Box<dyn Read>);
+    let stored = stream_file(f, open, encoding, size, &mut data)?;
+    Ok(stored.map(|stored| ReadFile { data, stored }))
+}
+
+// Compress the file `open` reads for `f` into `out` piece by piece, so it is never held
+// in memory whole, `Ok(Err(_))` if it cannot be read
+//
+// Whatever the file holds now is read, even if it changed size since scanning,
+// `size` only bounds the zstd window. CRLF text is read twice, once to tell it is.
+fn stream_file<'a, W: Write>(
+    f: &QZFile,
+    open: impl Fn() -> std::io::Result<Box<dyn Read + 'a>>,
+    encoding: Encoding,
+    size: u64,
+    out: W,
+) -> Result<Result<Stored, String>, errors::WriteError> {
+    // Whether a file is CRLF text is only known once all of it was seen
+    let crlf = match encoding.normalize_line_endings {
+        true => match open().and_then(is_crlf_text) {
+            Ok(crlf) => crlf,
+            Err(err) => return Ok(Err(err.to_string())),
+        },
+        false => false,
+    };
+    let mut reader = match open() {
+        Ok(reader) => reader,
+        Err(err) => return Ok(Err(err.to_string())),
+    };
+
+    let mut compressor = Compressor::new(f, encoding, size, out);
+    let mut lines = crate::CrlfToLf::default();
+    let (mut buf, mut text) = (vec![0; STREAM_PIECE], vec![]);
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Ok(Err(err.to_string())),
+        };
+        let piece = match crlf {
+            true => {
+                text.clear();
+                lines.convert(&buf[..n], &mut text);
+                &text
+            }
+            false => &buf[..n],
+        };
+        compressor
+            .write(piece)
+            .map_err(|err| compression_error(f, err))?;
+    }
+    if crlf {
+        text.clear();
+        lines.finish(&mut text);
+        compressor
+            .write(&text)
+            .map_err(|err| compression_error(f, err))?;
+    }
+    let mut stored = compressor
+        .finish()
+        .map_err(|err| compression_error(f, err))?;
+    stored.crlf = crlf;
+    Ok(Ok(stored))
+}
+
+// Whether `reader` reads text with CRLF line endings, see `crlf_to_lf`
+fn is_crlf_text(mut reader: Box<dyn Read + '_>) -> std::io::Result<bool> {
+    let mut lines = crate::CrlfToLf::default();
+    let (mut buf, mut text) = (vec![0; STREAM_PIECE], vec![]);
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        text.clear();
+        lines.convert(&buf[..n], &mut text);
+    }
+    Ok(lines.finish(&mut text))
+}
+
+// Size and checksum of the file at `path` as packing reads it, `None` if `crlf` asks for
+// CRLF text and it is not
+fn read_checksum(
+    source: &dyn FileSource,
+    path: &std::path::Path,
+    crlf: bool,
+) -> std::io::Result<Option<(u64, u32)>> {
+    let mut reader = source.open(path)?;
+    let (mut len, mut hasher) = (0, crc32fast::Hasher::new());
+    let mut lines = crate::CrlfToLf::default();
+    let (mut buf, mut text) = (vec![0; STREAM_PIECE], vec![]);
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        let piece = match crlf {
+            true => {
+                text.clear();
+                lines.convert(&buf[..n], &mut text);
+                &text
+            }
+            false => &buf[..n],
+        };
+        len += piece.len() as u64;
+        hasher.update(piece);
+    }
+    if crlf {
+        text.clear();
+        if !lines.finish(&mut text) {
+            return Ok(None);
+        }
+        len += text.len() as u64;
+        hasher.update(&text);
+    }
+    Ok(Some((len, hasher.finalize())))
+}
+
+// Counts and hashes the compressed data of a file on its way to `out`,
+// in all and since `chunk_start`
+struct Tally<W> {
+    out: W,
+    len: u64,
+    hasher: crc32fast::Hasher,
+    chunk_start: u64,
+    chunk_hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for Tally<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.len += n as u64;
+        self.hasher.update(&buf[..n]);
+        self.chunk_hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Content of a file compressed into `out` as it is written, in separate chunks of
+// `chunk_size` bytes for chunked files
+struct Compressor<'a, W: Write> {
+    f: &'a QZFile,
+    encoding: Encoding<'a>,
+    size: u64,
+    chunk_size: u64,
+    // Encoder of the current chunk once it has data, holding `out` meanwhile
+    encoder: Option<compression::Encoder<Tally<W>>>,
+    out: Option<Tally<W>>,
+    chunk_len: u64,
+    chunks: Vec<Chunk>,
+    original_size: u64,
+    original_hasher: crc32fast::Hasher,
+    // Start of the content, telling nested archives apart
+    head: Vec<u8>,
+}
+
+impl<'a, W: Write> Compressor<'a, W> {
+    fn new(f: &'a QZFile, encoding: Encoding<'a>, size: u64, out: W) -> Compressor<'a, W> {
+        // Uncompressed files can be read at any offset already
+        let chunk_size = match (encoding.chunk_size, &f.compression) {
+            (Some(size), CompressionAlgo::ZSTD | CompressionAlgo::LZ4) => size.max(1),
+            _ => u64::MAX,
+        };
+        Compressor {
+            f,
+            encoding,
+            size,
+            chunk_size,
+            encoder: None,
+            out: Some(Tally {
+                out,
+                len: 0,
+                hasher: crc32fast::Hasher::new(),
+                chunk_start: 0,
+                chunk_hasher: crc32fast::Hasher::new(),
+            }),
+            chunk_len: 0,
+            chunks: vec![],
+            original_size: 0,
+            original_hasher: crc32fast::Hasher::new(),
+            head: vec![],
+        }
+    }
+
+    fn write(&mut self, mut piece: &[u8]) -> std::io::Result<()> {
+        self.original_size += piece.len() as u64;
+        self.original_hasher.update(piece);
+        let missing = (PREFIX_SIZE as usize).saturating_sub(self.head.len());
+        self.head
+            .extend_from_slice(&piece[..missing.min(piece.len())]);
+
+        while !piece.is_empty() {
+            if self.encoder.is_none() {
+                self.start_chunk()?;
+            }
+            let n = (self.chunk_size - self.chunk_len).min(piece.len() as u64) as usize;
+            self.encoder.as_mut().unwrap().write_all(&piece[..n])?;
+            self.chunk_len += n as u64;
+            piece = &piece[n..];
+            if self.chunk_len == self.chunk_size {
+                self.end_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn start_chunk(&mut self) -> std::io::Result<()> {
+        let mut out = self.out.take().unwrap();
+        out.chunk_start = out.len;
+        out.chunk_hasher = crc32fast::Hasher::new();
+        let size = self.size.min(self.chunk_size);
+        self.encoder = Some(encoder(self.f, self.encoding, size, out)?);
+        Ok(())
+    }
+
+    fn end_chunk(&mut self) -> std::io::Result<()> {
+        let out = self.encoder.take().unwrap().finish()?;
+        self.chunks.push(Chunk {
+            offset: out.chunk_start,
+            size: out.len - out.chunk_start,
+            original_size: self.chunk_len,
+            checksum: out.chunk_hasher.clone().finalize(),
+        });
+        self.out = Some(out);
+        self.chunk_len = 0;
+        Ok(())
+    }
+
+    // End the last chunk, a file of a single one being stored unchunked
+    fn finish(mut self) -> std::io::Result<Stored> {
+        if self.encoder.is_some() || self.chunks.is_empty() {
+            if self.encoder.is_none() {
+                self.start_chunk()?;
+            }
+            self.end_chunk()?;
+        }
+        if self.chunks.len() == 1 {
+            self.chunks.clear();
+        }
+        let out = self.out.take().unwrap();
+        Ok(Stored {
+            size: out.len,
+            checksum: out.hasher.finalize(),
+            original_size: self.original_size,
+            original_checksum: self.original_hasher.finalize(),
+            chunks: self.chunks,
+            crlf: false,
+            nested: crate::is_archive(&self.head),
+        })
+    }
+}
+
+// Device and inode of a file with several hard links
+#[cfg(unix)]
+pub(crate) fn link_id(path: &std::path::Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let m = fs::metadata(path).ok()?;
+    if m.nlink() > 1 {
+        Some((m.dev(), m.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn link_id(_path: &std::path::Path) -> Option<(u64, u64)> {
+    None
+}
+
+// A file to pack with its path on disk and its path within the archive
+type FileJob<'a> = (&'a mut QZFile, std::path::PathBuf, String);
+
+// State carried through `write_files`
+struct PackState<'a> {
+    stats: PackStats,
+    encoding: Encoding<'a>,
+    pool: Option<&'a rayon::ThreadPool>,
+    progress: &'a dyn ProgressSink,
+    source: &'a dyn FileSource,
+    // Bound on the files read at once, see `QZArchiveBuilder::io_threads`
+    io: IoLimit,
+    // Files that could not be read by archive path, with their path on disk and the reason
+    failed: std::collections::BTreeMap<String, (String, String)>,
+    // First packed file (archive path and entry) of every hard linked inode
+    links: std::collections::HashMap<(u64, u64), (String, QZFile)>,
+}
+
+// A file of a batch after reading files in parallel
+enum Pending {
+    Read(Result<ReadFile, String>),
+    // Streamed when it is appended, see `STREAM_SIZE`
+    Large,
+    // Symbolic links and later hard links, storing no data of their own
+    Empty,
+}
+
+// Collect the files below `d` in stored order, `path` is where `d` is on disk
+// and `a_path` its path within the archive
+fn collect_files<'a>(
+    d: &'a mut QZDir,
+    path: &std::path::Path,
+    a_path: &str,
+    files: &mut Vec<FileJob<'a>>,
+) {
+    for e in &mut d.content {
+        match e {
+            QZEntry::Dir(ref mut sub) => {
+                let path = path.join(&sub.name);
+                let a_path = format!("{a_path}/{}", sub.name);
+                collect_files(sub, &path, &a_path, files);
+            }
+            QZEntry::File(ref mut f) => {
+                let path = path.join(&f.name);
+                let a_path = format!("{a_path}/{}", f.name);
+                files.push((f, path, a_path));
+            }
+        }
+    }
+}
+
+// Sort the entries below `d` by name and drop their owners, so the tree only depends
+// on names and content
+fn normalize_tree(d: &mut QZDir) {
+    d.content.sort_by(|a, b| a.name().cmp(b.name()));
+    (d.uid, d.gid) = (None, None);
+    for e in &mut d.content {
+        match e {
+            QZEntry::Dir(sub) => normalize_tree(sub),
+            QZEntry::File(f) => (f.uid, f.gid) = (None, None),
+        }
+    }
+}
+
+// Leave out the files below `d` that could not be read, `a_path` is the path of `d`
+fn remove_failed(
+    d: &mut QZDir,
+    a_path: &str,
+    failed: &std::collections::BTreeMap<String, (String, String)>,
+) {
+    d.content.retain(|e| match e {
+        QZEntry::File(f) => !failed.contains_key(&format!("{a_path}/{}", f.name)),
+        QZEntry::Dir(_) => true,
+    });
+    for e in &mut d.content {
+        if let QZEntry::Dir(ref mut sub) = e {
+            remove_failed(sub, &format!("{a_path}/{}", sub.name), failed);
+        }
+    }
+}
+
+// Let `f` share the data stored for `stored`
+fn share_stored(f: &mut QZFile, stored: &QZFile) {
+    f.compression = stored.compression.clone();
+    f.checksum = stored.checksum;
+    f.index_start = stored.index_start;
+    f.index_size = stored.index_size;
+    f.original_size = stored.original_size;
+    f.original_checksum = stored.original_checksum;
+    f.chunks = stored.chunks.clone();
+    f.crlf = stored.crlf;
+    f.nested = stored.nested;
+}
+
+// Count the file `f` storing `input_size` bytes of content in `stats`
+fn count_stored(stats: &mut PackStats, f: &QZFile, input_size: u64) {
+    let algo = stats
+        .algorithms
+        .entry(f.compression.to_string())
+        .or_default();
+    algo.files += 1;
+    algo.input_size += input_size;
+    algo.stored_size += f.index_size;
+    stats.input_size += input_size;
+}
+
+// Take over what an interrupted run recorded in `records` for the first of `files`,
+// returning how many of them are packed already
+//
+// A file is only taken over while it reads as it did and its data in the body checks out,
+// the body is cut after the last one.
+fn restore(
+    files: &mut [FileJob],
+    records: &[Record],
+    body: &mut Body,
+    state: &mut PackState,
+) -> Result<usize, errors::WriteError> {
+    // Records follow the stored order, which only holds as long as the tree is the same
+    let n = files
+        .iter()
+        .zip(records)
+        .take_while(|((f, _, a_path), record)| {
+            record.path() == a_path
+                && match record {
+                    Record::Stored(_, stored) => {
+                        stored.symlink == f.symlink
+                            && stored.compression.to_string() == f.compression.to_string()
+                    }
+                    Record::Failed(..) => true,
+                }
+        })
+        .count();
+    let (source, io) = (state.source, &state.io);
+    let unchanged: Vec<bool> = in_pool(state.pool, || {
+        files[..n]
+            .par_iter()
+            .zip(&records[..n])
+            .map(|((f, path, _), record)| match record {
+                Record::Stored(_, stored) if stored.hard_link.is_some() || f.symlink.is_some() => {
+                    true
+                }
+                Record::Stored(_, stored) => io
+                    .run(|| read_checksum(source, path, stored.crlf))
+                    .is_ok_and(|read| {
+                        read.is_some_and(|(len, checksum)| {
+                            stored.original_size == Some(len)
+                                && stored.original_checksum == Some(checksum)
+                        })
+                    }),
+                Record::Failed(..) => io.run(|| read_checksum(source, path, false)).is_err(),
+            })
+            .collect()
+    });
+
+    let Some(mut spooled) = body.reader() else {
+        return Ok(0);
+    };
+    let (mut len, mut hasher) = (0, crc32fast::Hasher::new());
+    let mut restored = 0;
+    let mut buf = vec![0; 64 * 1024];
+    for (((f, path, a_path), record), unchanged) in files.iter_mut().zip(records).zip(unchanged) {
+        if !unchanged {
+            break;
+        }
+        match record {
+            Record::Failed(_, err) => {
+                let failed = (path.display().to_string(), err.clone());
+                state.failed.insert(a_path.clone(), failed);
+            }
+            Record::Stored(_, stored) => {
+                let id = f.symlink.is_none().then(|| source.link_id(path)).flatten();
+                if let Some(first) = &stored.hard_link {
+                    match id.and_then(|id| state.links.get(&id)) {
+                        Some((path, linked)) if path == first => share_stored(f, linked),
+                        _ => break,
+                    }
+                    f.hard_link = Some(first.clone());
+                    restored += 1;
+                    continue;
+                }
+
+                // Stored one after another, each where the previous one ends
+                if stored.index_start != len {
+                    break;
+                }
+                let (mut file_hasher, mut body_hasher) = (crc32fast::Hasher::new(), hasher.clone());
+                let mut left = stored.index_size;
+                while left > 0 {
+                    let size = left.min(buf.len() as u64) as usize;
+                    let piece = &mut buf[..size];
+                    if spooled.read_exact(piece).is_err() {
+                        break;
+                    }
+                    file_hasher.update(piece);
+                    body_hasher.update(piece);
+                    left -= piece.len() as u64;
+                }
+                if left > 0 || file_hasher.finalize() != stored.checksum {
+                    break;
+                }
+                hasher = body_hasher;
+                share_stored(f, stored);
+                len += stored.index_size;
+                if f.symlink.is_none() {
+                    count_stored(&mut state.stats, f, stored.original_size.unwrap_or(0));
+                    if let Some(id) = id {
+                        state.links.insert(id, (a_path.clone(), (**f).clone()));
+                    }
+                }
+            }
+        }
+        restored += 1;
+    }
+    body.truncate(len, hasher)?;
+    Ok(restored)
+}
+
+// Compress `files` into `f_content`, recording their offsets and checksums,
+// the first `start` of them are packed already
+//
+// Batches of files are read and compressed in parallel, then appended in stored order,
+// so the body is the same whatever the number of threads.
+fn write_files(
+    mut files: Vec<FileJob>,
+    start: usize,
+    body: &mut Body,
+    state: &mut PackState,
+) -> Result<(), errors::WriteError> {
+    let threads = state
+        .pool
+        .map_or_else(rayon::current_num_threads, |p| p.current_num_threads());
+
+    // Sizes on disk, so progress is reported against a known total
+    let sizes: Vec<u64> = files[start..]
+        .iter()
+        .map(|(f, path, _)| match f.symlink {
+            Some(_) => 0,
+            None => state.source.len(path).unwrap_or(0),
+        })
+        .collect();
+    state
+        .progress
+        .add_work(sizes.len() as u64, sizes.iter().sum());
+
+    let mut done = start;
+    while done < files.len() {
+        let end = files.len().min(done + threads * 4);
+        let batch = &mut files[done..end];
+        let sizes = &sizes[done - start..end - start];
+        // Later links to an inode share the data stored for the first one
+        let ids: Vec<Option<(u64, u64)>> = batch
+            .iter()
+            .map(|(f, path, _)| {
+                f.symlink
+                    .is_none()
+                    .then(|| state.source.link_id(path))
+                    .flatten()
+            })
+            .collect();
+        let (encoding, links, progress, source, io) = (
+            state.encoding,
+            &state.links,
+            state.progress,
+            state.source,
+            &state.io,
+        );
+        let read: Vec<_> = in_pool(state.pool, || {
+            batch
+                .par_iter()
+                .zip(&ids)
+                .zip(sizes)
+                .map(|(((f, path, a_path), id), size)| {
+                    let linked = id.is_some_and(|id| links.contains_key(&id));
+                    let read = if f.symlink.is_some() || linked {
+                        Ok(Pending::Empty)
+                    } else if *size > STREAM_SIZE {
+                        // Reported once streamed
+                        return Ok(Pending::Large);
+                    } else {
+                        read_file_entry(f, path, source, io, encoding, *size).map(Pending::Read)
+                    };
+                    progress.file_done(a_path, *size);
+                    read
+                })
+                .collect()
+        });
+
+        for ((((f, path, a_path), id), read), size) in
+            batch.iter_mut().zip(ids).zip(read).zip(sizes)
+        {
+            if let Some((first, stored)) = id.and_then(|id| state.links.get(&id)) {
+                share_stored(f, stored);
+                f.hard_link = Some(first.clone());
+                if matches!(read, Ok(Pending::Large)) {
+                    state.progress.file_done(a_path, *size);
+                }
+                continue;
+            }
+
+            let read = match read? {
+                Pending::Read(Ok(file)) => {
+                    let input_size = file.stored.original_size;
+                    body.append(f, file)?;
+                    Ok(input_size)
+                }
+                Pending::Read(Err(err)) => Err(err),
+                Pending::Large => {
+                    let read = body.stream(f, path, state, *size);
+                    state.progress.file_done(a_path, *size);
+                    read?
+                }
+                // Symbolic links hold no data
+                Pending::Empty => {
+                    let file = ReadFile::new(
+                        compress_entry(f, &[], state.encoding)?,
+                        &[],
+                        vec![],
+                        false,
+                        false,
+                    );
+                    body.append(f, file)?;
+                    continue;
+                }
+            };
+            let input_size = match read {
+                Ok(input_size) => input_size,
+                Err(err) => {
+                    let failed = (path.display().to_string(), err);
+                    state.failed.insert(a_path.clone(), failed);
+                    continue;
+                }
+            };
+
+            count_stored(&mut state.stats, f, input_size);
+
+            if let Some(id) = id {
+                state.links.insert(id, (a_path.clone(), f.clone()));
+            }
+        }
+        done = end;
+        body.checkpoint(&files[..done], &state.failed)?;
+    }
+    Ok(())
+}
+
+/// Figures collected while creating an archive
+#[derive(Debug, Clone, Default)]
+pub struct PackStats {
+    /// Number of files, hard links included
+    pub files: u64,
+    /// Number of directories below the root
+    pub dirs: u64,
+    /// Files among `files` stored as hard links, sharing the data of an earlier one
+    pub hard_links: u64,
+    /// Files and directories left out as the options ask or as they cannot be stored,
+    /// see `ProgressSink::skipped`
+    pub skipped: u64,
+    /// Files left out as they could not be read, see `QZArchiveBuilder::skip_errors`
+    pub unreadable: u64,
+    /// Bytes read from the packed files
+    pub input_size: u64,
+    /// Bytes written, including the header and all volumes
+    pub output_size: u64,
+    /// Size of the zstd dictionary stored in the header, 0 without one
+    pub dictionary_size: u64,
+    /// Breakdown by compression algorithm of the files that store data
+    pub algorithms: std::collections::BTreeMap<String, AlgoStats>,
+    /// Time taken to create the archive
+    pub elapsed: std::time::Duration,
+    /// Part of `elapsed` spent reading and compressing the files
+    pub compress_time: std::time::Duration,
+    /// Part of `elapsed` spent writing the header and body to the archive
+    pub write_time: std::time::Duration,
+    /// Part of `elapsed` spent checking the written archive, see `QZArchiveBuilder::verify`
+    pub verify_time: std::time::Duration,
+}
+
+/// Files stored with one compression algorithm
+#[derive(Debug, Clone, Default)]
+pub struct AlgoStats {
+    pub files: u64,
+    pub input_size: u64,
+    pub stored_size: u64,
+}
+
+/// Builder for creating QZ Archives
+#[derive(Debug, Clone)]
+pub struct QZArchiveBuilder {
+    dir: String,
+    sources: Vec<String>,
+    files: Option<Vec<String>>,
+    out_file: String,
+    name: String,
+    description: String,
+    config: CompressionConfig,
+    header_compression: CompressionAlgo,
+    verify: bool,
+    volume_size: Option<u64>,
+    comments: std::collections::BTreeMap<String, String>,
+    metadata: std::collections::BTreeMap<String, String>,
+    buffer_size: usize,
+    temp_dir: Option<std::path::PathBuf>,
+    xattrs: bool,
+    recovery: Option<usize>,
+    skip_errors: bool,
+    relative_symlinks: bool,
+    recursive: bool,
+    hidden: bool,
+    normalize_line_endings: bool,
+    reproducible: bool,
+    root_name: Option<String>,
+    // Directory sources are stored relative to, instead of under their names
+    base: Option<String>,
+    order: FileOrder,
+    threads: usize,
+    io_threads: usize,
+    progress: progress::SharedSink,
+    special_files: SpecialFiles,
+    // Files to pack from, the real filesystem if not set
+    source: Option<vfs::SharedSource>,
+    resume: bool,
+}
+
+impl QZArchiveBuilder {
+    /// Pack `dir` into `out_file`, naming the archive after the output file
+    ///
+    /// An empty `dir` gives a valid archive, whose root lists no entries. If `dir` is a file,
+    /// just that file is packed, stored under its name as with `with_sources`.
+    pub fn new(dir: &str, out_file: &str) -> QZArchiveBuilder {
+        let name = std::path::Path::new(out_file)
+            .file_stem()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+
+        QZArchiveBuilder {
+            dir: dir.to_string(),
+            sources: vec![],
+            files: None,
+            out_file: out_file.to_string(),
+            name: name.to_string(),
+            description: String::new(),
+            config: CompressionConfig::default(),
+            header_compression: CompressionAlgo::ZSTD,
+            verify: false,
+            volume_size: None,
+            comments: std::collections::BTreeMap::new(),
+            metadata: std::collections::BTreeMap::new(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            temp_dir: None,
+            xattrs: false,
+            recovery: None,
+            skip_errors: false,
+            relative_symlinks: false,
+            recursive: true,
+            hidden: true,
+            normalize_line_endings: false,
+            reproducible: false,
+            root_name: None,
+            base: None,
+            order: FileOrder::Traversal,
+            threads: 0,
+            io_threads: 0,
+            progress: progress::SharedSink::default(),
+            special_files: SpecialFiles::Skip,
+            source: None,
+            resume: false,
+        }
+    }
+
+    /// Pack the directory `dir` of `source` into `out_file`, e.g. a `MemoryFs`
+    ///
+    /// Use `create_in_memory` to keep the archive off disk as well.
+    pub fn from_source(
+        source: std::sync::Arc<dyn FileSource>,
+        dir: &str,
+        out_file: &str,
+    ) -> QZArchiveBuilder {
+        let mut builder = QZArchiveBuilder::new(dir, out_file);
+        builder.source = Some(vfs::SharedSource(source));
+        builder
+    }
+
+    /// Pack several directories and files into `out_file`, each stored under its own name
+    ///
+    /// Sources that would end up with the same name are rejected when creating the archive.
+    pub fn with_sources(sources: &[&str], out_file: &str) -> QZArchiveBuilder {
+        let mut builder = QZArchiveBuilder::new("", out_file);
+        builder.sources = sources.iter().map(|s| s.to_string()).collect();
+        builder
+    }
+
+    /// Pack exactly the listed `files` below `base` into `out_file`, keeping their paths
+    ///
+    /// Paths are relative to `base` and may not leave it, absolute paths and `..` are rejected.
+    /// Parent directories are created as needed, listed directories are stored without content.
+    pub fn with_files(base: &str, files: &[&str], out_file: &str) -> QZArchiveBuilder {
+        let mut builder = QZArchiveBuilder::new(base, out_file);
+        builder.files = Some(files.iter().map(|f| f.to_string()).collect());
+        builder
+    }
+
+    /// Name of the archive
+    pub fn name(mut self, name: &str) -> QZArchiveBuilder {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Description stored in the header
+    pub fn description(mut self, description: &str) -> QZArchiveBuilder {
+        self.description = description.to_string();
+        self
+    }
+
+    /// Compression used for all files
+    pub fn compression(mut self, compression: CompressionAlgo) -> QZArchiveBuilder {
+        self.config.algorithm = compression;
+        self
+    }
+
+    /// Compression used for the header, `ZSTD` by default
+    ///
+    /// Headers that would not shrink are stored uncompressed.
+    pub fn header_compression(mut self, compression: CompressionAlgo) -> QZArchiveBuilder {
+        self.header_compression = compression;
+        self
+    }
+
+    /// Re-open and check the archive after writing it, removing it again on failure
+    pub fn verify(mut self, verify: bool) -> QZArchiveBuilder {
+        self.verify = verify;
+        self
+    }
+
+    /// Split the archive into volumes of at most `size` bytes,
+    /// named `out_file.001`, `out_file.002`, ...
+    pub fn volume_size(mut self, size: u64) -> QZArchiveBuilder {
+        self.volume_size = Some(size);
+        self
+    }
+
+    /// Attach a comment to the file at `path` (relative to the packed directory)
+    pub fn comment(mut self, path: &str, comment: &str) -> QZArchiveBuilder {
+        self.comments.insert(path.to_string(), comment.to_string());
+        self
+    }
+
+    /// Store a key-value pair in the archive header
+    pub fn metadata(mut self, key: &str, value: &str) -> QZArchiveBuilder {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Size of the buffers used when writing the archive,
+    /// values below `MIN_BUFFER_SIZE` are raised to it
+    pub fn buffer_size(mut self, size: usize) -> QZArchiveBuilder {
+        self.buffer_size = size.max(MIN_BUFFER_SIZE);
+        self
+    }
+
+    /// Write the archive and its body while packing to temporary files in `dir`
+    /// before moving it into place, instead of next to the output file
+    ///
+    /// If `dir` is not writable the output file's directory is used.
+    pub fn temp_dir(mut self, dir: impl AsRef<std::path::Path>) -> QZArchiveBuilder {
+        self.temp_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Leave out files that cannot be read, warning about each, instead of failing
+    ///
+    /// Without it every file is still attempted, the error then lists all that failed.
+    pub fn skip_errors(mut self, skip: bool) -> QZArchiveBuilder {
+        self.skip_errors = skip;
+        self
+    }
+
+    /// Store absolute symbolic link targets that point into the packed directory as
+    /// relative ones, so the links keep working wherever the archive is extracted
+    ///
+    /// Other targets are always stored as given.
+    pub fn relative_symlinks(mut self, relative: bool) -> QZArchiveBuilder {
+        self.relative_symlinks = relative;
+        self
+    }
+
+    /// Pack only the files directly within the packed directory, leaving out its subdirectories
+    ///
+    /// Files listed for `with_files` are packed regardless.
+    pub fn recursive(mut self, recursive: bool) -> QZArchiveBuilder {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Whether files and directories whose name starts with `.` are packed, `true` by default
+    ///
+    /// Without them hidden entries found when walking directories are left out,
+    /// along with everything below hidden directories. Packed directories and files
+    /// named explicitly are packed regardless.
+    pub fn hidden(mut self, hidden: bool) -> QZArchiveBuilder {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Write the same bytes for the same names and content wherever and by whoever it is packed
+    ///
+    /// Entries are stored sorted by name instead of in the order the filesystem lists them,
+    /// without owners, and the root is stored without a name unless `root_name` sets one.
+    /// The archive name still defaults to the output file name, set it with `name` to be
+    /// independent of that as well. Timestamps are never stored and compression does not
+    /// depend on the number of threads, so nothing else varies. Archives are the same only
+    /// when written by the same version of qz, which is recorded in the header.
+    pub fn reproducible(mut self, reproducible: bool) -> QZArchiveBuilder {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Store the root directory as `name` instead of the basename of the packed directory
+    ///
+    /// Archives packed from several sources, a list of files or below a `base` have an
+    /// empty root name.
+    /// Lookups ignore it either way, paths within the archive start below the root.
+    pub fn root_name(mut self, name: &str) -> QZArchiveBuilder {
+        self.root_name = Some(name.to_string());
+        self
+    }
+
+    /// Store the packed directories and files at their paths relative to `base` instead of
+    /// under their own names, e.g. `/src/main.rs` for `/home/me/project/src/main.rs`
+    /// below `/home/me/project`
+    ///
+    /// Parent directories up to `base` are created as needed, packing `base` itself stores
+    /// its content at the root. The stored tree then only depends on where the sources are
+    /// relative to `base`, not on where `base` is. Sources outside of `base` are rejected,
+    /// as is combining a base with `with_files`, whose paths are relative to its `base` already.
+    pub fn base(mut self, base: &str) -> QZArchiveBuilder {
+        self.base = Some(base.to_string());
+        self
+    }
+
+    /// Store files in the body in `order`, e.g. to keep files read together adjacent
+    ///
+    /// The archive holds the same tree in any order, lookups are unaffected.
+    pub fn file_order(mut self, order: FileOrder) -> QZArchiveBuilder {
+        self.order = order;
+        self
+    }
+
+    /// Choose what happens to fifos, sockets and device nodes, skipped by default
+    pub fn special_files(mut self, policy: SpecialFiles) -> QZArchiveBuilder {
+        self.special_files = policy;
+        self
+    }
+
+    /// Compress on a dedicated pool of `threads` threads, 0 uses the global rayon pool
+    ///
+    /// With 1 thread files are read and compressed one after another. The archive
+    /// written is the same for any number of threads.
+    pub fn threads(mut self, threads: usize) -> QZArchiveBuilder {
+        self.threads = threads;
+        self
+    }
+
+    /// Read at most `threads` files at once, 0 lets every compression thread read
+    ///
+    /// Compression still runs on all threads of `threads`, e.g. slow network storage can
+    /// be read one file at a time while the files read are compressed in parallel.
+    pub fn io_threads(mut self, threads: usize) -> QZArchiveBuilder {
+        self.io_threads = threads;
+        self
+    }
+
+    /// Report every file read and compressed to `sink`, and every file left out as a warning
+    pub fn progress(mut self, sink: std::sync::Arc<dyn ProgressSink>) -> QZArchiveBuilder {
+        self.progress = progress::SharedSink(sink);
+        self
+    }
+
+    /// Compress LZ4 entries with LZ4-HC, trading speed for a better ratio
+    ///
+    /// The result is regular LZ4 data, reading it is unaffected.
+    pub fn lz4_high_compression(mut self, hc: bool) -> QZArchiveBuilder {
+        self.config.lz4_hc = hc;
+        self
+    }
+
+    /// Compress files larger than `size` bytes in chunks of `size` bytes each, 0 disables it
+    ///
+    /// Each chunk can be decompressed by itself, so `QZArchive::read_range` on a large file
+    /// only decompresses the chunks it covers. Smaller chunks compress somewhat worse.
+    /// Uncompressed files are never split.
+    pub fn chunk_size(mut self, size: u64) -> QZArchiveBuilder {
+        self.config.chunk_size = Some(size).filter(|n| *n > 0);
+        self
+    }
+
+    /// Store text files whose lines all end with CRLF with LF line endings instead
+    ///
+    /// Files without NUL bytes count as text. Their content then reads the same whatever
+    /// platform it was packed on, and so does its checksum. Each converted file is marked
+    /// (`QZFile::crlf`), extracting with `QZArchive::set_restore_line_endings` writes it
+    /// with CRLF again. Binary files and text mixing both line endings are stored unchanged.
+    pub fn normalize_line_endings(mut self, normalize: bool) -> QZArchiveBuilder {
+        self.normalize_line_endings = normalize;
+        self
+    }
+
+    /// Let zstd find matches across long distances within each file
+    ///
+    /// Improves the ratio of large files repeating content far apart, such as disk images
+    /// or concatenated logs, at some cost in memory. The window grows to
+    /// `2^compression::MAX_WINDOW_LOG` bytes unless set with `window_log`.
+    /// Reading the archive is unaffected.
+    pub fn long_distance_matching(mut self, enable: bool) -> QZArchiveBuilder {
+        self.config.long_distance = enable;
+        self
+    }
+
+    /// Let zstd refer back up to `2^log` bytes, from `compression::MIN_WINDOW_LOG` to
+    /// `compression::MAX_WINDOW_LOG`
+    ///
+    /// Larger windows find more repetition in large files and need more memory to compress
+    /// and decompress, the window of a file never exceeds its size.
+    pub fn window_log(mut self, log: u32) -> QZArchiveBuilder {
+        self.config.window_log = Some(log);
+        self
+    }
+
+    /// Train a zstd dictionary on a sample of the smaller files and compress all `ZSTD`
+    /// entries with it, storing it once in the header
+    ///
+    /// Archives of many small, similar files such as JSON records compress much better
+    /// that way. If there is too little sample data the archive is written without one.
+    pub fn dictionary(mut self, dictionary: bool) -> QZArchiveBuilder {
+        self.config.train_dictionary = dictionary;
+        self
+    }
+
+    /// Compress all `ZSTD` entries with `dictionary` instead of training one, storing it
+    /// in the header
+    ///
+    /// Use a dictionary from `QZArchive::export_dictionary` or trained with
+    /// `compression::train_dictionary`, reusing it across archives of similar files
+    /// saves the training and keeps their ratio the same.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> QZArchiveBuilder {
+        self.config.dictionary = Some(dictionary.to_vec());
+        self
+    }
+
+    /// Compress with `config`, replacing the algorithm, level and every other setting
+    /// of it made so far
+    pub fn compression_config(mut self, config: CompressionConfig) -> QZArchiveBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Compress files whose name matches `pattern` with `compression` instead of the
+    /// algorithm of the archive, e.g. `*.bin` with `NONE`
+    ///
+    /// `*` matches any run of characters and `?` a single one. Of several matching
+    /// patterns the one given first applies.
+    pub fn compression_for(
+        mut self,
+        pattern: &str,
+        compression: CompressionAlgo,
+    ) -> QZArchiveBuilder {
+        self.config
+            .overrides
+            .push((pattern.to_string(), compression));
+        self
+    }
+
+    /// Compress at `level` instead of the default of the algorithm, see `CompressionConfig::level`
+    pub fn compression_level(mut self, level: i32) -> QZArchiveBuilder {
+        self.config.level = Some(level);
+        self
+    }
+
+    /// Store the extended attributes of files and directories
+    #[cfg(feature = "xattr")]
+    pub fn xattrs(mut self, xattrs: bool) -> QZArchiveBuilder {
+        self.xattrs = xattrs;
+        self
+    }
+
+    /// Append Reed-Solomon parity after the body, so that up to `parity_blocks` damaged
+    /// blocks out of every `recovery::GROUP_SIZE` can be reconstructed with `QZArchive::recover`
+    #[cfg(feature = "recovery")]
+    pub fn recovery(mut self, parity_blocks: usize) -> QZArchiveBuilder {
+        self.recovery = Some(parity_blocks).filter(|n| *n > 0);
+        self
+    }
+
+    /// Keep the body next to `out_file` while packing and record which files it holds,
+    /// so that `create` after an interruption takes over the files packed already
+    ///
+    /// The body is kept as `out_file.partial` and the record as `out_file.checkpoint`, updated
+    /// every few seconds and removed once the archive is written. A file is only taken over
+    /// if it is unchanged and the compression settings are the same, the archive then is the
+    /// same as one packed without interruption. Not supported together with `recovery`.
+    pub fn resume(mut self, resume: bool) -> QZArchiveBuilder {
+        self.resume = resume;
+        self
+    }
+
+    /// Write the archive
+    ///
+    /// Files are compressed into a temporary body file next to the output, or in `temp_dir`,
+    /// which is copied behind the header once that is known. Memory use so stays bounded
+    /// however large the archive, except with `recovery`, whose parity is computed over
+    /// the body held in memory.
+    pub fn create(&self) -> Result<(), errors::WriteError> {
+        self.create_with_stats().map(|_| ())
+    }
+
+    /// Write the archive and report what was packed
+    pub fn create_with_stats(&self) -> Result<PackStats, errors::WriteError> {
+        let started = std::time::Instant::now();
+        // Recovery records are computed over the whole body, which then has to be at hand
+        let body = if self.recovery.is_some() {
+            if self.resume {
+                return Err(errors::WriteError::InvalidInput(
+                    "packing with recovery records cannot be resumed".to_string(),
+                ));
+            }
+            Body::new(Spool::Memory(vec![]))
+        } else if self.resume {
+            Body::resumable(&self.out_file, self.buffer_size)?
+        } else {
+            Body::spooled(&self.out_file, self.temp_dir.as_deref(), self.buffer_size)?
+        };
+        let (mut archive, body, mut stats) = self.pack(body)?;
+        stats.compress_time = started.elapsed();
+        let writing = std::time::Instant::now();
+        body.write_archive(self, &mut archive)?;
+        stats.write_time = writing.elapsed();
+
+        // VERIFY
+
+        if self.verify {
+            let verifying = std::time::Instant::now();
+            let out_file = &self.out_file;
+            let res = read_archive(out_file).map_err(|err| err.to_string());
+            let res = res.and_then(|a| {
+                a.check_archive_deep().map_err(|failed| {
+                    let paths: Vec<String> = failed.into_iter().map(|(path, _)| path).collect();
+                    format!("damaged files: {}", paths.join(", "))
+                })
+            });
+            if let Err(err) = res {
+                remove_archive(out_file, archive.volumes.as_ref());
+                return Err(errors::WriteError::Other(format!(
+                    "verification of {out_file} failed: {err}"
+                )));
+            }
+            stats.verify_time = verifying.elapsed();
+        }
+
+        stats.output_size = archive_size(&self.out_file, archive.volumes.as_ref());
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+
+    /// Pack into an archive held in memory instead of writing `out_file`
+    ///
+    /// Volume splitting does not apply. Read the result with `read_archive_from_bytes`.
+    pub fn create_in_memory(&self) -> Result<Vec<u8>, errors::WriteError> {
+        let (mut archive, body, _) = self.pack(Body::new(Spool::Memory(vec![])))?;
+        let data = encode_archive(
+            &mut archive,
+            &self.header_compression,
+            &body.into_bytes(),
+            FORMAT_VERSION,
+        )?;
+
+        // VERIFY
+
+        if self.verify {
+            let res = read_archive_from_bytes(data.clone()).map_err(|err| err.to_string());
+            let res = res.and_then(|a| {
+                a.check_archive_deep().map_err(|failed| {
+                    let paths: Vec<String> = failed.into_iter().map(|(path, _)| path).collect();
+                    format!("damaged files: {}", paths.join(", "))
+                })
+            });
+            if let Err(err) = res {
+                return Err(errors::WriteError::Other(format!(
+                    "verification failed: {err}"
+                )));
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Pack in memory and report what the archive would be like, without writing it
+    ///
+    /// `output_size` is that of a single file archive, volume splitting is not applied.
+    pub fn measure(&self) -> Result<PackStats, errors::WriteError> {
+        let started = std::time::Instant::now();
+        let (archive, body, mut stats) = self.pack(Body::new(Spool::Discard))?;
+        stats.compress_time = started.elapsed();
+        let (encoded, _) = encode_header(&archive, &self.header_compression, FORMAT_VERSION);
+
+        stats.output_size = PREFIX_SIZE + encoded.len() as u64 + body.len;
+        stats.elapsed = started.elapsed();
+        Ok(stats)
+    }
+
+    // Checksum over the settings that decide the body, a resumed one has to match them
+    fn fingerprint(&self) -> u32 {
+        let settings = format!(
+            "{:?} {:?} {}",
+            self.config, self.order, self.normalize_line_endings
+        );
+        crc32fast::hash(settings.as_bytes())
+    }
+
+    // Build the header of the archive, appending the files to `body`
+    fn pack(
+        &self,
+        mut body: Body,
+    ) -> Result<(QZArchiveHeader, Body, PackStats), errors::WriteError> {
+        self.config.check_level()?;
+        self.config.check_window_log()?;
+
+        // SCAN DIR
+        let on_disk = self.source.is_none();
+        let base = self.base.as_deref();
+        if base.is_some() && (self.files.is_some() || !on_disk) {
+            return Err(errors::WriteError::InvalidInput(
+                "a base can only be given for directories and files on disk".to_string(),
+            ));
+        }
+        let packs_dir = self.sources.is_empty() && self.files.is_none() && on_disk;
+        if packs_dir {
+            check_source(&self.dir)?;
+        }
+        // A single file given instead of a directory is packed like a source of its own
+        let mut sources =
+            if packs_dir && (base.is_some() || std::path::Path::new(&self.dir).is_file()) {
+                std::slice::from_ref(&self.dir)
+            } else {
+                &self.sources[..]
+            };
+        let source: std::sync::Arc<dyn FileSource> = match &self.source {
+            Some(source) => source.0.clone(),
+            None => std::sync::Arc::new(vfs::OsFs),
+        };
+        let opts = ScanOptions {
+            compression: self.config.algorithm.clone(),
+            overrides: self.config.overrides.clone(),
+            xattrs: self.xattrs,
+            skip: if on_disk {
+                output_paths(&self.out_file)
+            } else {
+                vec![]
+            },
+            relative_symlinks: self.relative_symlinks,
+            special_files: self.special_files,
+            recursive: self.recursive,
+            hidden: self.hidden,
+            source: source.clone(),
+            progress: self.progress.0.clone(),
+            skipped: std::sync::Arc::default(),
+            root: match base {
+                Some(base) => fs::canonicalize(base).ok(),
+                None if sources.is_empty() && on_disk => fs::canonicalize(&self.dir).ok(),
+                None => None,
+            },
+        };
+        let mut root = if let Some(files) = &self.files {
+            pack_files(&self.dir, files, &opts)?
+        } else if let Some(base) = base {
+            pack_below(base, sources, &opts)?
+        } else if sources.is_empty() {
+            pack_dir(&self.dir, &opts)?
+        } else {
+            pack_sources(sources, &opts)?
+        };
+        let sorted_sources: Vec<String>;
+        if let (true, QZEntry::Dir(d)) = (self.reproducible, &mut root) {
+            // Entries of sources are matched up with them in order, which has to follow
+            if base.is_none() && self.files.is_none() && !sources.is_empty() {
+                let mut sorted: Vec<(QZEntry, String)> =
+                    d.content.drain(..).zip(sources.iter().cloned()).collect();
+                sorted.sort_by(|a, b| a.0.name().cmp(b.0.name()));
+                (d.content, sorted_sources) = sorted.into_iter().unzip();
+                sources = &sorted_sources;
+            }
+            normalize_tree(d);
+            d.name = String::new();
+        }
+        if let (Some(name), QZEntry::Dir(d)) = (&self.root_name, &mut root) {
+            d.name = name.clone();
+        }
+        let packed = if sources.is_empty() {
+            self.dir.clone()
+        } else {
+            sources.join(", ")
+        };
+        // Sources and bases add levels above the directories packed
+        if crate::tree_depth(&root) > crate::DEFAULT_MAX_DEPTH {
+            return Err(too_deep(&packed));
+        }
+
+        for (path, comment) in &self.comments {
+            match find_file_mut(&mut root, path) {
+                Some(f) => {
+                    f.comment = Some(comment.clone());
+                }
+                None => {
+                    return Err(errors::WriteError::InvalidInput(format!(
+                        "cannot attach comment, no file {path} in {packed}"
+                    )));
+                }
+            }
+        }
+
+        // PROCESS & MAKE FILE
+
+        let mut stats = PackStats::default();
+        let mut dictionary = None;
+
+        if let QZEntry::Dir(ref mut d) = root {
+            let pool = thread_pool(self.threads).map_err(errors::WriteError::Other)?;
+            let mut files = vec![];
+            if let Some(base) = base {
+                // The tree mirrors the directories below `base`
+                collect_files(d, std::path::Path::new(base), "", &mut files);
+            } else if sources.is_empty() {
+                collect_files(d, std::path::Path::new(&self.dir), "", &mut files);
+            } else {
+                // Entries were created in the order of `sources`
+                for (e, source) in d.content.iter_mut().zip(sources) {
+                    match e {
+                        QZEntry::Dir(ref mut sub) => {
+                            let a_path = format!("/{}", sub.name);
+                            collect_files(sub, std::path::Path::new(source), &a_path, &mut files);
+                        }
+                        QZEntry::File(ref mut f) => {
+                            let a_path = format!("/{}", f.name);
+                            files.push((f, std::path::PathBuf::from(source), a_path));
+                        }
+                    }
+                }
+            }
+            order_files(&mut files, &self.order);
+            let fingerprint = self.fingerprint();
+            let previous = body.previous(fingerprint);
+            if let Some(shared) = &self.config.dictionary {
+                // Only trained dictionaries can be decoded by every reader, raw content cannot
+                if !shared.starts_with(&compression::DICTIONARY_MAGIC) {
+                    return Err(errors::WriteError::InvalidInput(
+                        "not a zstd dictionary".to_string(),
+                    ));
+                }
+                dictionary = Some(shared.clone());
+            } else if let Some((settings, _)) =
+                previous.as_ref().filter(|_| self.config.train_dictionary)
+            {
+                // Training again may give another dictionary than the recorded files used
+                dictionary = settings.dictionary.clone();
+            } else if self.config.train_dictionary {
+                dictionary = train_dictionary(&files, source.as_ref());
+            }
+            let mut state = PackState {
+                stats: PackStats::default(),
+                encoding: Encoding {
+                    level: self.config.level,
+                    lz4_hc: self.config.lz4_hc,
+                    chunk_size: self.config.chunk_size,
+                    dictionary: dictionary.as_deref().unwrap_or_default(),
+                    long_distance: self.config.long_distance,
+                    window_log: self.config.window_log,
+                    normalize_line_endings: self.normalize_line_endings,
+                },
+                pool: pool.as_ref(),
+                progress: self.progress.0.as_ref(),
+                source: source.as_ref(),
+                io: IoLimit::new(self.io_threads),
+                failed: std::collections::BTreeMap::new(),
+                links: std::collections::HashMap::new(),
+            };
+            let start = match &previous {
+                Some((_, records)) => restore(&mut files, records, &mut body, &mut state)?,
+                // Left by a run with other settings or an older log
+                None => {
+                    body.truncate(0, crc32fast::Hasher::new())?;
+                    0
+                }
+            };
+            let settings = checkpoint::Settings {
+                fingerprint,
+                dictionary: dictionary.clone(),
+            };
+            body.start_checkpoints(settings, &files[..start], &state.failed)?;
+            write_files(files, start, &mut body, &mut state)?;
+            remove_failed(d, "", &state.failed);
+
+            if !state.failed.is_empty() && !self.skip_errors {
+                let failed: Vec<String> = state
+                    .failed
+                    .values()
+                    .map(|(path, err)| format!("{path}: {err}"))
+                    .collect();
+                return Err(errors::WriteError::Other(format!(
+                    "failed to read {} files: {}",
+                    failed.len(),
+                    failed.join(", ")
+                )));
+            }
+            for (path, err) in state.failed.values() {
+                self.progress
+                    .0
+                    .skipped(path, &SkipReason::Unreadable(err.clone()));
+            }
+            stats = state.stats;
+            stats.unreadable = state.failed.len() as u64;
+        }
+
+        stats.dictionary_size = dictionary.as_ref().map_or(0, |d| d.len() as u64);
+        stats.skipped = opts.skipped.load(std::sync::atomic::Ordering::Relaxed);
+        for (_, e) in walk_entries(&root) {
+            match e {
+                QZEntry::Dir(_) => stats.dirs += 1,
+                QZEntry::File(f) => {
+                    stats.files += 1;
+                    if f.hard_link.is_some() {
+                        stats.hard_links += 1;
+                    }
+                }
+            }
+        }
+
+        let archive = QZArchiveHeader {
+            name: self.name.clone(),
+            info: self.description.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            root,
+            volumes: None,
+            metadata: self.metadata.clone(),
+            recovery: self.recovery.map(QZRecovery::new),
+            dictionary,
+            body_checksum: Some(body.checksum()),
+        };
+
+        Ok((archive, body, stats))
+    }
+}
+
+/// Creating a QZ Archive
+///
+/// With `verify` set the written archive is re-opened and checked,
+/// and removed again if any file fails to verify.
+/// With `volume_size` set the archive is split into volumes of at most that many bytes,
+/// named `out_file.001`, `out_file.002`, ...
+pub fn create_archive(
+    dir: &str,
+    out_file: &str,
+    name: &str,
+    description: &str,
+    compression: CompressionAlgo,
+    verify: bool,
+    volume_size: Option<u64>,
+) -> Result<(), errors::WriteError> {
+    let config = CompressionConfig {
+        algorithm: compression,
+        ..Default::default()
+    };
+    create_archive_with_config(
+        dir,
+        out_file,
+        name,
+        description,
+        config,
+        verify,
+        volume_size,
+    )
+}
+
+/// Creating a QZ Archive like `create_archive`, compressing as `config` says
+///
+/// See `QZArchiveBuilder::compression_config`.
+pub fn create_archive_with_config(
+    dir: &str,
+    out_file: &str,
+    name: &str,
+    description: &str,
+    config: CompressionConfig,
+    verify: bool,
+    volume_size: Option<u64>,
+) -> Result<(), errors::WriteError> {
+    let mut builder = QZArchiveBuilder::new(dir, out_file)
+        .name(name)
+        .description(description)
+        .compression_config(config)
+        .verify(verify);
+    if let Some(size) = volume_size {
+        builder = builder.volume_size(size);
+    }
+    builder.create()
+}
+
+/// Creating a QZ Archive like `create_archive`, compressing with zstd and `dictionary`
+///
+/// See `QZArchiveBuilder::with_dictionary`.
+pub fn create_archive_with_dictionary(
+    dir: &str,
+    out_file: &str,
+    name: &str,
+    description: &str,
+    verify: bool,
+    volume_size: Option<u64>,
+    dictionary: &[u8],
+) -> Result<(), errors::WriteError> {
+    let config = CompressionConfig {
+        dictionary: Some(dictionary.to_vec()),
+        ..Default::default()
+    };
+    create_archive_with_config(
+        dir,
+        out_file,
+        name,
+        description,
+        config,
+        verify,
+        volume_size,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{pack_files, scratch_dir};
+    use crate::vfs::FileKind;
+    use std::path::{Path, PathBuf};
+
+    // Directory tree of `depth` nested directories on disk with a file in the innermost
+    fn nested_dirs(depth: usize) -> (std::path::PathBuf, String) {
+        let root = scratch_dir();
+        let mut dir = root.clone();
+        let mut path = String::new();
+        for _ in 0..depth {
+            dir.push("d");
+            path.push_str("/d");
+        }
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("f"), b"deep").unwrap();
+        (root, format!("{path}/f"))
+    }
+
+    #[test]
+    fn packs_deep_trees() {
+        let (root, path) = nested_dirs(200);
+        let out_file = root.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        QZArchiveBuilder::new(root.to_str().unwrap(), out_file)
+            .create()
+            .unwrap();
+
+        let a = crate::read_archive(out_file).unwrap();
+        assert_eq!(a.read_file(&path).unwrap(), b"deep");
+        let _ = fs::remove_file(out_file);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn packs_deep_trees_up_to_the_read_limit() {
+        let (root, path) = nested_dirs(crate::DEFAULT_MAX_DEPTH);
+        let out_file = root.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        QZArchiveBuilder::new(root.to_str().unwrap(), out_file)
+            .create()
+            .unwrap();
+
+        let a = crate::read_archive(out_file).unwrap();
+        assert_eq!(a.read_file(&path).unwrap(), b"deep");
+        let _ = fs::remove_file(out_file);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn refuses_sources_nesting_deeper_than_the_read_limit() {
+        // Stored below its own name, the tree gains a level
+        let (root, _) = nested_dirs(crate::DEFAULT_MAX_DEPTH);
+        let out_file = root.with_extension("qz");
+        let res =
+            QZArchiveBuilder::with_sources(&[root.to_str().unwrap()], out_file.to_str().unwrap())
+                .create();
+
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("more than 256 levels deep"), "{err}");
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn refuses_trees_deeper_than_the_read_limit() {
+        let (root, _) = nested_dirs(crate::DEFAULT_MAX_DEPTH + 44);
+        let out_file = root.with_extension("qz");
+        let res =
+            QZArchiveBuilder::new(root.to_str().unwrap(), out_file.to_str().unwrap()).create();
+
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("more than 256 levels deep"), "{err}");
+        assert!(!out_file.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn packs_empty_directories_into_readable_archives() {
+        for dictionary in [false, true] {
+            let (dir, out_file) = pack_files(&[], |b| b.dictionary(dictionary).verify(true));
+            let a = crate::read_archive(&out_file).unwrap();
+            assert!(a.ls("/").unwrap().is_empty());
+            assert!(a.entries().is_empty());
+            assert!(a.check_archive().is_ok());
+            assert!(a.check_archive_deep().is_ok());
+            let _ = fs::remove_file(&out_file);
+            let _ = fs::remove_dir_all(&dir);
+        }
+
+        let dir = scratch_dir();
+        let data = QZArchiveBuilder::new(dir.to_str().unwrap(), "unused.qz")
+            .create_in_memory()
+            .unwrap();
+        let a = crate::read_archive_from_bytes(data).unwrap();
+        assert!(a.ls("/").unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_special_files_unless_told_to_fail() {
+        let dir = scratch_dir();
+        fs::write(dir.join("a.txt"), b"alpha").unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(dir.join("socket")).unwrap();
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+
+        QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .create()
+            .unwrap();
+        let a = crate::read_archive(out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), ["a.txt"]);
+        let _ = fs::remove_file(out_file);
+
+        let res = QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .special_files(SpecialFiles::Error)
+            .create();
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("socket"), "{err}");
+        assert!(!std::path::Path::new(out_file).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // `MemoryFs` whose files changed size since they were listed, as logs being written do
+    struct ShortReads(vfs::MemoryFs);
+
+    impl FileSource for ShortReads {
+        fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+            self.0.list(path)
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            if path.ends_with("unreadable") {
+                return Err(std::io::Error::other("device went away"));
+            }
+            self.0.read(path)
+        }
+
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.0.read_link(path)
+        }
+
+        fn len(&self, path: &Path) -> std::io::Result<u64> {
+            self.0.len(path).map(|len| len + 4096)
+        }
+    }
+
+    #[test]
+    fn packs_what_files_hold_when_read() {
+        let mut fs = vfs::MemoryFs::new();
+        fs.add_file("log", "short");
+        let source = std::sync::Arc::new(ShortReads(fs));
+        let data = QZArchiveBuilder::from_source(source.clone(), "", "unused.qz")
+            .create_in_memory()
+            .unwrap();
+        let a = crate::read_archive_from_bytes(data).unwrap();
+        assert_eq!(a.read_file("/log").unwrap(), b"short");
+        assert!(a.check_archive_deep().is_ok());
+
+        let mut fs = vfs::MemoryFs::new();
+        fs.add_file("log", "short");
+        fs.add_file("unreadable", "lost");
+        let source = std::sync::Arc::new(ShortReads(fs));
+        let res = QZArchiveBuilder::from_source(source, "", "unused.qz").create_in_memory();
+        let err = res.unwrap_err().to_string();
+        assert!(
+            err.contains("unreadable") && err.contains("device went away"),
+            "{err}"
+        );
+    }
+
+    // `MemoryFs` that cannot hold a whole file beyond `STREAM_SIZE` bytes in memory,
+    // with a file `broken` failing to read halfway through
+    struct SmallMemory(vfs::MemoryFs);
+
+    struct Broken;
+
+    impl std::io::Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("device went away"))
+        }
+    }
+
+    impl FileSource for SmallMemory {
+        fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+            self.0.list(path)
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            if self.0.len(path)? > STREAM_SIZE {
+                return Err(std::io::Error::other("out of memory"));
+            }
+            self.0.read(path)
+        }
+
+        fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+            let reader = self.0.open(path)?;
+            match path.ends_with("broken") {
+                true => Ok(Box::new(reader.take(STREAM_SIZE).chain(Broken))),
+                false => Ok(reader),
+            }
+        }
+
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.0.read_link(path)
+        }
+
+        fn len(&self, path: &Path) -> std::io::Result<u64> {
+            self.0.len(path)
+        }
+    }
+
+    #[test]
+    fn streams_files_larger_than_memory() {
+        let big: Vec<u8> = (0..3 * STREAM_SIZE)
+            .map(|i| (i % 251) as u8 ^ (i >> 12) as u8)
+            .collect();
+        let mut files = vfs::MemoryFs::new();
+        files.add_file("big", big.clone());
+        files.add_file("docs/big", big.clone());
+        files.add_file("broken", big.clone());
+        files.add_file("small", "small");
+        let dir = scratch_dir();
+        let out_file = format!("{}.qz", dir.display());
+
+        for chunk_size in [None, Some(STREAM_SIZE / 2)] {
+            let source = std::sync::Arc::new(SmallMemory(files.clone()));
+            let mut builder =
+                QZArchiveBuilder::from_source(source, "", &out_file).skip_errors(true);
+            if let Some(size) = chunk_size {
+                builder = builder.chunk_size(size);
+            }
+            let stats = builder.create_with_stats().unwrap();
+            assert_eq!(stats.unreadable, 1);
+
+            let a = read_archive(&out_file).unwrap();
+            assert_eq!(a.read_file("/big").unwrap(), big);
+            assert_eq!(a.read_file("/docs/big").unwrap(), big);
+            assert_eq!(a.read_file("/small").unwrap(), b"small");
+            assert!(a.get_entry("/broken").is_err());
+            let QZEntry::File(f) = a.get_entry("/big").unwrap() else {
+                panic!("not a file");
+            };
+            assert_eq!(f.chunks.len(), if chunk_size.is_some() { 6 } else { 0 });
+            assert!(a.check_archive_deep().is_ok());
+        }
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // `MemoryFs` counting how many files are read at once, the most in `peak`, and as
+    // its progress sink how many are between being read and done, the most in
+    // `compressing_peak`
+    #[derive(Default)]
+    struct CountedReads {
+        files: vfs::MemoryFs,
+        reading: std::sync::atomic::AtomicUsize,
+        peak: std::sync::atomic::AtomicUsize,
+        compressing: std::sync::atomic::AtomicUsize,
+        compressing_peak: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FileSource for CountedReads {
+        fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+            self.files.list(path)
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            use std::sync::atomic::Ordering;
+
+            let now = self.reading.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            let now = self.compressing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.compressing_peak.fetch_max(now, Ordering::SeqCst);
+            // Long enough for other threads to start reading meanwhile
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            let data = self.files.read(path);
+            self.reading.fetch_sub(1, Ordering::SeqCst);
+            data
+        }
+
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.files.read_link(path)
+        }
+
+        fn len(&self, path: &Path) -> std::io::Result<u64> {
+            self.files.len(path)
+        }
+    }
+
+    impl ProgressSink for CountedReads {
+        fn add_work(&self, _files: u64, _bytes: u64) {}
+
+        fn file_done(&self, _path: &str, _bytes: u64) {
+            self.compressing
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn reads_no_more_files_at_once_than_the_limits_allow() {
+        // Compression threads, I/O threads and the most reads expected at once
+        for (threads, io_threads, most) in [(4, 1, 1), (4, 2, 2), (2, 0, 2)] {
+            let mut source = CountedReads::default();
+            for i in 0..32 {
+                source.files.add_file(format!("f{i}"), format!("file {i}"));
+            }
+            let source = std::sync::Arc::new(source);
+            let data = QZArchiveBuilder::from_source(source.clone(), "", "unused.qz")
+                .threads(threads)
+                .io_threads(io_threads)
+                .progress(source.clone())
+                .create_in_memory()
+                .unwrap();
+            let peak = source.peak.load(std::sync::atomic::Ordering::SeqCst);
+            assert!(peak >= 1 && peak <= most, "{threads} {io_threads}: {peak}");
+            // Files are compressed by no more threads than `threads` either
+            let compressing = source
+                .compressing_peak
+                .load(std::sync::atomic::Ordering::SeqCst);
+            assert!(
+                compressing >= 1 && compressing <= threads,
+                "{threads} {io_threads}: {compressing}"
+            );
+            let a = crate::read_archive_from_bytes(data).unwrap();
+            assert_eq!(a.read_file("/f31").unwrap(), b"file 31");
+        }
+    }
+
+    // `MemoryFs` listing every entry twice, as a case folding file system can
+    struct DoubleListing(vfs::MemoryFs);
+
+    impl FileSource for DoubleListing {
+        fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+            let paths = self.0.list(path)?;
+            Ok(paths.iter().chain(&paths).cloned().collect())
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            self.0.read(path)
+        }
+
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.0.read_link(path)
+        }
+
+        fn len(&self, path: &Path) -> std::io::Result<u64> {
+            self.0.len(path)
+        }
+    }
+
+    // `MemoryFs` whose process is killed on reading the `crash_at`th file
+    struct Crashing {
+        files: vfs::MemoryFs,
+        reads: std::sync::atomic::AtomicUsize,
+        crash_at: usize,
+    }
+
+    impl FileSource for Crashing {
+        fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+            self.files.list(path)
+        }
+
+        fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+            let reads = self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            assert!(reads + 1 < self.crash_at, "killed");
+            self.files.read(path)
+        }
+
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.files.read_link(path)
+        }
+
+        fn len(&self, path: &Path) -> std::io::Result<u64> {
+            self.files.len(path)
+        }
+    }
+
+    #[test]
+    fn resumed_packing_gives_the_uninterrupted_archive() {
+        let files = || {
+            let mut files = vfs::MemoryFs::new();
+            for i in 0..40 {
+                files.add_file(
+                    format!("d{}/f{i}.txt", i % 3),
+                    format!("file {i} ").repeat(i * 50),
+                );
+            }
+            files
+        };
+        let dir = scratch_dir();
+        let out_file = dir.join("out.qz");
+        let out_file = out_file.to_str().unwrap();
+        let builder = |source: std::sync::Arc<dyn FileSource>| {
+            QZArchiveBuilder::from_source(source, "", out_file)
+                .threads(1)
+                .reproducible(true)
+                .resume(true)
+        };
+        builder(std::sync::Arc::new(files())).create().unwrap();
+        let uninterrupted = fs::read(out_file).unwrap();
+        fs::remove_file(out_file).unwrap();
+
+        let crashing = Crashing {
+            files: files(),
+            reads: Default::default(),
+            crash_at: 30,
+        };
+        let res = std::panic::catch_unwind(|| builder(std::sync::Arc::new(crashing)).create());
+        assert!(res.is_err());
+        assert!(!Path::new(out_file).exists());
+        let (_, records) = checkpoint::load(&checkpoint::log_path(out_file)).unwrap();
+        assert!(records.len() >= 20, "{}", records.len());
+
+        builder(std::sync::Arc::new(files())).create().unwrap();
+        assert!(fs::read(out_file).unwrap() == uninterrupted);
+        assert!(!Path::new(&checkpoint::body_path(out_file)).exists());
+        assert!(!Path::new(&checkpoint::log_path(out_file)).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reproducible_archives_are_the_same_bytes_every_run() {
+        let names = ["b/z.txt", "a.txt", "b/y.txt", "c/d/e.txt", "b/x.txt"];
+        let pack = |names: &[&str], threads: usize| {
+            let dir = scratch_dir();
+            for name in names {
+                let path = dir.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, name.repeat(500)).unwrap();
+            }
+            let out_file = dir.with_extension("qz");
+            let out_file = out_file.to_str().unwrap();
+            QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+                .name("same")
+                .reproducible(true)
+                .threads(threads)
+                .create()
+                .unwrap();
+            let data = fs::read(out_file).unwrap();
+            let _ = fs::remove_file(out_file);
+            let _ = fs::remove_dir_all(&dir);
+            data
+        };
+        let reversed: Vec<&str> = names.iter().rev().copied().collect();
+        assert!(pack(&names, 1) == pack(&reversed, 4));
+    }
+
+    #[test]
+    fn tells_apart_what_packing_failed_on() {
+        let dir = scratch_dir();
+        let out_file = dir.join("out.qz");
+        let out_file = out_file.to_str().unwrap();
+
+        let files = std::sync::Arc::new(vfs::MemoryFs::new());
+        let err = QZArchiveBuilder::from_source(files, "missing", out_file)
+            .create()
+            .unwrap_err();
+        assert!(
+            matches!(&err, errors::WriteError::SourceRead(path, _) if path == "missing"),
+            "{err}"
+        );
+
+        let unwritable = dir.join("no/such/dir/out.qz");
+        let err = QZArchiveBuilder::new(dir.to_str().unwrap(), unwritable.to_str().unwrap())
+            .create()
+            .unwrap_err();
+        assert!(matches!(err, errors::WriteError::Output(..)), "{err}");
+
+        let missing = dir.join("missing");
+        let err = QZArchiveBuilder::with_sources(&[missing.to_str().unwrap()], out_file)
+            .create()
+            .unwrap_err();
+        assert!(matches!(err, errors::WriteError::InvalidInput(_)), "{err}");
+        assert!(err.to_string().contains("does not exist"), "{err}");
+        assert!(!Path::new(out_file).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn packs_several_sources_under_their_own_names() {
+        let dir = scratch_dir();
+        for (path, data) in [
+            ("one/a.txt", "a"),
+            ("two/sub/b.txt", "b"),
+            ("loose.txt", "c"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        let source = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let (one, two, loose) = (source("one"), source("two"), source("loose.txt"));
+        QZArchiveBuilder::with_sources(&[&one, &two, &loose], out_file)
+            .create()
+            .unwrap();
+
+        let a = read_archive(out_file).unwrap();
+        let mut root = a.ls("/").unwrap();
+        root.sort();
+        assert_eq!(root, ["loose.txt", "one", "two"]);
+        assert_eq!(a.read_file("/one/a.txt").unwrap(), b"a");
+        assert_eq!(a.read_file("/two/sub/b.txt").unwrap(), b"b");
+        assert_eq!(a.read_file("/loose.txt").unwrap(), b"c");
+
+        let err = QZArchiveBuilder::with_sources(&[&one, &one], out_file)
+            .create()
+            .unwrap_err();
+        assert!(matches!(err, errors::WriteError::InvalidInput(_)), "{err}");
+        let _ = fs::remove_file(out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn packs_exactly_the_listed_files() {
+        let files: &[(&str, &[u8])] = &[
+            ("a.txt", b"a"),
+            ("sub/b.txt", b"b"),
+            ("sub/left.txt", b"x"),
+            ("empty/c.txt", b"c"),
+            ("other.txt", b"x"),
+        ];
+        let (dir, out_file) = pack_files(files, |b| b);
+        let base = dir.to_str().unwrap();
+        QZArchiveBuilder::with_files(base, &["sub/b.txt", "a.txt", "empty"], &out_file)
+            .create()
+            .unwrap();
+
+        let a = read_archive(&out_file).unwrap();
+        let mut root = a.ls("/").unwrap();
+        root.sort();
+        assert_eq!(root, ["a.txt", "empty", "sub"]);
+        assert_eq!(a.ls("/sub").unwrap(), ["b.txt"]);
+        assert!(a.ls("/empty").unwrap().is_empty());
+        assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"b");
+
+        for listed in ["../other.txt", "/etc/passwd", "missing.txt"] {
+            let res = QZArchiveBuilder::with_files(base, &[listed], &out_file).create();
+            assert!(res.is_err(), "{listed}");
+        }
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn counts_what_was_packed() {
+        let files: &[(&str, &[u8])] = &[
+            ("a.txt", b"alpha"),
+            ("sub/b.bin", b"beta"),
+            ("sub/deeper/c.txt", b"gamma"),
+            (".hidden", b"secret"),
+        ];
+        let (dir, out_file) = pack_files(files, |b| b);
+        fs::create_dir(dir.join("empty")).unwrap();
+        let stats = QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .hidden(false)
+            .compression_for("*.bin", CompressionAlgo::NONE)
+            .create_with_stats()
+            .unwrap();
+
+        assert_eq!((stats.files, stats.dirs, stats.skipped), (3, 3, 1));
+        assert_eq!((stats.hard_links, stats.unreadable), (0, 0));
+        assert_eq!(stats.input_size, 14);
+        assert_eq!(stats.output_size, fs::metadata(&out_file).unwrap().len());
+        let none = &stats.algorithms["none"];
+        assert_eq!((none.files, none.input_size, none.stored_size), (1, 4, 4));
+        assert_eq!(stats.algorithms["zstd"].files, 2);
+        assert!(stats.elapsed >= stats.compress_time + stats.write_time);
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_names_that_are_no_single_component() {
+        for name in ["a/b", "a\\b", "", ".", "..", "nul\0"] {
+            let err = check_name(name, Path::new("x")).unwrap_err();
+            assert!(matches!(err, errors::WriteError::InvalidInput(_)), "{name}");
+        }
+        assert!(check_name("a.b c", Path::new("x")).is_ok());
+
+        #[cfg(unix)]
+        {
+            let dir = scratch_dir();
+            fs::write(dir.join("ok.txt"), b"ok").unwrap();
+            fs::write(dir.join("win\\name.txt"), b"x").unwrap();
+            let out_file = dir.with_extension("qz").to_str().unwrap().to_string();
+            let err = QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+                .create()
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.contains("'win\\name.txt' is not a valid entry name"),
+                "{err}"
+            );
+            assert!(!Path::new(&out_file).exists());
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[test]
+    fn lz4_high_compression_stores_less() {
+        let text: String = (0..5000)
+            .map(|i| format!("line {} of {} holds {}\n", i, i * 7 % 13, i * i % 101))
+            .collect();
+        let stored = |hc: bool| {
+            let (dir, out_file) = pack_files(&[("a.txt", text.as_bytes())], |b| {
+                b.compression(CompressionAlgo::LZ4).lz4_high_compression(hc)
+            });
+            let a = read_archive(&out_file).unwrap();
+            assert_eq!(a.read_file("/a.txt").unwrap(), text.as_bytes());
+            let size = a.lookup_file("/a.txt".as_ref()).unwrap().index_size;
+            let _ = fs::remove_file(&out_file);
+            let _ = fs::remove_dir_all(&dir);
+            size
+        };
+        let (fast, hc) = (stored(false), stored(true));
+        assert!(hc < fast, "{hc} >= {fast}");
+    }
+
+    // Sink recording what was left out, by file name
+    #[derive(Default)]
+    struct Skips(std::sync::Mutex<Vec<(String, SkipReason)>>);
+
+    impl ProgressSink for Skips {
+        fn add_work(&self, _files: u64, _bytes: u64) {}
+
+        fn file_done(&self, _path: &str, _bytes: u64) {}
+
+        fn skipped(&self, path: &str, reason: &SkipReason) {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((name.to_string(), reason.clone()));
+        }
+    }
+
+    #[test]
+    fn leaves_out_the_archive_being_written() {
+        let (dir, _) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let out_file = dir.join("out.qz");
+        let out_file = out_file.to_str().unwrap();
+        // The second run finds the archive of the first one in place
+        for run in 0..2 {
+            let skips = std::sync::Arc::new(Skips::default());
+            let stats = QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+                .progress(skips.clone())
+                .create_with_stats()
+                .unwrap();
+            let a = read_archive(out_file).unwrap();
+            assert_eq!(a.ls("/").unwrap(), ["a.txt"]);
+            assert_eq!(stats.files, 1);
+            let skips = skips.0.lock().unwrap();
+            assert!(run == 0 || skips.contains(&("out.qz".to_string(), SkipReason::Output)));
+            assert!(skips
+                .iter()
+                .all(|(_, reason)| *reason == SkipReason::Output));
+            assert!(skips.iter().all(|(name, _)| name.starts_with("out.qz")));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn skips_and_reports_unreadable_files_if_asked() {
+        let mut files = vfs::MemoryFs::new();
+        files.add_file("log", "short");
+        files.add_file("sub/unreadable", "lost");
+        let dir = scratch_dir();
+        let out_file = dir.join("out.qz");
+        let out_file = out_file.to_str().unwrap();
+        let skips = std::sync::Arc::new(Skips::default());
+        let stats =
+            QZArchiveBuilder::from_source(std::sync::Arc::new(ShortReads(files)), "", out_file)
+                .skip_errors(true)
+                .progress(skips.clone())
+                .create_with_stats()
+                .unwrap();
+
+        assert_eq!((stats.files, stats.unreadable), (1, 1));
+        let skips = skips.0.lock().unwrap();
+        assert_eq!(skips.len(), 1);
+        assert_eq!(skips[0].0, "unreadable");
+        assert!(
+            matches!(&skips[0].1, SkipReason::Unreadable(err) if err.contains("device went away")),
+            "{:?}",
+            skips[0].1
+        );
+        let a = read_archive(out_file).unwrap();
+        assert_eq!(a.read_file("/log").unwrap(), b"short");
+        assert!(a.get_entry("/sub/unreadable").is_err());
+        assert!(a.check_archive_deep().is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Small JSON records alike enough for a dictionary to pay off
+    fn records(count: usize, offset: usize) -> vfs::MemoryFs {
+        let mut files = vfs::MemoryFs::new();
+        for i in offset..offset + count {
+            let record = format!(
+                "{{\"id\": {i}, \"user\": \"user{}\", \"active\": {}, \"tags\": [\"a{}\"]}}",
+                i * 31 % 1000,
+                i % 2 == 0,
+                i % 7
+            );
+            files.add_file(format!("r/{i}.json"), record);
+        }
+        files
+    }
+
+    #[test]
+    fn trained_dictionaries_shrink_archives_of_small_files() {
+        let files = std::sync::Arc::new(records(500, 0));
+        let pack = |dictionary: bool| {
+            QZArchiveBuilder::from_source(files.clone(), "", "unused.qz")
+                .dictionary(dictionary)
+                .create_in_memory()
+                .unwrap()
+        };
+        let (plain, trained) = (pack(false), pack(true));
+        assert!(
+            trained.len() < plain.len(),
+            "{} >= {}",
+            trained.len(),
+            plain.len()
+        );
+
+        let a = crate::read_archive_from_bytes(trained).unwrap();
+        assert!(a.export_dictionary().is_some());
+        assert_eq!(
+            a.read_file("/r/42.json").unwrap(),
+            files.read(Path::new("r/42.json")).unwrap()
+        );
+        assert!(a.check_archive_deep().is_ok());
+        let a = crate::read_archive_from_bytes(plain).unwrap();
+        assert!(a.export_dictionary().is_none());
+    }
+
+    #[test]
+    fn archives_share_an_exported_dictionary() {
+        let data = QZArchiveBuilder::from_source(std::sync::Arc::new(records(500, 0)), "", "")
+            .dictionary(true)
+            .create_in_memory()
+            .unwrap();
+        let dictionary = crate::read_archive_from_bytes(data)
+            .unwrap()
+            .export_dictionary()
+            .unwrap();
+
+        // Other records packed with the first archive's dictionary instead of training one
+        let files = std::sync::Arc::new(records(200, 1000));
+        let data = QZArchiveBuilder::from_source(files.clone(), "", "")
+            .with_dictionary(&dictionary)
+            .create_in_memory()
+            .unwrap();
+        let a = crate::read_archive_from_bytes(data.clone()).unwrap();
+        assert_eq!(a.export_dictionary(), Some(dictionary));
+        assert_eq!(
+            a.read_file("/r/1100.json").unwrap(),
+            files.read(Path::new("r/1100.json")).unwrap()
+        );
+        assert!(a.check_archive_deep().is_ok());
+
+        let plain = QZArchiveBuilder::from_source(files, "", "")
+            .create_in_memory()
+            .unwrap();
+        assert!(data.len() < plain.len());
+    }
+
+    #[test]
+    fn names_the_root_as_configured() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let root_name = |out_file: &str| {
+            let a = read_archive(out_file).unwrap();
+            assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+            a.get_entry("/").unwrap().name().to_string()
+        };
+        let basename = dir.file_name().unwrap().to_str().unwrap();
+        assert_eq!(root_name(&out_file), basename);
+
+        QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .root_name("custom")
+            .create()
+            .unwrap();
+        assert_eq!(root_name(&out_file), "custom");
+
+        QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .reproducible(true)
+            .create()
+            .unwrap();
+        assert_eq!(root_name(&out_file), "");
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn create_archive_functions_pack_as_configured() {
+        let records: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("{{\"id\": {i}, \"name\": \"record {i}\", \"ok\": true}}").into())
+            .collect();
+        let files: Vec<(String, &[u8])> = records
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (format!("r{i}.json"), r.as_slice()))
+            .collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(n, r)| (n.as_str(), *r)).collect();
+        let (dir, out_file) = pack_files(&files, |b| b);
+        let dir = dir.to_str().unwrap();
+
+        create_archive(
+            dir,
+            &out_file,
+            "plain",
+            "text",
+            CompressionAlgo::LZ4,
+            true,
+            None,
+        )
+        .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!((a.name(), a.description()), ("plain", "text"));
+        let QZEntry::File(f) = a.get_entry("/r7.json").unwrap() else {
+            panic!("not a file");
+        };
+        assert!(matches!(f.compression, CompressionAlgo::LZ4));
+        assert_eq!(a.read_file("/r7.json").unwrap(), records[7]);
+
+        let config = CompressionConfig {
+            chunk_size: Some(16),
+            ..Default::default()
+        };
+        create_archive_with_config(dir, &out_file, "chunked", "", config, false, None).unwrap();
+        let a = read_archive(&out_file).unwrap();
+        let QZEntry::File(f) = a.get_entry("/r7.json").unwrap() else {
+            panic!("not a file");
+        };
+        assert!(matches!(f.compression, CompressionAlgo::ZSTD) && f.chunks.len() > 1);
+        assert_eq!(a.read_file("/r7.json").unwrap(), records[7]);
+
+        let dictionary = compression::train_dictionary(&records, 4096).unwrap();
+        create_archive_with_dictionary(dir, &out_file, "dict", "", false, None, &dictionary)
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.export_dictionary(), Some(dictionary));
+        assert_eq!(a.read_file("/r7.json").unwrap(), records[7]);
+
+        let config = CompressionConfig {
+            level: Some(23),
+            ..Default::default()
+        };
+        let err = create_archive_with_config(dir, &out_file, "", "", config, false, None);
+        assert!(matches!(err, Err(errors::WriteError::InvalidInput(_))));
+        let config = CompressionConfig {
+            window_log: Some(40),
+            ..Default::default()
+        };
+        let err = create_archive_with_config(dir, &out_file, "", "", config, false, None);
+        assert!(matches!(err, Err(errors::WriteError::InvalidInput(_))));
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn refuses_duplicate_names_within_a_directory() {
+        let mut fs = vfs::MemoryFs::new();
+        fs.add_file("docs/readme", "text");
+        let source = std::sync::Arc::new(DoubleListing(fs));
+        let res = QZArchiveBuilder::from_source(source, "", "unused.qz").create_in_memory();
+        let err = res.unwrap_err().to_string();
+        assert!(
+            err.contains("duplicate entry name 'readme' in docs"),
+            "{err}"
+        );
+
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("a/data")).unwrap();
+        fs::create_dir_all(dir.join("b/data")).unwrap();
+        let out_file = dir.join("out.qz");
+        let sources = [
+            dir.join("a/data").to_str().unwrap().to_string(),
+            dir.join("b/data").to_str().unwrap().to_string(),
+        ];
+        let sources: Vec<&str> = sources.iter().map(|s| s.as_str()).collect();
+        let res = QZArchiveBuilder::with_sources(&sources, out_file.to_str().unwrap()).create();
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("would both be stored as 'data'"), "{err}");
+        assert!(!out_file.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn packs_only_the_top_level_without_recursion() {
+        let (dir, _) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                ("sub/b.txt", b"beta"),
+                ("sub/deep/c.txt", b"c"),
+            ],
+            |b| b,
+        );
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        let skips = std::sync::Arc::new(Skips::default());
+        let stats = QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .recursive(false)
+            .progress(skips.clone())
+            .create_with_stats()
+            .unwrap();
+
+        let a = read_archive(out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), ["a.txt"]);
+        assert!(a.get_entry("/sub").is_err());
+        assert_eq!(stats.files, 1);
+        let skips = skips.0.lock().unwrap();
+        assert_eq!(*skips, [("sub".to_string(), SkipReason::NotRecursive)]);
+        assert!(!SkipReason::NotRecursive.is_unexpected());
+        let _ = fs::remove_file(out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compresses_files_as_their_name_overrides() {
+        let data = b"compressible ".repeat(256);
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", &data),
+                ("sub/b.bin", &data),
+                ("c.log", &data),
+                ("d.dat", &data),
+            ],
+            |b| {
+                b.compression(CompressionAlgo::ZSTD)
+                    .compression_for("*.bin", CompressionAlgo::NONE)
+                    .compression_for("?.log", CompressionAlgo::LZ4)
+                    .compression_for("*.log", CompressionAlgo::NONE)
+            },
+        );
+
+        let a = read_archive(&out_file).unwrap();
+        let algo = |path: &str| {
+            a.lookup_file(Path::new(path))
+                .unwrap()
+                .compression
+                .to_string()
+        };
+        assert_eq!(algo("/a.txt"), "zstd");
+        assert_eq!(algo("/sub/b.bin"), "none");
+        assert_eq!(algo("/c.log"), "lz4");
+        assert_eq!(algo("/d.dat"), "zstd");
+        assert_eq!(
+            a.lookup_file(Path::new("/sub/b.bin")).unwrap().index_size,
+            data.len() as u64
+        );
+        for path in ["/a.txt", "/sub/b.bin", "/c.log", "/d.dat"] {
+            assert_eq!(a.read_file(path).unwrap(), data);
+        }
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stores_files_in_the_order_asked_for() {
+        let files: &[(&str, &[u8])] = &[
+            ("a.txt", b"alpha"),
+            ("b.rs", b"beta"),
+            ("c", b"gamma"),
+            ("sub/d.txt", b"delta"),
+            ("sub/e.rs", b"epsilon"),
+        ];
+        // Archive paths in the order their data is stored
+        let body_order = |order: FileOrder| {
+            let (dir, out_file) = pack_files(files, |b| b.file_order(order));
+            let a = read_archive(&out_file).unwrap();
+            let mut stored: Vec<(u64, String)> = a
+                .files()
+                .into_iter()
+                .map(|(path, f)| (f.index_start, path))
+                .collect();
+            stored.sort();
+            for (name, data) in files {
+                assert_eq!(&a.read_file(format!("/{name}")).unwrap(), data);
+            }
+            assert_eq!(a.ls("/").unwrap(), ["sub", "a.txt", "b.rs", "c"]);
+            let _ = fs::remove_file(&out_file);
+            let _ = fs::remove_dir_all(&dir);
+            stored.into_iter().map(|(_, path)| path).collect::<Vec<_>>()
+        };
+
+        let by_extension = body_order(FileOrder::Extension);
+        assert_eq!(by_extension[0], "/c");
+        let ext = |path: &String| path.rsplit_once('.').map(|(_, ext)| ext.to_string());
+        let groups: Vec<_> = by_extension.iter().map(ext).collect();
+        let mut sorted = groups.clone();
+        sorted.sort();
+        assert_eq!(groups, sorted);
+
+        let listed = FileOrder::Paths(vec![
+            "/sub/e.rs".to_string(),
+            "/missing".to_string(),
+            "/a.txt".to_string(),
+        ]);
+        let by_paths = body_order(listed);
+        assert_eq!(by_paths[..2], ["/sub/e.rs", "/a.txt"]);
+        assert_eq!(by_paths.len(), files.len());
+    }
+
+    #[test]
+    fn packs_hidden_files_unless_told_not_to() {
+        let (dir, out_file) = pack_files(
+            &[
+                ("a.txt", b"alpha"),
+                (".hidden", b"secret"),
+                (".config/b.txt", b"beta"),
+                ("sub/.c.txt", b"gamma"),
+            ],
+            |b| b,
+        );
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), [".config", "sub", ".hidden", "a.txt"]);
+        assert_eq!(a.read_file("/sub/.c.txt").unwrap(), b"gamma");
+
+        let skips = std::sync::Arc::new(Skips::default());
+        QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+            .hidden(false)
+            .progress(skips.clone())
+            .create()
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), ["sub", "a.txt"]);
+        assert!(a.ls("/sub").unwrap().is_empty());
+        let mut skipped = skips.0.lock().unwrap().clone();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        let hidden = |name: &str| (name.to_string(), SkipReason::Hidden);
+        assert_eq!(
+            skipped,
+            [hidden(".c.txt"), hidden(".config"), hidden(".hidden")]
+        );
+
+        // Files named explicitly are packed regardless
+        let base = dir.to_str().unwrap();
+        QZArchiveBuilder::with_files(base, &[".hidden", "a.txt"], &out_file)
+            .hidden(false)
+            .create()
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), [".hidden", "a.txt"]);
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn timings_add_up_to_the_time_taken() {
+        let text = b"compressible ".repeat(64 * 1024);
+        let (dir, out_file) = pack_files(&[("a.txt", &text), ("sub/b.txt", &text)], |b| b);
+
+        for verify in [false, true] {
+            let stats = QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file)
+                .verify(verify)
+                .create_with_stats()
+                .unwrap();
+            assert_eq!(stats.input_size, 2 * text.len() as u64);
+            assert_eq!(stats.output_size, fs::metadata(&out_file).unwrap().len());
+            let zstd = &stats.algorithms["zstd"];
+            assert_eq!((zstd.files, zstd.input_size), (2, stats.input_size));
+            assert!(zstd.stored_size < stats.output_size);
+            assert!(stats.compress_time > std::time::Duration::ZERO);
+            assert!(stats.write_time > std::time::Duration::ZERO);
+            assert_eq!(stats.verify_time > std::time::Duration::ZERO, verify);
+            let phases = stats.compress_time + stats.write_time + stats.verify_time;
+            assert!(stats.elapsed >= phases, "{:?} < {phases:?}", stats.elapsed);
+        }
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn long_distance_matching_finds_far_repetition() {
+        // A random block repeated beyond the default window, with random data between
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+        let block = random(1 << 20);
+        let mut data = block.clone();
+        data.extend(random(8 << 20));
+        data.extend(&block);
+
+        let stored = |ldm: bool, window_log: Option<u32>| {
+            let (dir, out_file) = pack_files(&[("image.bin", &data)], |b| {
+                let b = b.long_distance_matching(ldm);
+                match window_log {
+                    Some(log) => b.window_log(log),
+                    None => b,
+                }
+            });
+            let a = read_archive(&out_file).unwrap();
+            assert_eq!(a.read_file("/image.bin").unwrap(), data);
+            let size = a.lookup_file(Path::new("/image.bin")).unwrap().index_size;
+            let _ = fs::remove_file(&out_file);
+            let _ = fs::remove_dir_all(&dir);
+            size
+        };
+        let plain = stored(false, None);
+        let ldm = stored(true, None);
+        assert!(plain > data.len() as u64 - (64 << 10), "{plain}");
+        assert!(ldm + (1 << 19) < plain, "{ldm} vs {plain}");
+        // A window too small to reach back does not help
+        assert!(stored(true, Some(20)) + (1 << 19) > plain);
+
+        let err = QZArchiveBuilder::new("unused", "unused.qz")
+            .window_log(compression::MAX_WINDOW_LOG + 1)
+            .create()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("window"), "{err}");
+    }
+
+    #[test]
+    fn explains_targets_that_are_missing_or_files() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let _ = fs::remove_file(&out_file);
+
+        let missing = dir.join("missing");
+        let missing = missing.to_str().unwrap();
+        let err = QZArchiveBuilder::new(missing, &out_file)
+            .create()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(missing), "{err}");
+        assert!(err.contains("does not exist"), "{err}");
+        assert!(!Path::new(&out_file).exists());
+
+        // A single file is packed on its own
+        let file = dir.join("a.txt");
+        QZArchiveBuilder::new(file.to_str().unwrap(), &out_file)
+            .create()
+            .unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.ls("/").unwrap(), ["a.txt"]);
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_below_the_base_do_not_depend_on_its_location() {
+        let stored_paths = |location: &str| {
+            let dir = scratch_dir();
+            let project = dir.join(location).join("project");
+            for (path, data) in [
+                ("src/main.rs", "fn main() {}"),
+                ("src/lib/x.rs", "x"),
+                ("README", "hi"),
+            ] {
+                let path = project.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, data).unwrap();
+            }
+            let out_file = dir.join("out.qz");
+            let out_file = out_file.to_str().unwrap();
+            let (src, readme) = (project.join("src"), project.join("README"));
+            QZArchiveBuilder::with_sources(
+                &[src.to_str().unwrap(), readme.to_str().unwrap()],
+                out_file,
+            )
+            .base(project.to_str().unwrap())
+            .create()
+            .unwrap();
+            let a = read_archive(out_file).unwrap();
+            let mut paths: Vec<String> = a.entries().into_iter().map(|(path, _)| path).collect();
+            paths.sort();
+            assert_eq!(a.read_file("/src/main.rs").unwrap(), b"fn main() {}");
+
+            let outside = dir.join(location);
+            let err = QZArchiveBuilder::with_sources(&[outside.to_str().unwrap()], out_file)
+                .base(project.to_str().unwrap())
+                .create()
+                .unwrap_err();
+            assert!(matches!(err, errors::WriteError::InvalidInput(_)), "{err}");
+            let _ = fs::remove_dir_all(&dir);
+            paths
+        };
+
+        let shallow = stored_paths("x");
+        assert_eq!(
+            shallow,
+            [
+                "/README",
+                "/src",
+                "/src/lib",
+                "/src/lib/x.rs",
+                "/src/main.rs"
+            ]
+        );
+        assert_eq!(stored_paths("a/b/c/d"), shallow);
+    }
+
+    #[test]
+    fn presets_resolve_to_their_documented_settings() {
+        let summary = |name: &str| {
+            let c = CompressionConfig::preset(name).unwrap();
+            (c.algorithm.to_string(), c.level, c.long_distance, c.lz4_hc)
+        };
+        assert_eq!(summary("fast"), ("lz4".to_string(), None, false, false));
+        assert_eq!(summary("default"), ("zstd".to_string(), None, false, false));
+        assert_eq!(summary("max"), ("zstd".to_string(), Some(19), true, false));
+        assert_eq!(summary("MAX"), summary("max"));
+        assert!(CompressionConfig::preset("fastest").is_none());
+
+        let text = b"compressible ".repeat(4096);
+        for name in CompressionConfig::PRESETS {
+            let config = CompressionConfig::preset(name).unwrap();
+            let algorithm = config.algorithm.to_string();
+            let (dir, out_file) = pack_files(&[("a.txt", &text), ("sub/b.txt", b"beta")], |b| {
+                b.compression_config(config)
+            });
+            let a = read_archive(&out_file).unwrap();
+            assert_eq!(a.read_file("/a.txt").unwrap(), text, "{name}");
+            assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"beta", "{name}");
+            let f = a.lookup_file(Path::new("/a.txt")).unwrap();
+            assert_eq!(f.compression.to_string(), algorithm, "{name}");
+            assert!(f.index_size < text.len() as u64, "{name}");
+            let _ = fs::remove_file(&out_file);
+            let _ = fs::remove_dir_all(&dir);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+/// Receives the progress of packing, extracting and checking archives
+///
+/// Calls may come from several threads at once. Sizes are of the uncompressed content.
+pub trait ProgressSink: Send + Sync {
+    /// `files` more files holding `bytes` bytes became known to be processed
+    fn add_work(&self, files: u64, bytes: u64);
+
+    /// The file at `path` within the archive holding `bytes` bytes was processed
+    fn file_done(&self, path: &str, bytes: u64);
+
+    /// Something went wrong without failing the operation, e.g. a checksum mismatch
+    /// ignored as `QZArchive::set_verify_checksums` allows or a file left out when packing
+    fn warning(&self, _message: &str) {}
+
+    /// The file or directory at `path` on disk was left out when packing
+    ///
+    /// By default only skips the options did not ask for are passed on to `warning`.
+    fn skipped(&self, path: &str, reason: &SkipReason) {
+        if reason.is_unexpected() {
+            self.warning(&format!("Skipping {path}, {reason}"));
+        }
+    }
+}
+
+/// Why a file or directory was left out when packing, see `ProgressSink::skipped`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// It is the archive being written
+    Output,
+    /// Its name starts with `.` and hidden entries are left out
+    Hidden,
+    /// It is a directory and subdirectories are left out
+    NotRecursive,
+    /// It is a fifo, socket or device node, named by its kind
+    Special(String),
+    /// It could not be read, with the error
+    Unreadable(String),
+}
+
+impl SkipReason {
+    /// Whether the file was left out for other reasons than the options asking for it
+    pub fn is_unexpected(&self) -> bool {
+        !matches!(self, SkipReason::Hidden | SkipReason::NotRecursive)
+    }
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::Output => write!(f, "it is the archive being written"),
+            SkipReason::Hidden => write!(f, "it is hidden"),
+            SkipReason::NotRecursive => write!(f, "it is a directory"),
+            SkipReason::Special(kind) => write!(f, "it is a {kind}"),
+            SkipReason::Unreadable(err) => write!(f, "it cannot be read: {err}"),
+        }
+    }
+}
+
+/// Sink ignoring all progress
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn add_work(&self, _files: u64, _bytes: u64) {}
+
+    fn file_done(&self, _path: &str, _bytes: u64) {}
+}
+
+// Shared sink that can live in `Debug` structs
+#[derive(Clone)]
+pub(crate) struct SharedSink(pub(crate) Arc<dyn ProgressSink>);
+
+impl Default for SharedSink {
+    fn default() -> SharedSink {
+        SharedSink(Arc::new(NoProgress))
+    }
+}
+
+impl std::fmt::Debug for SharedSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressSink")
+    }
+}
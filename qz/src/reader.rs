@@ -0,0 +1,190 @@
+use crate::compression;
+use crate::errors;
+use crate::source::ReadAt;
+use crate::{CompressionAlgo, QZArchive, QZFile};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+
+/// Reader streaming the content of one file in an archive
+///
+/// Obtained from `QZArchive::open_file`. The checksum of the stored data is verified
+/// once it has been read through to its end, a mismatch fails that read with `InvalidData`.
+/// Files stored in chunks are decompressed one chunk at a time, each checked as it is read.
+/// `NONE` entries are also `Seek`able, seeking skips checksum verification.
+///
+/// ```no_run
+/// let archive = qz::read_archive("archive.qz").unwrap();
+/// let mut reader = archive.open_file("docs/readme.txt").unwrap();
+/// std::io::copy(&mut reader, &mut std::io::stdout()).unwrap();
+/// ```
+pub struct QZFileReader<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    Stored(StoredReader<'a>),
+    Zstd(compression::ZstdDecoder<BufReader<StoredReader<'a>>>),
+    Lz4(lz4_flex::frame::FrameDecoder<BufReader<StoredReader<'a>>>),
+    // Raw LZ4 blocks of older archives can only be decompressed as a whole
+    Lz4Block(Cursor<Vec<u8>>),
+    Chunked(ChunkedReader<'a>),
+}
+
+impl<'a> QZFileReader<'a> {
+    pub(crate) fn new(
+        archive: &'a QZArchive,
+        f: &QZFile,
+    ) -> Result<QZFileReader<'a>, errors::FileReadError> {
+        let start = archive.stored_position(f);
+        let stored = StoredReader {
+            archive,
+            name: f.name.clone(),
+            start,
+            pos: start,
+            end: start + f.index_size,
+            hasher: Some(crc32fast::Hasher::new()),
+            checksum: f.checksum,
+        };
+
+        let inner = match f.compression {
+            _ if !f.chunks.is_empty() => Inner::Chunked(ChunkedReader {
+                archive,
+                file: f.clone(),
+                next: 0,
+                current: Cursor::new(vec![]),
+            }),
+            CompressionAlgo::NONE => Inner::Stored(stored),
+            CompressionAlgo::ZSTD => {
+                let buffered = BufReader::with_capacity(archive.buffer_size, stored);
+                match compression::zstd_decoder(buffered, archive.dictionary()) {
+                    Ok(d) => Inner::Zstd(d),
+                    Err(err) => return Err(crate::decompress_error(&f.name, err)),
+                }
+            }
+            CompressionAlgo::LZ4 if archive.format < compression::LZ4_FRAME_VERSION => {
+                Inner::Lz4Block(Cursor::new(f.read_file(archive)?))
+            }
+            CompressionAlgo::LZ4 => {
+                let buffered = BufReader::with_capacity(archive.buffer_size, stored);
+                Inner::Lz4(lz4_flex::frame::FrameDecoder::new(buffered))
+            }
+        };
+
+        Ok(QZFileReader { inner })
+    }
+}
+
+impl Read for QZFileReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.inner {
+            Inner::Stored(r) => r.read(buf),
+            Inner::Zstd(r) => r.read(buf),
+            Inner::Lz4(r) => r.read(buf),
+            Inner::Lz4Block(r) => r.read(buf),
+            Inner::Chunked(r) => r.read(buf),
+        }
+    }
+}
+
+impl Seek for QZFileReader<'_> {
+    /// Only supported for `NONE` entries
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match &mut self.inner {
+            Inner::Stored(r) => r.seek(pos),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "seeking is only supported for uncompressed entries",
+            )),
+        }
+    }
+}
+
+// Content of a file stored in chunks, decompressing the next chunk once the current is used up
+struct ChunkedReader<'a> {
+    archive: &'a QZArchive,
+    file: QZFile,
+    next: usize,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() || self.next == self.file.chunks.len() {
+                return Ok(n);
+            }
+            let chunk = self
+                .file
+                .read_chunk(self.archive, self.next)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            self.current = Cursor::new(chunk);
+            self.next += 1;
+        }
+    }
+}
+
+// Stored bytes of an entry, checksummed while read sequentially
+struct StoredReader<'a> {
+    archive: &'a QZArchive,
+    name: String,
+    start: u64,
+    pos: u64,
+    end: u64,
+    hasher: Option<crc32fast::Hasher>,
+    checksum: u32,
+}
+
+impl Read for StoredReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.end.saturating_sub(self.pos) as usize);
+        let n = if n == 0 {
+            0
+        } else {
+            self.archive.source.read_at(&mut buf[..n], self.pos)?
+        };
+        if n == 0 && self.pos < self.end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "archive ends inside of entry",
+            ));
+        }
+        self.pos += n as u64;
+
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..n]);
+        }
+        if self.pos == self.end {
+            if let Some(hasher) = self.hasher.take() {
+                self.archive
+                    .check_checksum(&self.name, hasher.finalize(), self.checksum)
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for StoredReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let len = (self.end - self.start) as i64;
+        let current = (self.pos - self.start) as i64;
+        let target = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => len + n,
+            SeekFrom::Current(n) => current + n,
+        };
+        if target < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start of entry",
+            ));
+        }
+
+        // The checksum only covers a read from start to end
+        if target != current {
+            self.hasher = None;
+        }
+        self.pos = self.start + target as u64;
+        Ok(self.pos - self.start)
+    }
+}
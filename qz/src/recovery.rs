@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+
+/// Data blocks protected by one group of parity blocks
+pub const GROUP_SIZE: usize = 16;
+
+/// Largest recovery block, smaller bodies use proportionally smaller blocks
+pub const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Layout of the Reed-Solomon parity stored after the archive body
+///
+/// The body is split into blocks of `block_size` bytes, the last one zero padded.
+/// Every group of `data_blocks` blocks gets `parity_blocks` parity blocks,
+/// which are stored group by group right after the body.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QZRecovery {
+    pub block_size: u64,
+    pub data_blocks: usize,
+    /// Damaged blocks per group that can be reconstructed
+    pub parity_blocks: usize,
+    /// Length of the body covered by the parity
+    pub body_size: u64,
+    /// CRC32 of every data block followed by every parity block
+    #[serde(default)]
+    pub checksums: Vec<u32>,
+}
+
+impl QZRecovery {
+    /// Recovery layout for `parity_blocks` parity blocks per group, filled in when writing
+    pub fn new(parity_blocks: usize) -> QZRecovery {
+        QZRecovery {
+            block_size: 0,
+            data_blocks: GROUP_SIZE,
+            parity_blocks,
+            body_size: 0,
+            checksums: vec![],
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.body_size.div_ceil(self.block_size.max(1))
+    }
+
+    fn group_count(&self) -> u64 {
+        self.block_count().div_ceil(self.data_blocks.max(1) as u64)
+    }
+
+    /// Bytes of parity stored after the body
+    pub fn parity_size(&self) -> u64 {
+        self.group_count() * self.parity_blocks as u64 * self.block_size
+    }
+}
+
+// Data block `i` of `body`, zero padded to the block size
+#[cfg(feature = "recovery")]
+fn block(body: &[u8], i: u64, block_size: u64) -> Vec<u8> {
+    let start = (i * block_size).min(body.len() as u64) as usize;
+    let end = ((i + 1) * block_size).min(body.len() as u64) as usize;
+    let mut block = body[start..end].to_vec();
+    block.resize(block_size as usize, 0);
+    block
+}
+
+/// Compute the parity of `body` for the layout of `recovery`
+///
+/// Returns the completed layout and the parity bytes to store after the body.
+#[cfg(feature = "recovery")]
+pub fn encode(body: &[u8], recovery: &QZRecovery) -> Result<(QZRecovery, Vec<u8>), String> {
+    let data_blocks = recovery.data_blocks;
+    let parity_blocks = recovery.parity_blocks;
+    let rs = reed_solomon_erasure::galois_8::ReedSolomon::new(data_blocks, parity_blocks)
+        .map_err(|err| format!("invalid recovery layout: {err:?}"))?;
+
+    let block_size = (body.len() as u64)
+        .div_ceil(data_blocks as u64)
+        .clamp(1, MAX_BLOCK_SIZE);
+    let mut layout = QZRecovery {
+        block_size,
+        data_blocks,
+        parity_blocks,
+        body_size: body.len() as u64,
+        checksums: vec![],
+    };
+
+    let mut parity = vec![];
+    let mut parity_checksums = vec![];
+    for g in 0..layout.group_count() {
+        let first = g * data_blocks as u64;
+        let mut shards: Vec<Vec<u8>> = (first..first + data_blocks as u64)
+            .map(|i| block(body, i, block_size))
+            .collect();
+        shards.resize(data_blocks + parity_blocks, vec![0; block_size as usize]);
+        rs.encode(&mut shards)
+            .map_err(|err| format!("failed to compute parity: {err:?}"))?;
+
+        for (i, shard) in shards.iter().enumerate() {
+            if i < data_blocks {
+                if first + (i as u64) < layout.block_count() {
+                    layout.checksums.push(crc32fast::hash(shard));
+                }
+            } else {
+                parity_checksums.push(crc32fast::hash(shard));
+                parity.extend_from_slice(shard);
+            }
+        }
+    }
+    layout.checksums.extend(parity_checksums);
+
+    Ok((layout, parity))
+}
+
+/// Reconstruct damaged blocks of `body` in place from `parity`
+///
+/// Returns the number of data blocks that were repaired, fails if a group
+/// has more damaged blocks than it has parity blocks.
+#[cfg(feature = "recovery")]
+pub fn recover(body: &mut [u8], parity: &[u8], recovery: &QZRecovery) -> Result<usize, String> {
+    let data_blocks = recovery.data_blocks;
+    let parity_blocks = recovery.parity_blocks;
+    let block_size = recovery.block_size;
+    let block_count = recovery.block_count();
+    if recovery.checksums.len() as u64
+        != block_count + recovery.group_count() * parity_blocks as u64
+    {
+        return Err("recovery checksums do not match the layout".to_string());
+    }
+    let rs = reed_solomon_erasure::galois_8::ReedSolomon::new(data_blocks, parity_blocks)
+        .map_err(|err| format!("invalid recovery layout: {err:?}"))?;
+
+    let mut repaired = 0;
+    for g in 0..recovery.group_count() {
+        let first = g * data_blocks as u64;
+        let mut shards: Vec<Option<Vec<u8>>> = vec![];
+        let mut damaged = vec![];
+
+        for i in first..first + data_blocks as u64 {
+            let shard = block(body, i, block_size);
+            // Padding blocks past the end are known to be zero
+            if i < block_count && crc32fast::hash(&shard) != recovery.checksums[i as usize] {
+                damaged.push(i);
+                shards.push(None);
+            } else {
+                shards.push(Some(shard));
+            }
+        }
+        for p in 0..parity_blocks as u64 {
+            let index = g * parity_blocks as u64 + p;
+            let start = (index * block_size) as usize;
+            let shard = parity.get(start..start + block_size as usize);
+            let checksum = recovery.checksums[(block_count + index) as usize];
+            match shard {
+                Some(shard) if crc32fast::hash(shard) == checksum => {
+                    shards.push(Some(shard.to_vec()))
+                }
+                _ => shards.push(None),
+            }
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+        rs.reconstruct_data(&mut shards).map_err(|_| {
+            format!(
+                "too many damaged blocks around offset {} to recover",
+                first * block_size
+            )
+        })?;
+
+        for i in damaged {
+            let shard = shards[(i - first) as usize].as_ref().unwrap();
+            let start = (i * block_size) as usize;
+            let end = (start + block_size as usize).min(body.len());
+            body[start..end].copy_from_slice(&shard[..end - start]);
+            repaired += 1;
+        }
+    }
+
+    Ok(repaired)
+}
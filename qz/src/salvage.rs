@@ -0,0 +1,324 @@
+use crate::source::ReadAt;
+use crate::{
+    compression, error_offset, errors, locate_archive, open_source, read_prefix, walk_entries,
+    QZArchive, QZArchiveHeader, QZDir, QZEntry, DEFAULT_MAX_DEPTH, MAX_HEADER_SIZE,
+};
+use serde::Deserialize;
+
+/// Archive opened by `try_read_archive`, possibly missing entries lost to a damaged header
+pub struct SalvagedArchive {
+    /// The archive holding every entry that could be recovered
+    pub archive: QZArchive,
+    /// Why the archive could not be read in full, `None` if its header is intact
+    pub error: Option<errors::ReadError>,
+    /// Offset and length of every part of the body no recovered file refers to
+    ///
+    /// Offsets count from the start of the archive. These parts hold the data of the
+    /// entries lost with the header.
+    pub unrecovered: Vec<(u64, u64)>,
+}
+
+/// Open an archive like `read_archive`, recovering as many entries as possible
+/// if its header is damaged
+///
+/// The header is decompressed up to the damage and every file and directory described
+/// completely before it is kept, along with the directories leading to them. Damage
+/// in the prefix in front of the header cannot be recovered from. Recovered files are
+/// still checked against their checksums when read.
+pub fn try_read_archive(path: &str) -> Result<SalvagedArchive, errors::ReadError> {
+    let error = match crate::read_archive(path) {
+        Ok(archive) => {
+            let unrecovered = unreferenced(&archive);
+            return Ok(SalvagedArchive {
+                archive,
+                error: None,
+                unrecovered,
+            });
+        }
+        Err(err) => err,
+    };
+
+    let (path, spanned) = locate_archive(path);
+    let f = match open_source(path, spanned) {
+        Ok(f) => f,
+        Err(_) => return Err(errors::ReadError::new("failed to open archive file")),
+    };
+    let prefix = read_prefix(&f)?;
+
+    let mut encoded = vec![0u8; prefix.header_size as usize];
+    if f.read_exact_at(&mut encoded, prefix.size).is_err() {
+        return Err(errors::ReadError::new("failed to read header"));
+    }
+    let mut decoded = compression::decompress_partial(
+        &encoded,
+        &prefix.header_compression,
+        MAX_HEADER_SIZE,
+        prefix.format,
+    );
+    decoded.truncate(MAX_HEADER_SIZE as usize);
+
+    let mut header = salvage_header(&decoded);
+    let mut body_len = prefix.trailer_len();
+    match &header.recovery {
+        Some(recovery) if recovery.body_size <= body_len => body_len = recovery.body_size,
+        _ => header.recovery = None,
+    }
+    if let QZEntry::Dir(d) = &mut header.root {
+        drop_outside(d, body_len);
+    }
+
+    let mut archive = crate::new_archive(f, &prefix, header);
+    archive.archive_file = path.to_string();
+    let unrecovered = unreferenced(&archive);
+    Ok(SalvagedArchive {
+        archive,
+        error: Some(error),
+        unrecovered,
+    })
+}
+
+// Header with what can be recovered from the possibly damaged JSON in `buf`
+fn salvage_header(buf: &[u8]) -> QZArchiveHeader {
+    let mut header = match complete_prefix(buf) {
+        Some(serde_json::Value::Object(header)) => header,
+        _ => serde_json::Map::new(),
+    };
+    let text = |key: &str| match header.get(key) {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        _ => String::new(),
+    };
+    let (name, info, version) = (text("name"), text("info"), text("version"));
+
+    let root = match header.remove("root").and_then(salvage_entry) {
+        Some(root @ QZEntry::Dir(_)) => root,
+        _ => QZEntry::Dir(QZDir {
+            name: String::new(),
+            content: vec![],
+            uid: None,
+            gid: None,
+            xattrs: std::collections::BTreeMap::new(),
+        }),
+    };
+
+    let mut field = |key: &str| header.remove(key).unwrap_or_default();
+    QZArchiveHeader {
+        name,
+        info,
+        version,
+        root,
+        volumes: serde_json::from_value(field("volumes")).unwrap_or_default(),
+        metadata: serde_json::from_value(field("metadata")).unwrap_or_default(),
+        recovery: serde_json::from_value(field("recovery")).unwrap_or_default(),
+        dictionary: serde_json::from_value(field("dictionary")).unwrap_or_default(),
+        body_checksum: serde_json::from_value(field("body_checksum")).unwrap_or_default(),
+    }
+}
+
+// Entry described by `v` with those of its children that are intact,
+// none if the entry itself is damaged
+fn salvage_entry(v: serde_json::Value) -> Option<QZEntry> {
+    let serde_json::Value::Object(mut entry) = v else {
+        return None;
+    };
+    if let Some(f) = entry.remove("File") {
+        return serde_json::from_value(f).ok().map(QZEntry::File);
+    }
+
+    let Some(serde_json::Value::Object(mut d)) = entry.remove("Dir") else {
+        return None;
+    };
+    let content = d.insert("content".to_string(), serde_json::Value::Array(vec![]));
+    let mut dir: QZDir = serde_json::from_value(serde_json::Value::Object(d)).ok()?;
+    if let Some(serde_json::Value::Array(content)) = content {
+        dir.content = content.into_iter().filter_map(salvage_entry).collect();
+    }
+    Some(QZEntry::Dir(dir))
+}
+
+// Leave out files that do not lie within a body of `body_len` bytes
+fn drop_outside(d: &mut QZDir, body_len: u64) {
+    d.content.retain_mut(|e| match e {
+        QZEntry::File(f) => f
+            .index_start
+            .checked_add(f.index_size)
+            .is_some_and(|end| end <= body_len),
+        QZEntry::Dir(sub) => {
+            drop_outside(sub, body_len);
+            true
+        }
+    });
+}
+
+// Longest start of the JSON document in `buf` that is valid, completed by closing
+// the objects and arrays still open where it ends
+fn complete_prefix(buf: &[u8]) -> Option<serde_json::Value> {
+    let (cuts, end) = cut_points(buf);
+    let buf = &buf[..end];
+    let err = match parse(buf) {
+        Ok(value) => return Some(value),
+        Err(err) => err,
+    };
+
+    // Everything before the error is valid, the last complete value before it is kept
+    let valid = error_offset(buf, &err);
+
+    // The error may be reported a little after the damage, so try a few earlier cuts
+    for cut in cuts.into_iter().rev().filter(|cut| *cut < valid).take(16) {
+        let mut doc = buf[..cut].to_vec();
+        let closing = open_brackets(&doc);
+        doc.extend(closing.iter().rev());
+        if let Ok(value) = parse(&doc) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn parse(buf: &[u8]) -> Result<serde_json::Value, serde_json::Error> {
+    let mut de = serde_json::Deserializer::from_slice(buf);
+    de.disable_recursion_limit();
+    serde_json::Value::deserialize(&mut de)
+}
+
+// Lengths `buf` can be cut to right after a complete value or an opening bracket,
+// and where it has to end at the latest to stay within the directory depth allowed
+fn cut_points(buf: &[u8]) -> (Vec<usize>, usize) {
+    let mut cuts = vec![];
+    let mut depth: usize = 0;
+    let mut in_str = false;
+    let mut escaped = false;
+
+    for (i, b) in buf.iter().enumerate() {
+        if in_str {
+            if escaped {
+                escaped = false;
+            } else if *b == b'\\' {
+                escaped = true;
+            } else if *b == b'"' {
+                in_str = false;
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_str = true,
+            b',' => cuts.push(i),
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > DEFAULT_MAX_DEPTH * 3 + 4 {
+                    return (cuts, i);
+                }
+                cuts.push(i + 1);
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    (cuts, buf.len())
+}
+
+// Brackets closing the objects and arrays open at the end of `buf`, innermost last
+fn open_brackets(buf: &[u8]) -> Vec<u8> {
+    let mut open = vec![];
+    let mut in_str = false;
+    let mut escaped = false;
+
+    for b in buf {
+        if in_str {
+            if escaped {
+                escaped = false;
+            } else if *b == b'\\' {
+                escaped = true;
+            } else if *b == b'"' {
+                in_str = false;
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_str = true,
+            b'{' => open.push(b'}'),
+            b'[' => open.push(b']'),
+            b'}' | b']' => {
+                open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    open
+}
+
+// Offset and length of the parts of the body of `a` no file refers to
+fn unreferenced(a: &QZArchive) -> Vec<(u64, u64)> {
+    let body_len = a.stored_body_len().unwrap_or(0);
+    let mut used: Vec<(u64, u64)> = walk_entries(a.header.root())
+        .into_iter()
+        .filter_map(|(_, e)| match e {
+            QZEntry::File(f) if f.index_size > 0 => {
+                Some((f.index_start, f.index_start + f.index_size))
+            }
+            _ => None,
+        })
+        .collect();
+    used.sort_unstable();
+
+    let mut gaps = vec![];
+    let mut pos = 0;
+    for (start, end) in used {
+        if start > pos {
+            gaps.push((a.body_offset() + pos, start - pos));
+        }
+        pos = pos.max(end);
+    }
+    if body_len > pos {
+        gaps.push((a.body_offset() + pos, body_len - pos));
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{pack_files, patch_file};
+    use crate::CompressionAlgo;
+
+    #[test]
+    fn recovers_the_entries_before_damage_to_the_header() {
+        let files: Vec<(String, Vec<u8>)> = (0..20)
+            .map(|i| (format!("f{i:02}.txt"), format!("file {i}").into_bytes()))
+            .collect();
+        let listed: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let (dir, out_file) = pack_files(&listed, |b| b.header_compression(CompressionAlgo::NONE));
+
+        let intact = try_read_archive(&out_file).unwrap();
+        assert!(intact.error.is_none());
+        assert!(intact.unrecovered.is_empty());
+        assert_eq!(intact.archive.ls("/").unwrap().len(), files.len());
+
+        // Overwrite the second half of the header
+        let a = crate::read_archive(&out_file).unwrap();
+        let header_start = a.body_offset() - a.header_size();
+        let half = a.header_size() / 2;
+        patch_file(&out_file, header_start + half, &vec![b'#'; half as usize]);
+        assert!(crate::read_archive(&out_file).is_err());
+
+        let salvaged = try_read_archive(&out_file).unwrap();
+        assert!(salvaged.error.is_some());
+        let recovered = salvaged.archive.ls("/").unwrap();
+        assert!(!recovered.is_empty());
+        assert!(recovered.len() < files.len(), "{recovered:?}");
+        for name in &recovered {
+            let data = &files.iter().find(|(n, _)| n == name).unwrap().1;
+            assert_eq!(
+                &salvaged.archive.read_file(format!("/{name}")).unwrap(),
+                data
+            );
+        }
+        assert!(!salvaged.unrecovered.is_empty());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,54 @@
+/// Units used by `human_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeUnits {
+    /// Powers of 1000: KB, MB, GB, ...
+    #[default]
+    Decimal,
+    /// Powers of 1024: KiB, MiB, GiB, ...
+    Binary,
+}
+
+/// Format `bytes` for display, e.g. `1023 B`, `1.0 KiB` or `12 MB`
+///
+/// Sizes below 10 units get one decimal, larger ones are rounded to whole units.
+pub fn human_size(bytes: u64, units: SizeUnits) -> String {
+    let (base, names) = match units {
+        SizeUnits::Decimal => (1000.0, ["B", "KB", "MB", "GB", "TB", "PB", "EB"]),
+        SizeUnits::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"]),
+    };
+
+    // Move up a unit as soon as rounding would show a full one of the next
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base - 0.5 && unit + 1 < names.len() {
+        value /= base;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else if value < 9.95 {
+        format!("{value:.1} {}", names[unit])
+    } else {
+        format!("{value:.0} {}", names[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_up_a_unit_at_the_boundary() {
+        assert_eq!(human_size(1023, SizeUnits::Binary), "1023 B");
+        assert_eq!(human_size(1024, SizeUnits::Binary), "1.0 KiB");
+        assert_eq!(human_size(999, SizeUnits::Decimal), "999 B");
+        assert_eq!(human_size(1000, SizeUnits::Decimal), "1.0 KB");
+        assert_eq!(human_size(9_949, SizeUnits::Decimal), "9.9 KB");
+        assert_eq!(human_size(9_950, SizeUnits::Decimal), "10 KB");
+        assert_eq!(human_size(999_999, SizeUnits::Decimal), "1.0 MB");
+        assert_eq!(human_size(1024 * 1024 - 1, SizeUnits::Binary), "1.0 MiB");
+        assert_eq!(human_size(0, SizeUnits::Binary), "0 B");
+        assert_eq!(human_size(u64::MAX, SizeUnits::Binary), "16 EiB");
+    }
+}
@@ -0,0 +1,213 @@
+#[cfg(feature = "fs")]
+use crate::volume;
+#[cfg(feature = "fs")]
+use std::fs::File;
+use std::io::Read;
+
+/// Reads at an explicit offset without moving a shared cursor
+///
+/// This lets one handle serve reads from many threads.
+pub trait ReadAt {
+    /// Read into `buf` starting at `offset`, returning the number of bytes read
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+
+    /// Fill `buf` starting at `offset`
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ));
+                }
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    // `seek_read` moves the cursor, but no read relies on its position
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+}
+
+/// Random access to an archive held elsewhere, e.g. fetched with HTTP range requests
+///
+/// Offsets count from the start of the archive. Paired with its index by
+/// `read_archive_with_index`, only the stored data of the files read is fetched.
+pub trait RangeSource: Send + Sync {
+    /// Total length of the archive in bytes
+    fn len(&self) -> std::io::Result<u64>;
+
+    /// Whether the archive holds no data at all
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Read into `buf` starting at `offset`, returning the number of bytes read
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+}
+
+/// Open archive data, either a single file or a set of volumes
+pub enum ArchiveSource {
+    #[cfg(feature = "fs")]
+    Single(File),
+    #[cfg(feature = "fs")]
+    Spanned(volume::VolumeSet),
+    /// Archive of the given length starting at the given offset of a larger file,
+    /// see `read_archive_at`
+    #[cfg(feature = "fs")]
+    Embedded(File, u64, u64),
+    /// Single file mapped into memory
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+    /// Archive held in memory, see `read_archive_from_bytes`
+    Memory(Vec<u8>),
+    /// Archive read from a `RangeSource` with its prefix and header already at hand,
+    /// see `read_archive_with_index`
+    Indexed(Vec<u8>, Box<dyn RangeSource>),
+}
+
+impl ArchiveSource {
+    /// Total length in bytes
+    pub fn len(&self) -> std::io::Result<u64> {
+        match self {
+            #[cfg(feature = "fs")]
+            ArchiveSource::Single(f) => Ok(f.metadata()?.len()),
+            #[cfg(feature = "fs")]
+            ArchiveSource::Spanned(v) => Ok(v.len()),
+            #[cfg(feature = "fs")]
+            ArchiveSource::Embedded(_, _, len) => Ok(*len),
+            #[cfg(feature = "mmap")]
+            ArchiveSource::Mapped(m) => Ok(m.len() as u64),
+            ArchiveSource::Memory(m) => Ok(m.len() as u64),
+            ArchiveSource::Indexed(_, remote) => remote.len(),
+        }
+    }
+
+    /// All bytes of the source, if it is mapped into or held in memory
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match self {
+            #[cfg(feature = "mmap")]
+            ArchiveSource::Mapped(m) => Some(m),
+            ArchiveSource::Memory(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Whether the source holds no data at all
+    pub fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Sequential reader over `len` bytes starting at `offset`
+    pub fn reader(&self, offset: u64, len: u64) -> SourceReader<'_> {
+        SourceReader {
+            source: self,
+            pos: offset,
+            end: offset + len,
+        }
+    }
+}
+
+impl ReadAt for ArchiveSource {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        match self {
+            #[cfg(feature = "fs")]
+            ArchiveSource::Single(f) => f.read_at(buf, offset),
+            #[cfg(feature = "fs")]
+            ArchiveSource::Spanned(v) => v.read_at(buf, offset),
+            #[cfg(feature = "fs")]
+            ArchiveSource::Embedded(f, start, len) => {
+                let n = buf.len().min(len.saturating_sub(offset) as usize);
+                f.read_at(&mut buf[..n], start + offset)
+            }
+            #[cfg(feature = "mmap")]
+            ArchiveSource::Mapped(m) => Ok(read_slice(m, buf, offset)),
+            ArchiveSource::Memory(m) => Ok(read_slice(m, buf, offset)),
+            // The index holds the archive up to the body, reads from there go to the source
+            ArchiveSource::Indexed(index, _) if offset < index.len() as u64 => {
+                Ok(read_slice(index, buf, offset))
+            }
+            ArchiveSource::Indexed(_, remote) => remote.read_at(buf, offset),
+        }
+    }
+}
+
+// Copy from `data` at `offset` into `buf`, short at the end of `data`
+fn read_slice(data: &[u8], buf: &mut [u8], offset: u64) -> usize {
+    let start = (offset as usize).min(data.len());
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    n
+}
+
+/// Reader over a region of an `ArchiveSource`, with its own position
+pub struct SourceReader<'a> {
+    source: &'a ArchiveSource,
+    pos: u64,
+    end: u64,
+}
+
+impl Read for SourceReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.end.saturating_sub(self.pos) as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self.source.read_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use crate::testing::pack_files;
+    use crate::{read_archive, CompressionAlgo};
+
+    #[test]
+    fn reads_two_entries_from_one_handle_on_two_threads() {
+        let first = b"first entry ".repeat(300);
+        let second = b"second entry ".repeat(300);
+        let (dir, out_file) = pack_files(&[("a", &first), ("b", &second)], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let source = ArchiveSource::Single(File::open(&out_file).unwrap());
+
+        std::thread::scope(|s| {
+            for (path, expected) in [("/a", &first), ("/b", &second)] {
+                let f = a.lookup_file(path.as_ref()).unwrap();
+                let pos = a.stored_position(&f);
+                let source = &source;
+                s.spawn(move || {
+                    for _ in 0..200 {
+                        let mut buf = vec![0; expected.len()];
+                        source.read_exact_at(&mut buf, pos).unwrap();
+                        assert_eq!(&buf, expected);
+                    }
+                });
+            }
+        });
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::errors;
+use crate::{read_archive, CompressionAlgo, QZArchive, QZArchiveBuilder};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Pack `dir` into a temporary archive and open it
+///
+/// Meant for checking that a directory survives a round trip, e.g. by comparing
+/// `read_file` results against the original files. The temporary file is removed
+/// right after opening where the platform allows it, the returned archive keeps it open.
+pub fn pack_and_read(
+    dir: &str,
+    compression: CompressionAlgo,
+) -> Result<QZArchive, errors::ReadError> {
+    let out_file = std::env::temp_dir().join(format!(
+        "qz-{}-{}.qz",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let out_file = out_file.to_str().unwrap();
+
+    let res = QZArchiveBuilder::new(dir, out_file)
+        .compression(compression)
+        .create();
+    if let Err(err) = res {
+        return Err(errors::ReadError::new(&format!(
+            "failed to pack {dir}: {err}"
+        )));
+    }
+
+    let a = read_archive(out_file);
+    let _ = std::fs::remove_file(out_file);
+    a
+}
+
+// Fresh empty directory below the system's temporary directory
+#[cfg(test)]
+pub(crate) fn scratch_dir() -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "qz-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Archive file next to a fresh directory holding `files`, packed by `configure`d builder
+#[cfg(test)]
+pub(crate) fn pack_files(
+    files: &[(&str, &[u8])],
+    configure: impl FnOnce(QZArchiveBuilder) -> QZArchiveBuilder,
+) -> (std::path::PathBuf, String) {
+    let dir = scratch_dir();
+    for (path, data) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+    let out_file = dir.with_extension("qz").to_str().unwrap().to_string();
+    configure(QZArchiveBuilder::new(dir.to_str().unwrap(), &out_file))
+        .create()
+        .unwrap();
+    (dir, out_file)
+}
+
+// Overwrite the bytes of `path` at `pos` with `data`, as damage on disk would
+#[cfg(test)]
+pub(crate) fn patch_file(path: &str, pos: u64, data: &[u8]) {
+    use std::io::{Seek, Write};
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(std::io::SeekFrom::Start(pos)).unwrap();
+    file.write_all(data).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_and_read_round_trips_a_directory() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), b"alpha").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), b"beta ".repeat(100)).unwrap();
+
+        for compression in [
+            CompressionAlgo::ZSTD,
+            CompressionAlgo::LZ4,
+            CompressionAlgo::NONE,
+        ] {
+            let a = pack_and_read(dir.to_str().unwrap(), compression).unwrap();
+            assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+            assert_eq!(a.read_file("/sub/b.txt").unwrap(), b"beta ".repeat(100));
+            assert!(a.check_archive_deep().is_ok());
+        }
+
+        let err = pack_and_read(dir.join("missing").to_str().unwrap(), CompressionAlgo::ZSTD)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("failed to pack"), "{err}");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::{Component, Path, PathBuf};
+
+/// Kind of an entry listed by a `FileSource`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    /// Fifo, socket, device node or similar, named for warnings
+    Special(&'static str),
+}
+
+/// Tree of files an archive can be packed from, see `QZArchiveBuilder::from_source`
+///
+/// Only listing, reading and links are required. Owners, hard links and extended
+/// attributes are optional and default to not being known.
+pub trait FileSource: Send + Sync {
+    /// Paths and kinds of the entries directly within the directory at `path`
+    fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>>;
+
+    /// Content of the file at `path`
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>>;
+
+    /// Reader over the content of the file at `path`, for reading it in pieces
+    ///
+    /// Reads all of it with `read` unless implemented.
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+        let data = self.read(path)?;
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+
+    /// Target of the symbolic link at `path`
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf>;
+
+    /// Size of the file at `path`
+    fn len(&self, path: &Path) -> std::io::Result<u64> {
+        self.read(path).map(|data| data.len() as u64)
+    }
+
+    /// Owning user and group of `path`
+    fn owner(&self, _path: &Path) -> (Option<u32>, Option<u32>) {
+        (None, None)
+    }
+
+    /// Device and inode of a file with several hard links
+    fn link_id(&self, _path: &Path) -> Option<(u64, u64)> {
+        None
+    }
+
+    /// Extended attributes of `path`
+    fn xattrs(
+        &self,
+        _path: &Path,
+    ) -> Result<std::collections::BTreeMap<String, Vec<u8>>, crate::errors::WriteError> {
+        Ok(std::collections::BTreeMap::new())
+    }
+}
+
+/// The real filesystem
+#[cfg(feature = "fs")]
+pub struct OsFs;
+
+#[cfg(feature = "fs")]
+impl FileSource for OsFs {
+    fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+        let mut entries = vec![];
+        for e in std::fs::read_dir(path)? {
+            let e = e?;
+            let file_type = e.file_type()?;
+            let kind = if file_type.is_symlink() {
+                FileKind::Symlink
+            } else if file_type.is_file() {
+                FileKind::File
+            } else if file_type.is_dir() {
+                FileKind::Dir
+            } else {
+                FileKind::Special(crate::pack::special_file_kind(&file_type))
+            };
+            entries.push((e.path(), kind));
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn len(&self, path: &Path) -> std::io::Result<u64> {
+        std::fs::metadata(path).map(|m| m.len())
+    }
+
+    fn owner(&self, path: &Path) -> (Option<u32>, Option<u32>) {
+        match std::fs::metadata(path) {
+            Ok(m) => crate::pack::owner(&m),
+            Err(_) => (None, None),
+        }
+    }
+
+    fn link_id(&self, path: &Path) -> Option<(u64, u64)> {
+        crate::pack::link_id(path)
+    }
+
+    fn xattrs(
+        &self,
+        path: &Path,
+    ) -> Result<std::collections::BTreeMap<String, Vec<u8>>, crate::errors::WriteError> {
+        crate::pack::read_xattrs(path)
+    }
+}
+
+/// Entry of a `MemoryFs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryNode {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// Tree of files held in memory, to pack from or extract into without touching disk
+///
+/// Paths are relative to the root of the tree, leading `/` and `.` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    nodes: std::collections::BTreeMap<PathBuf, MemoryNode>,
+}
+
+// `path` made of its normal components only, `None` if it leaves the root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Add `node` at `path`, creating missing parent directories
+    ///
+    /// An existing entry at `path` is replaced. Paths leaving the root are ignored.
+    pub fn insert(&mut self, path: impl AsRef<Path>, node: MemoryNode) {
+        let Some(path) = normalize(path.as_ref()) else {
+            return;
+        };
+        for parent in path.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() {
+                break;
+            }
+            self.nodes.insert(parent.to_path_buf(), MemoryNode::Dir);
+        }
+        if !path.as_os_str().is_empty() {
+            self.nodes.insert(path, node);
+        }
+    }
+
+    /// Add a file holding `data`
+    pub fn add_file(&mut self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) {
+        self.insert(path, MemoryNode::File(data.into()));
+    }
+
+    /// Add an empty directory
+    pub fn add_dir(&mut self, path: impl AsRef<Path>) {
+        self.insert(path, MemoryNode::Dir);
+    }
+
+    /// Add a symbolic link to `target`
+    pub fn add_symlink(&mut self, path: impl AsRef<Path>, target: impl Into<PathBuf>) {
+        self.insert(path, MemoryNode::Symlink(target.into()));
+    }
+
+    /// Entry at `path`
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<&MemoryNode> {
+        self.nodes.get(&normalize(path.as_ref())?)
+    }
+
+    /// All entries with their paths, parents before their content
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &MemoryNode)> {
+        self.nodes.iter().map(|(path, node)| (path.as_path(), node))
+    }
+}
+
+impl FileSource for MemoryFs {
+    fn list(&self, path: &Path) -> std::io::Result<Vec<(PathBuf, FileKind)>> {
+        let dir = normalize(path).ok_or_else(|| not_found(path))?;
+        if !dir.as_os_str().is_empty() && self.nodes.get(&dir) != Some(&MemoryNode::Dir) {
+            return Err(not_found(path));
+        }
+
+        Ok(self
+            .nodes
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(dir.as_path()))
+            .map(|(p, node)| {
+                let kind = match node {
+                    MemoryNode::File(_) => FileKind::File,
+                    MemoryNode::Dir => FileKind::Dir,
+                    MemoryNode::Symlink(_) => FileKind::Symlink,
+                };
+                (path.join(p.file_name().unwrap()), kind)
+            })
+            .collect())
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        match self.get(path) {
+            Some(MemoryNode::File(data)) => Ok(data.clone()),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn std::io::Read + '_>> {
+        match self.get(path) {
+            Some(MemoryNode::File(data)) => Ok(Box::new(data.as_slice())),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+        match self.get(path) {
+            Some(MemoryNode::Symlink(target)) => Ok(target.clone()),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn len(&self, path: &Path) -> std::io::Result<u64> {
+        match self.get(path) {
+            Some(MemoryNode::File(data)) => Ok(data.len() as u64),
+            _ => Err(not_found(path)),
+        }
+    }
+}
+
+// Shared source that can live in `Debug` structs
+#[cfg(feature = "fs")]
+#[derive(Clone)]
+pub(crate) struct SharedSource(pub(crate) std::sync::Arc<dyn FileSource>);
+
+#[cfg(feature = "fs")]
+impl std::fmt::Debug for SharedSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FileSource")
+    }
+}
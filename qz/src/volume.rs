@@ -0,0 +1,239 @@
+#[cfg(feature = "fs")]
+use crate::source::ReadAt;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "fs")]
+use std::fs::File;
+#[cfg(feature = "fs")]
+use std::io::BufWriter;
+#[cfg(feature = "fs")]
+use std::io::Write;
+
+/// Volume layout of an archive split into several files
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QZVolumes {
+    /// Maximum size of a single volume in bytes
+    pub size: u64,
+    /// Number of volumes
+    pub count: u64,
+}
+
+/// Filename of the volume with the given index (starting at 0)
+pub fn volume_path(base: &str, index: u64) -> String {
+    format!("{}.{:03}", base, index + 1)
+}
+
+/// Base archive name if `path` names the first volume (`archive.qz.001`)
+pub fn volume_base(path: &str) -> Option<&str> {
+    path.strip_suffix(".001")
+}
+
+//   ---------
+//   | WRITE |
+//   ---------
+
+/// Writer spreading a byte stream over volumes of a fixed size
+///
+/// Volumes are written to temporary files and only renamed into place by `finish`.
+#[cfg(feature = "fs")]
+pub struct VolumeWriter {
+    base: String,
+    size: u64,
+    buffer_size: usize,
+    written: u64,
+    current: Option<BufWriter<File>>,
+    // Temporary files of the volumes written so far
+    parts: Vec<std::path::PathBuf>,
+    temp_dir: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "fs")]
+impl VolumeWriter {
+    pub fn new(base: &str, size: u64, buffer_size: usize) -> VolumeWriter {
+        VolumeWriter {
+            base: base.to_string(),
+            size,
+            buffer_size,
+            written: 0,
+            current: None,
+            parts: vec![],
+            temp_dir: None,
+        }
+    }
+
+    /// Stage volumes in `dir` instead of next to them, falling back to the latter
+    /// if `dir` is not writable
+    pub fn temp_dir(mut self, dir: Option<&std::path::Path>) -> VolumeWriter {
+        self.temp_dir = dir.map(|d| d.to_path_buf());
+        self
+    }
+
+    // Flush and sync the volume being written
+    fn close_volume(&mut self) -> std::io::Result<()> {
+        if let Some(f) = self.current.take() {
+            f.into_inner()?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn next_volume(&mut self) -> std::io::Result<()> {
+        self.close_volume()?;
+        let (f, tmp) = crate::write::create_temp(
+            &volume_path(&self.base, self.parts.len() as u64),
+            self.temp_dir.as_deref(),
+        )?;
+        self.current = Some(BufWriter::with_capacity(self.buffer_size, f));
+        self.parts.push(tmp);
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Create `count` volumes, padding with empty ones if the stream ended early
+    pub fn finish(mut self, count: u64) -> std::io::Result<()> {
+        while (self.parts.len() as u64) < count {
+            self.next_volume()?;
+        }
+        self.close_volume()?;
+        for (i, tmp) in self.parts.iter().enumerate() {
+            crate::write::persist_temp(tmp, &volume_path(&self.base, i as u64))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.current.is_none() || self.written == self.size {
+            self.next_volume()?;
+        }
+        let n = buf.len().min((self.size - self.written) as usize);
+        let n = self.current.as_mut().unwrap().write(&buf[..n])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.current.as_mut() {
+            Some(f) => f.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+//   --------
+//   | READ |
+//   --------
+
+/// Set of volumes presented as one continuous archive
+#[cfg(feature = "fs")]
+pub struct VolumeSet {
+    files: Vec<File>,
+    size: u64,
+    len: u64,
+}
+
+#[cfg(feature = "fs")]
+impl VolumeSet {
+    /// Open all volumes belonging to `base`
+    pub fn open(base: &str) -> std::io::Result<VolumeSet> {
+        let mut files = vec![];
+        let mut lens = vec![];
+
+        loop {
+            let f = match File::open(volume_path(base, files.len() as u64)) {
+                Ok(f) => f,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound && !files.is_empty() => {
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            lens.push(f.metadata()?.len());
+            files.push(f);
+        }
+
+        // Every volume but the last has to be filled completely
+        let size = lens[0];
+        if lens[..lens.len() - 1].iter().any(|l| *l != size) || lens[lens.len() - 1] > size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "volumes have inconsistent sizes",
+            ));
+        }
+
+        Ok(VolumeSet {
+            files,
+            size,
+            len: lens.iter().sum(),
+        })
+    }
+
+    /// Volume size and count as found on disk
+    pub fn layout(&self) -> QZVolumes {
+        QZVolumes {
+            size: self.size,
+            count: self.files.len() as u64,
+        }
+    }
+
+    /// Combined length of all volumes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the volumes hold no data at all
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(feature = "fs")]
+impl ReadAt for VolumeSet {
+    // Reads stop at volume boundaries, `read_exact_at` continues in the next volume
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if offset >= self.len || buf.is_empty() || self.size == 0 {
+            return Ok(0);
+        }
+        let index = (offset / self.size) as usize;
+        let v_offset = offset % self.size;
+        let n = buf.len().min((self.size - v_offset) as usize);
+
+        self.files[index].read_at(&mut buf[..n], v_offset)
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use crate::testing::pack_files;
+    use crate::{read_archive, CompressionAlgo};
+
+    #[test]
+    fn reads_files_crossing_volume_boundaries() {
+        let a_data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let b_data = vec![7u8; 3000];
+        let (dir, out_file) = pack_files(&[("a.bin", &a_data), ("b.bin", &b_data)], |b| {
+            b.compression(CompressionAlgo::NONE).volume_size(1024)
+        });
+        assert!(!std::path::Path::new(&out_file).exists());
+        assert!(std::path::Path::new(&volume_path(&out_file, 1)).exists());
+
+        let a = read_archive(&volume_path(&out_file, 0)).unwrap();
+        let count = a.header.volumes.as_ref().unwrap().count;
+        assert!(count > 2);
+        let f = a.lookup_file("/a.bin".as_ref()).unwrap();
+        let start = a.stored_position(&f);
+        assert_ne!(start / 1024, (start + f.index_size - 1) / 1024);
+        assert_eq!(a.read_file("/a.bin").unwrap(), a_data);
+        assert_eq!(a.read_file("/b.bin").unwrap(), b_data);
+        assert_eq!(
+            a.read_range("/a.bin", 1000, 2000).unwrap(),
+            &a_data[1000..3000]
+        );
+        assert!(a.check_archive_deep().is_ok());
+
+        for i in 0..count {
+            let _ = std::fs::remove_file(volume_path(&out_file, i));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1262 @@
+use crate::pack::{append_file, recompress_data};
+#[cfg(feature = "recovery")]
+use crate::recovery;
+use crate::source::{self, ReadAt};
+use crate::{
+    compression, errors, in_pool, read_archive, volume, CompressionAlgo, CompressionConfig,
+    QZArchive, QZArchiveHeader, QZDir, QZEntry, QZFile, QZVolumes, FORMAT_VERSION, MAGIC,
+    MIN_BUFFER_SIZE, PREFIX_SIZE,
+};
+use rayon::prelude::*;
+use std::fs;
+use std::io::Write;
+
+// File at `path` below `root`, for updating it while packing
+pub(crate) fn find_file_mut<'a>(root: &'a mut QZEntry, path: &str) -> Option<&'a mut QZFile> {
+    let mut current = root;
+
+    for c in std::path::Path::new(path).components() {
+        let name = match c {
+            std::path::Component::Normal(name) => name.to_str()?,
+            std::path::Component::RootDir => continue,
+            _ => return None,
+        };
+        let QZEntry::Dir(d) = current else {
+            return None;
+        };
+        current = d.content.iter_mut().find(|e| e.name() == name)?;
+    }
+
+    match current {
+        QZEntry::File(f) => Some(f),
+        QZEntry::Dir(_) => None,
+    }
+}
+
+// Add `f` at `path` below `root`, creating the directories leading to it
+fn insert_file(
+    root: &mut QZEntry,
+    path: &std::path::Path,
+    f: QZFile,
+) -> Result<(), errors::WriteError> {
+    let mut names = vec![];
+    for c in path.components() {
+        match c {
+            std::path::Component::Normal(name) => match name.to_str() {
+                Some(name) => names.push(name),
+                None => {
+                    return Err(errors::WriteError::InvalidInput(
+                        "path is not valid UTF-8".to_string(),
+                    ))
+                }
+            },
+            std::path::Component::RootDir => {}
+            _ => {
+                return Err(errors::WriteError::InvalidInput(
+                    "path contains invalid components".to_string(),
+                ))
+            }
+        }
+    }
+    names.pop();
+
+    let mut current = root;
+    for name in names {
+        let QZEntry::Dir(d) = current else {
+            unreachable!()
+        };
+        let i = match d.content.iter().position(|e| e.name() == name) {
+            Some(i) => i,
+            None => {
+                d.content.push(QZEntry::Dir(QZDir {
+                    name: name.to_string(),
+                    content: vec![],
+                    uid: None,
+                    gid: None,
+                    xattrs: std::collections::BTreeMap::new(),
+                }));
+                d.content.len() - 1
+            }
+        };
+        current = &mut d.content[i];
+        if let QZEntry::File(_) = current {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "{name} is a file"
+            )));
+        }
+    }
+
+    let QZEntry::Dir(d) = current else {
+        unreachable!()
+    };
+    if d.content.iter().any(|e| e.name() == f.name) {
+        return Err(errors::WriteError::InvalidInput(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    d.content.push(QZEntry::File(f));
+    Ok(())
+}
+
+// Call `visit` for every file below `entry`
+fn visit_files_mut(entry: &mut QZEntry, visit: &mut impl FnMut(&mut QZFile)) {
+    match entry {
+        QZEntry::File(f) => visit(f),
+        QZEntry::Dir(d) => {
+            for c in &mut d.content {
+                visit_files_mut(c, visit);
+            }
+        }
+    }
+}
+
+// Fixed prefix in front of the encoded header
+fn encode_prefix(encoded: &[u8], compression: &CompressionAlgo, format: u8) -> Vec<u8> {
+    let mut prefix = MAGIC.to_vec();
+    prefix.push(format);
+    prefix.push(compression.tag());
+    prefix.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+    prefix.extend_from_slice(&crc32fast::hash(encoded).to_le_bytes());
+    prefix
+}
+
+// Serialize and compress an archive header, returning the compression actually used
+//
+// Small headers can grow when compressed, those are stored uncompressed instead.
+pub(crate) fn encode_header(
+    header: &QZArchiveHeader,
+    compression: &CompressionAlgo,
+    format: u8,
+) -> (Vec<u8>, CompressionAlgo) {
+    let header = serde_json::to_vec(header).unwrap();
+
+    // COMPRESSION
+    let compressed =
+        compression::compress_versioned(&header, compression, compression::DEFAULT_LEVEL, format)
+            .unwrap();
+
+    if compressed.len() < header.len() {
+        (compressed, compression.clone())
+    } else {
+        (header, CompressionAlgo::NONE)
+    }
+}
+
+/// Write header and body to `out_file`, split into volumes if `volume_size` is set
+///
+/// The volume layout is recorded in `header.volumes`.
+/// The header is stored uncompressed if `header_compression` would not make it smaller.
+/// Output is written through buffers of `buffer_size` bytes.
+pub fn write_archive(
+    out_file: &str,
+    header: &mut QZArchiveHeader,
+    header_compression: &CompressionAlgo,
+    body: &[u8],
+    volume_size: Option<u64>,
+    buffer_size: usize,
+) -> Result<(), errors::WriteError> {
+    write_versioned(
+        out_file,
+        header,
+        header_compression,
+        body,
+        volume_size,
+        buffer_size,
+        FORMAT_VERSION,
+        None,
+    )
+}
+
+// `write_archive` for a body whose entries were written for format version `format`,
+// staging the output in `temp_dir` if given
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_versioned(
+    out_file: &str,
+    header: &mut QZArchiveHeader,
+    header_compression: &CompressionAlgo,
+    body: &[u8],
+    volume_size: Option<u64>,
+    buffer_size: usize,
+    format: u8,
+    temp_dir: Option<&std::path::Path>,
+) -> Result<(), errors::WriteError> {
+    header.volumes = None;
+    let parity = encode_recovery(header, body)?;
+    write_stream(
+        out_file,
+        header,
+        header_compression,
+        &mut &body[..],
+        body.len() as u64,
+        &parity,
+        volume_size,
+        buffer_size,
+        format,
+        temp_dir,
+    )
+}
+
+// `write_versioned` for a body of `body_len` bytes read from `body`,
+// followed by the recovery records `parity` already computed for it
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_stream(
+    out_file: &str,
+    header: &mut QZArchiveHeader,
+    header_compression: &CompressionAlgo,
+    body: &mut dyn std::io::Read,
+    body_len: u64,
+    parity: &[u8],
+    volume_size: Option<u64>,
+    buffer_size: usize,
+    format: u8,
+    temp_dir: Option<&std::path::Path>,
+) -> Result<(), errors::WriteError> {
+    // Archives without a prefix use version 1 semantics
+    let format = format.max(1);
+    let buffer_size = buffer_size.max(MIN_BUFFER_SIZE);
+    header.volumes = None;
+    let (mut encoded, mut used_compression) = encode_header(header, header_compression, format);
+
+    if let Some(size) = volume_size {
+        if size == 0 {
+            return Err(errors::WriteError::InvalidInput(
+                "volume size has to be at least one byte".to_string(),
+            ));
+        }
+
+        // The volume count is part of the header, so settle it before writing
+        let mut count = 1;
+        loop {
+            header.volumes = Some(QZVolumes { size, count });
+            (encoded, used_compression) = encode_header(header, header_compression, format);
+            let total = PREFIX_SIZE + encoded.len() as u64 + body_len + parity.len() as u64;
+            let needed = total.div_ceil(size).max(1);
+            if needed <= count {
+                break;
+            }
+            count = needed;
+        }
+    }
+
+    let prefix = encode_prefix(&encoded, &used_compression, format);
+
+    // SAVE
+
+    let res = match &header.volumes {
+        Some(volumes) => {
+            let mut w =
+                volume::VolumeWriter::new(out_file, volumes.size, buffer_size).temp_dir(temp_dir);
+            w.write_all(&prefix)
+                .and_then(|_| w.write_all(&encoded))
+                .and_then(|_| copy_body(body, body_len, &mut w))
+                .and_then(|_| w.write_all(parity))
+                .and_then(|_| w.finish(volumes.count))
+        }
+        None => {
+            // Write to a temporary file and rename once complete,
+            // so an interrupted run never leaves a partial archive behind
+            create_temp(out_file, temp_dir).and_then(|(f, tmp_file)| {
+                let mut f = std::io::BufWriter::with_capacity(buffer_size, f);
+                f.write_all(&prefix)?;
+                f.write_all(&encoded)?;
+                copy_body(body, body_len, &mut f)?;
+                f.write_all(parity)?;
+                f.into_inner()?.sync_all()?;
+                persist_temp(&tmp_file, out_file)
+            })
+        }
+    };
+
+    if let Err(err) = res {
+        return Err(errors::WriteError::Output(out_file.to_string(), err));
+    }
+
+    Ok(())
+}
+
+// Copy exactly `len` bytes of body from `body` to `w`
+fn copy_body(
+    body: &mut dyn std::io::Read,
+    len: u64,
+    w: &mut dyn std::io::Write,
+) -> std::io::Result<()> {
+    let copied = std::io::copy(&mut std::io::Read::take(body, len), w)?;
+    if copied < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("body ended after {copied} of {len} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+// Temporary file for writing `out_file`, in `temp_dir` if given and writable,
+// otherwise next to `out_file`
+pub(crate) fn create_temp(
+    out_file: &str,
+    temp_dir: Option<&std::path::Path>,
+) -> std::io::Result<(fs::File, std::path::PathBuf)> {
+    if let Some(dir) = temp_dir {
+        let name = std::path::Path::new(out_file)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("archive");
+        // Several writers may share the directory
+        let tmp = dir.join(format!("{name}.{}.tmp", std::process::id()));
+        if let Ok(f) = fs::File::create(&tmp) {
+            return Ok((f, tmp));
+        }
+    }
+    let tmp = std::path::PathBuf::from(format!("{out_file}.tmp"));
+    Ok((fs::File::create(&tmp)?, tmp))
+}
+
+// Move the complete temporary file `tmp` to `out_file`
+//
+// A temporary file on another filesystem than `out_file` cannot be renamed, it is then
+// copied next to `out_file` first so the final rename replaces the archive at once.
+pub(crate) fn persist_temp(tmp: &std::path::Path, out_file: &str) -> std::io::Result<()> {
+    if fs::rename(tmp, out_file).is_ok() {
+        return Ok(());
+    }
+    let local = format!("{out_file}.tmp");
+    let res = fs::copy(tmp, &local)
+        .and_then(|_| fs::File::open(&local)?.sync_all())
+        .and_then(|_| fs::rename(&local, out_file));
+    let _ = fs::remove_file(tmp);
+    if res.is_err() {
+        let _ = fs::remove_file(&local);
+    }
+    res
+}
+
+// Prefix, header, body and recovery records of a single file archive
+pub(crate) fn encode_archive(
+    header: &mut QZArchiveHeader,
+    header_compression: &CompressionAlgo,
+    body: &[u8],
+    format: u8,
+) -> Result<Vec<u8>, errors::WriteError> {
+    let format = format.max(1);
+    header.volumes = None;
+    let parity = encode_recovery(header, body)?;
+    let (encoded, used_compression) = encode_header(header, header_compression, format);
+
+    let mut data = encode_prefix(&encoded, &used_compression, format);
+    data.extend_from_slice(&encoded);
+    data.extend_from_slice(body);
+    data.extend_from_slice(&parity);
+    Ok(data)
+}
+
+/// Join the volumes of a split archive back into a single archive file
+///
+/// The volume set is fully checked before anything is written.
+pub fn join_volumes(volume: &str, out_file: &str) -> Result<(), errors::WriteError> {
+    let a = match read_archive(volume) {
+        Ok(a) => a,
+        Err(err) => {
+            return Err(errors::WriteError::Other(format!(
+                "failed to open {volume}: {err}"
+            )));
+        }
+    };
+    if !matches!(a.source, source::ArchiveSource::Spanned(_)) {
+        return Err(errors::WriteError::InvalidInput(format!(
+            "{volume} is not a split archive"
+        )));
+    }
+
+    if let Err(failed) = a.check_archive() {
+        let paths: Vec<String> = failed.into_iter().map(|(path, _)| path).collect();
+        return Err(errors::WriteError::Other(format!(
+            "damaged files: {}",
+            paths.join(", ")
+        )));
+    }
+
+    // BODY
+    let body = match a.read_body() {
+        Ok(body) => body,
+        Err(err) => {
+            return Err(errors::WriteError::SourceRead(volume.to_string(), err));
+        }
+    };
+
+    let body_len = a
+        .files()
+        .iter()
+        .map(|(_, f)| f.index_start + f.index_size)
+        .max()
+        .unwrap_or(0);
+    if (body.len() as u64) < body_len {
+        return Err(errors::WriteError::Other(format!(
+            "volumes hold {} body bytes but the archive needs {body_len}",
+            body.len()
+        )));
+    }
+
+    let mut header = a.header.clone();
+    write_versioned(
+        out_file,
+        &mut header,
+        &a.header_compression,
+        &body,
+        None,
+        a.buffer_size,
+        a.format,
+        a.temp_dir.as_deref(),
+    )
+}
+
+// Recompute the recovery records requested by `header` for `body`
+#[cfg(feature = "recovery")]
+pub(crate) fn encode_recovery(
+    header: &mut QZArchiveHeader,
+    body: &[u8],
+) -> Result<Vec<u8>, errors::WriteError> {
+    let Some(layout) = &header.recovery else {
+        return Ok(vec![]);
+    };
+    let (layout, parity) = recovery::encode(body, layout).map_err(errors::WriteError::Other)?;
+    header.recovery = Some(layout);
+    Ok(parity)
+}
+
+// Without the feature recovery records cannot be kept up to date, so they are dropped
+#[cfg(not(feature = "recovery"))]
+pub(crate) fn encode_recovery(
+    header: &mut QZArchiveHeader,
+    _body: &[u8],
+) -> Result<Vec<u8>, errors::WriteError> {
+    header.recovery = None;
+    Ok(vec![])
+}
+
+// Combined size of an archive and all of its volumes
+pub(crate) fn archive_size(out_file: &str, volumes: Option<&QZVolumes>) -> u64 {
+    let len = |path: &str| fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    match volumes {
+        Some(volumes) => (0..volumes.count)
+            .map(|i| len(&volume::volume_path(out_file, i)))
+            .sum(),
+        None => len(out_file),
+    }
+}
+
+// Remove an archive and all of its volumes
+pub(crate) fn remove_archive(out_file: &str, volumes: Option<&QZVolumes>) {
+    match volumes {
+        Some(volumes) => {
+            for i in 0..volumes.count {
+                let _ = fs::remove_file(volume::volume_path(out_file, i));
+            }
+        }
+        None => {
+            let _ = fs::remove_file(out_file);
+        }
+    }
+}
+
+impl QZArchive {
+    /// Stage rewrites of the archive in `dir` instead of next to the archive file
+    ///
+    /// Rewritten archives are renamed into place once complete. Writing next to the
+    /// archive keeps that rename on one filesystem, from another the staged file is
+    /// copied over first. If `dir` is not writable the archive's directory is used.
+    pub fn set_temp_dir(&mut self, dir: impl AsRef<std::path::Path>) {
+        self.temp_dir = Some(dir.as_ref().to_path_buf());
+    }
+
+    // Everything stored after the header, up to the recovery records if there are any
+    fn read_body(&self) -> std::io::Result<Vec<u8>> {
+        let mut body = vec![0u8; self.stored_body_len()? as usize];
+        self.source.read_exact_at(&mut body, self.body_offset())?;
+        Ok(body)
+    }
+
+    /// Change name and/or description and rewrite the archive file
+    ///
+    /// Only the header is re-encoded, all entry offsets stay the same.
+    pub fn set_info(
+        &mut self,
+        name: Option<&str>,
+        info: Option<&str>,
+    ) -> Result<(), errors::WriteError> {
+        if let Some(name) = name {
+            self.header.name = name.to_string();
+        }
+        if let Some(info) = info {
+            self.header.info = info.to_string();
+        }
+        self.rewrite_header(false)
+    }
+
+    /// Set a header metadata value and rewrite the archive file, an empty `value` removes `key`
+    pub fn set_metadata(&mut self, key: &str, value: &str) -> Result<(), errors::WriteError> {
+        if value.is_empty() {
+            self.header.metadata.remove(key);
+        } else {
+            self.header
+                .metadata
+                .insert(key.to_string(), value.to_string());
+        }
+        self.rewrite_header(false)
+    }
+
+    /// Copy the file at `path` of `src` to the same path of this archive and rewrite it
+    ///
+    /// The stored bytes are appended to the body as they are, along with the entry
+    /// and its compression, checksum and other metadata, so nothing is decompressed
+    /// or compressed again. Missing directories on the way are created. Fails if
+    /// something is stored at `path` already, or if this archive could not read the entry
+    /// as stored: `ZSTD` entries need both archives to have the same dictionary, `LZ4`
+    /// ones the same framing, see `compression::LZ4_FRAME_VERSION`.
+    pub fn copy_entry_from(
+        &mut self,
+        src: &QZArchive,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), errors::WriteError> {
+        self.check_writable()?;
+        let path = path.as_ref();
+        let copy_err = |err: errors::FileReadError| {
+            errors::WriteError::Other(format!("cannot copy {}: {err}", path.display()))
+        };
+        let mut f = src.lookup_file(path).map_err(copy_err)?;
+        let stored = src.read_raw(path).map_err(copy_err)?;
+
+        let readable = match f.compression {
+            CompressionAlgo::ZSTD => src.dictionary() == self.dictionary(),
+            CompressionAlgo::LZ4 => {
+                (src.format < compression::LZ4_FRAME_VERSION)
+                    == (self.format < compression::LZ4_FRAME_VERSION)
+            }
+            CompressionAlgo::NONE => true,
+        };
+        // Chunks came with format version 3
+        if !readable || (!f.chunks.is_empty() && self.format < 3) {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "cannot copy {}, {} stores it in a way {} cannot read",
+                path.display(),
+                src.archive_file,
+                self.archive_file
+            )));
+        }
+
+        let mut body = self
+            .read_body()
+            .map_err(|err| errors::WriteError::SourceRead(self.archive_file.clone(), err))?;
+        // Copies of hard links get their own data, the file they linked to may be missing
+        f.hard_link = None;
+        f.index_start = body.len() as u64;
+        let mut header = self.header.clone();
+        insert_file(&mut header.root, path, f)?;
+
+        // Extend the checksum of the old body, so damage in it is not covered up
+        if let Some(checksum) = header.body_checksum {
+            let mut hasher = crc32fast::Hasher::new_with_initial_len(checksum, body.len() as u64);
+            hasher.update(&stored);
+            header.body_checksum = Some(hasher.finalize());
+        }
+        body.extend_from_slice(&stored);
+
+        let volume_size = header.volumes.as_ref().map(|v| v.size);
+        write_versioned(
+            &self.archive_file,
+            &mut header,
+            &self.header_compression,
+            &body,
+            volume_size,
+            self.buffer_size,
+            self.format,
+            self.temp_dir.as_deref(),
+        )?;
+        self.reopen()
+    }
+
+    /// Compress every file again with `algo` at `level` and rewrite the archive
+    ///
+    /// Files are decompressed and checked against their checksums first, chunked files
+    /// keep their chunk size unless stored uncompressed. `ZSTD` files are compressed
+    /// with the dictionary of the archive if it has one. The archive is written in the
+    /// current format version. Returns the size of the body before and after.
+    pub fn recompress(
+        &mut self,
+        algo: CompressionAlgo,
+        level: i32,
+    ) -> Result<(u64, u64), errors::WriteError> {
+        self.check_writable()?;
+        let config = CompressionConfig {
+            algorithm: algo.clone(),
+            level: Some(level),
+            ..Default::default()
+        };
+        config.check_level()?;
+
+        // Hard links share the data of the file they link to, which is compressed once
+        let mut stored: Vec<&QZFile> = vec![];
+        let mut seen = std::collections::HashSet::new();
+        for (_, f) in self.files() {
+            if f.symlink.is_none() && seen.insert((f.index_start, f.index_size)) {
+                stored.push(f);
+            }
+        }
+
+        let dictionary = self.dictionary();
+        let compressed: Result<Vec<_>, errors::WriteError> = in_pool(self.pool.as_ref(), || {
+            stored
+                .par_iter()
+                .map(|f| {
+                    let data = f.read_file(self).map_err(|err| {
+                        errors::WriteError::Other(format!("failed to read {}: {err}", f.name))
+                    })?;
+                    let mut target = (*f).clone();
+                    target.compression = algo.clone();
+                    let file = recompress_data(&target, &data, level, dictionary)?;
+                    Ok((target, file))
+                })
+                .collect()
+        });
+
+        let old_len = self
+            .stored_body_len()
+            .map_err(|err| errors::WriteError::SourceRead(self.archive_file.clone(), err))?;
+        let mut body = vec![];
+        let mut placed = std::collections::HashMap::new();
+        for (mut target, file) in compressed? {
+            let key = (target.index_start, target.index_size);
+            append_file(&mut target, file, &mut body);
+            placed.insert(key, target);
+        }
+
+        let mut header = self.header.clone();
+        visit_files_mut(&mut header.root, &mut |f| {
+            // Links store no data, so anywhere in the body will do
+            if f.symlink.is_some() {
+                f.index_start = 0;
+                return;
+            }
+            if let Some(placed) = placed.get(&(f.index_start, f.index_size)) {
+                f.compression = placed.compression.clone();
+                f.index_start = placed.index_start;
+                f.index_size = placed.index_size;
+                f.checksum = placed.checksum;
+                f.chunks = placed.chunks.clone();
+                f.original_size = placed.original_size;
+                f.original_checksum = placed.original_checksum;
+                f.crlf = placed.crlf;
+                f.nested = placed.nested;
+            }
+        });
+        header.body_checksum = Some(crc32fast::hash(&body));
+
+        let old_volumes = header.volumes.clone();
+        let volume_size = old_volumes.as_ref().map(|v| v.size);
+        write_versioned(
+            &self.archive_file,
+            &mut header,
+            &self.header_compression,
+            &body,
+            volume_size,
+            self.buffer_size,
+            FORMAT_VERSION,
+            self.temp_dir.as_deref(),
+        )?;
+        // Drop volumes the smaller archive no longer needs
+        if let (Some(old), Some(new)) = (&old_volumes, &header.volumes) {
+            for i in new.count..old.count {
+                let _ = fs::remove_file(volume::volume_path(&self.archive_file, i));
+            }
+        }
+        self.reopen()?;
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+
+        Ok((old_len, body.len() as u64))
+    }
+
+    /// Recompute the checksums of every entry from its stored bytes and rewrite the header
+    ///
+    /// Trusts the body as it is, so a damaged entry is accepted afterwards. The checksums
+    /// of the stored data, its chunks and its content are all derived from the body, as is
+    /// the checksum of the whole body. Fails without changing anything if stored data no
+    /// longer decompresses, as no checksum would make it readable again.
+    /// Returns how many entries changed, the archive is only rewritten if any did.
+    pub fn repair_checksums(&mut self) -> Result<usize, errors::WriteError> {
+        let mut changed = vec![];
+
+        for (path, f) in self.files() {
+            let stored = f.read_stored(self).map_err(|err| {
+                errors::WriteError::Other(format!("failed to read {path}: {err}"))
+            })?;
+            let mut repaired = f.clone();
+            repaired.checksum = crc32fast::hash(&stored);
+            for c in &mut repaired.chunks {
+                if let Some(stored) =
+                    stored.get(c.offset as usize..c.offset.saturating_add(c.size) as usize)
+                {
+                    c.checksum = crc32fast::hash(stored);
+                }
+            }
+            if repaired.original_checksum.is_some() {
+                let data = repaired.decompress_stored(self, stored).map_err(|err| {
+                    errors::WriteError::InvalidInput(format!("cannot repair {path}, {err}"))
+                })?;
+                repaired.original_checksum = Some(crc32fast::hash(&data));
+            }
+            let chunks = |f: &QZFile| f.chunks.iter().map(|c| c.checksum).collect::<Vec<_>>();
+            if repaired.checksum != f.checksum
+                || chunks(&repaired) != chunks(f)
+                || repaired.original_checksum != f.original_checksum
+            {
+                changed.push((path, repaired));
+            }
+        }
+
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        for (path, repaired) in &changed {
+            if let Some(f) = find_file_mut(&mut self.header.root, path) {
+                f.checksum = repaired.checksum;
+                f.chunks = repaired.chunks.clone();
+                f.original_checksum = repaired.original_checksum;
+            }
+        }
+        self.rewrite_header(true)?;
+
+        Ok(changed.len())
+    }
+
+    /// Reconstruct damaged parts of the body from the recovery records and rewrite the archive
+    ///
+    /// Returns the number of repaired blocks, the archive is only rewritten if any were.
+    /// Fails if the archive has no recovery records or a part is damaged beyond their capacity.
+    #[cfg(feature = "recovery")]
+    pub fn recover(&mut self) -> Result<usize, errors::WriteError> {
+        self.check_writable()?;
+        let Some(layout) = self.header.recovery.clone() else {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "{} has no recovery records",
+                self.archive_file
+            )));
+        };
+
+        let read_err =
+            |err: std::io::Error| errors::WriteError::SourceRead(self.archive_file.clone(), err);
+        let mut body = self.read_body().map_err(read_err)?;
+        let mut parity = vec![0u8; layout.parity_size() as usize];
+        let end = self.body_offset() + layout.body_size;
+        // A truncated parity block only counts as damaged
+        let available = self.source.len().map_err(read_err)?.saturating_sub(end);
+        let n = parity.len().min(available as usize);
+        self.source
+            .read_exact_at(&mut parity[..n], end)
+            .map_err(read_err)?;
+
+        let repaired =
+            recovery::recover(&mut body, &parity, &layout).map_err(errors::WriteError::Other)?;
+        if repaired == 0 {
+            return Ok(0);
+        }
+
+        let volume_size = self.header.volumes.as_ref().map(|v| v.size);
+        write_versioned(
+            &self.archive_file,
+            &mut self.header,
+            &self.header_compression,
+            &body,
+            volume_size,
+            self.buffer_size,
+            self.format,
+            self.temp_dir.as_deref(),
+        )?;
+        self.reopen()?;
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+
+        Ok(repaired)
+    }
+
+    // Archives read from memory have no file to save changes to, embedded ones
+    // share theirs with data that would be lost
+    fn check_writable(&self) -> Result<(), errors::WriteError> {
+        match self.source {
+            source::ArchiveSource::Memory(_) => Err(errors::WriteError::InvalidInput(
+                "archives read from memory cannot be rewritten".to_string(),
+            )),
+            source::ArchiveSource::Embedded(..) => Err(errors::WriteError::InvalidInput(
+                "archives embedded in another file cannot be rewritten".to_string(),
+            )),
+            source::ArchiveSource::Indexed(..) => Err(errors::WriteError::InvalidInput(
+                "archives read through an index cannot be rewritten".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Write the current header in front of the unchanged body and re-open the result,
+    // with `rehash_body` the body checksum is recomputed for a body changed outside of qz
+    fn rewrite_header(&mut self, rehash_body: bool) -> Result<(), errors::WriteError> {
+        // Keep the index in line with the edited header even if writing fails
+        self.refresh_index();
+        self.check_writable()?;
+        let body = match self.read_body() {
+            Ok(body) => body,
+            Err(err) => {
+                return Err(errors::WriteError::SourceRead(
+                    self.archive_file.clone(),
+                    err,
+                ));
+            }
+        };
+
+        if rehash_body && self.header.body_checksum.is_some() {
+            self.header.body_checksum = Some(crc32fast::hash(&body));
+        }
+
+        let old_volumes = self.header.volumes.clone();
+        let volume_size = old_volumes.as_ref().map(|v| v.size);
+        write_versioned(
+            &self.archive_file,
+            &mut self.header,
+            &self.header_compression,
+            &body,
+            volume_size,
+            self.buffer_size,
+            self.format,
+            self.temp_dir.as_deref(),
+        )?;
+
+        // Drop volumes the smaller header no longer needs
+        if let (Some(old), Some(new)) = (&old_volumes, &self.header.volumes) {
+            for i in new.count..old.count {
+                let _ = fs::remove_file(volume::volume_path(&self.archive_file, i));
+            }
+        }
+
+        self.reopen()
+    }
+
+    // Pick up the header and source of the freshly written archive file
+    fn reopen(&mut self) -> Result<(), errors::WriteError> {
+        #[cfg(feature = "mmap")]
+        let mapped = self.source.as_slice().is_some();
+
+        match read_archive(&self.archive_file) {
+            Ok(a) => {
+                self.header = a.header;
+                self.refresh_index();
+                self.header_size = a.header_size;
+                self.prefix_size = a.prefix_size;
+                self.header_compression = a.header_compression;
+                self.format = a.format;
+                self.source = a.source;
+                #[cfg(feature = "mmap")]
+                if mapped {
+                    self.map()
+                        .map_err(|err| errors::WriteError::Other(err.to_string()))?;
+                }
+                Ok(())
+            }
+            Err(err) => Err(errors::WriteError::Other(format!(
+                "failed to re-open {}: {err}",
+                self.archive_file
+            ))),
+        }
+    }
+}
+
+/// Open an archive file for editing
+///
+/// Fails like `read_archive`, and if the archive file or its first volume is read only.
+pub fn open_rw(path: &str) -> Result<QZArchiveMut, errors::ReadError> {
+    let archive = read_archive(path)?;
+    let file = match &archive.source {
+        source::ArchiveSource::Spanned(_) => volume::volume_path(&archive.archive_file, 0),
+        _ => archive.archive_file.clone(),
+    };
+    match fs::metadata(&file) {
+        Ok(m) if m.permissions().readonly() => {
+            return Err(errors::ReadError::new(&format!("{file} is read only")));
+        }
+        Ok(_) => {}
+        Err(err) => {
+            return Err(errors::ReadError::new(&format!(
+                "failed to open {file}: {err}"
+            )));
+        }
+    }
+
+    Ok(QZArchiveMut {
+        header: archive.header.clone(),
+        archive,
+        modified: false,
+    })
+}
+
+/// Archive opened with `open_rw`, collecting edits until they are saved at once
+///
+/// Edits only change the header held by this handle, `save` rewrites the archive file
+/// with it. Reads through `Deref` see the archive as last saved, so entries are always
+/// read with the header that belongs to the body on disk. Dropping the handle discards
+/// unsaved edits.
+pub struct QZArchiveMut {
+    archive: QZArchive,
+    // `archive.header` with all edits applied
+    header: QZArchiveHeader,
+    modified: bool,
+}
+
+impl std::ops::Deref for QZArchiveMut {
+    type Target = QZArchive;
+
+    fn deref(&self) -> &QZArchive {
+        &self.archive
+    }
+}
+
+impl QZArchiveMut {
+    /// The header as it will be saved, including unsaved edits
+    pub fn pending_header(&self) -> &QZArchiveHeader {
+        &self.header
+    }
+
+    /// Whether there are edits not saved yet
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Change name and/or description
+    pub fn set_info(&mut self, name: Option<&str>, info: Option<&str>) {
+        if let Some(name) = name {
+            self.header.name = name.to_string();
+        }
+        if let Some(info) = info {
+            self.header.info = info.to_string();
+        }
+        self.modified = true;
+    }
+
+    /// Set a header metadata value, an empty `value` removes `key`
+    pub fn set_metadata(&mut self, key: &str, value: &str) {
+        if value.is_empty() {
+            self.header.metadata.remove(key);
+        } else {
+            self.header
+                .metadata
+                .insert(key.to_string(), value.to_string());
+        }
+        self.modified = true;
+    }
+
+    /// Set the comment of the file at `path`, an empty `comment` removes it
+    pub fn set_comment(&mut self, path: &str, comment: &str) -> Result<(), errors::WriteError> {
+        let Some(f) = find_file_mut(&mut self.header.root, path) else {
+            return Err(errors::WriteError::InvalidInput(format!(
+                "no file at {path}"
+            )));
+        };
+        f.comment = (!comment.is_empty()).then(|| comment.to_string());
+        self.modified = true;
+        Ok(())
+    }
+
+    /// Rewrite the archive file with all edits, nothing is written without any
+    ///
+    /// On failure the edits are kept, so saving can be retried.
+    pub fn save(&mut self) -> Result<(), errors::WriteError> {
+        if !self.modified {
+            return Ok(());
+        }
+        let saved = std::mem::replace(&mut self.archive.header, self.header.clone());
+        if let Err(err) = self.archive.rewrite_header(false) {
+            self.archive.header = saved;
+            return Err(err);
+        }
+        self.header = self.archive.header.clone();
+        self.modified = false;
+        Ok(())
+    }
+
+    /// The archive as last saved, discarding unsaved edits
+    pub fn into_archive(self) -> QZArchive {
+        self.archive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{pack_files, patch_file};
+
+    #[test]
+    fn repair_fixes_a_drifted_checksum() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha"), ("b.txt", b"beta")], |b| b);
+        let mut a = read_archive(&out_file).unwrap();
+        find_file_mut(&mut a.header.root, "/a.txt")
+            .unwrap()
+            .checksum ^= 1;
+        a.rewrite_header(false).unwrap();
+        assert!(a.check_archive().is_err());
+
+        assert_eq!(a.repair_checksums().unwrap(), 1);
+        assert!(a.check_archive().is_ok());
+        assert_eq!(a.repair_checksums().unwrap(), 0);
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repair_accepts_a_patched_body() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| {
+            b.compression(CompressionAlgo::NONE)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let pos = a.stored_position(&a.lookup_file("/a.txt".as_ref()).unwrap());
+        patch_file(&out_file, pos, b"A");
+
+        let mut a = read_archive(&out_file).unwrap();
+        assert!(a.check_archive_quick().is_err());
+        assert_eq!(a.repair_checksums().unwrap(), 1);
+        assert!(a.check_body().unwrap());
+        assert!(a.check_archive_quick().is_ok());
+        assert!(a.check_archive().is_ok());
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"Alpha");
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn repair_refuses_data_that_does_not_decompress() {
+        let data = vec![b'x'; 4096];
+        let (dir, out_file) = pack_files(&[("a.txt", &data)], |b| {
+            b.compression(CompressionAlgo::ZSTD)
+        });
+        let a = read_archive(&out_file).unwrap();
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        let pos = a.stored_position(&f) + f.index_size / 2;
+        patch_file(&out_file, pos, &[0xff; 4]);
+
+        let mut a = read_archive(&out_file).unwrap();
+        let err = a.repair_checksums().unwrap_err().to_string();
+        assert!(err.contains("cannot repair /a.txt"), "{err}");
+        assert!(a.check_archive_quick().is_err());
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn joins_volumes_into_one_archive_reading_the_same() {
+        let data: Vec<u8> = (0..6000u32).map(|i| (i % 253) as u8).collect();
+        let (dir, out_file) = pack_files(&[("a.bin", &data), ("b.txt", b"beta")], |b| {
+            b.compression(CompressionAlgo::NONE).volume_size(1024)
+        });
+        let first = volume::volume_path(&out_file, 0);
+        let count = read_archive(&first).unwrap().header.volumes.unwrap().count;
+        let joined = format!("{out_file}.joined");
+
+        join_volumes(&first, &joined).unwrap();
+        let a = read_archive(&joined).unwrap();
+        assert!(a.header.volumes.is_none());
+        assert!(matches!(a.source, source::ArchiveSource::Single(_)));
+        assert_eq!(a.read_file("/a.bin").unwrap(), data);
+        assert_eq!(a.read_file("/b.txt").unwrap(), b"beta");
+        assert!(a.check_archive_deep().is_ok());
+
+        let _ = fs::remove_file(&joined);
+        for i in 0..count {
+            let _ = fs::remove_file(volume::volume_path(&out_file, i));
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn edits_the_name_in_place() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b.name("before"));
+        let mut a = read_archive(&out_file).unwrap();
+        let offset = a.lookup_file("/a.txt".as_ref()).unwrap().offset();
+
+        a.set_info(Some("after"), Some("now described")).unwrap();
+        a.set_metadata("owner", "ops").unwrap();
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!(a.name(), "after");
+        assert_eq!(a.description(), "now described");
+        assert_eq!(
+            a.header.metadata.get("owner").map(String::as_str),
+            Some("ops")
+        );
+        assert_eq!(a.lookup_file("/a.txt".as_ref()).unwrap().offset(), offset);
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        assert!(a.check_archive().is_ok());
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stores_headers_in_their_smaller_representation() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let data = fs::read(&out_file).unwrap();
+        let a = crate::read_archive(&out_file).unwrap();
+        let json = serde_json::to_vec(&a.header).unwrap();
+        for algo in [CompressionAlgo::ZSTD, CompressionAlgo::LZ4] {
+            let compressed = compression::compress_versioned(
+                &json,
+                &algo,
+                compression::DEFAULT_LEVEL,
+                FORMAT_VERSION,
+            )
+            .unwrap();
+            let (encoded, used) = encode_header(&a.header, &algo, FORMAT_VERSION);
+            assert_eq!(encoded.len(), compressed.len().min(json.len()));
+            assert_eq!(used.tag() == algo.tag(), compressed.len() < json.len());
+        }
+        let header_size = u64::from_le_bytes(data[5..13].try_into().unwrap());
+        assert_eq!(header_size, a.header_size());
+
+        // An uncompressed header is read as it is stored
+        let mut plain = data[..3].to_vec();
+        plain.push(FORMAT_VERSION);
+        plain.push(CompressionAlgo::NONE.tag());
+        plain.extend_from_slice(&(json.len() as u64).to_le_bytes());
+        plain.extend_from_slice(&crc32fast::hash(&json).to_le_bytes());
+        plain.extend_from_slice(&json);
+        plain.extend_from_slice(&data[(PREFIX_SIZE + header_size) as usize..]);
+        let a = crate::read_archive_from_bytes(plain).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn open_rw_edits_are_saved_at_once() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b.name("old"));
+        let mut a = open_rw(&out_file).unwrap();
+        assert!(!a.is_modified());
+        a.set_info(Some("new"), Some("described"));
+        a.set_metadata("owner", "me");
+        a.set_comment("/a.txt", "first file").unwrap();
+        assert!(a.set_comment("/missing", "x").is_err());
+        assert!(a.is_modified());
+        // Reads see the archive as saved until then
+        assert_eq!(a.name(), "old");
+        assert_eq!(a.pending_header().name, "new");
+        assert_eq!(read_archive(&out_file).unwrap().name(), "old");
+
+        a.save().unwrap();
+        assert!(!a.is_modified());
+        let a = read_archive(&out_file).unwrap();
+        assert_eq!((a.name(), a.description()), ("new", "described"));
+        assert_eq!(
+            a.header.metadata.get("owner").map(String::as_str),
+            Some("me")
+        );
+        let f = a.lookup_file("/a.txt".as_ref()).unwrap();
+        assert_eq!(f.comment.as_deref(), Some("first file"));
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+
+        let mut permissions = fs::metadata(&out_file).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&out_file, permissions).unwrap();
+        assert!(open_rw(&out_file).is_err());
+        let _ = fs::remove_file(&out_file);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn copies_entries_between_archives_as_stored() {
+        let data = b"compressible ".repeat(1024);
+        let (src_dir, src_file) =
+            pack_files(&[("sub/deep/a.txt", &data), ("b.txt", b"beta")], |b| {
+                b.compression(CompressionAlgo::ZSTD)
+            });
+        let (dst_dir, dst_file) = pack_files(&[("b.txt", b"other")], |b| {
+            b.compression(CompressionAlgo::LZ4)
+        });
+        let src = read_archive(&src_file).unwrap();
+        let mut dst = read_archive(&dst_file).unwrap();
+
+        dst.copy_entry_from(&src, "/sub/deep/a.txt").unwrap();
+        let err = dst.copy_entry_from(&src, "/b.txt").unwrap_err().to_string();
+        assert!(err.contains("b.txt"), "{err}");
+        assert!(dst.copy_entry_from(&src, "/missing").is_err());
+
+        let dst = read_archive(&dst_file).unwrap();
+        assert_eq!(dst.read_file("/sub/deep/a.txt").unwrap(), data);
+        assert_eq!(dst.read_file("/b.txt").unwrap(), b"other");
+        let copied = dst.lookup_file("/sub/deep/a.txt".as_ref()).unwrap();
+        assert_eq!(copied.compression.to_string(), "zstd");
+        assert_eq!(
+            dst.read_raw("/sub/deep/a.txt").unwrap(),
+            src.read_raw("/sub/deep/a.txt").unwrap()
+        );
+        dst.check_archive_deep().unwrap();
+        let _ = std::fs::remove_file(&src_file);
+        let _ = std::fs::remove_file(&dst_file);
+        let _ = std::fs::remove_dir_all(&src_dir);
+        let _ = std::fs::remove_dir_all(&dst_dir);
+    }
+
+    #[test]
+    fn recompresses_stored_files_without_changing_them() {
+        let text = b"compressible ".repeat(4096);
+        let files: &[(&str, &[u8])] =
+            &[("a.txt", &text), ("sub/b.txt", b"beta"), ("sub/empty", b"")];
+        let (dir, out_file) = pack_files(files, |b| b.compression(CompressionAlgo::NONE));
+        let mut a = read_archive(&out_file).unwrap();
+        let manifest = a.manifest();
+
+        let (before, after) = a.recompress(CompressionAlgo::ZSTD, 3).unwrap();
+        assert_eq!(before, text.len() as u64 + 4);
+        assert!(after < before, "{after} >= {before}");
+
+        let mut a = read_archive(&out_file).unwrap();
+        for (name, data) in files {
+            let path = format!("/{name}");
+            assert_eq!(&a.read_file(&path).unwrap(), data);
+            let f = a.lookup_file(path.as_ref()).unwrap();
+            assert_eq!(f.compression.to_string(), "zstd");
+        }
+        assert_eq!(a.manifest(), manifest);
+        a.check_archive_deep().unwrap();
+        assert!(a.recompress(CompressionAlgo::ZSTD, 100).is_err());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stages_writes_in_the_temp_dir_or_next_to_the_output() {
+        let (dir, out_file) = pack_files(&[("a.txt", b"alpha")], |b| b);
+        let staging = crate::testing::scratch_dir();
+        let (_, tmp) = create_temp(&out_file, Some(&staging)).unwrap();
+        assert_eq!(tmp.parent().unwrap(), staging);
+        let _ = std::fs::remove_file(&tmp);
+        let (_, tmp) = create_temp(&out_file, Some(&staging.join("missing"))).unwrap();
+        assert_eq!(tmp.to_str().unwrap(), format!("{out_file}.tmp"));
+        let _ = std::fs::remove_file(&tmp);
+
+        for temp_dir in [staging.clone(), staging.join("missing")] {
+            let packed = format!("{out_file}.packed");
+            crate::QZArchiveBuilder::new(dir.to_str().unwrap(), &packed)
+                .temp_dir(&temp_dir)
+                .create()
+                .unwrap();
+            let mut a = read_archive(&packed).unwrap();
+            a.set_temp_dir(&temp_dir);
+            a.set_info(Some("renamed"), None).unwrap();
+            let a = read_archive(&packed).unwrap();
+            assert_eq!(a.header.name, "renamed");
+            assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+            let _ = std::fs::remove_file(&packed);
+        }
+        // Nothing is left behind
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+        assert!(!std::path::Path::new(&format!("{out_file}.packed.tmp")).exists());
+        let _ = std::fs::remove_file(&out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&staging);
+    }
+}
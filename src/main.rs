@@ -1,6 +1,13 @@
 use clap::arg_enum;
 use clap::{App, Arg};
-use qz::read_archive;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "fuse")]
+mod mount;
+#[cfg(feature = "sfx")]
+mod sfx;
 
 arg_enum! {
     enum Compression {
@@ -10,232 +17,2302 @@ arg_enum! {
     }
 }
 
-fn main() {
-    let args = App::new("QZip")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author("JMARyA <jmarya0@icloud.com>")
-        .about("QZip Format")
-        .subcommand(
-            App::new("new")
-                .about("create new .qz file")
-                .arg(
-                    Arg::with_name("archive")
-                        .required(true)
-                        .value_name("ARCHIVE")
-                        .help("Archive Filename"),
-                )
-                .arg(
-                    Arg::with_name("target")
-                        .required(true)
-                        .value_name("TARGET")
-                        .help("directory to pack"),
-                )
-                .arg(
-                    Arg::with_name("name")
-                        .short("n")
-                        .long("name")
-                        .help("name of the archive")
-                        .value_name("NAME"),
-                )
-                .arg(
-                    Arg::with_name("desc")
-                        .short("d")
-                        .long("description")
-                        .help("path to text file containing a description")
-                        .value_name("DESCRIPTION_FILE"),
-                )
-                .arg(
-                    Arg::with_name("compression")
-                        .short("c")
-                        .long("compression")
-                        .help("compression to use")
-                        .possible_values(&Compression::variants())
-                        .value_name("COMPRESSION")
-                        .case_insensitive(true),
-                ),
-        )
-        .subcommand(
-            App::new("ls")
-                .about("list contents of .qz file")
-                .arg(
-                    Arg::with_name("archive")
-                        .required(true)
-                        .value_name("ARCHIVE")
-                        .help("Archive File"),
-                )
-                .arg(
-                    Arg::with_name("path")
-                        .help("list files at specified path")
-                        .default_value("/")
-                        .value_name("PATH")
-                        .required(false),
-                ),
-        )
-        .subcommand(
-            App::new("extract")
-                .about("extract a .qz file")
-                .arg(
-                    Arg::with_name("archive")
-                        .required(true)
-                        .value_name("ARCHIVE")
-                        .help("Archive Filename"),
-                )
-                .arg(
-                    Arg::with_name("target directory")
-                        .short("d")
-                        .long("destination")
-                        .required(false)
-                        .value_name("DESTINATION")
-                        .help("directory to unpack"),
-                )
-                .arg(
-                    Arg::with_name("path")
-                        .required(false)
-                        .value_name("PATH")
-                        .help("specific path to be unpacked"),
-                ),
+// Split a `KEY=VALUE` argument, exiting on malformed input
+fn split_pair(pair: &str) -> (&str, &str) {
+    match pair.split_once('=') {
+        Some(pair) => pair,
+        None => {
+            eprintln!("Invalid argument '{pair}', expected KEY=VALUE");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Exit if `--xattrs` was given to a build without extended attribute support
+fn check_xattrs_arg(cmd: &clap::ArgMatches) {
+    if cmd.is_present("xattrs") && !cfg!(feature = "xattr") {
+        eprintln!("This build of qz does not support extended attributes");
+        std::process::exit(1);
+    }
+}
+
+// Parse the `--recovery` argument, exiting on malformed input or missing support
+fn recovery_arg(cmd: &clap::ArgMatches) -> Option<usize> {
+    let blocks = cmd.value_of("recovery")?;
+    if !cfg!(feature = "recovery") {
+        eprintln!("This build of qz does not support recovery records");
+        std::process::exit(1);
+    }
+    match blocks.parse() {
+        Ok(blocks) => Some(blocks),
+        Err(_) => {
+            eprintln!("Invalid number of recovery blocks: {blocks}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Parse the `--buffer-size` argument, exiting on malformed input
+fn buffer_size_arg(cmd: &clap::ArgMatches) -> Option<usize> {
+    let size = cmd.value_of("buffer size")?;
+    match size.parse() {
+        Ok(size) => Some(size),
+        Err(_) => {
+            eprintln!("Invalid buffer size '{size}'");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Parse the `--cache-size` argument, exiting on malformed input
+#[cfg(feature = "fuse")]
+fn cache_size_arg(cmd: &clap::ArgMatches) -> Option<usize> {
+    let size = cmd.value_of("cache size")?;
+    match size.parse() {
+        Ok(size) => Some(size),
+        Err(_) => {
+            eprintln!("Invalid cache size '{size}'");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Description from `--description-text`, else read from the `--description` file,
+// exiting if that fails
+fn description_arg(cmd: &clap::ArgMatches) -> Option<String> {
+    if let Some(text) = cmd.value_of("desc text") {
+        return Some(text.to_string());
+    }
+    let desc_file = cmd.value_of("desc")?;
+    match std::fs::read_to_string(desc_file) {
+        Ok(description) => Some(description),
+        Err(_) => {
+            eprintln!("Failed to read description file");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Archive path given on the command line, with a leading slash and no repeated or
+// trailing ones
+fn archive_path(path: &str) -> String {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    format!("/{}", parts.join("/"))
+}
+
+// Archive holding `path` of `a` and the path within it, descending into the
+// archives stored as files along the way
+fn nested_path(a: qz::QZArchive, path: &str) -> Result<(qz::QZArchive, String), String> {
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    for i in 1..=parts.len() {
+        let outer = format!("/{}", parts[..i].join("/"));
+        match a.get_entry(&outer) {
+            Ok(qz::QZEntry::File(f)) if f.nested => {
+                let inner = a.open_nested(&outer).map_err(|err| err.to_string())?;
+                return nested_path(inner, &parts[i..].join("/"));
+            }
+            Ok(qz::QZEntry::Dir(_)) => {}
+            _ => break,
+        }
+    }
+    Ok((a, archive_path(path)))
+}
+
+// Directory for temporary files, `--temp-dir` or the system's
+fn temp_dir_arg(cmd: &clap::ArgMatches) -> std::path::PathBuf {
+    match cmd.value_of("temp dir") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => std::env::temp_dir(),
+    }
+}
+
+// Whether the `verified` record of an earlier `qz test --remember` vouches for a check of
+// the archive with `fingerprint`, a deep check covering the stored bytes as well
+fn covers_check(verified: &str, fingerprint: &str, deep: bool) -> bool {
+    match verified.trim().strip_prefix(fingerprint) {
+        Some(" deep") => true,
+        Some(" stored") => !deep,
+        _ => false,
+    }
+}
+
+// Exit code of `qz test` for `damaged` files, capped at what an exit code can hold
+fn damaged_exit_code(damaged: usize) -> i32 {
+    damaged.min(255) as i32
+}
+
+// Line of `qz ls` for `entry` at `path`, `long` adding the compression algorithm and
+// checksum of files, symlink targets and comments
+fn listing_line(path: &str, entry: &qz::QZEntry, long: bool) -> String {
+    match entry {
+        qz::QZEntry::Dir(_) if long => format!("-\t-\t-\t{path}"),
+        qz::QZEntry::Dir(_) => path.to_string(),
+        qz::QZEntry::File(file) if long => {
+            let comment = match &file.comment {
+                Some(comment) => format!("\t{comment}"),
+                None => String::new(),
+            };
+            let path = match &file.symlink {
+                Some(target) => format!("{path} -> {target}"),
+                None => path.to_string(),
+            };
+            format!(
+                "{0}\t{1}\t{2:08x}\t{path}{comment}",
+                size(file.index_size),
+                file.compression,
+                file.checksum
+            )
+        }
+        qz::QZEntry::File(file) => format!("{0}\t{path}", size(file.index_size)),
+    }
+}
+
+// Create a file in `dir` under a random name, failing rather than opening anything
+// already there so no one can point it elsewhere beforehand
+fn create_temp_file(
+    dir: &std::path::Path,
+    prefix: &str,
+) -> std::io::Result<(std::path::PathBuf, std::fs::File)> {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut attempts = 0;
+    loop {
+        // Seeded from the system's randomness, every state hashes differently
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let path = dir.join(format!("{prefix}-{:016x}.qz", hasher.finish()));
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists && attempts < 16 => {
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Copy the archive staged at `path` to `out`, removing it whether that succeeds or not
+fn send_staged(path: &std::path::Path, out: &mut impl std::io::Write) -> std::io::Result<u64> {
+    let res = std::fs::File::open(path).and_then(|mut f| std::io::copy(&mut f, out));
+    let _ = std::fs::remove_file(path);
+    res
+}
+
+// Buffer the archive streamed from `input` in a temporary file in `dir`, which is
+// removed right after buffering, the open handle keeps it readable
+fn buffer_archive(
+    dir: &std::path::Path,
+    input: &mut impl std::io::Read,
+) -> std::io::Result<std::fs::File> {
+    let (tmp, mut file) = create_temp_file(dir, "qz-stdin")?;
+    let res = std::io::copy(input, &mut file);
+    let _ = std::fs::remove_file(&tmp);
+    res.map(|_| file)
+}
+
+// Open the archive piped to stdin
+//
+// Entries are read at arbitrary offsets, so the stream is buffered in a temporary file
+// first, see `buffer_archive`.
+fn read_stdin_archive(cmd: &clap::ArgMatches) -> Result<qz::QZArchive, qz::errors::ReadError> {
+    let dir = temp_dir_arg(cmd);
+    match buffer_archive(&dir, &mut std::io::stdin().lock()) {
+        Ok(file) => qz::read_archive_from_file(file),
+        Err(err) => Err(qz::errors::ReadError::new(&format!(
+            "failed to buffer stdin in {}: {err}",
+            dir.display()
+        ))),
+    }
+}
+
+// Open an archive, `-` reading it from stdin, warning if a newer qz wrote it
+// or failing with `--strict`
+fn read_archive(
+    cmd: &clap::ArgMatches,
+    path: &str,
+) -> Result<qz::QZArchive, qz::errors::ReadError> {
+    let mut a = match path {
+        "-" => read_stdin_archive(cmd)?,
+        _ => qz::read_archive(path)?,
+    };
+    if let Some(dir) = cmd.value_of("temp dir") {
+        a.set_temp_dir(dir);
+    }
+    if let Err(err) = a.check_version() {
+        if cmd.is_present("strict") {
+            return Err(err);
+        }
+        eprintln!("Warning: {err}");
+    }
+    Ok(a)
+}
+
+// Parse the global `--threads` argument, exiting on malformed input, 0 if not given
+fn threads_arg(cmd: &clap::ArgMatches) -> usize {
+    count_arg(cmd, "threads")
+}
+
+// Number of threads reading or writing files given by `--io-threads`, 0 if not given
+fn io_threads_arg(cmd: &clap::ArgMatches) -> usize {
+    count_arg(cmd, "io threads")
+}
+
+// Thread count of the option `name`, exiting on anything but a positive number
+fn count_arg(cmd: &clap::ArgMatches, name: &str) -> usize {
+    let Some(threads) = cmd.value_of(name) else {
+        return 0;
+    };
+    match threads.parse() {
+        Ok(threads) if threads > 0 => threads,
+        _ => {
+            eprintln!("Invalid number of threads '{threads}'");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Bar of the bytes processed, showing the files done as its message
+struct ProgressBar {
+    bar: indicatif::ProgressBar,
+    files: AtomicU64,
+    done: AtomicU64,
+    // Whether warnings are printed, not with `--quiet`
+    warnings: bool,
+    // Whether every file left out is printed, with `--verbose`
+    verbose: bool,
+}
+
+impl ProgressBar {
+    fn update(&self) {
+        self.bar.set_message(format!(
+            "{}/{} files",
+            self.done.load(Ordering::Relaxed),
+            self.files.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+impl qz::ProgressSink for ProgressBar {
+    fn add_work(&self, files: u64, bytes: u64) {
+        self.files.fetch_add(files, Ordering::Relaxed);
+        self.bar.inc_length(bytes);
+        self.update();
+    }
+
+    fn file_done(&self, _path: &str, bytes: u64) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+        self.bar.inc(bytes);
+        self.update();
+    }
+
+    fn warning(&self, message: &str) {
+        if self.warnings {
+            self.bar.suspend(|| eprintln!("Warning: {message}"));
+        }
+    }
+
+    fn skipped(&self, path: &str, reason: &qz::SkipReason) {
+        if self.verbose {
+            self.bar.suspend(|| eprintln!("Skipping {path}, {reason}"));
+        } else if reason.is_unexpected() {
+            self.warning(&format!("Skipping {path}, {reason}"));
+        }
+    }
+}
+
+// Progress bar for `cmd`, none with `--quiet` or when stdout is no terminal
+fn progress_bar(cmd: &clap::ArgMatches) -> Option<Arc<ProgressBar>> {
+    if cmd.is_present("quiet") || !std::io::stdout().is_terminal() {
+        return None;
+    }
+    let bar = indicatif::ProgressBar::new(0).with_style(
+        indicatif::ProgressStyle::with_template(
+            "{wide_bar} {decimal_bytes}/{decimal_total_bytes} {msg} ({eta})",
         )
-        .subcommand(
-            App::new("info").about("list archive info").arg(
+        .unwrap(),
+    );
+    Some(Arc::new(ProgressBar {
+        bar,
+        files: AtomicU64::new(0),
+        done: AtomicU64::new(0),
+        warnings: true,
+        verbose: cmd.is_present("verbose"),
+    }))
+}
+
+// Progress bar that is never drawn, counting files and bytes for statistics
+// and printing warnings unless `--quiet` is given
+fn hidden_progress_bar(cmd: &clap::ArgMatches) -> Arc<ProgressBar> {
+    Arc::new(ProgressBar {
+        bar: indicatif::ProgressBar::hidden(),
+        files: AtomicU64::new(0),
+        done: AtomicU64::new(0),
+        warnings: !cmd.is_present("quiet"),
+        verbose: cmd.is_present("verbose"),
+    })
+}
+
+// Sink for `cmd`, the progress bar if one is shown
+fn progress_sink(bar: &Option<Arc<ProgressBar>>, cmd: &clap::ArgMatches) -> Arc<ProgressBar> {
+    match bar {
+        Some(bar) => bar.clone(),
+        None => hidden_progress_bar(cmd),
+    }
+}
+
+// Print `message` confirming success, unless `--quiet` is given
+fn status(cmd: &clap::ArgMatches, message: &str) {
+    write_status(&mut std::io::stdout(), cmd, message);
+}
+
+// `status` writing to `out` instead of stdout
+fn write_status(out: &mut impl std::io::Write, cmd: &clap::ArgMatches, message: &str) {
+    if !cmd.is_present("quiet") {
+        let _ = writeln!(out, "{message}");
+    }
+}
+
+// Remove the progress bar before printing results
+fn finish_progress(bar: &Option<Arc<ProgressBar>>) {
+    if let Some(bar) = bar {
+        bar.bar.finish_and_clear();
+    }
+}
+
+// Lines showing the entries of `a` as a tree like `tree` does, with their uncompressed sizes
+fn tree_lines(a: &qz::QZArchive) -> Vec<String> {
+    let entries = a.entries();
+    let depth = |path: &str| path.matches('/').count();
+
+    // Whether each entry is the last one in its directory, found walking backwards
+    let mut last = vec![false; entries.len()];
+    let mut followed: Vec<bool> = vec![];
+    for (i, (path, _)) in entries.iter().enumerate().rev() {
+        let d = depth(path);
+        followed.resize(d + 1, false);
+        last[i] = !followed[d];
+        followed[d] = true;
+    }
+
+    let (_, total) = a.dir_size("/").unwrap();
+    let mut lines = vec![format!("/ ({})", size(total))];
+    // Per level above the current entry whether its directory has more entries after it
+    let mut open: Vec<bool> = vec![];
+    for ((path, e), last) in entries.iter().zip(last) {
+        let d = depth(path);
+        open.truncate(d - 1);
+        let indent: String = open
+            .iter()
+            .map(|more| if *more { "│   " } else { "    " })
+            .collect();
+        let branch = if last { "└── " } else { "├── " };
+        let line = match e {
+            qz::QZEntry::Dir(dir) => {
+                let (_, original) = a.dir_size(path).unwrap();
+                format!("{}/ ({})", dir.name, size(original))
+            }
+            qz::QZEntry::File(f) => match &f.symlink {
+                Some(target) => format!("{} -> {target}", f.name),
+                None => {
+                    let original = f.original_size.unwrap_or(f.index_size);
+                    format!("{} ({})", f.name, size(original))
+                }
+            },
+        };
+        lines.push(format!("{indent}{branch}{line}"));
+        open.push(!last);
+    }
+    lines
+}
+
+fn size(bytes: u64) -> String {
+    qz::human_size(bytes, qz::SizeUnits::Decimal)
+}
+
+// Bytes per second for `bytes` processed in `time`
+fn throughput(bytes: u64, time: std::time::Duration) -> String {
+    if time.is_zero() {
+        return "-".to_string();
+    }
+    format!("{}/s", size((bytes as f64 / time.as_secs_f64()) as u64))
+}
+
+fn ratio(input: u64, stored: u64) -> String {
+    if input == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", stored as f64 / input as f64 * 100.0)
+}
+
+// What `new --verbose` reports was packed and left out
+fn format_summary(stats: &qz::PackStats) -> String {
+    [
+        format!("Files: {}", stats.files),
+        format!("Directories: {}", stats.dirs),
+        format!("Hard links: {}", stats.hard_links),
+        format!("Left out: {}", stats.skipped),
+        format!("Unreadable: {}", stats.unreadable),
+    ]
+    .join("\n")
+}
+
+fn format_stats(stats: &qz::PackStats) -> String {
+    let mut lines = vec![
+        format_summary(stats),
+        format!("Input size: {}", size(stats.input_size)),
+        format!("Output size: {}", size(stats.output_size)),
+        format!("Ratio: {}", ratio(stats.input_size, stats.output_size)),
+    ];
+    if stats.dictionary_size > 0 {
+        lines.push(format!("Dictionary: {}", size(stats.dictionary_size)));
+    }
+    for (algo, s) in &stats.algorithms {
+        lines.push(format!(
+            "  {algo}: {} files, {} -> {} ({})",
+            s.files,
+            size(s.input_size),
+            size(s.stored_size),
+            ratio(s.input_size, s.stored_size)
+        ));
+    }
+    lines.push(format!("Elapsed: {:.2?}", stats.elapsed));
+    lines.push(format!(
+        "  compressing: {:.2?} ({})",
+        stats.compress_time,
+        throughput(stats.input_size, stats.compress_time)
+    ));
+    if !stats.write_time.is_zero() {
+        lines.push(format!(
+            "  writing: {:.2?} ({})",
+            stats.write_time,
+            throughput(stats.output_size, stats.write_time)
+        ));
+    }
+    if !stats.verify_time.is_zero() {
+        lines.push(format!("  verifying: {:.2?}", stats.verify_time));
+    }
+    lines.join("\n")
+}
+
+// `--threads`, for the subcommands compressing, verifying or extracting in parallel
+fn threads_flag() -> Arg<'static, 'static> {
+    Arg::with_name("threads")
+        .long("threads")
+        .value_name("N")
+        .takes_value(true)
+        .alias("compression-threads")
+        .help("use at most N threads for compression, verification and extraction, 1 runs sequentially")
+}
+
+// `--io-threads`, for the subcommands reading or writing many files
+fn io_threads_flag() -> Arg<'static, 'static> {
+    Arg::with_name("io threads")
+        .long("io-threads")
+        .value_name("N")
+        .takes_value(true)
+        .help("read or write at most N files at once when packing or extracting, all threads by default")
+}
+
+// `--strict`, for the subcommands opening an archive
+fn strict_flag() -> Arg<'static, 'static> {
+    Arg::with_name("strict")
+        .long("strict")
+        .help("refuse archives written by a newer version of qz")
+}
+
+// `--temp-dir`, for the subcommands opening or writing an archive
+fn temp_dir_flag() -> Arg<'static, 'static> {
+    Arg::with_name("temp dir")
+        .long("temp-dir")
+        .value_name("DIR")
+        .takes_value(true)
+        .help("write archives to a temporary file in DIR before moving them into place, instead of next to them")
+}
+
+// Command line interface, each subcommand taking only the flags it uses
+fn app() -> App<'static, 'static> {
+    App::new("QZip")
+    .version(env!("CARGO_PKG_VERSION"))
+    .author("JMARyA <jmarya0@icloud.com>")
+    .about("QZip Format")
+    .arg(
+        Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .global(true)
+            .help("only print errors and requested output, no progress bars, warnings or confirmations"),
+    )
+    .subcommand(
+        App::new("new")
+            .about("create new .qz file")
+            .arg(threads_flag())
+            .arg(io_threads_flag())
+            .arg(
+                Arg::with_name("fail on special")
+                    .long("fail-on-special")
+                    .help("fail on fifos, sockets and device files instead of leaving them out"),
+            )
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive Filename"),
+            )
+            .arg(
+                Arg::with_name("target")
+                    .required_unless("files from")
+                    .multiple(true)
+                    .value_name("TARGET")
+                    .help("directory to pack, or several directories and files"),
+            )
+            .arg(
+                Arg::with_name("files from")
+                    .long("files-from")
+                    .value_name("FILE")
+                    .help("pack exactly the paths listed in FILE (- for stdin), one per line, relative to TARGET or the current directory"),
+            )
+            .arg(
+                Arg::with_name("name")
+                    .short("n")
+                    .long("name")
+                    .help("name of the archive")
+                    .value_name("NAME"),
+            )
+            .arg(
+                Arg::with_name("desc")
+                    .short("d")
+                    .long("description")
+                    .help("path to text file containing a description")
+                    .value_name("DESCRIPTION_FILE"),
+            )
+            .arg(
+                Arg::with_name("desc text")
+                    .long("description-text")
+                    .help("description given inline, takes precedence over --description")
+                    .value_name("TEXT"),
+            )
+            .arg(
+                Arg::with_name("compression")
+                    .short("c")
+                    .long("compression")
+                    .help("compression to use")
+                    .possible_values(&Compression::variants())
+                    .value_name("COMPRESSION")
+                    .case_insensitive(true),
+            )
+            .arg(
+                Arg::with_name("compression for")
+                    .long("compression-for")
+                    .help("compress files whose name matches PATTERN (* and ? as wildcards) with COMPRESSION, the first matching pattern applies")
+                    .value_name("PATTERN=COMPRESSION")
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("order by extension")
+                    .long("order-by-extension")
+                    .help("store files grouped by extension, so similar files lie next to each other"),
+            )
+            .arg(
+                Arg::with_name("order from")
+                    .long("order-from")
+                    .value_name("FILE")
+                    .conflicts_with("order by extension")
+                    .help("store the archive paths listed in FILE first and in that order, one per line"),
+            )
+            .arg(
+                Arg::with_name("no recursion")
+                    .long("no-recursion")
+                    .help("only pack the files directly in DIR, leaving out its subdirectories"),
+            )
+            .arg(
+                Arg::with_name("no hidden")
+                    .long("no-hidden")
+                    .help("leave out files and directories whose name starts with '.'"),
+            )
+            .arg(
+                Arg::with_name("skip errors")
+                    .long("skip-errors")
+                    .help("leave out files that cannot be read instead of failing"),
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .help("print every file left out and a summary of what was packed"),
+            )
+            .arg(
+                Arg::with_name("resume")
+                    .long("resume")
+                    .help("keep a checkpoint while packing, and continue from the one an interrupted run left"),
+            )
+            .arg(
+                Arg::with_name("reproducible")
+                    .long("reproducible")
+                    .help("store entries sorted by name, without owners or root name, for byte-identical archives of the same content"),
+            )
+            .arg(
+                Arg::with_name("text")
+                    .long("text")
+                    .help("store text files with CRLF line endings with LF, marking them to restore on extract"),
+            )
+            .arg(
+                Arg::with_name("relative symlinks")
+                    .long("relative-symlinks")
+                    .help("store absolute link targets within the packed directory as relative"),
+            )
+            .arg(
+                Arg::with_name("root name")
+                    .long("root-name")
+                    .value_name("NAME")
+                    .help("name to store the root directory as instead of the packed directory's"),
+            )
+            .arg(
+                Arg::with_name("base")
+                    .long("base")
+                    .value_name("DIR")
+                    .conflicts_with("files from")
+                    .help("store each TARGET at its path relative to DIR instead of under its own name"),
+            )
+            .arg(
+                Arg::with_name("level")
+                    .long("level")
+                    .value_name("LEVEL")
+                    .help("compression level, 1-22 for zstd and 1-12 for LZ4-HC"),
+            )
+            .arg(
+                Arg::with_name("preset")
+                    .long("preset")
+                    .alias("level-preset")
+                    .value_name("PRESET")
+                    .possible_values(qz::CompressionConfig::PRESETS)
+                    .case_insensitive(true)
+                    .help("start from tuned settings, fast (lz4), default (zstd 5) or max (zstd 19 with --long), other options adjust them"),
+            )
+            .arg(
+                Arg::with_name("lz4 hc")
+                    .long("lz4-hc")
+                    .help("use LZ4-HC for a better ratio with -c lz4, at the cost of speed"),
+            )
+            .arg(
+                Arg::with_name("long")
+                    .long("long")
+                    .help("let zstd match across long distances, for large files repeating content far apart"),
+            )
+            .arg(
+                Arg::with_name("window log")
+                    .long("window-log")
+                    .value_name("N")
+                    .help("let zstd refer back up to 2^N bytes (10 to 26)"),
+            )
+            .arg(
+                Arg::with_name("dictionary")
+                    .long("dictionary")
+                    .help("train a zstd dictionary shared by all files, for many small similar files"),
+            )
+            .arg(
+                Arg::with_name("dict")
+                    .long("dict")
+                    .value_name("FILE")
+                    .conflicts_with("dictionary")
+                    .help("compress with the zstd dictionary in FILE, e.g. from qz dict extract"),
+            )
+            .arg(
+                Arg::with_name("no header compression")
+                    .long("no-header-compression")
+                    .help("store the archive header uncompressed"),
+            )
+            .arg(
+                Arg::with_name("verify")
+                    .long("verify")
+                    .help("check the archive after creating it"),
+            )
+            .arg(
+                Arg::with_name("stats")
+                    .long("stats")
+                    .help("print statistics about the created archive"),
+            )
+            .arg(
+                Arg::with_name("xattrs")
+                    .long("xattrs")
+                    .help("store extended attributes (needs the xattr feature)"),
+            )
+            .arg(
+                Arg::with_name("recovery")
+                    .long("recovery")
+                    .value_name("BLOCKS")
+                    .help("store parity to recover up to BLOCKS damaged blocks out of every 16 (needs the recovery feature)"),
+            )
+            .arg(
+                Arg::with_name("metadata")
+                    .short("m")
+                    .long("metadata")
+                    .help("store a key-value pair in the archive header")
+                    .value_name("KEY=VALUE")
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("comment")
+                    .long("comment")
+                    .help("attach a comment to a file in the archive")
+                    .value_name("PATH=COMMENT")
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("buffer size")
+                    .long("buffer-size")
+                    .help("size of I/O buffers in bytes")
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("volume size")
+                    .long("volume-size")
+                    .help("split the archive into volumes of at most this many bytes")
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("chunk size")
+                    .long("chunk-size")
+                    .help("compress larger files in chunks of this many bytes, for faster random access")
+                    .value_name("BYTES"),
+            ),
+    )
+    .subcommand(
+        App::new("ls")
+            .about("list contents of .qz file")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
                 Arg::with_name("archive")
                     .required(true)
                     .value_name("ARCHIVE")
                     .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("path")
+                    .help("list files at specified path, which may lead into archives stored in the archive")
+                    .default_value("/")
+                    .value_name("PATH")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("long")
+                    .short("l")
+                    .help("show size, compression and checksum of files"),
+            )
+            .arg(
+                Arg::with_name("type")
+                    .long("type")
+                    .value_name("TYPE")
+                    .possible_values(&["f", "d", "l"])
+                    .help("only list files (f), directories (d) or symbolic links (l)"),
             ),
-        )
-        .subcommand(
-            App::new("test").about("test archive integrity").arg(
+    )
+    .subcommand(
+        App::new("du")
+            .about("show stored and uncompressed size of the entries of a directory in a .qz file")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
                 Arg::with_name("archive")
                     .required(true)
                     .value_name("ARCHIVE")
                     .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("path")
+                    .help("directory to show, or a single file")
+                    .default_value("/")
+                    .value_name("PATH")
+                    .required(false),
             ),
-        )
-        .get_matches();
+    )
+    .subcommand(
+        App::new("extract")
+            .about("extract a .qz file")
+            .arg(threads_flag())
+            .arg(io_threads_flag())
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive Filename"),
+            )
+            .arg(
+                Arg::with_name("target directory")
+                    .short("d")
+                    .long("destination")
+                    .required(false)
+                    .value_name("DESTINATION")
+                    .help("directory to unpack"),
+            )
+            .arg(
+                Arg::with_name("path")
+                    .required(false)
+                    .multiple(true)
+                    .value_name("PATH")
+                    .help("specific paths to be unpacked"),
+            )
+            .arg(
+                Arg::with_name("buffer size")
+                    .long("buffer-size")
+                    .help("size of I/O buffers in bytes")
+                    .value_name("BYTES"),
+            )
+            .arg(
+                Arg::with_name("strip components")
+                    .long("strip-components")
+                    .required(false)
+                    .value_name("NUMBER")
+                    .help("remove NUMBER leading components from extracted paths")
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("preserve owner")
+                    .long("preserve-owner")
+                    .help("restore file owners and groups (needs root)"),
+            )
+            .arg(
+                Arg::with_name("flatten")
+                    .long("flatten")
+                    .help("extract all files directly into the destination, numbering duplicate names"),
+            )
+            .arg(
+                Arg::with_name("verify written")
+                    .long("verify")
+                    .help("read every extracted file back and fail if it differs from what was written"),
+            )
+            .arg(
+                Arg::with_name("crlf")
+                    .long("crlf")
+                    .help("write text files packed with --text with CRLF line endings again"),
+            )
+            .arg(
+                Arg::with_name("resume")
+                    .long("resume")
+                    .help("skip files already extracted completely by an earlier run"),
+            )
+            .arg(
+                Arg::with_name("stats")
+                    .long("stats")
+                    .help("print the number of files and bytes extracted and the time taken"),
+            )
+            .arg(
+                Arg::with_name("xattrs")
+                    .long("xattrs")
+                    .help("restore extended attributes (needs the xattr feature)"),
+            ),
+    )
+    .subcommand(
+        App::new("info")
+            .about("list archive info")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("tree")
+                    .long("tree")
+                    .help("also print the directory structure with the size of every entry"),
+            ),
+    )
+    .subcommand(
+        App::new("manifest")
+            .about("print checksum, size and path of every file in a .qz file")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            ),
+    )
+    .subcommand(
+        App::new("set")
+            .about("change name or description of a .qz file")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("name")
+                    .short("n")
+                    .long("name")
+                    .help("name of the archive")
+                    .value_name("NAME"),
+            )
+            .arg(
+                Arg::with_name("desc")
+                    .short("d")
+                    .long("description")
+                    .help("path to text file containing a description")
+                    .value_name("DESCRIPTION_FILE"),
+            )
+            .arg(
+                Arg::with_name("desc text")
+                    .long("description-text")
+                    .help("description given inline, takes precedence over --description")
+                    .value_name("TEXT"),
+            ),
+    )
+    .subcommand(
+        App::new("meta")
+            .about("set header metadata of a .qz file, an empty value removes the key")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("metadata")
+                    .required(true)
+                    .multiple(true)
+                    .value_name("KEY=VALUE")
+                    .help("metadata to set"),
+            ),
+    )
+    .subcommand(
+        App::new("verify")
+            .about("compare a .qz file against a directory, e.g. after extracting it")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("dir")
+                    .required(true)
+                    .value_name("DIR")
+                    .help("directory to compare against"),
+            ),
+    )
+    .subcommand(
+        App::new("recompress")
+            .about("compress every file of a .qz file again with another algorithm")
+            .arg(threads_flag())
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("compression")
+                    .short("c")
+                    .long("compression")
+                    .help("compression to use")
+                    .possible_values(&Compression::variants())
+                    .value_name("COMPRESSION")
+                    .case_insensitive(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("level")
+                    .long("level")
+                    .value_name("LEVEL")
+                    .help("compression level, 1-22 for zstd"),
+            ),
+    )
+    .subcommand(
+        App::new("repair")
+            .about("recover damaged blocks of a .qz file from its recovery records and recompute the stored checksums from its content")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            ),
+    )
+    .subcommand(
+        App::new("bench")
+            .about("compare how a directory packs with every compression algorithm")
+            .arg(threads_flag())
+            .arg(
+                Arg::with_name("dir")
+                    .required(true)
+                    .value_name("DIR")
+                    .help("directory to pack"),
+            ),
+    )
+    .subcommand(
+        App::new("join")
+            .about("join a split archive into a single .qz file")
+            .arg(
+                Arg::with_name("volume")
+                    .required(true)
+                    .value_name("VOLUME")
+                    .help("first volume of the archive"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive Filename"),
+            ),
+    )
+    .subcommand(
+        App::new("header")
+            .about("print archive header as json")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            ),
+    )
+    .subcommand(
+        App::new("test")
+            .about("test archive integrity")
+            .arg(threads_flag())
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .after_help(
+                "EXIT CODES:\n    0        archive is ok\n    1-255    number of damaged files (capped at 255)\n    255      also if the archive could not be read, reported as 'Error reading archive'",
+            )
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("quick")
+                    .long("quick")
+                    .help("check the stored bytes in a single pass over the body while it is intact"),
+            )
+            .arg(
+                Arg::with_name("deep")
+                    .long("deep")
+                    .conflicts_with("quick")
+                    .help("decompress every file and check its content as well"),
+            )
+            .arg(
+                Arg::with_name("remember")
+                    .long("remember")
+                    .help("record a check that passed in ARCHIVE.verified and skip checking again while the archive is unchanged"),
+            )
+            .arg(
+                Arg::with_name("force")
+                    .long("force")
+                    .help("with --remember, check the archive even if it is unchanged since it last passed"),
+            ),
+    )
+    .subcommand(
+        App::new("index")
+            .about("write the header of a .qz file to a sidecar index, for reading single files over range requests")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Index to write, ARCHIVE with the extension .qzidx by default"),
+            ),
+    )
+    .subcommand(
+        App::new("fsck")
+            .about("check that the files of a .qz file neither overlap nor leave parts of its body unused")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            ),
+    )
+    .subcommand(
+        App::new("dict")
+            .about("work with the zstd dictionary of a .qz file")
+            .subcommand(
+                App::new("extract")
+                    .about("write the dictionary of an archive to a file, for qz new --dict")
+                    .arg(strict_flag())
+                    .arg(temp_dir_flag())
+                    .arg(
+                        Arg::with_name("archive")
+                            .required(true)
+                            .value_name("ARCHIVE")
+                            .help("Archive File"),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .required(true)
+                            .value_name("FILE")
+                            .help("File to write the dictionary to"),
+                    ),
+            ),
+    )
+    .subcommand(
+        App::new("sfx")
+            .about("build a self-extracting binary that unpacks a .qz file into the directory it is run in (needs the sfx feature)")
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .required(true)
+                    .value_name("FILE")
+                    .help("Executable to write"),
+            ),
+    )
+    .subcommand(
+        App::new("mount")
+            .about("mount a .qz file as a read-only directory until it is unmounted (needs the fuse feature)")
+            .arg(strict_flag())
+            .arg(temp_dir_flag())
+            .arg(
+                Arg::with_name("archive")
+                    .required(true)
+                    .value_name("ARCHIVE")
+                    .help("Archive File"),
+            )
+            .arg(
+                Arg::with_name("mountpoint")
+                    .required(true)
+                    .value_name("MOUNTPOINT")
+                    .help("Directory to mount the archive on"),
+            )
+            .arg(
+                Arg::with_name("cache size")
+                    .long("cache-size")
+                    .help("bytes of decompressed files to keep in memory")
+                    .value_name("BYTES"),
+            ),
+    )
+}
+
+fn main() {
+    // A self-extracting binary only unpacks what is appended to it
+    #[cfg(feature = "sfx")]
+    if let Some((exe, start, len)) = sfx::embedded() {
+        std::process::exit(sfx::run(&exe, start, len));
+    }
+
+    let args = app().get_matches();
 
     match args.subcommand() {
         ("info", Some(cmd)) => {
             let archive_file = cmd.value_of("archive").unwrap();
-            let a = read_archive(archive_file).unwrap();
-            println!("QZ Archive v.{}: \'{}\'", &a.header.version, &a.header.name);
-            println!("{}", &a.header.info);
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            print!("{}", a.header);
+            if cmd.is_present("tree") {
+                for line in tree_lines(&a) {
+                    println!("{line}");
+                }
+            }
         }
-        ("ls", Some(cmd)) => {
+        ("manifest", Some(cmd)) => {
             let archive_file = cmd.value_of("archive").unwrap();
-            let path = format!("/{}", cmd.value_of("path").unwrap());
-            let path = path.replace("//", "/");
-            let a = read_archive(archive_file).unwrap();
-            println!("QZ Archive \'{}\' : {}", &a.header.name, &path);
-            let dir_content = a.ls(&path).unwrap();
-            for f in dir_content {
-                let path = std::path::Path::new(&path).join(f);
-                let path = path.to_str().unwrap();
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            print!("{}", a.manifest());
+        }
+        ("set", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let name = cmd.value_of("name");
+
+            let description = description_arg(cmd);
 
-                let info = a.get_entry(path).unwrap();
-                match info {
-                    qz::QZEntry::Dir(dir) => {
-                        println!("{path}");
+            let mut a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            if let Err(err) = a.set_info(name, description.as_deref()) {
+                eprintln!("Error writing archive: {err}");
+                std::process::exit(1);
+            }
+        }
+        ("meta", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let mut a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            for pair in cmd.values_of("metadata").unwrap() {
+                let (key, value) = split_pair(pair);
+                if let Err(err) = a.set_metadata(key, value) {
+                    eprintln!("Error writing archive: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("verify", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let dir = cmd.value_of("dir").unwrap();
+            let a = match read_archive(cmd, archive_file) {
+                Ok(a) => a,
+                Err(err) => {
+                    eprintln!("Error reading archive: {err}");
+                    std::process::exit(1);
+                }
+            };
+            match a.verify_against_dir(dir) {
+                Ok(differences) if differences.is_empty() => {
+                    status(cmd, &format!("{dir} matches the archive"))
+                }
+                Ok(differences) => {
+                    for d in &differences {
+                        println!("{d}");
+                    }
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Error comparing archive: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("recompress", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let compression: qz::CompressionAlgo =
+                match cmd.value_of("compression").unwrap().parse() {
+                    Ok(algo) => algo,
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1);
                     }
-                    qz::QZEntry::File(file) => {
-                        let size = file.index_size;
-                        println!("{0}B\t{path}", file_size::fit_4(size));
+                };
+            let level = match cmd.value_of("level") {
+                Some(level) => match level.parse() {
+                    Ok(level) => level,
+                    Err(_) => {
+                        eprintln!("Invalid compression level '{level}'");
+                        std::process::exit(1);
                     }
+                },
+                None => qz::compression::DEFAULT_LEVEL,
+            };
+            let mut a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            if let Err(err) = a.set_threads(threads_arg(cmd)) {
+                eprintln!("Error recompressing archive: {err}");
+                std::process::exit(1);
+            }
+            match a.recompress(compression, level) {
+                Ok((before, after)) => {
+                    let change = if after <= before {
+                        format!("saved {}", size(before - after))
+                    } else {
+                        format!("{} larger", size(after - before))
+                    };
+                    status(
+                        cmd,
+                        &format!("Recompressed {} to {}, {change}", size(before), size(after)),
+                    );
+                }
+                Err(err) => {
+                    eprintln!("Error recompressing archive: {err}");
+                    std::process::exit(1);
                 }
             }
         }
-        ("new", Some(cmd)) => {
-            let mut archive_file = String::from(cmd.value_of("archive").unwrap());
+        ("repair", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let mut a = match read_archive(cmd, archive_file) {
+                Ok(a) => a,
+                Err(err) => {
+                    eprintln!("Error reading archive: {err}");
+                    std::process::exit(1);
+                }
+            };
+            #[cfg(feature = "recovery")]
+            if a.header.recovery.is_some() {
+                match a.recover() {
+                    Ok(repaired) => status(cmd, &format!("Recovered {repaired} blocks")),
+                    Err(err) => {
+                        eprintln!("Error recovering archive: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            match a.repair_checksums() {
+                Ok(changed) => status(cmd, &format!("Repaired the checksums of {changed} files")),
+                Err(err) => {
+                    eprintln!("Error repairing archive: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("bench", Some(cmd)) => {
+            let dir = cmd.value_of("dir").unwrap();
+            let mut results = vec![];
+            for algo in [
+                qz::CompressionAlgo::NONE,
+                qz::CompressionAlgo::ZSTD,
+                qz::CompressionAlgo::LZ4,
+            ] {
+                // Nothing is written, the builder only needs an output name
+                let stats = qz::QZArchiveBuilder::new(dir, "")
+                    .compression(algo.clone())
+                    .threads(threads_arg(cmd))
+                    .measure();
+                match stats {
+                    Ok(stats) => results.push((algo, stats)),
+                    Err(err) => {
+                        eprintln!("Error packing {dir}: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            results.sort_by_key(|(_, stats)| stats.output_size);
 
-            if !archive_file.ends_with(".qz") {
-                archive_file = format!("{archive_file}.qz");
+            println!("{:<6} {:>8} {:>7} {:>10}", "algo", "size", "ratio", "time");
+            for (algo, stats) in &results {
+                println!(
+                    "{:<6} {:>8} {:>7} {:>10}",
+                    algo.to_string(),
+                    size(stats.output_size),
+                    ratio(stats.input_size, stats.output_size),
+                    format!("{:.2?}", stats.elapsed)
+                );
+            }
+        }
+        ("join", Some(cmd)) => {
+            let volume = cmd.value_of("volume").unwrap();
+            let archive_file = cmd.value_of("output").unwrap();
+            if let Err(err) = qz::join_volumes(volume, archive_file) {
+                eprintln!("Error joining volumes: {err}");
+                std::process::exit(1);
+            }
+        }
+        ("header", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            println!("{}", a.header_json());
+        }
+        ("ls", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let path = archive_path(cmd.value_of("path").unwrap());
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            let name = a.name().to_string();
+            let (a, inner) = nested_path(a, &path).unwrap_or_else(|err| {
+                eprintln!("Error reading nested archive: {err}");
+                std::process::exit(1);
+            });
+            let listing = match cmd.value_of("type") {
+                Some("f") => a.ls_filtered(&inner, qz::EntryKind::File),
+                Some("d") => a.ls_filtered(&inner, qz::EntryKind::Dir),
+                Some(_) => a.ls_filtered(&inner, qz::EntryKind::Symlink),
+                None => a.ls(&inner),
+            };
+            let dir_content = match listing {
+                Ok(content) => content,
+                Err(err) => {
+                    match err {
+                        qz::errors::ListingError::NotFound => {
+                            eprintln!("no such directory: {path}")
+                        }
+                        qz::errors::ListingError::IsFile => eprintln!("not a directory: {path}"),
+                        qz::errors::ListingError::NotADirectory(file) => {
+                            eprintln!("not a directory: {file}")
+                        }
+                        qz::errors::ListingError::InvalidPath => eprintln!("invalid path: {path}"),
+                        qz::errors::ListingError::Other(err) => {
+                            eprintln!("Error listing {path}: {err}")
+                        }
+                    }
+                    std::process::exit(1);
+                }
+            };
+            let title = match name.as_str() {
+                "" => "QZ Archive (unnamed)".to_string(),
+                name => format!("QZ Archive '{name}'"),
+            };
+            status(cmd, &format!("{title} : {path}"));
+            let long = cmd.is_present("long");
+            for f in dir_content {
+                let info = a.get_entry(std::path::Path::new(&inner).join(&f)).unwrap();
+                let path = std::path::Path::new(&path).join(f);
+                println!("{}", listing_line(path.to_str().unwrap(), &info, long));
             }
+        }
+        ("du", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let path = archive_path(cmd.value_of("path").unwrap());
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            let children = match a.get_entry(&path) {
+                Ok(qz::QZEntry::Dir(_)) => a.ls(&path).unwrap_or_default(),
+                Ok(qz::QZEntry::File(_)) => vec![],
+                Err(err) => {
+                    match err {
+                        qz::errors::EntryError::NothingFound => eprintln!("not found: {path}"),
+                        qz::errors::EntryError::NotADirectory(file) => {
+                            eprintln!("not a directory: {file}")
+                        }
+                        qz::errors::EntryError::PathError => eprintln!("invalid path: {path}"),
+                        qz::errors::EntryError::Other(err) => {
+                            eprintln!("Error reading {path}: {err}")
+                        }
+                    }
+                    std::process::exit(1);
+                }
+            };
 
-            let target = cmd.value_of("target").unwrap();
+            // Like du, entries come first and the total of `path` last
+            for child in children {
+                let child = std::path::Path::new(&path).join(child);
+                let (stored, original) = a.dir_size(&child).unwrap();
+                println!("{}\t{}\t{}", size(stored), size(original), child.display());
+            }
+            let (stored, original) = a.dir_size(&path).unwrap();
+            println!("{}\t{}\t{path}", size(stored), size(original));
+        }
+        ("new", Some(cmd)) => {
+            let mut archive_file = String::from(cmd.value_of("archive").unwrap());
+            let targets: Vec<&str> = cmd.values_of("target").unwrap_or_default().collect();
 
-            let mut name = std::path::Path::new(&archive_file)
-                .file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap();
+            // `-` writes to stdout, through a temporary file as the header goes first
+            let to_stdout = archive_file == "-";
+            let mut name = if to_stdout {
+                if cmd.is_present("volume size") {
+                    eprintln!("Archives written to stdout cannot be split into volumes");
+                    std::process::exit(1);
+                }
+                if cmd.is_present("resume") {
+                    eprintln!("Archives written to stdout cannot be resumed");
+                    std::process::exit(1);
+                }
+                let first = targets.first().map(|t| std::path::Path::new(*t));
+                first
+                    .and_then(|t| t.file_name())
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("archive")
+                    .to_string()
+            } else {
+                if !archive_file.ends_with(".qz") {
+                    archive_file = format!("{archive_file}.qz");
+                }
+                std::path::Path::new(&archive_file)
+                    .file_stem()
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            };
             let name_op = cmd.value_of("name");
 
             if let Some(name_op) = name_op {
-                name = name_op;
+                name = name_op.to_string();
             }
 
-            let desc_file = cmd.value_of("desc");
-            let mut description = String::new();
+            let description = description_arg(cmd).unwrap_or_default();
 
-            if let Some(desc_file) = desc_file {
-                let description_res = std::fs::read_to_string(desc_file);
-                if description_res.is_err() {
-                    println!("Failed to read description file");
-                    std::process::exit(1);
+            let mut config = cmd
+                .value_of("preset")
+                .and_then(qz::CompressionConfig::preset)
+                .unwrap_or_default();
+
+            if let Some(compression_option) = cmd.value_of("compression") {
+                match compression_option.parse() {
+                    Ok(algo) => {
+                        // The level of a preset belongs to its algorithm
+                        if std::mem::discriminant(&algo)
+                            != std::mem::discriminant(&config.algorithm)
+                        {
+                            config.level = None;
+                        }
+                        config.algorithm = algo;
+                    }
+                    Err(err) => {
+                        eprintln!("{err}");
+                        std::process::exit(1);
+                    }
                 }
-                description = description_res.unwrap();
             }
 
-            let compression_option = cmd.value_of("compression");
-            let mut compression = qz::CompressionAlgo::ZSTD;
-
-            if let Some(compression_option) = compression_option {
-                match compression_option {
-                    "none" => {
-                        compression = qz::CompressionAlgo::NONE;
+            config.lz4_hc |= cmd.is_present("lz4 hc");
+            config.train_dictionary = cmd.is_present("dictionary");
+            config.long_distance |= cmd.is_present("long");
+            if let Some(window_log) = cmd.value_of("window log") {
+                match window_log.parse() {
+                    Ok(log) => config.window_log = Some(log),
+                    Err(_) => {
+                        eprintln!("Invalid window log '{window_log}'");
+                        std::process::exit(1);
                     }
-                    "zstd" => {
-                        compression = qz::CompressionAlgo::ZSTD;
+                }
+            }
+            if let Some(overrides) = cmd.values_of("compression for") {
+                for pair in overrides {
+                    let (pattern, algo) = split_pair(pair);
+                    match algo.parse() {
+                        Ok(algo) => config.overrides.push((pattern.to_string(), algo)),
+                        Err(err) => {
+                            eprintln!("{err}");
+                            std::process::exit(1);
+                        }
                     }
-                    "lz4" => {
-                        compression = qz::CompressionAlgo::LZ4;
+                }
+            }
+            if let Some(level) = cmd.value_of("level") {
+                match level.parse() {
+                    Ok(level) => config.level = Some(level),
+                    Err(_) => {
+                        eprintln!("Invalid compression level '{level}'");
+                        std::process::exit(1);
                     }
-                    _ => {}
                 }
             }
+            if let Some(dict) = cmd.value_of("dict") {
+                match std::fs::read(dict) {
+                    Ok(dict) => config.dictionary = Some(dict),
+                    Err(err) => {
+                        eprintln!("Failed to read dictionary {dict}: {err}");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(chunk_size) = cmd.value_of("chunk size") {
+                match chunk_size.parse() {
+                    Ok(size) => config.chunk_size = Some(size).filter(|size| *size > 0),
+                    Err(_) => {
+                        eprintln!("Invalid chunk size '{chunk_size}'");
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let verify = cmd.is_present("verify");
+
+            let mut volume_size = None;
 
-            qz::create_archive(target, &archive_file, name, &description, compression);
+            if let Some(volume_size_option) = cmd.value_of("volume size") {
+                match volume_size_option.parse::<u64>() {
+                    Ok(size) => {
+                        volume_size = Some(size);
+                    }
+                    Err(_) => {
+                        eprintln!("Invalid volume size '{volume_size_option}'");
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            // Everything that can fail is read before a stdout archive is staged,
+            // so no exit below leaves the staged file behind
+            if cmd.is_present("files from") && targets.len() > 1 {
+                eprintln!("--files-from takes at most one TARGET directory");
+                std::process::exit(1);
+            }
+            let file_list = cmd.value_of("files from").map(|list| {
+                let list = if list == "-" {
+                    std::io::read_to_string(std::io::stdin())
+                } else {
+                    std::fs::read_to_string(list)
+                };
+                list.unwrap_or_else(|err| {
+                    eprintln!("Failed to read file list: {err}");
+                    std::process::exit(1);
+                })
+            });
+            let order = cmd.value_of("order from").map(|list| {
+                let list = std::fs::read_to_string(list).unwrap_or_else(|err| {
+                    eprintln!("Failed to read order list: {err}");
+                    std::process::exit(1);
+                });
+                list.lines()
+                    .map(|l| l.trim_end_matches('\r'))
+                    .filter(|l| !l.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            });
+            check_xattrs_arg(cmd);
+            let recovery = recovery_arg(cmd);
+            let buffer_size = buffer_size_arg(cmd);
+            let metadata: Vec<(&str, &str)> = cmd
+                .values_of("metadata")
+                .map(|pairs| pairs.map(split_pair).collect())
+                .unwrap_or_default();
+            let comments: Vec<(&str, &str)> = cmd
+                .values_of("comment")
+                .map(|pairs| pairs.map(split_pair).collect())
+                .unwrap_or_default();
+
+            let staged = to_stdout.then(|| {
+                let (path, _) =
+                    create_temp_file(&temp_dir_arg(cmd), "qz-stdout").unwrap_or_else(|err| {
+                        eprintln!("Failed to create temporary archive: {err}");
+                        std::process::exit(1);
+                    });
+                path
+            });
+            if let Some(staged) = &staged {
+                archive_file = staged.to_str().unwrap().to_string();
+            }
+
+            // A single directory is packed as the archive root,
+            // anything else is stored under its own name
+            let builder = if let Some(list) = &file_list {
+                let files: Vec<&str> = list
+                    .lines()
+                    .map(|l| l.trim_end_matches('\r'))
+                    .filter(|l| !l.is_empty())
+                    .collect();
+                let base = targets.first().copied().unwrap_or(".");
+                qz::QZArchiveBuilder::with_files(base, &files, &archive_file)
+            } else if targets.len() == 1 && std::path::Path::new(targets[0]).is_dir() {
+                qz::QZArchiveBuilder::new(targets[0], &archive_file)
+            } else {
+                qz::QZArchiveBuilder::with_sources(&targets, &archive_file)
+            };
+            let mut builder = builder
+                .name(&name)
+                .description(&description)
+                .compression_config(config)
+                .verify(verify);
+
+            if cmd.is_present("skip errors") {
+                builder = builder.skip_errors(true);
+            }
+            if cmd.is_present("resume") {
+                builder = builder.resume(true);
+            }
+            if cmd.is_present("no recursion") {
+                builder = builder.recursive(false);
+            }
+            if cmd.is_present("no hidden") {
+                builder = builder.hidden(false);
+            }
+            if cmd.is_present("order by extension") {
+                builder = builder.file_order(qz::FileOrder::Extension);
+            }
+            if let Some(paths) = order {
+                builder = builder.file_order(qz::FileOrder::Paths(paths));
+            }
+            if cmd.is_present("relative symlinks") {
+                builder = builder.relative_symlinks(true);
+            }
+            if cmd.is_present("text") {
+                builder = builder.normalize_line_endings(true);
+            }
+            if cmd.is_present("reproducible") {
+                builder = builder.reproducible(true);
+            }
+            if let Some(root_name) = cmd.value_of("root name") {
+                builder = builder.root_name(root_name);
+            }
+            if let Some(base) = cmd.value_of("base") {
+                builder = builder.base(base);
+            }
+            builder = builder
+                .threads(threads_arg(cmd))
+                .io_threads(io_threads_arg(cmd));
+            if let Some(dir) = cmd.value_of("temp dir") {
+                builder = builder.temp_dir(dir);
+            }
+            if cmd.is_present("fail on special") {
+                builder = builder.special_files(qz::SpecialFiles::Error);
+            }
+            if cmd.is_present("no header compression") {
+                builder = builder.header_compression(qz::CompressionAlgo::NONE);
+            }
+
+            #[cfg(feature = "xattr")]
+            {
+                builder = builder.xattrs(cmd.is_present("xattrs"));
+            }
+
+            #[cfg(feature = "recovery")]
+            if let Some(blocks) = recovery {
+                builder = builder.recovery(blocks);
+            }
+            #[cfg(not(feature = "recovery"))]
+            let _ = recovery;
+
+            if let Some(volume_size) = volume_size {
+                builder = builder.volume_size(volume_size);
+            }
+
+            if let Some(buffer_size) = buffer_size {
+                builder = builder.buffer_size(buffer_size);
+            }
+
+            for (key, value) in metadata {
+                builder = builder.metadata(key, value);
+            }
+
+            for (path, comment) in comments {
+                builder = builder.comment(path, comment);
+            }
+
+            let bar = progress_bar(cmd);
+            builder = builder.progress(progress_sink(&bar, cmd));
+            let res = builder.create_with_stats();
+            finish_progress(&bar);
+            let stats = match res {
+                Ok(stats) => stats,
+                Err(err) => {
+                    if let Some(staged) = &staged {
+                        let _ = std::fs::remove_file(staged);
+                    }
+                    eprintln!("Error creating archive: {err}");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(staged) = &staged {
+                if let Err(err) = send_staged(staged, &mut std::io::stdout().lock()) {
+                    eprintln!("Error writing archive to stdout: {err}");
+                    std::process::exit(1);
+                }
+            }
+            let report = if cmd.is_present("stats") {
+                Some(format_stats(&stats))
+            } else if cmd.is_present("verbose") {
+                Some(format_summary(&stats))
+            } else {
+                None
+            };
+            if let Some(report) = report {
+                // Stdout may carry the archive
+                if to_stdout {
+                    eprintln!("{report}");
+                } else {
+                    println!("{report}");
+                }
+            }
+        }
+        ("index", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let index_file = match cmd.value_of("output") {
+                Some(output) => output.to_string(),
+                None => std::path::Path::new(archive_file)
+                    .with_extension("qzidx")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            let res = std::fs::File::create(&index_file).and_then(|f| {
+                let mut out = std::io::BufWriter::new(f);
+                a.write_index(&mut out)?;
+                out.into_inner()?.sync_all()
+            });
+            if let Err(err) = res {
+                eprintln!("Failed to write index {index_file}: {err}");
+                std::process::exit(1);
+            }
+        }
+        ("fsck", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            let issues = a.check_layout().unwrap_or_else(|err| {
+                eprintln!("Error checking archive: {err}");
+                std::process::exit(1);
+            });
+            if issues.is_empty() {
+                status(cmd, "Layout ok");
+            } else {
+                for issue in &issues {
+                    println!("{issue}");
+                }
+                std::process::exit(1);
+            }
         }
         ("test", Some(cmd)) => {
             let archive_file = cmd.value_of("archive").unwrap();
-            let a = read_archive(archive_file).unwrap();
-
-            fn check_recursive(a: &qz::QZArchive, path: &str) {
-                //println!("checking path {}", &path);
-                let dir_content = a.ls(path).unwrap();
-                for f in dir_content {
-                    let entry = a
-                        .get_entry(std::path::Path::new(path).join(f).to_str().unwrap())
-                        .unwrap();
-                    match entry {
-                        qz::QZEntry::Dir(d) => {
-                            check_recursive(
-                                a,
-                                std::path::Path::new(path).join(&d.name).to_str().unwrap(),
-                            );
+            let mut a = match read_archive(cmd, archive_file) {
+                Ok(a) => a,
+                Err(err) => {
+                    eprintln!("Error reading archive: {err}");
+                    std::process::exit(255);
+                }
+            };
+            if let Err(err) = a.set_threads(threads_arg(cmd)) {
+                eprintln!("Error checking archive: {err}");
+                std::process::exit(255);
+            }
+
+            // With `--remember` a check that passed is recorded next to the archive with its
+            // fingerprint, archives read from stdin have no place for it
+            let deep = cmd.is_present("deep");
+            let verified_file = (cmd.is_present("remember") && archive_file != "-").then(|| {
+                format!(
+                    "{}.verified",
+                    qz::volume::volume_base(archive_file).unwrap_or(archive_file)
+                )
+            });
+            let fingerprint = match verified_file.as_ref().map(|_| a.fingerprint()) {
+                Some(Ok(fingerprint)) => fingerprint,
+                Some(Err(err)) => {
+                    eprintln!("Error reading archive: {err}");
+                    std::process::exit(255);
+                }
+                None => String::new(),
+            };
+            let verified = verified_file
+                .as_ref()
+                .and_then(|f| std::fs::read_to_string(f).ok());
+            if !cmd.is_present("force")
+                && verified
+                    .as_deref()
+                    .is_some_and(|verified| covers_check(verified, &fingerprint, deep))
+            {
+                status(cmd, "Everything ok (unchanged since last verified)");
+                return;
+            }
+
+            // Cheap enough to catch truncated archives before reading any file
+            if let Err(err) = a.check_body_len() {
+                if let Some(verified_file) = &verified_file {
+                    let _ = std::fs::remove_file(verified_file);
+                }
+                let reason = match err {
+                    qz::errors::FileReadError::Other(reason) => reason,
+                    err => err.to_string(),
+                };
+                eprintln!("Error checking archive: Damaged body ({reason})");
+                std::process::exit(1);
+            }
+
+            let bar = progress_bar(cmd);
+            a.set_progress(progress_sink(&bar, cmd));
+            let res = if cmd.is_present("quick") {
+                a.check_archive_quick()
+            } else if deep {
+                a.check_archive_deep()
+            } else {
+                a.check_archive()
+            };
+            finish_progress(&bar);
+
+            if let Err(failed) = res {
+                if let Some(verified_file) = &verified_file {
+                    let _ = std::fs::remove_file(verified_file);
+                }
+                for (path, err) in &failed {
+                    match err {
+                        qz::errors::FileReadError::Checksum(real, exp) => {
+                            eprintln!("Error checking archive: Damaged file {path} (Expected Checksum {exp} but got {real})");
+                        }
+                        qz::errors::FileReadError::CompressionError(_, reason) => {
+                            eprintln!("Error checking archive: Damaged file {path} (checksum matches but data fails to decompress, {reason})");
                         }
-                        qz::QZEntry::File(file) => {
-                            let f_path = std::path::Path::new(path).join(&file.name);
-                            //println!("checking file path {}", f_path.to_str().unwrap());
-                            let res = a.check_file(f_path.to_str().unwrap());
-                            if let Err(err) = res {
-                                if let qz::errors::FileReadError::Checksum(real, exp) = err {
-                                    println!("Error checking archive: Damaged file {} (Expected Checksum {} but got {})", f_path.to_str().unwrap(), exp, real);
-                                }
-                                std::process::exit(1);
-                            }
+                        qz::errors::FileReadError::Truncated => {
+                            eprintln!("Error checking archive: Truncated file {path} (archive ends within its data)");
+                        }
+                        err => {
+                            eprintln!("Error checking archive: Damaged file {path} ({err})");
                         }
                     }
                 }
+                std::process::exit(damaged_exit_code(failed.len()));
             }
 
-            check_recursive(&a, "/");
-            println!("Everything ok")
+            if let Some(verified_file) = &verified_file {
+                // A forced check keeps record of an earlier deep one of the same archive
+                let was_deep = verified
+                    .as_deref()
+                    .is_some_and(|verified| covers_check(verified, &fingerprint, true));
+                let check = if deep || was_deep { "deep" } else { "stored" };
+                if let Err(err) = std::fs::write(verified_file, format!("{fingerprint} {check}\n"))
+                {
+                    eprintln!("Warning: failed to write {verified_file}: {err}");
+                }
+            }
+            status(cmd, "Everything ok");
         }
-        ("extract", Some(_)) => {
-            todo!();
+        ("extract", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let dest = cmd.value_of("target directory").unwrap_or(".");
+            let paths: Vec<String> = match cmd.values_of("path") {
+                Some(paths) => paths.map(archive_path).collect(),
+                None => vec!["/".to_string()],
+            };
+
+            let strip = cmd.value_of("strip components").unwrap();
+            let strip: usize = match strip.parse() {
+                Ok(strip) => strip,
+                Err(_) => {
+                    eprintln!("Invalid number of components '{strip}'");
+                    std::process::exit(1);
+                }
+            };
+
+            let mut a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            if let Some(buffer_size) = buffer_size_arg(cmd) {
+                a.set_buffer_size(buffer_size);
+            }
+            a.set_preserve_owner(cmd.is_present("preserve owner"));
+            a.set_resume(cmd.is_present("resume"));
+            a.set_flatten(cmd.is_present("flatten"));
+            a.set_restore_line_endings(cmd.is_present("crlf"));
+            a.set_io_threads(io_threads_arg(cmd));
+            a.set_verify_written(cmd.is_present("verify written"));
+            if let Err(err) = a.set_threads(threads_arg(cmd)) {
+                eprintln!("Error extracting archive: {err}");
+                std::process::exit(1);
+            }
+            check_xattrs_arg(cmd);
+            #[cfg(feature = "xattr")]
+            a.set_restore_xattrs(cmd.is_present("xattrs"));
+            let bar = progress_bar(cmd);
+            let counter = progress_sink(&bar, cmd);
+            a.set_progress(counter.clone());
+            let started = std::time::Instant::now();
+            let res = a.extract_paths(&paths, dest, strip);
+            let elapsed = started.elapsed();
+            finish_progress(&bar);
+            match res {
+                Ok(_) if cmd.is_present("stats") => {
+                    let bytes = counter.bar.position();
+                    println!("Files: {}", counter.done.load(Ordering::Relaxed));
+                    println!("Extracted: {}", size(bytes));
+                    println!("Elapsed: {elapsed:.2?} ({})", throughput(bytes, elapsed));
+                }
+                Ok(_) => {}
+                Err(qz::errors::FileReadError::Missing(missing)) => {
+                    for path in missing {
+                        eprintln!("Not found in archive: {path}");
+                    }
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Error extracting archive: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("dict", Some(cmd)) => match cmd.subcommand() {
+            ("extract", Some(cmd)) => {
+                let archive_file = cmd.value_of("archive").unwrap();
+                let output = cmd.value_of("output").unwrap();
+                let a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                    eprintln!("Error reading archive: {err}");
+                    std::process::exit(1);
+                });
+                let Some(dict) = a.export_dictionary() else {
+                    eprintln!("Archive {archive_file} has no dictionary");
+                    std::process::exit(1);
+                };
+                if let Err(err) = std::fs::write(output, dict) {
+                    eprintln!("Failed to write dictionary {output}: {err}");
+                    std::process::exit(1);
+                }
+            }
+            _ => {
+                println!("{}", cmd.usage());
+            }
+        },
+        #[cfg(not(feature = "sfx"))]
+        ("sfx", Some(_)) => {
+            eprintln!("This build of qz does not support self-extracting binaries");
+            std::process::exit(1);
+        }
+        #[cfg(feature = "sfx")]
+        ("sfx", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let output = cmd.value_of("output").unwrap();
+            if let Err(err) = sfx::build(archive_file, output) {
+                eprintln!("Error building {output}: {err}");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(not(feature = "fuse"))]
+        ("mount", Some(_)) => {
+            eprintln!("This build of qz does not support mounting archives");
+            std::process::exit(1);
+        }
+        #[cfg(feature = "fuse")]
+        ("mount", Some(cmd)) => {
+            let archive_file = cmd.value_of("archive").unwrap();
+            let mountpoint = cmd.value_of("mountpoint").unwrap();
+            let cache_size = cache_size_arg(cmd).unwrap_or(mount::DEFAULT_CACHE_SIZE);
+
+            let mut a = read_archive(cmd, archive_file).unwrap_or_else(|err| {
+                eprintln!("Error reading archive: {err}");
+                std::process::exit(1);
+            });
+            a.set_cache_size(cache_size);
+            if !std::path::Path::new(mountpoint).is_dir() {
+                eprintln!("Mountpoint {mountpoint} is not a directory");
+                std::process::exit(1);
+            }
+            if let Err(err) = mount::mount(a, archive_file, mountpoint) {
+                eprintln!("Error mounting archive: {err}");
+                std::process::exit(1);
+            }
         }
         _ => {
             println!("{}", args.usage());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_only_take_the_flags_they_use() {
+        let parse = |args: &[&str]| app().get_matches_from_safe(args).is_ok();
+        assert!(parse(&[
+            "qz",
+            "extract",
+            "--threads",
+            "2",
+            "--io-threads",
+            "1",
+            "a.qz"
+        ]));
+        assert!(parse(&["qz", "test", "--threads", "2", "--strict", "a.qz"]));
+        assert!(parse(&[
+            "qz",
+            "ls",
+            "--temp-dir",
+            "/tmp",
+            "--strict",
+            "a.qz"
+        ]));
+        assert!(!parse(&["qz", "ls", "--threads", "2", "a.qz"]));
+        assert!(!parse(&["qz", "test", "--io-threads", "2", "a.qz"]));
+        assert!(!parse(&["qz", "bench", "--strict", "dir"]));
+        assert!(parse(&["qz", "new", "--fail-on-special", "a.qz", "dir"]));
+        assert!(!parse(&["qz", "new", "--strict", "a.qz", "dir"]));
+        assert!(!parse(&["qz", "--strict", "info", "a.qz"]));
+    }
+
+    #[test]
+    fn remembered_checks_cover_only_the_same_archive() {
+        assert!(covers_check("12 0000abcd deep\n", "12 0000abcd", true));
+        assert!(covers_check("12 0000abcd deep\n", "12 0000abcd", false));
+        assert!(covers_check("12 0000abcd stored\n", "12 0000abcd", false));
+        assert!(!covers_check("12 0000abcd stored\n", "12 0000abcd", true));
+        assert!(!covers_check("12 0000abcd deep\n", "12 0000abce", false));
+        assert!(!covers_check("12 0000abcd\n", "12 0000abcd", false));
+        assert!(!covers_check("", "12 0000abcd", false));
+    }
+
+    #[test]
+    fn exit_codes_count_damaged_files_up_to_255() {
+        assert_eq!(damaged_exit_code(1), 1);
+        assert_eq!(damaged_exit_code(254), 254);
+        assert_eq!(damaged_exit_code(255), 255);
+        assert_eq!(damaged_exit_code(1000), 255);
+    }
+
+    #[test]
+    fn long_listing_shows_the_compression_of_files() {
+        let dir = std::env::temp_dir().join(format!("qz-ls-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.txt"), "alpha ".repeat(100)).unwrap();
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        qz::QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .compression(qz::CompressionAlgo::LZ4)
+            .create()
+            .unwrap();
+        let a = qz::read_archive(out_file).unwrap();
+
+        let file = a.get_entry("/a.txt").unwrap();
+        let line = listing_line("/a.txt", &file, true);
+        let columns: Vec<&str> = line.split('\t').collect();
+        assert_eq!(columns[1], "lz4");
+        assert_eq!(columns[3], "/a.txt");
+        assert!(!listing_line("/a.txt", &file, false).contains("lz4"));
+        let sub = a.get_entry("/sub").unwrap();
+        assert_eq!(listing_line("/sub", &sub, true), "-\t-\t-\t/sub");
+        let _ = std::fs::remove_file(out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn temp_files_get_fresh_private_names() {
+        let dir = std::env::temp_dir();
+        let (a, _) = create_temp_file(&dir, "qz-test").unwrap();
+        let (b, _) = create_temp_file(&dir, "qz-test").unwrap();
+        assert_ne!(a, b);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&a).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+        let _ = std::fs::remove_file(&a);
+        let _ = std::fs::remove_file(&b);
+    }
+
+    #[test]
+    fn archives_round_trip_through_pipes() {
+        let dir = std::env::temp_dir().join(format!("qz-pipe-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "alpha").unwrap();
+        let tmp = std::env::temp_dir();
+        let (staged, _) = create_temp_file(&tmp, "qz-stdout").unwrap();
+        qz::QZArchiveBuilder::new(dir.to_str().unwrap(), staged.to_str().unwrap())
+            .create()
+            .unwrap();
+
+        let mut pipe = vec![];
+        send_staged(&staged, &mut pipe).unwrap();
+        assert!(!staged.exists());
+        let file = buffer_archive(&tmp, &mut pipe.as_slice()).unwrap();
+        let a = qz::read_archive_from_file(file).unwrap();
+        assert_eq!(a.read_file("/a.txt").unwrap(), b"alpha");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn archive_paths_are_normalized() {
+        assert_eq!(archive_path("dir///sub"), "/dir/sub");
+        assert_eq!(archive_path("dir/sub/"), "/dir/sub");
+        assert_eq!(archive_path("//dir//sub//"), "/dir/sub");
+        assert_eq!(archive_path("/"), "/");
+        assert_eq!(archive_path("///"), "/");
+        assert_eq!(archive_path(""), "/");
+    }
+
+    #[test]
+    fn tree_shows_every_entry_in_order() {
+        let dir = std::env::temp_dir().join(format!("qz-tree-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub/deep")).unwrap();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("a.txt"), "alpha").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "beta").unwrap();
+        std::fs::write(dir.join("sub/deep/c.txt"), "gamma").unwrap();
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+        // Stored sorted by name, not in the order the filesystem lists them
+        qz::QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .reproducible(true)
+            .create()
+            .unwrap();
+        let a = qz::read_archive(out_file).unwrap();
+
+        let expected = [
+            "/ (14 B)",
+            "├── a.txt (5 B)",
+            "├── empty/ (0 B)",
+            "└── sub/ (9 B)",
+            "    ├── b.txt (4 B)",
+            "    └── deep/ (5 B)",
+            "        └── c.txt (5 B)",
+        ];
+        assert_eq!(tree_lines(&a), expected);
+        let _ = std::fs::remove_file(out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn quiet_mode_prints_only_errors() {
+        for args in [
+            &["qz", "-q", "test", "a.qz"][..],
+            &["qz", "test", "--quiet", "a.qz"],
+            &["qz", "extract", "-q", "a.qz"],
+            &["qz", "new", "a.qz", "dir", "-q"],
+        ] {
+            let matches = app().get_matches_from_safe(args).unwrap();
+            let (_, cmd) = matches.subcommand();
+            let cmd = cmd.unwrap();
+            let mut out = vec![];
+            write_status(&mut out, cmd, "Everything ok");
+            assert!(out.is_empty(), "{args:?}");
+            assert!(progress_bar(cmd).is_none());
+            assert!(!hidden_progress_bar(cmd).warnings);
+        }
+
+        let matches = app().get_matches_from_safe(["qz", "test", "a.qz"]).unwrap();
+        let cmd = matches.subcommand_matches("test").unwrap();
+        let mut out = vec![];
+        write_status(&mut out, cmd, "Everything ok");
+        assert_eq!(out, b"Everything ok\n");
+        assert!(hidden_progress_bar(cmd).warnings);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn summary_counts_what_was_packed_and_left_out() {
+        let dir = std::env::temp_dir().join(format!("qz-summary-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        std::fs::create_dir_all(dir.join(".cache")).unwrap();
+        std::fs::write(dir.join("a.txt"), "alpha").unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "beta").unwrap();
+        std::fs::write(dir.join(".hidden"), "secret").unwrap();
+        std::fs::write(dir.join(".cache/c"), "c").unwrap();
+        std::fs::hard_link(dir.join("a.txt"), dir.join("sub/deeper/a2.txt")).unwrap();
+        let out_file = dir.with_extension("qz");
+        let out_file = out_file.to_str().unwrap();
+
+        let stats = qz::QZArchiveBuilder::new(dir.to_str().unwrap(), out_file)
+            .hidden(false)
+            .skip_errors(true)
+            .create_with_stats()
+            .unwrap();
+        assert_eq!(
+            format_summary(&stats),
+            "Files: 3\nDirectories: 2\nHard links: 1\nLeft out: 2\nUnreadable: 0"
+        );
+        assert!(format_stats(&stats).starts_with(&format_summary(&stats)));
+        let _ = std::fs::remove_file(out_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
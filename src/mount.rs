@@ -0,0 +1,321 @@
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime};
+
+/// Bytes of decompressed files kept in memory unless configured otherwise
+pub const DEFAULT_CACHE_SIZE: usize = 256 * 1024 * 1024;
+
+// Nothing changes while mounted, so the kernel may keep attributes and lookups
+const TTL: Duration = Duration::from_secs(60);
+
+// An entry of the archive, its inode is its index in `ArchiveFs::nodes` plus one
+struct Node {
+    path: String,
+    kind: FileType,
+    // Size of the content, unknown for compressed files of archives not recording it
+    size: Option<u64>,
+    nlink: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    target: Option<String>,
+    parent: u64,
+    children: Vec<(String, u64)>,
+}
+
+impl Node {
+    fn new(path: String, kind: FileType, parent: u64) -> Node {
+        Node {
+            path,
+            kind,
+            size: None,
+            nlink: 1,
+            uid: None,
+            gid: None,
+            target: None,
+            parent,
+            children: vec![],
+        }
+    }
+}
+
+// Content size of `f` as far as the header tells
+fn file_size(f: &qz::QZFile) -> Option<u64> {
+    match (f.original_size, &f.compression) {
+        (Some(size), _) => Some(size),
+        (None, qz::CompressionAlgo::NONE) => Some(f.index_size),
+        (None, _) => None,
+    }
+}
+
+/// Read-only view of an archive as a FUSE filesystem
+///
+/// The inode table is built from the header when mounting. Hard links share the inode
+/// of the file they link to. Entries without a stored owner belong to the owner
+/// of the archive file, all entries carry its modification time.
+pub struct ArchiveFs {
+    archive: qz::QZArchive,
+    nodes: Vec<Node>,
+    mtime: SystemTime,
+    uid: u32,
+    gid: u32,
+}
+
+impl ArchiveFs {
+    pub fn new(archive: qz::QZArchive, archive_file: &str) -> std::io::Result<ArchiveFs> {
+        // A split archive may only exist as its volumes
+        let meta = std::fs::metadata(archive_file)
+            .or_else(|_| std::fs::metadata(qz::volume::volume_path(archive_file, 0)))?;
+
+        let mut root = Node::new("/".to_string(), FileType::Directory, fuser::FUSE_ROOT_ID);
+        if let qz::QZEntry::Dir(d) = archive.header.root() {
+            root.uid = d.uid;
+            root.gid = d.gid;
+        }
+        let mut fs = ArchiveFs {
+            archive,
+            nodes: vec![root],
+            mtime: meta.modified()?,
+            uid: meta.uid(),
+            gid: meta.gid(),
+        };
+        fs.build();
+        Ok(fs)
+    }
+
+    // Fill the inode table from the header
+    fn build(&mut self) {
+        let root = self.archive.header.root().clone();
+        let mut by_path = HashMap::new();
+        let mut links = vec![];
+        let mut stack = vec![(fuser::FUSE_ROOT_ID, &root)];
+
+        while let Some((parent, entry)) = stack.pop() {
+            let qz::QZEntry::Dir(d) = entry else {
+                continue;
+            };
+            for c in &d.content {
+                let path = match self.nodes[parent as usize - 1].path.as_str() {
+                    "/" => format!("/{}", c.name()),
+                    p => format!("{p}/{}", c.name()),
+                };
+                let mut node = match c {
+                    qz::QZEntry::Dir(d) => {
+                        let mut node = Node::new(path.clone(), FileType::Directory, parent);
+                        node.uid = d.uid;
+                        node.gid = d.gid;
+                        node
+                    }
+                    qz::QZEntry::File(f) => {
+                        if let Some(first) = &f.hard_link {
+                            links.push((parent, c.name().to_string(), first.clone(), f));
+                            continue;
+                        }
+                        let mut node = match &f.symlink {
+                            Some(target) => {
+                                let mut node = Node::new(path.clone(), FileType::Symlink, parent);
+                                node.size = Some(target.len() as u64);
+                                node.target = Some(target.clone());
+                                node
+                            }
+                            None => {
+                                let mut node =
+                                    Node::new(path.clone(), FileType::RegularFile, parent);
+                                node.size = file_size(f);
+                                node
+                            }
+                        };
+                        node.uid = f.uid;
+                        node.gid = f.gid;
+                        node
+                    }
+                };
+                let ino = self.nodes.len() as u64 + 1;
+                node.parent = parent;
+                self.nodes.push(node);
+                self.nodes[parent as usize - 1]
+                    .children
+                    .push((c.name().to_string(), ino));
+                by_path.insert(path, ino);
+                if let qz::QZEntry::Dir(_) = c {
+                    stack.push((ino, c));
+                }
+            }
+        }
+
+        // Links go last, so the file they point at has its inode regardless of order
+        for (parent, name, first, f) in links {
+            let ino = match by_path.get(&format!("/{}", first.trim_start_matches('/'))) {
+                Some(ino) => {
+                    self.nodes[*ino as usize - 1].nlink += 1;
+                    *ino
+                }
+                None => {
+                    let path = match self.nodes[parent as usize - 1].path.as_str() {
+                        "/" => format!("/{name}"),
+                        p => format!("{p}/{name}"),
+                    };
+                    let mut node = Node::new(path, FileType::RegularFile, parent);
+                    node.size = file_size(f);
+                    node.uid = f.uid;
+                    node.gid = f.gid;
+                    self.nodes.push(node);
+                    self.nodes.len() as u64
+                }
+            };
+            self.nodes[parent as usize - 1].children.push((name, ino));
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn attr(&mut self, ino: u64) -> Option<FileAttr> {
+        let node = self.nodes.get_mut((ino as usize).checked_sub(1)?)?;
+
+        // Files whose size the header does not record are decompressed once to learn it
+        let size = match node.size {
+            Some(size) => size,
+            None => {
+                let size = match self.archive.read_file(&node.path) {
+                    Ok(data) => data.len() as u64,
+                    Err(_) => 0,
+                };
+                node.size = Some(size);
+                size
+            }
+        };
+
+        let perm = match node.kind {
+            FileType::Directory => 0o555,
+            FileType::Symlink => 0o777,
+            _ => 0o444,
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            crtime: self.mtime,
+            kind: node.kind,
+            perm,
+            nlink: node.nlink,
+            uid: node.uid.unwrap_or(self.uid),
+            gid: node.gid.unwrap_or(self.gid),
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for ArchiveFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = self.node(parent).and_then(|node| {
+            node.children
+                .iter()
+                .find(|(c_name, _)| OsStr::new(c_name) == name)
+                .map(|(_, ino)| *ino)
+        });
+        match child.and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino).and_then(|node| node.target.as_ref()) {
+            Some(target) => reply.data(target.as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if node.kind == FileType::Directory {
+            reply.error(libc::EISDIR);
+            return;
+        }
+        let Ok(offset) = u64::try_from(offset) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        match self.archive.read_range(&node.path, offset, size as usize) {
+            Ok(data) => reply.data(&data),
+            Err(err) => {
+                eprintln!("Error reading {}: {err}", node.path);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if node.kind != FileType::Directory {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+
+        let entries = [(ino, "."), (node.parent, "..")].into_iter().chain(
+            node.children
+                .iter()
+                .map(|(name, ino)| (*ino, name.as_str())),
+        );
+        for (i, (c_ino, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            let kind = self.nodes[c_ino as usize - 1].kind;
+            // `offset` of an entry is where to continue after it
+            if reply.add(c_ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Serve `archive` read-only at `mountpoint`, returning once it is unmounted
+pub fn mount(archive: qz::QZArchive, archive_file: &str, mountpoint: &str) -> std::io::Result<()> {
+    let fs = ArchiveFs::new(archive, archive_file)?;
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(archive_file.to_string()),
+        MountOption::Subtype("qz".to_string()),
+    ];
+    fuser::mount2(fs, mountpoint, &options)
+}
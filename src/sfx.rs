@@ -0,0 +1,151 @@
+use std::io::{Read, Seek, Write};
+
+// Ends a self-extracting binary, followed by offset and length of the archive
+// in little endian
+const TRAILER_MAGIC: &[u8; 8] = b"QZSFX\0\0\x01";
+const TRAILER_SIZE: u64 = 24;
+
+// Offset and length of the archive appended to the executable at `path`, if any
+fn appended_archive(path: &std::path::Path) -> std::io::Result<Option<(u64, u64)>> {
+    let mut f = std::fs::File::open(path)?;
+    let len = f.metadata()?.len();
+    if len < TRAILER_SIZE {
+        return Ok(None);
+    }
+    let mut trailer = [0u8; TRAILER_SIZE as usize];
+    f.seek(std::io::SeekFrom::Start(len - TRAILER_SIZE))?;
+    f.read_exact(&mut trailer)?;
+    if &trailer[16..] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+    let start = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let archive_len = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+    if start.saturating_add(archive_len) > len - TRAILER_SIZE {
+        return Ok(None);
+    }
+    Ok(Some((start, archive_len)))
+}
+
+/// Path of the running executable and the archive appended to it,
+/// `None` if it is a plain qz binary
+pub fn embedded() -> Option<(String, u64, u64)> {
+    let exe = std::env::current_exe().ok()?;
+    let (start, len) = appended_archive(&exe).ok()??;
+    Some((exe.to_str()?.to_string(), start, len))
+}
+
+/// Extract the archive appended to this executable into the current directory,
+/// returning the exit code
+pub fn run(exe: &str, start: u64, len: u64) -> i32 {
+    let a = match qz::read_archive_range(exe, start, len) {
+        Ok(a) => a,
+        Err(err) => {
+            eprintln!("Error reading bundled archive: {err}");
+            return 1;
+        }
+    };
+    let name = match a.name() {
+        "" => "archive".to_string(),
+        name => format!("'{name}'"),
+    };
+    println!("Extracting {name} into the current directory");
+    if let Err(err) = a.extract_all(".", 0) {
+        eprintln!("Error extracting: {err}");
+        return 1;
+    }
+    0
+}
+
+/// Write the running qz executable followed by `archive_file` to `out_file`,
+/// giving a binary that extracts the archive when run
+///
+/// The archive is checked before anything is written. Split archives have to be
+/// joined first.
+pub fn build(archive_file: &str, out_file: &str) -> Result<(), String> {
+    let a = qz::read_archive(archive_file).map_err(|err| err.to_string())?;
+    if a.header.volumes.is_some() {
+        return Err(format!(
+            "{archive_file} is split into volumes, join them with qz join first"
+        ));
+    }
+    if let Err(failed) = a.check_archive_quick() {
+        let paths: Vec<String> = failed.into_iter().map(|(path, _)| path).collect();
+        return Err(format!("damaged files: {}", paths.join(", ")));
+    }
+
+    let exe = std::env::current_exe().map_err(|err| format!("failed to find qz: {err}"))?;
+    let stub_len = match appended_archive(&exe) {
+        Ok(Some((start, _))) => start,
+        Ok(None) => std::fs::metadata(&exe)
+            .map_err(|err| err.to_string())?
+            .len(),
+        Err(err) => return Err(format!("failed to read {}: {err}", exe.display())),
+    };
+
+    let write = || -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(out_file)?);
+        std::io::copy(&mut std::fs::File::open(&exe)?.take(stub_len), &mut out)?;
+        let archive_len = std::io::copy(&mut std::fs::File::open(archive_file)?, &mut out)?;
+        out.write_all(&stub_len.to_le_bytes())?;
+        out.write_all(&archive_len.to_le_bytes())?;
+        out.write_all(TRAILER_MAGIC)?;
+        let out = out.into_inner()?;
+        out.sync_all()?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            out.set_permissions(std::fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    };
+    write().map_err(|err| {
+        let _ = std::fs::remove_file(out_file);
+        format!("failed to write {out_file}: {err}")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_binaries_carry_the_archive_to_extract() {
+        let dir = std::env::temp_dir().join(format!("qz-sfx-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/a.txt"), "alpha").unwrap();
+        let archive_file = dir.with_extension("qz");
+        let archive_file = archive_file.to_str().unwrap();
+        qz::QZArchiveBuilder::new(dir.to_str().unwrap(), archive_file)
+            .create()
+            .unwrap();
+        let out_file = format!("{archive_file}.sfx");
+
+        build(archive_file, &out_file).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!(appended_archive(&exe).unwrap(), None);
+        let (start, len) = appended_archive(out_file.as_ref()).unwrap().unwrap();
+        assert_eq!(start, std::fs::metadata(&exe).unwrap().len());
+        assert_eq!(len, std::fs::metadata(archive_file).unwrap().len());
+        let a = qz::read_archive_range(&out_file, start, len).unwrap();
+        assert_eq!(a.read_file("/sub/a.txt").unwrap(), b"alpha");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&out_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+
+        // Damaged archives are refused before anything is written
+        let mut data = std::fs::read(archive_file).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(archive_file, data).unwrap();
+        let _ = std::fs::remove_file(&out_file);
+        let err = build(archive_file, &out_file).unwrap_err();
+        assert!(err.contains("damaged files: /sub/a.txt"), "{err}");
+        assert!(!std::path::Path::new(&out_file).exists());
+        let _ = std::fs::remove_file(archive_file);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}